@@ -1,6 +1,12 @@
 fn build_grpc() {
+    let out_dir = std::path::PathBuf::from(std::env::var("OUT_DIR").unwrap());
     tonic_build::configure()
+        // served by the reflection service
+        .file_descriptor_set_path(out_dir.join("matchengine_descriptor.bin"))
         .type_attribute(".", "#[derive(serde::Serialize, serde::Deserialize)]")
+        // fields added later are missing in old operation logs
+        .type_attribute("matchengine.OrderPutRequest", "#[serde(default)]")
+        .type_attribute("matchengine.AssetRegisterRequest", "#[serde(default)]")
         .compile(
             &["proto/exchange/matchengine.proto"],
             &["proto/exchange", "proto/third_party/googleapis"],
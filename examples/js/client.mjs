@@ -14,6 +14,14 @@ const client = caller(`${server}`, { file, load }, "Matchengine");
 export async function balanceQuery(user_id) {
   const balances = (await client.BalanceQuery({ user_id: user_id })).balances;
   let result = {};
+  // zero balances are omitted by the server
+  for (const asset of (await client.AssetList({})).asset_lists) {
+    result[asset.name] = {
+      asset_name: asset.name,
+      available: "0",
+      frozen: "0"
+    };
+  }
   for (const entry of balances) {
     result[entry.asset_name] = entry;
   }
@@ -38,6 +46,16 @@ export async function balanceUpdate(
   });
 }
 
+export async function adjustBalance(user_id, asset, business_id, delta, reason) {
+  return await client.AdjustBalance({
+    user_id,
+    asset,
+    business_id,
+    delta,
+    reason
+  });
+}
+
 export async function orderPut(
   user_id,
   market,
@@ -76,6 +94,14 @@ export async function marketSummary(market) {
   return (await client.MarketSummary({ market: [market] })).market_summaries;
 }
 
+export async function getKlines(market, interval, from, to, limit) {
+  return (await client.GetKlines({ market, interval, from, to, limit })).klines;
+}
+
+export async function myTrades(user_id, market, from, to, cursor, limit) {
+  return await client.MyTrades({ user_id, market, from, to, cursor, limit });
+}
+
 export async function orderCancel(user_id, market, order_id) {
   return await client.OrderCancel({ user_id, market, order_id });
 }
@@ -99,3 +125,7 @@ export async function debugReset() {
 export async function debugReload() {
   return await client.DebugReload({});
 }
+
+export async function healthCheck() {
+  return await client.HealthCheck({});
+}
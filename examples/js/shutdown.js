@@ -0,0 +1,84 @@
+import { userId, ORDER_SIDE_BID } from "./config.mjs"; // dotenv
+import {
+  balanceQuery,
+  balanceUpdate,
+  healthCheck,
+  debugReset
+} from "./client.mjs";
+import { putLimitOrder, decimalEqual, sleep } from "./util.mjs";
+
+import { spawn } from "child_process";
+import { strict as assert } from "assert";
+
+// Run from this directory with no other matchengine running, the engine is started with
+// the config of the repo root. A SIGTERM must leave a slice covering every operation,
+// so the restarted engine replays nothing and has the same balances.
+const engineBin = process.env.MATCHENGINE_BIN || "../../target/debug/matchengine";
+
+function startEngine() {
+  return spawn(engineBin, [], { cwd: "../..", stdio: "inherit" });
+}
+
+async function waitReady() {
+  for (let i = 0; i < 60; i++) {
+    try {
+      const health = await healthCheck();
+      if (health.ready) {
+        return health;
+      }
+    } catch (error) {
+      // not listening yet
+    }
+    await sleep(1000);
+  }
+  throw new Error("matchengine is not ready in time");
+}
+
+function terminate(engine) {
+  if (engine.exitCode !== null || engine.signalCode !== null) {
+    return { code: engine.exitCode, signal: engine.signalCode };
+  }
+  return new Promise(resolve => {
+    engine.on("exit", (code, signal) => resolve({ code, signal }));
+    engine.kill("SIGTERM");
+  });
+}
+
+async function main() {
+  let engine = startEngine();
+  try {
+    await waitReady();
+    await debugReset();
+
+    // operations are still in the writers when the signal comes
+    const baseId = Date.now() * 1000;
+    const deposits = [];
+    for (let i = 0; i < 100; i++) {
+      deposits.push(
+        balanceUpdate(userId, "USDT", "deposit", baseId + i, "1.5", {})
+      );
+    }
+    await Promise.all(deposits);
+    await putLimitOrder(ORDER_SIDE_BID, "10", "1.1");
+    const balancesBefore = await balanceQuery(userId);
+    decimalEqual(balancesBefore.USDT.available, "139");
+    decimalEqual(balancesBefore.USDT.frozen, "11");
+
+    const exit = await terminate(engine);
+    assert.deepEqual(exit, { code: 0, signal: null });
+
+    engine = startEngine();
+    const health = await waitReady();
+    assert.equal(health.operation_log_id, health.last_slice_operation_log_id);
+    const balancesAfter = await balanceQuery(userId);
+    assert.deepEqual(balancesAfter, balancesBefore);
+    console.log("shutdown test passed");
+  } catch (error) {
+    console.error("Catched error:", error);
+    process.exitCode = 1;
+  } finally {
+    await terminate(engine);
+  }
+}
+
+main();
@@ -0,0 +1,7 @@
+CREATE TABLE trigger_order_slice (
+    id BIGINT CHECK (id >= 0) NOT NULL,
+    slice_id BIGINT NOT NULL,
+    market VARCHAR(30) NOT NULL,
+    params TEXT NOT NULL,
+    PRIMARY KEY (slice_id, id)
+);
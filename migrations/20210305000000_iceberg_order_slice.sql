@@ -0,0 +1,4 @@
+ALTER TABLE order_slice
+    ADD COLUMN display_qty DECIMAL(30, 8) NOT NULL DEFAULT 0,
+    ADD COLUMN visible DECIMAL(30, 8) NOT NULL DEFAULT 0,
+    ADD COLUMN priority BIGINT CHECK (priority >= 0) NOT NULL DEFAULT 0;
@@ -0,0 +1 @@
+ALTER TABLE order_slice ADD COLUMN expire_at TIMESTAMP(0);
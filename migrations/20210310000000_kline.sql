@@ -0,0 +1,12 @@
+CREATE TABLE kline (
+    time TIMESTAMP(0) NOT NULL,
+    market VARCHAR(30) NOT NULL,
+    interval BIGINT CHECK (interval > 0) NOT NULL,
+    open DECIMAL(30, 8) NOT NULL,
+    high DECIMAL(30, 8) NOT NULL,
+    low DECIMAL(30, 8) NOT NULL,
+    close DECIMAL(30, 8) NOT NULL,
+    volume DECIMAL(30, 8) NOT NULL,
+    quote_volume DECIMAL(30, 16) NOT NULL,
+    PRIMARY KEY (market, interval, time)
+);
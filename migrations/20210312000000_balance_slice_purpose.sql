@@ -0,0 +1 @@
+ALTER TABLE balance_slice ADD COLUMN purpose VARCHAR(30) NOT NULL DEFAULT '';
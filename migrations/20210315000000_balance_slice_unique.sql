@@ -0,0 +1 @@
+CREATE UNIQUE INDEX balance_slice_idx_unique ON balance_slice (slice_id, user_id, asset, t, purpose);
@@ -0,0 +1,6 @@
+CREATE TABLE market_state_slice (
+    slice_id BIGINT NOT NULL,
+    market VARCHAR(30) NOT NULL,
+    state VARCHAR(16) NOT NULL,
+    PRIMARY KEY (slice_id, market)
+);
@@ -0,0 +1,2 @@
+ALTER TABLE order_slice ADD COLUMN finished_paid_fee DECIMAL(30, 16) NOT NULL DEFAULT 0;
+ALTER TABLE order_history ADD COLUMN finished_paid_fee DECIMAL(30, 16) NOT NULL DEFAULT 0;
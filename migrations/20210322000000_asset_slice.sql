@@ -0,0 +1,6 @@
+CREATE TABLE asset_slice (
+    slice_id BIGINT NOT NULL,
+    name VARCHAR(30) NOT NULL,
+    params TEXT NOT NULL,
+    PRIMARY KEY (slice_id, name)
+);
@@ -0,0 +1,4 @@
+CREATE TABLE slice_payload (
+    slice_id BIGINT NOT NULL PRIMARY KEY,
+    data BYTEA NOT NULL
+);
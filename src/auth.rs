@@ -0,0 +1,231 @@
+use crate::config::{self, Permission};
+use std::collections::HashMap;
+use thiserror::Error;
+use x509_parser::certificate::X509Certificate;
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum AuthError {
+    #[error("missing api key")]
+    MissingKey,
+    #[error("invalid api key")]
+    InvalidKey,
+    #[error("permission denied")]
+    PermissionDenied,
+    #[error("unknown client certificate")]
+    UnknownCertificate,
+}
+
+impl From<AuthError> for tonic::Status {
+    fn from(e: AuthError) -> tonic::Status {
+        match e {
+            AuthError::MissingKey | AuthError::InvalidKey | AuthError::UnknownCertificate => tonic::Status::unauthenticated(e.to_string()),
+            AuthError::PermissionDenied => tonic::Status::permission_denied(e.to_string()),
+        }
+    }
+}
+
+// what the caller of a request is allowed to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UserScope {
+    pub permission: Permission,
+    pub user_id: Option<u32>,
+}
+
+impl UserScope {
+    // `user_id` is the user the request acts for, if any
+    pub fn authorize(&self, permission: Permission, user_id: Option<u32>) -> Result<(), AuthError> {
+        if self.permission < permission {
+            return Err(AuthError::PermissionDenied);
+        }
+        match (self.user_id, user_id) {
+            (Some(bound), Some(user_id)) if bound != user_id => Err(AuthError::PermissionDenied),
+            _ => Ok(()),
+        }
+    }
+}
+
+pub struct ApiKeyStore {
+    header: String,
+    keys: HashMap<String, UserScope>,
+    names: HashMap<String, String>,
+    // by the common name of the client certificate
+    subjects: HashMap<String, UserScope>,
+}
+
+impl ApiKeyStore {
+    pub fn new(config: &config::AuthConfig) -> ApiKeyStore {
+        let keys = config
+            .keys
+            .iter()
+            .map(|key| {
+                (
+                    key.key.clone(),
+                    UserScope {
+                        permission: key.permission,
+                        user_id: key.user_id,
+                    },
+                )
+            })
+            .collect();
+        let names = config
+            .keys
+            .iter()
+            .filter(|key| !key.name.is_empty())
+            .map(|key| (key.key.clone(), key.name.clone()))
+            .collect();
+        let subjects = config
+            .client_certs
+            .iter()
+            .map(|cert| {
+                (
+                    cert.subject.clone(),
+                    UserScope {
+                        permission: cert.permission,
+                        user_id: cert.user_id,
+                    },
+                )
+            })
+            .collect();
+        ApiKeyStore {
+            header: config.header.to_lowercase(),
+            keys,
+            names,
+            subjects,
+        }
+    }
+    // without any key configured, every call is allowed as before
+    pub fn is_enabled(&self) -> bool {
+        !self.keys.is_empty()
+    }
+    pub fn header(&self) -> &str {
+        &self.header
+    }
+    pub fn authenticate(&self, key: Option<&str>) -> Result<UserScope, AuthError> {
+        if !self.is_enabled() {
+            return Ok(UserScope {
+                permission: Permission::Admin,
+                user_id: None,
+            });
+        }
+        let key = key.ok_or(AuthError::MissingKey)?;
+        self.keys.get(key).copied().ok_or(AuthError::InvalidKey)
+    }
+    // A client certificate with a configured subject gets its scope, whatever the key. The ones of
+    // other subjects are rejected, unless no subject is configured and the key is checked instead.
+    pub fn authenticate_peer(&self, subject: Option<&str>, key: Option<&str>) -> Result<UserScope, AuthError> {
+        match subject {
+            Some(subject) if !self.subjects.is_empty() => self.subjects.get(subject).copied().ok_or(AuthError::UnknownCertificate),
+            _ => self.authenticate(key),
+        }
+    }
+    pub fn key_name(&self, key: Option<&str>) -> Option<&str> {
+        key.and_then(|key| self.names.get(key)).map(String::as_str)
+    }
+    pub fn authorize(&self, key: Option<&str>, permission: Permission, user_id: Option<u32>) -> Result<UserScope, AuthError> {
+        let scope = self.authenticate(key)?;
+        scope.authorize(permission, user_id)?;
+        Ok(scope)
+    }
+}
+
+// the common name of the subject of a der certificate
+pub fn subject_common_name(der: &[u8]) -> Option<String> {
+    let (_, cert): (_, X509Certificate) = x509_parser::parse_x509_certificate(der).ok()?;
+    let common_name = cert.subject().iter_common_name().next()?;
+    common_name.as_str().ok().map(String::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_api_key_store() -> ApiKeyStore {
+        let key = |key: &str, permission, user_id| config::ApiKey {
+            key: key.to_string(),
+            permission,
+            user_id,
+            name: if key == "admin" { "alice".to_string() } else { String::new() },
+        };
+        ApiKeyStore::new(&config::AuthConfig {
+            header: "X-API-KEY".to_string(),
+            keys: vec![
+                key("reader", Permission::ReadOnly, None),
+                key("trader", Permission::Trade, None),
+                key("trader_101", Permission::Trade, Some(101)),
+                key("admin", Permission::Admin, None),
+            ],
+            client_certs: vec![config::ClientCert {
+                subject: "reader-bot".to_string(),
+                permission: Permission::ReadOnly,
+                user_id: None,
+            }],
+        })
+    }
+
+    #[test]
+    fn test_permission_levels() {
+        let store = get_api_key_store();
+        assert_eq!(store.header(), "x-api-key");
+
+        assert!(store.authorize(Some("reader"), Permission::ReadOnly, Some(101)).is_ok());
+        assert_eq!(
+            store.authorize(Some("reader"), Permission::Trade, Some(101)),
+            Err(AuthError::PermissionDenied)
+        );
+
+        assert!(store.authorize(Some("trader"), Permission::ReadOnly, None).is_ok());
+        assert!(store.authorize(Some("trader"), Permission::Trade, Some(101)).is_ok());
+        assert_eq!(
+            store.authorize(Some("trader"), Permission::Admin, None),
+            Err(AuthError::PermissionDenied)
+        );
+
+        assert!(store.authorize(Some("admin"), Permission::Admin, None).is_ok());
+        assert!(store.authorize(Some("admin"), Permission::Trade, Some(102)).is_ok());
+
+        assert_eq!(store.key_name(Some("admin")), Some("alice"));
+        assert_eq!(store.key_name(Some("trader")), None);
+        assert_eq!(store.key_name(None), None);
+    }
+
+    #[test]
+    fn test_user_bound_key() {
+        let store = get_api_key_store();
+        assert!(store.authorize(Some("trader_101"), Permission::Trade, Some(101)).is_ok());
+        assert_eq!(
+            store.authorize(Some("trader_101"), Permission::Trade, Some(102)),
+            Err(AuthError::PermissionDenied)
+        );
+        // requests not acting for a user
+        assert!(store.authorize(Some("trader_101"), Permission::ReadOnly, None).is_ok());
+    }
+
+    #[test]
+    fn test_unauthenticated() {
+        let store = get_api_key_store();
+        assert_eq!(store.authenticate(None), Err(AuthError::MissingKey));
+        assert_eq!(store.authenticate(Some("guess")), Err(AuthError::InvalidKey));
+        assert_eq!(tonic::Status::from(AuthError::InvalidKey).code(), tonic::Code::Unauthenticated);
+
+        let disabled = ApiKeyStore::new(&Default::default());
+        assert!(disabled.authorize(None, Permission::Admin, Some(101)).is_ok());
+    }
+
+    #[test]
+    fn test_client_certificate() {
+        let store = get_api_key_store();
+        let pem = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/certs/reader.pem")).unwrap();
+        let (_, pem) = x509_parser::pem::parse_x509_pem(&pem).unwrap();
+        assert_eq!(subject_common_name(&pem.contents).as_deref(), Some("reader-bot"));
+        assert_eq!(subject_common_name(b"not a certificate"), None);
+
+        let scope = store.authenticate_peer(Some("reader-bot"), None).unwrap();
+        assert_eq!(scope.permission, Permission::ReadOnly);
+        // the certificate decides, not the key
+        assert_eq!(
+            store.authenticate_peer(Some("stranger-bot"), Some("admin")),
+            Err(AuthError::UnknownCertificate)
+        );
+        assert_eq!(store.authenticate_peer(None, Some("trader")).unwrap().permission, Permission::Trade);
+    }
+}
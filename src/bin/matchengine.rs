@@ -5,18 +5,25 @@
 #![allow(clippy::single_char_pattern)]
 #![allow(clippy::await_holding_refcell_ref)] // FIXME
 
+use dingir_exchange::auth::ApiKeyStore;
+use dingir_exchange::command_queue::CommandQueue;
 use dingir_exchange::config;
-use dingir_exchange::controller::Controller;
+use dingir_exchange::controller::{self, Controller};
+use dingir_exchange::database;
+use dingir_exchange::logging;
+use dingir_exchange::metrics;
 use dingir_exchange::persist;
-use dingir_exchange::server::{GrpcHandler, MatchengineServer};
+use dingir_exchange::server::{self, auth_interceptor, GrpcHandler, MatchengineServer};
+use dingir_exchange::websocket::{self, EngineFeeds};
 //use dingir_exchange::sqlxextend;
 
 use dingir_exchange::types::ConnectionType;
 use sqlx::Connection;
+use std::sync::Arc;
 
 fn main() {
     dotenv::dotenv().ok();
-    env_logger::init();
+    logging::init_from_config_file(&dotenv::var("CONFIG_FILE").unwrap_or_default());
     let rt: tokio::runtime::Runtime = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
@@ -24,6 +31,19 @@ fn main() {
 
     rt.block_on(async {
         let stub = prepare().await.expect("Init state error");
+        let auth = Arc::new(ApiKeyStore::new(&stub.settings.auth));
+        if stub.settings.metrics_port != 0 {
+            let addr = ([0, 0, 0, 0], stub.settings.metrics_port).into();
+            let engine_metrics = stub.metrics.clone();
+            tokio::spawn(async move {
+                if let Err(e) = metrics::serve(addr, engine_metrics).await {
+                    tracing::error!("metrics server exit: {}", e);
+                }
+            });
+        }
+        if !auth.is_enabled() {
+            tracing::warn!("no api key is configured, grpc calls are not authenticated");
+        }
         stub.prepare_stub();
         Controller::prepare_runtime(&rt as *const tokio::runtime::Runtime);
 
@@ -33,8 +53,8 @@ fn main() {
                 .build()
                 .expect("build auxiliary runtime");
 
-            println!("start grpc under single-thread runtime");
-            aux_rt.block_on(grpc_run()).unwrap()
+            tracing::info!("start grpc under single-thread runtime");
+            aux_rt.block_on(grpc_run(auth)).unwrap()
         });
 
         tokio::runtime::Handle::current()
@@ -52,37 +72,70 @@ async fn prepare() -> anyhow::Result<Controller> {
     let config_file = dotenv::var("CONFIG_FILE")?;
     conf.merge(config_rs::File::with_name(&config_file)).unwrap();
     let settings: config::Settings = conf.try_into().unwrap();
-    println!("Settings: {:?}", settings);
+    tracing::info!("Settings: {:?}", settings);
+    settings.check()?;
 
     let mut conn = ConnectionType::connect(&settings.db_log).await?;
-    persist::MIGRATOR.run(&mut conn).await?;
+    database::run_migrations(&mut conn, &persist::MIGRATOR).await?;
+    if settings.db_history != settings.db_log {
+        let mut history_conn = ConnectionType::connect(&settings.db_history).await?;
+        database::run_migrations(&mut history_conn, &persist::MIGRATOR).await?;
+    }
     let mut grpc_stub = Controller::new(settings);
     persist::init_from_db(&mut conn, &mut grpc_stub).await?;
+    let balance_seed = grpc_stub.settings.balance_seed.clone();
+    if !balance_seed.is_empty() {
+        grpc_stub.load_balance_seed(&balance_seed)?;
+    }
     Ok(grpc_stub)
 }
 
-async fn grpc_run() -> Result<(), Box<dyn std::error::Error>> {
+async fn grpc_run(auth: Arc<ApiKeyStore>) -> Result<(), Box<dyn std::error::Error>> {
     persist::init_persist_timer();
+    controller::init_order_expire_timer();
+
+    // on this thread as well, since it subscribes to the controller
+    let websocket_config = unsafe { controller::G_STUB.as_ref().unwrap() }.settings.websocket.clone();
+    if websocket_config.port != 0 {
+        let listener = tokio::net::TcpListener::bind(("0.0.0.0", websocket_config.port)).await?;
+        tokio::spawn(websocket::serve(listener, websocket_config, auth.clone(), Arc::new(EngineFeeds)));
+    }
 
     let addr = "0.0.0.0:50051".parse().unwrap();
-    let grpc = GrpcHandler {};
-    println!("Starting gprc service");
+    let stub = unsafe { controller::G_STUB.as_ref().unwrap() };
+    let (commands, command_receiver) = CommandQueue::new(&stub.settings.command_queue, stub.metrics.clone());
+    tokio::spawn(server::run_commands(command_receiver));
+    let reflection = server::reflection_service(stub.settings.grpc_reflection)?;
+    let tls = server::tls_config(&stub.settings.grpc_tls)?;
+    let grpc = GrpcHandler {
+        auth: auth.clone(),
+        commands: Arc::new(commands),
+        snapshot_lock: Default::default(),
+    };
+    tracing::info!("starting grpc service");
 
     let (tx, rx) = tokio::sync::oneshot::channel::<()>();
 
     tokio::spawn(async move {
-        tokio::signal::ctrl_c().await.ok();
-        println!("Ctrl-c received, shutting down");
+        server::shutdown_signal().await;
         tx.send(()).ok();
     });
 
-    tonic::transport::Server::builder()
-        .add_service(MatchengineServer::new(grpc))
+    let mut builder = tonic::transport::Server::builder();
+    match tls {
+        Some(tls) => builder = builder.tls_config(tls)?,
+        None => tracing::warn!("serving grpc in plaintext"),
+    }
+    builder
+        .add_service(MatchengineServer::with_interceptor(grpc, auth_interceptor(auth)))
+        .add_optional_service(reflection)
         .serve_with_shutdown(addr, async {
             rx.await.ok();
         })
         .await?;
 
-    println!("Shutted down");
+    // the running calls are finished now
+    server::graceful_shutdown()?;
+    tracing::info!("shut down");
     Ok(())
 }
@@ -4,9 +4,9 @@
 #![allow(clippy::too_many_arguments)]
 #![allow(clippy::single_char_pattern)]
 
-use database::{DatabaseWriter, DatabaseWriterConfig};
-use dingir_exchange::{config, database, message, models, types};
-use types::{ConnectionType, DbType};
+use database::{DatabaseWriter, DatabaseWriterConfig, INSERT_LIMIT};
+use dingir_exchange::{config, database, logging, message, models, types};
+use types::ConnectionType;
 
 use rdkafka::consumer::{stream_consumer, ConsumerContext, DefaultConsumerContext, StreamConsumer};
 
@@ -27,13 +27,13 @@ use sqlx::Connection;
 
 fn main() {
     dotenv::dotenv().ok();
-    env_logger::init();
 
     let mut conf = config_rs::Config::new();
     let config_file = dotenv::var("CONFIG_FILE").unwrap();
     conf.merge(config_rs::File::with_name(&config_file)).unwrap();
+    logging::init(&conf.get("log").unwrap_or_default());
     let settings: config::Settings = conf.try_into().unwrap();
-    log::debug!("Settings: {:?}", settings);
+    tracing::debug!("Settings: {:?}", settings);
 
     let rt: tokio::runtime::Runtime = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
@@ -56,12 +56,17 @@ fn main() {
             .await
             .ok();
 
-        let pool = sqlx::Pool::<DbType>::connect(&settings.db_history).await.unwrap();
+        let pool = database::pool_options(&settings.db_pool)
+            .connect(&settings.db_history)
+            .await
+            .unwrap();
 
         let persistor: DatabaseWriter<models::TradeRecord> = DatabaseWriter::new(&DatabaseWriterConfig {
             spawn_limit: 4,
             apply_benchmark: true,
             capability_limit: 8192,
+            batch_size: INSERT_LIMIT as usize,
+            flush_interval: std::time::Duration::default(),
         })
         .start_schedule(&pool)
         .unwrap();
@@ -76,12 +81,12 @@ fn main() {
 
             tokio::select! {
                 _ = tokio::signal::ctrl_c() => {
-                    log::info!("Ctrl-c received, shutting down");
+                    tracing::info!("Ctrl-c received, shutting down");
                     break;
                 },
 
                 err = cr_main.run_stream(|cr|cr.stream()) => {
-                    log::error!("Kafka consumer error: {}", err);
+                    tracing::error!("Kafka consumer error: {}", err);
                 }
             }
         }
@@ -5,16 +5,26 @@
 #![allow(clippy::single_char_pattern)]
 #![allow(clippy::await_holding_refcell_ref)] // FIXME
 
-use actix_web::{web, App, HttpRequest, HttpServer, Responder};
-use sqlx::postgres::Postgres;
-use sqlx::Pool;
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::http::header::{self, HeaderName};
+use actix_web::{web, App, HttpMessage, HttpRequest, HttpResponse, HttpServer, Responder};
+use futures::future::{self, Either};
+use futures::FutureExt;
 use std::collections::HashMap;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
+use dingir_exchange::auth::ApiKeyStore;
+use dingir_exchange::config::{self, Permission};
+use dingir_exchange::database;
+use dingir_exchange::logging;
 use dingir_exchange::restapi;
 
-use restapi::personal_history::my_orders;
+use restapi::cors::Cors;
+use restapi::jsonrpc::{jsonrpc, MatchengineBackend};
+use restapi::personal_history::{balance_history, my_orders, orders};
+use restapi::precision::DisplayPrecision;
 use restapi::public_history::{order_trades, recent_trades};
+use restapi::ratelimit::RateLimiter;
 use restapi::state::{AppCache, AppState};
 use restapi::tradingview::{chart_config, history, symbols, ticker, unix_timestamp};
 use restapi::types::UserInfo;
@@ -41,41 +51,122 @@ async fn get_user(req: HttpRequest, data: web::Data<AppState>) -> impl Responder
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv::dotenv().ok();
-    env_logger::init();
     let mut conf = config_rs::Config::new();
     let config_file = dotenv::var("CONFIG_FILE").unwrap();
     conf.merge(config_rs::File::with_name(&config_file)).unwrap();
+    logging::init(&conf.get("log").unwrap_or_default());
 
     let restapi_cfg: Option<config_rs::Value> = conf.get("restapi").ok();
 
-    let dburl = conf.get_str("db_history").unwrap();
-    log::debug!("Prepared db connection: {}", &dburl);
+    // only reads, from the replica if there is one
+    let dburl = conf
+        .get_str("db_history_replica")
+        .ok()
+        .filter(|url| !url.is_empty())
+        .unwrap_or_else(|| conf.get_str("db_history").unwrap());
+    let db_pool: config::DbPoolConfig = conf.get("db_pool").unwrap_or_default();
+    tracing::debug!("Prepared db connection: {}", &dburl);
+
+    // the same assets and markets as the matchengine, for the display precision of the amounts
+    let assets: Vec<config::Asset> = conf.get("assets").unwrap_or_default();
+    let markets: Vec<config::Market> = conf.get("markets").unwrap_or_default();
 
     let user_map = web::Data::new(AppState {
         user_addr_map: Mutex::new(HashMap::new()),
-        db: Pool::<Postgres>::connect(&dburl).await.unwrap(),
+        db: database::pool_options(&db_pool).connect(&dburl).await.unwrap(),
         config: restapi_cfg.and_then(|v| v.try_into().ok()).unwrap_or_else(Default::default),
+        precision: DisplayPrecision::new(&assets, &markets).unwrap(),
     });
 
     let workers = user_map.config.workers;
+    let rate_limiter = Arc::new(RateLimiter::new(&user_map.config.rate_limit));
+    let auth = Arc::new(ApiKeyStore::new(&user_map.config.auth));
+    let cors = Arc::new(Cors::new(&user_map.config.cors));
+    let matchengine = web::Data::new(MatchengineBackend::new(&user_map.config.matchengine).unwrap());
 
     let server = HttpServer::new(move || {
-        App::new().app_data(user_map.clone()).app_data(AppCache::new()).service(
-            web::scope("/restapi")
-                .route("/ping", web::get().to(ping))
-                .route("/user/{id_or_addr}", web::get().to(get_user))
-                .route("/recenttrades/{market}", web::get().to(recent_trades))
-                .route("/ordertrades/{market}/{order_id}", web::get().to(order_trades))
-                .route("/closedorders/{market}/{user_id}", web::get().to(my_orders))
-                .route("/ticker_{ticker_inv}/{market}", web::get().to(ticker))
-                .service(
-                    web::scope("/tradingview")
-                        .route("/time", web::get().to(unix_timestamp))
-                        .route("/config", web::get().to(chart_config))
-                        .route("/symbols", web::get().to(symbols))
-                        .route("/history", web::get().to(history)),
-                ),
-        )
+        let rate_limiter = rate_limiter.clone();
+        let auth = auth.clone();
+        let cors = cors.clone();
+        App::new()
+            .app_data(user_map.clone())
+            .app_data(matchengine.clone())
+            .app_data(AppCache::new())
+            // the handlers restrict user-bound keys with the attached scope
+            .wrap_fn(move |req, srv| {
+                if auth.is_enabled() {
+                    let key = req.headers().get(auth.header()).and_then(|key| key.to_str().ok());
+                    match auth.authorize(key, Permission::ReadOnly, None) {
+                        Ok(scope) => {
+                            HttpMessage::extensions_mut(&req).insert(scope);
+                        }
+                        Err(e) => return Either::Right(future::err(e.into())),
+                    }
+                }
+                Either::Left(srv.call(req))
+            })
+            // the last one runs first, so over-limit clients don't reach the key check
+            .wrap_fn(move |req, srv| {
+                if rate_limiter.is_enabled() {
+                    let client = rate_limiter.client_key(&req);
+                    if let Err(e) = rate_limiter.check(&client, req.path()) {
+                        tracing::debug!("rate limited {} on {}", client, req.path());
+                        return Either::Right(future::err(e.into()));
+                    }
+                }
+                Either::Left(srv.call(req))
+            })
+            // runs before the others: the preflights carry no api key, and the rejections need the CORS headers too
+            .wrap_fn(move |req, srv| {
+                if !cors.is_enabled() {
+                    return Either::Left(Either::Left(srv.call(req)));
+                }
+                let header = |name: HeaderName| req.headers().get(name).and_then(|value| value.to_str().ok()).map(String::from);
+                let origin = header(header::ORIGIN);
+                let request_method = header(header::ACCESS_CONTROL_REQUEST_METHOD);
+                if Cors::is_preflight(req.method().as_str(), origin.as_deref(), request_method.as_deref()) {
+                    let request_headers = header(header::ACCESS_CONTROL_REQUEST_HEADERS);
+                    let response = match cors.preflight(origin.as_deref(), request_method.as_deref(), request_headers.as_deref()) {
+                        Some(headers) => {
+                            let mut response = HttpResponse::NoContent();
+                            for (name, value) in headers {
+                                response.header(name, value);
+                            }
+                            response.finish()
+                        }
+                        None => HttpResponse::Forbidden().finish(),
+                    };
+                    return Either::Right(future::ok(req.into_response(response)));
+                }
+                let headers = cors.response_headers(origin.as_deref());
+                let http_req = req.request().clone();
+                Either::Left(Either::Right(srv.call(req).map(move |res| {
+                    let mut res = res.unwrap_or_else(|e| ServiceResponse::from_err(e, http_req));
+                    for (name, value) in headers {
+                        res.headers_mut().insert(name, value);
+                    }
+                    Ok(res)
+                })))
+            })
+            .service(
+                web::scope("/restapi")
+                    .route("/ping", web::get().to(ping))
+                    .route("/user/{id_or_addr}", web::get().to(get_user))
+                    .route("/recenttrades/{market}", web::get().to(recent_trades))
+                    .route("/ordertrades/{market}/{order_id}", web::get().to(order_trades))
+                    .route("/closedorders/{market}/{user_id}", web::get().to(my_orders))
+                    .route("/balance_history/{user_id}", web::get().to(balance_history))
+                    .route("/orders/{user_id}", web::get().to(orders))
+                    .route("/ticker_{ticker_inv}/{market}", web::get().to(ticker))
+                    .route("/jsonrpc", web::post().to(jsonrpc))
+                    .service(
+                        web::scope("/tradingview")
+                            .route("/time", web::get().to(unix_timestamp))
+                            .route("/config", web::get().to(chart_config))
+                            .route("/symbols", web::get().to(symbols))
+                            .route("/history", web::get().to(history)),
+                    ),
+            )
     });
 
     let server = match workers {
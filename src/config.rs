@@ -1,6 +1,46 @@
+use rust_decimal::prelude::Zero;
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
 use std::str::FromStr;
+use std::time::Duration;
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, Serialize, Deserialize)]
+pub enum RoundingStrategy {
+    Bankers,
+    HalfUp,
+    Truncate,
+    AwayFromZero,
+}
+
+impl Default for RoundingStrategy {
+    fn default() -> Self {
+        RoundingStrategy::Bankers
+    }
+}
+
+impl FromStr for RoundingStrategy {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Bankers" => Ok(RoundingStrategy::Bankers),
+            "HalfUp" => Ok(RoundingStrategy::HalfUp),
+            "Truncate" => Ok(RoundingStrategy::Truncate),
+            "AwayFromZero" => Ok(RoundingStrategy::AwayFromZero),
+            _ => Err(anyhow::anyhow!("invalid rounding strategy {}", s)),
+        }
+    }
+}
+
+impl From<RoundingStrategy> for rust_decimal::RoundingStrategy {
+    fn from(strategy: RoundingStrategy) -> rust_decimal::RoundingStrategy {
+        match strategy {
+            RoundingStrategy::Bankers => rust_decimal::RoundingStrategy::BankersRounding,
+            RoundingStrategy::HalfUp => rust_decimal::RoundingStrategy::RoundHalfUp,
+            RoundingStrategy::Truncate => rust_decimal::RoundingStrategy::RoundDown,
+            RoundingStrategy::AwayFromZero => rust_decimal::RoundingStrategy::RoundUp,
+        }
+    }
+}
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Default)]
 #[serde(default)]
@@ -8,6 +48,11 @@ pub struct Asset {
     pub name: String,
     pub prec_save: u32,
     pub prec_show: u32,
+    pub rounding: RoundingStrategy,
+    pub min_deposit: Option<Decimal>,
+    pub max_withdrawal: Option<Decimal>,
+    // the total a user may withdraw within the withdrawal window
+    pub daily_withdrawal_limit: Option<Decimal>,
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
@@ -24,7 +69,99 @@ pub struct Market {
     pub base: MarketUnit,
     pub quote: MarketUnit,
     pub fee_prec: u32,
+    // how the fees are rounded to the precision of their asset, the rounding of the asset if unset
+    pub fee_rounding: Option<RoundingStrategy>,
+    pub fee_side: FeeSide,
     pub min_amount: Decimal,
+    // min price * amount of an order, in quote
+    pub min_notional: Decimal,
+    pub matching_mode: MatchingMode,
+    pub circuit_breaker: CircuitBreakerConfig,
+    // new orders are priced within this ratio around the last trade price, 0.1 for ±10%, zero disables it
+    pub price_band: Decimal,
+    pub kline: KlineConfig,
+    // the maker fee rate of an order can be down to the negative of it, and the taker fee rate
+    // must be at least it, so a trade never pays out more than it charges. Zero disables rebates.
+    pub max_maker_rebate: Decimal,
+    // the price of a limit order must be a multiple of it, zero for any price at the quote precision
+    pub tick_size: Decimal,
+    // the amount of an order must be a multiple of it, zero for any amount at the base precision
+    pub lot_size: Decimal,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KlineConfig {
+    // candle intervals in seconds
+    pub intervals: Vec<u64>,
+    // candles kept in memory for each interval
+    pub max_candles: usize,
+}
+
+impl Default for KlineConfig {
+    fn default() -> Self {
+        KlineConfig {
+            intervals: vec![60, 300, 3600, 86400],
+            max_candles: 1000,
+        }
+    }
+}
+
+// Halt a market when the trade price moves too much within `window`, until `cooldown` has passed.
+// A zero `max_move` disables it.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CircuitBreakerConfig {
+    // ratio to the oldest trade price in the window, 0.1 for 10%
+    pub max_move: Decimal,
+    #[serde(with = "humantime_serde")]
+    pub window: Duration,
+    #[serde(with = "humantime_serde")]
+    pub cooldown: Duration,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            max_move: Decimal::zero(),
+            window: Duration::from_secs(60),
+            cooldown: Duration::from_secs(300),
+        }
+    }
+}
+
+// how a taker is split among the makers at the same price
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum MatchingMode {
+    // the earliest maker is filled first
+    PriceTime,
+    // every maker gets a share proportional to its visible amount
+    ProRata,
+}
+
+impl Default for MatchingMode {
+    fn default() -> Self {
+        MatchingMode::PriceTime
+    }
+}
+
+// The asset the fees of a market are charged in. A fee in the asset a user pays is taken from its
+// AVAILABLE balance, and charged in the received asset instead when that doesn't cover it.
+// The rebates are always paid in the received asset.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum FeeSide {
+    // the asset each side receives, or the `fee_account.fee_asset` when the user holds enough of it
+    Received,
+    // the buyer pays its fee in quote as well
+    Quote,
+    // the seller pays its fee in base as well
+    Base,
+}
+
+impl Default for FeeSide {
+    fn default() -> Self {
+        FeeSide::Received
+    }
 }
 
 impl Default for MarketUnit {
@@ -41,27 +178,627 @@ impl Default for Market {
         Market {
             name: "".to_string(),
             fee_prec: 4,
+            fee_rounding: None,
+            fee_side: FeeSide::Received,
             min_amount: Decimal::from_str("0.01").unwrap(),
+            min_notional: Decimal::zero(),
+            matching_mode: MatchingMode::PriceTime,
+            circuit_breaker: Default::default(),
+            price_band: Decimal::zero(),
+            kline: Default::default(),
+            max_maker_rebate: Decimal::zero(),
+            tick_size: Decimal::zero(),
+            lot_size: Decimal::zero(),
             base: Default::default(),
             quote: Default::default(),
         }
     }
 }
 
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct BalanceUpdateConfig {
+    // max entries of the dedup cache
+    pub capacity: usize,
+    #[serde(with = "humantime_serde")]
+    pub entry_ttl: Duration,
+    #[serde(with = "humantime_serde")]
+    pub timer_interval: Duration,
+    pub strategy: DedupStrategy,
+    pub withdrawal_window: WithdrawalWindow,
+}
+
+impl Default for BalanceUpdateConfig {
+    fn default() -> Self {
+        BalanceUpdateConfig {
+            capacity: 1_000_000,
+            entry_ttl: Duration::from_secs(3600),
+            timer_interval: Duration::from_secs(60),
+            strategy: DedupStrategy::Ttl,
+            withdrawal_window: WithdrawalWindow::Rolling,
+        }
+    }
+}
+
+// how the dedup cache of the balance updates forgets its entries
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DedupStrategy {
+    // after `entry_ttl`, and on every `timer_interval`
+    Ttl,
+    // only when pushed out by newer entries beyond `capacity`
+    Lru,
+}
+
+impl Default for DedupStrategy {
+    fn default() -> Self {
+        DedupStrategy::Ttl
+    }
+}
+
+// the withdrawals counted toward the daily withdrawal limits
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum WithdrawalWindow {
+    // the last 24 hours
+    Rolling,
+    // since 00:00 UTC of the day
+    CalendarDay,
+}
+
+impl Default for WithdrawalWindow {
+    fn default() -> Self {
+        WithdrawalWindow::Rolling
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct FeeTier {
+    // the tier applies once the rolling trade volume of the user reaches `min_volume`
+    pub min_volume: Decimal,
+    // negative for a rebate, which the lowest taker fee must cover
+    pub maker_fee: Decimal,
+    pub taker_fee: Decimal,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct OpenOrderLimit {
+    pub user_id: u32,
+    pub max_open_orders_per_market: usize,
+}
+
+// the resting orders a user can have in each market, a new order is rejected at the limit
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct OpenOrderLimitConfig {
+    // zero for no limit
+    pub max_open_orders_per_market: usize,
+    // the limits of some users instead of the default one, e.g. for market makers
+    pub user_overrides: Vec<OpenOrderLimit>,
+}
+
+// the idempotency keys of the order puts
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct OrderIdempotencyConfig {
+    // max entries of the cache, zero disables it
+    pub capacity: usize,
+    #[serde(with = "humantime_serde")]
+    pub entry_ttl: Duration,
+}
+
+impl Default for OrderIdempotencyConfig {
+    fn default() -> Self {
+        OrderIdempotencyConfig {
+            capacity: 100_000,
+            entry_ttl: Duration::from_secs(3600),
+        }
+    }
+}
+
+// The commands to the engine wait in this queue, they are executed one at a time
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct CommandQueueConfig {
+    // the cancels and the other commands are rejected when this many are waiting
+    pub capacity: usize,
+    // the new orders are rejected earlier, at this part of the capacity, so the cancels still get in
+    pub shed_ratio: f64,
+}
+
+impl Default for CommandQueueConfig {
+    fn default() -> Self {
+        CommandQueueConfig {
+            capacity: 10_000,
+            shed_ratio: 0.8,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct FeeTierConfig {
+    // an empty table means the fee rates of the orders are used
+    pub tiers: Vec<FeeTier>,
+    #[serde(with = "humantime_serde")]
+    pub volume_window: Duration,
+    // max entries of the user volume cache
+    pub cache_capacity: usize,
+    #[serde(with = "humantime_serde")]
+    pub cache_ttl: Duration,
+}
+
+impl Default for FeeTierConfig {
+    fn default() -> Self {
+        FeeTierConfig {
+            tiers: Vec::new(),
+            volume_window: Duration::from_secs(30 * 86400),
+            cache_capacity: 100_000,
+            cache_ttl: Duration::from_secs(600),
+        }
+    }
+}
+
+// The fees of trades are credited to the AVAILABLE balance of a reserved user, so the fee
+// revenue shows up in the balance history.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct FeeAccountConfig {
+    // the reserved user, it can't place orders. None burns the fees.
+    pub user_id: Option<u32>,
+    // Charge the fees in this asset instead of the received one, converted at the trade price.
+    // Only the other asset of the market can be converted, and only when the user can afford it,
+    // otherwise the received asset is charged.
+    pub fee_asset: Option<String>,
+}
+
+// what to do with the operation logs covered by a new slice
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum OperationLogCompactionMode {
+    Keep,
+    Delete,
+    // move them to files under `archive_dir`
+    Archive,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct OperationLogCompactionConfig {
+    pub mode: OperationLogCompactionMode,
+    pub archive_dir: String,
+}
+
+impl Default for OperationLogCompactionConfig {
+    fn default() -> Self {
+        OperationLogCompactionConfig {
+            mode: OperationLogCompactionMode::Keep,
+            archive_dir: "operation_log_archive".to_string(),
+        }
+    }
+}
+
+// how the slices are written, the codec of a slice is read from its header on load
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum SliceCodec {
+    // a row per balance and order in the slice tables
+    None,
+    // the whole slice in one compressed row
+    Gzip,
+    Zstd,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct SliceCompressionConfig {
+    pub codec: SliceCodec,
+    // 0-9 for gzip, 1-22 for zstd
+    pub level: i32,
+}
+
+impl Default for SliceCompressionConfig {
+    fn default() -> Self {
+        SliceCompressionConfig {
+            codec: SliceCodec::None,
+            level: 3,
+        }
+    }
+}
+
+// comparing the balances loaded on startup with the slice they are loaded from
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum BalanceReconcileMode {
+    Off,
+    // log the discrepancies
+    Report,
+    // log them and refuse to start
+    Verify,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum MessageBackend {
+    // publish to the topics on `brokers`
+    Kafka,
+    // publish to the subjects like `trades.<market>` on `nats_url`
+    Nats,
+}
+
+impl Default for MessageBackend {
+    fn default() -> Self {
+        MessageBackend::Kafka
+    }
+}
+
+// Messages kafka fails to take are retried with exponential backoff by the sender thread,
+// and appended to `path` as json lines after `max_retries` failures, for later reingestion.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct DeadLetterConfig {
+    // max messages waiting for a retry, more failed messages go to the dead-letter file at once
+    pub retry_capacity: usize,
+    pub max_retries: u32,
+    #[serde(with = "humantime_serde")]
+    pub initial_backoff: Duration,
+    #[serde(with = "humantime_serde")]
+    pub max_backoff: Duration,
+    pub path: String,
+}
+
+impl Default for DeadLetterConfig {
+    fn default() -> Self {
+        DeadLetterConfig {
+            retry_capacity: 10_000,
+            max_retries: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            path: "dead_letter.log".to_string(),
+        }
+    }
+}
+
+// how the kafka messages are keyed, messages with the same key go to the same partition in order
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum PartitionStrategy {
+    // orders and balances by user, trades by market since a trade has two users
+    ByUser,
+    // orders and trades by market, balances by user since they have no market
+    ByMarket,
+    // no key, best throughput but no ordering
+    RoundRobin,
+}
+
+impl Default for PartitionStrategy {
+    fn default() -> Self {
+        PartitionStrategy::ByMarket
+    }
+}
+
+// a higher permission includes the lower ones
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Serialize, Deserialize)]
+pub enum Permission {
+    ReadOnly,
+    Trade,
+    Admin,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct ApiKey {
+    pub key: String,
+    pub permission: Permission,
+    // a key bound to a user can only act for that user
+    #[serde(default)]
+    pub user_id: Option<u32>,
+    // who uses the key, recorded with the manual balance adjustments
+    #[serde(default)]
+    pub name: String,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct ClientCert {
+    pub subject: String,
+    pub permission: Permission,
+    #[serde(default)]
+    pub user_id: Option<u32>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct AuthConfig {
+    // the grpc metadata key or http header carrying the api key
+    pub header: String,
+    // no keys means no authentication
+    pub keys: Vec<ApiKey>,
+    // the scopes of the client certificates under mutual tls, by the common name of their subject
+    pub client_certs: Vec<ClientCert>,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            header: "x-api-key".to_string(),
+            keys: Vec::new(),
+            client_certs: Vec::new(),
+        }
+    }
+}
+
+// The tls of the grpc server. `client_ca_path` turns on mutual tls: the clients must present a
+// certificate signed by that ca, and the `auth.client_certs` give them their scope.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct GrpcTlsConfig {
+    // serve without tls, for local development only
+    pub plaintext: bool,
+    // pem files of the certificate chain and the private key of the server
+    pub cert_path: String,
+    pub key_path: String,
+    pub client_ca_path: String,
+}
+
+impl Default for GrpcTlsConfig {
+    fn default() -> Self {
+        GrpcTlsConfig {
+            plaintext: false,
+            cert_path: String::new(),
+            key_path: String::new(),
+            client_ca_path: String::new(),
+        }
+    }
+}
+
+// Rebuild the state at a point in the past, for investigations. The engine loads the last slice
+// before the target, replays the operation logs up to it and then serves queries only.
+// The `log` section, shared by all the binaries. RUST_LOG overrides `level` when set.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct LogConfig {
+    // a filter as RUST_LOG, such as "info" or "info,dingir_exchange::market=debug"
+    pub level: String,
+    pub format: LogFormat,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: "info".to_string(),
+            format: LogFormat::Pretty,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    // multi-line and colored, for reading in a terminal
+    Pretty,
+    // one json object per line, with the fields of the current spans
+    Json,
+}
+
+impl Default for LogFormat {
+    fn default() -> Self {
+        LogFormat::Pretty
+    }
+}
+
+// the sqlx pools of `db_log` and `db_history`, each of them is built with these limits
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct DbPoolConfig {
+    pub max_connections: u32,
+    // kept open even when idle
+    pub min_connections: u32,
+    // waiting longer for a connection fails the query instead of hanging
+    #[serde(with = "humantime_serde")]
+    pub acquire_timeout: Duration,
+    // idle connections above `min_connections` are closed after this long, none never closes them
+    #[serde(with = "humantime_serde")]
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for DbPoolConfig {
+    fn default() -> Self {
+        DbPoolConfig {
+            max_connections: 10,
+            min_connections: 0,
+            acquire_timeout: Duration::from_secs(30),
+            idle_timeout: Some(Duration::from_secs(600)),
+        }
+    }
+}
+
+// what to do with the history rows once the queue of a table is full
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum HistoryOverflow {
+    // refuse the commands changing the state until the queue drains
+    Block,
+    // keep taking commands, the rows beyond the bound are appended to `spill_path` as json lines
+    Spill,
+}
+
+impl Default for HistoryOverflow {
+    fn default() -> Self {
+        HistoryOverflow::Block
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum HistoryBackend {
+    // the history tables on `db_history`
+    Postgres,
+    // the same tables on `history_writer.clickhouse`, for analytics
+    ClickHouse,
+    // both of them, each with its own queue
+    Both,
+}
+
+impl Default for HistoryBackend {
+    fn default() -> Self {
+        HistoryBackend::Postgres
+    }
+}
+
+// rows are inserted over the http interface, in the `JSONEachRow` format
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ClickHouseConfig {
+    pub url: String,
+    pub database: String,
+    pub user: String,
+    pub password: String,
+    // the rows beyond the queue bound with `HistoryOverflow::Spill`, and the ones not inserted on shutdown
+    pub spill_path: String,
+}
+
+impl Default for ClickHouseConfig {
+    fn default() -> Self {
+        ClickHouseConfig {
+            url: "http://127.0.0.1:8123".to_string(),
+            database: "dingir".to_string(),
+            user: "default".to_string(),
+            password: String::new(),
+            spill_path: "clickhouse_spill.log".to_string(),
+        }
+    }
+}
+
+// The history is written in batches, a batch is written once it is full or its first entry has
+// waited for `flush_interval`. A larger batch means fewer writes but more latency.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct HistoryWriterConfig {
+    // at most 5000
+    pub batch_size: usize,
+    #[serde(with = "humantime_serde")]
+    pub flush_interval: Duration,
+    // rows queued for each table at most, including the ones being written
+    pub queue_limit: usize,
+    pub overflow: HistoryOverflow,
+    pub spill_path: String,
+    pub backend: HistoryBackend,
+    pub clickhouse: ClickHouseConfig,
+}
+
+impl Default for HistoryWriterConfig {
+    fn default() -> Self {
+        HistoryWriterConfig {
+            batch_size: 1000,
+            flush_interval: Duration::from_millis(200),
+            queue_limit: 8192,
+            overflow: HistoryOverflow::Block,
+            spill_path: "history_spill.log".to_string(),
+            backend: HistoryBackend::Postgres,
+            clickhouse: Default::default(),
+        }
+    }
+}
+
+// The streaming subscriptions over websocket, for the clients without grpc.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct WebsocketConfig {
+    // 0 disables the websocket gateway
+    pub port: u16,
+    #[serde(with = "humantime_serde")]
+    pub ping_interval: Duration,
+    // a client sending nothing, not even a pong, for this long is dropped
+    #[serde(with = "humantime_serde")]
+    pub idle_timeout: Duration,
+}
+
+impl Default for WebsocketConfig {
+    fn default() -> Self {
+        WebsocketConfig {
+            port: 0,
+            ping_interval: Duration::from_secs(20),
+            idle_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct ReplayTarget {
+    // the last operation log to replay, inclusive
+    pub operation_log_id: Option<u64>,
+    // replay the operation logs logged at or before this unix timestamp
+    pub timestamp: Option<f64>,
+}
+
+impl ReplayTarget {
+    pub fn is_set(&self) -> bool {
+        self.operation_log_id.is_some() || self.timestamp.is_some()
+    }
+}
+
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 #[serde(default)]
 pub struct Settings {
     pub debug: bool,
     pub db_log: String,
     pub db_history: String,
+    // a read replica of `db_history` for the history queries, empty sends them to `db_history`
+    pub db_history_replica: String,
+    pub db_pool: DbPoolConfig,
+    pub log: LogConfig,
     pub assets: Vec<Asset>,
     pub markets: Vec<Market>,
     pub brokers: String,
+    pub message_backend: MessageBackend,
+    pub partition_strategy: PartitionStrategy,
+    pub dead_letter: DeadLetterConfig,
+    pub nats_url: String,
     pub consumer_group: String,
+    // seconds between the periodic slices
     pub slice_interval: i32,
     pub slice_keeptime: i32,
+    // connections loading the slice on startup, each fetches a part of the tables
+    pub slice_load_connections: usize,
+    pub balance_reconcile: BalanceReconcileMode,
+    pub operation_log_compaction: OperationLogCompactionConfig,
+    pub slice_compression: SliceCompressionConfig,
+    pub replay_until: ReplayTarget,
     pub history_thread: i32,
+    pub history_writer: HistoryWriterConfig,
     pub cache_timeout: f64,
+    pub balance_update: BalanceUpdateConfig,
+    pub order_idempotency: OrderIdempotencyConfig,
+    pub command_queue: CommandQueueConfig,
+    pub open_order_limit: OpenOrderLimitConfig,
+    pub fee_tier: FeeTierConfig,
+    pub fee_account: FeeAccountConfig,
+    // The ids of the system accounts, e.g. the fee account, which must be listed here when set.
+    // They get no deposits, withdrawals or orders, only the balance adjustments of an operator.
+    pub reserved_user_ids: Vec<u32>,
+    // page size of the open orders query when the request has no limit
+    pub order_query_default_limit: usize,
+    // initial capacity of the balance map, it is reallocated with it when the state is reset
+    pub balance_map_capacity: usize,
+    // A csv or json file of balances deposited on startup, for test environments. Entries already
+    // in the dedup cache are skipped, empty disables it.
+    pub balance_seed: String,
+    pub auth: AuthConfig,
+    pub grpc_tls: GrpcTlsConfig,
+    // serve the grpc reflection service, so tools like grpcurl can list the calls without the protos
+    pub grpc_reflection: bool,
+    // prometheus metrics are served on this port, 0 disables them
+    pub metrics_port: u16,
+    pub websocket: WebsocketConfig,
+    // how often the expired orders are swept
+    #[serde(with = "humantime_serde")]
+    pub order_expire_interval: Duration,
+    // on SIGTERM, the process exits anyway if the final slice and the flushing take longer
+    #[serde(with = "humantime_serde")]
+    pub shutdown_timeout: Duration,
+}
+
+impl Settings {
+    // the settings the engine cannot run with, checked once they are loaded
+    pub fn check(&self) -> anyhow::Result<()> {
+        if self.order_query_default_limit == 0 {
+            return Err(anyhow::anyhow!("order_query_default_limit must be greater than 0"));
+        }
+        Ok(())
+    }
 }
 
 impl Default for Settings {
@@ -70,14 +807,59 @@ impl Default for Settings {
             debug: false,
             db_log: Default::default(),
             db_history: Default::default(),
+            db_history_replica: Default::default(),
+            db_pool: Default::default(),
+            log: Default::default(),
             assets: Vec::new(),
             markets: Vec::new(),
             consumer_group: "kline_data_fetcher".to_string(),
             brokers: "127.0.0.1:9092".to_string(),
+            message_backend: MessageBackend::Kafka,
+            partition_strategy: PartitionStrategy::ByMarket,
+            dead_letter: Default::default(),
+            nats_url: "nats://127.0.0.1:4222".to_string(),
             slice_interval: 86400,
             slice_keeptime: 86400 * 3,
+            slice_load_connections: 4,
+            balance_reconcile: BalanceReconcileMode::Off,
+            operation_log_compaction: Default::default(),
+            slice_compression: Default::default(),
+            replay_until: Default::default(),
             history_thread: 10,
+            history_writer: Default::default(),
             cache_timeout: 0.45,
+            balance_update: Default::default(),
+            order_idempotency: Default::default(),
+            command_queue: Default::default(),
+            open_order_limit: Default::default(),
+            fee_tier: Default::default(),
+            fee_account: Default::default(),
+            reserved_user_ids: Vec::new(),
+            order_query_default_limit: 10,
+            balance_map_capacity: 64,
+            balance_seed: Default::default(),
+            auth: Default::default(),
+            grpc_tls: Default::default(),
+            grpc_reflection: true,
+            metrics_port: 50055,
+            websocket: Default::default(),
+            order_expire_interval: Duration::from_secs(1),
+            shutdown_timeout: Duration::from_secs(30),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_settings_check() {
+        assert!(Settings::default().check().is_ok());
+        let settings = Settings {
+            order_query_default_limit: 0,
+            ..Default::default()
+        };
+        assert!(settings.check().is_err());
+    }
+}
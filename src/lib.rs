@@ -5,11 +5,16 @@
 #![allow(clippy::single_char_pattern)]
 #![allow(clippy::await_holding_refcell_ref)] // FIXME
 
+pub mod auth;
 pub mod matchengine;
-pub use matchengine::{asset, controller, dto, history, market, persist, sequencer, server};
+pub use matchengine::{
+    asset, clickhouse, client, command_queue, controller, dto, fee, history, idempotency, kline, market, metrics, persist, reserves,
+    sequencer, server, subscription, surveillance, websocket,
+};
 pub mod storage;
 pub use storage::{database, models, sqlxextend};
 pub mod config;
+pub mod logging;
 pub mod message;
 pub mod restapi;
 pub mod types;
@@ -0,0 +1,25 @@
+use crate::config::{LogConfig, LogFormat};
+use tracing_subscriber::EnvFilter;
+
+// Install the global subscriber, the records of the crates still using `log` are forwarded to it.
+pub fn init(config: &LogConfig) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(&config.level));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    let result = match config.format {
+        LogFormat::Pretty => builder.pretty().try_init(),
+        LogFormat::Json => builder.json().with_current_span(true).with_span_list(true).try_init(),
+    };
+    if let Err(e) = result {
+        eprintln!("init logging fail: {}", e);
+    }
+}
+
+// the `log` section of the config file, read before the rest so their loading is logged too
+pub fn init_from_config_file(config_file: &str) {
+    let mut conf = config_rs::Config::new();
+    let config: LogConfig = match conf.merge(config_rs::File::with_name(config_file)) {
+        Ok(conf) => conf.get("log").unwrap_or_default(),
+        Err(_) => Default::default(),
+    };
+    init(&config);
+}
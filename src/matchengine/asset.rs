@@ -1,24 +1,28 @@
+use crate::config;
 use crate::history::HistoryWriter;
 use crate::message::{BalanceMessage, MessageManager};
+use crate::metrics::Metrics;
 use crate::models;
-use crate::utils;
-use crate::{config, utils::FTimestamp};
+use crate::subscription::SubscriptionHub;
+use crate::types::BusinessKind;
+use crate::utils::{Clock, FTimestamp, MonotonicClock, SystemClock};
 use models::BalanceHistory;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use rust_decimal::prelude::Zero;
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 use ttl_cache::TtlCache;
 
 use num_enum::TryFromPrimitive;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 
 use std::rc::Rc;
 use std::time::Duration;
 
-const BALANCE_MAP_INIT_SIZE_ASSET: usize = 64;
+pub const BALANCE_MAP_INIT_SIZE_ASSET: usize = 64;
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Eq, Hash, Copy, TryFromPrimitive)]
 #[repr(i16)]
@@ -37,6 +41,23 @@ pub struct BalanceMapKey {
 pub struct AssetInfo {
     pub prec_save: u32,
     pub prec_show: u32,
+    pub rounding: config::RoundingStrategy,
+    pub min_deposit: Option<Decimal>,
+    pub max_withdrawal: Option<Decimal>,
+    pub daily_withdrawal_limit: Option<Decimal>,
+}
+
+impl From<&config::Asset> for AssetInfo {
+    fn from(item: &config::Asset) -> AssetInfo {
+        AssetInfo {
+            prec_save: item.prec_save,
+            prec_show: item.prec_show,
+            rounding: item.rounding,
+            min_deposit: item.min_deposit,
+            max_withdrawal: item.max_withdrawal,
+            daily_withdrawal_limit: item.daily_withdrawal_limit,
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -46,18 +67,36 @@ pub struct AssetManager {
 
 impl AssetManager {
     pub fn new(asset_config: &[config::Asset]) -> Result<AssetManager> {
-        println!("asset {:?}", asset_config);
-        let mut assets = HashMap::new();
+        tracing::info!(assets = ?asset_config, "load assets");
+        let mut asset_manager = AssetManager { assets: HashMap::new() };
         for item in asset_config.iter() {
-            assets.insert(
-                item.name.clone(),
-                AssetInfo {
-                    prec_save: item.prec_save,
-                    prec_show: item.prec_show,
-                },
-            );
+            asset_manager.register_asset(item)?;
+        }
+        Ok(asset_manager)
+    }
+    // registering the same definition twice is a no-op
+    pub fn register_asset(&mut self, cfg: &config::Asset) -> Result<()> {
+        if cfg.name.is_empty() {
+            return Err(anyhow!("invalid asset name"));
+        }
+        if cfg.prec_show > cfg.prec_save {
+            return Err(anyhow!(
+                "invalid precision for asset {}: prec_show({}) > prec_save({})",
+                cfg.name,
+                cfg.prec_show,
+                cfg.prec_save
+            ));
+        }
+        let asset_info = AssetInfo::from(cfg);
+        match self.assets.get(&cfg.name) {
+            Some(existed) if *existed == asset_info => Ok(()),
+            Some(_) => Err(anyhow!("asset {} already exists with a different definition", cfg.name)),
+            None => {
+                tracing::info!("register asset {} {:?}", cfg.name, asset_info);
+                self.assets.insert(cfg.name.clone(), asset_info);
+                Ok(())
+            }
         }
-        Ok(AssetManager { assets })
     }
     pub fn asset_exist(&self, name: &str) -> bool {
         self.assets.contains_key(name)
@@ -71,33 +110,240 @@ impl AssetManager {
     pub fn asset_prec_show(&self, name: &str) -> u32 {
         self.asset_get(name).unwrap().prec_show
     }
+    pub fn asset_rounding(&self, name: &str) -> config::RoundingStrategy {
+        self.asset_get(name).unwrap().rounding
+    }
+    // rounded to prec_show for display, an unknown asset is left as it is
+    pub fn round_show(&self, name: &str, value: Decimal) -> Decimal {
+        match self.asset_get(name) {
+            Some(asset) if asset.prec_save != asset.prec_show => value.round_dp_with_strategy(asset.prec_show, asset.rounding.into()),
+            _ => value,
+        }
+    }
+}
+
+// why a balance is frozen
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
+pub enum FreezePurpose {
+    Order,
+    Withdrawal,
+    Custom(String),
+}
+
+impl FreezePurpose {
+    pub fn as_str(&self) -> &str {
+        match self {
+            FreezePurpose::Order => "order",
+            FreezePurpose::Withdrawal => "withdrawal",
+            FreezePurpose::Custom(name) => name,
+        }
+    }
+}
+
+impl From<&str> for FreezePurpose {
+    fn from(name: &str) -> FreezePurpose {
+        match name {
+            "order" => FreezePurpose::Order,
+            "withdrawal" => FreezePurpose::Withdrawal,
+            _ => FreezePurpose::Custom(name.to_string()),
+        }
+    }
 }
 
 //#[derive(default)]
 pub struct BalanceManager {
     pub asset_manager: AssetManager,
     pub balances: HashMap<BalanceMapKey, Decimal>,
+    // margin mode: AVAILABLE balance may go below zero, bounded by the user's credit limit
+    pub allow_negative: bool,
+    pub credit_limits: HashMap<u32, Decimal>,
+    // (user_id, asset) -> the FREEZE balance held for purposes other than orders
+    pub holds: HashMap<(u32, String), BTreeMap<FreezePurpose, Decimal>>,
+    // the user collecting the fees, fees are burned without it
+    pub fee_account: Option<u32>,
+    // the asset fees are preferably charged in
+    pub fee_asset: Option<String>,
+    // the balance map is reallocated with it on reset
+    init_capacity: usize,
+    // user_id -> the subscribers of the balance changes of the user
+    balance_subscribers: HashMap<u32, SubscriptionHub<BalanceChange>>,
+}
+
+// the balances of an asset of a user after a change of either
+#[derive(Debug, Clone, PartialEq)]
+pub struct BalanceChange {
+    pub user_id: u32,
+    pub asset: String,
+    pub available: Decimal,
+    pub frozen: Decimal,
+}
+
+// Balance changes applied all together or not at all, see `BalanceManager::commit`
+#[derive(Debug, Default)]
+pub struct BalanceTransaction {
+    changes: Vec<(BalanceMapKey, Decimal)>,
+}
+
+impl BalanceTransaction {
+    // both return the index of the change, the balance after it is at that index in the result of `commit`
+    pub fn add(&mut self, user_id: u32, balance_type: BalanceType, asset: &str, amount: &Decimal) -> usize {
+        debug_assert!(amount.is_sign_positive());
+        self.stage(user_id, balance_type, asset, *amount)
+    }
+    pub fn sub(&mut self, user_id: u32, balance_type: BalanceType, asset: &str, amount: &Decimal) -> usize {
+        debug_assert!(amount.is_sign_positive());
+        self.stage(user_id, balance_type, asset, -amount)
+    }
+    fn stage(&mut self, user_id: u32, balance_type: BalanceType, asset: &str, change: Decimal) -> usize {
+        let key = BalanceMapKey {
+            user_id,
+            balance_type,
+            asset: asset.to_owned(),
+        };
+        self.changes.push((key, change));
+        self.changes.len() - 1
+    }
+    pub fn len(&self) -> usize {
+        self.changes.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
 }
 
 #[derive(Default)]
 pub struct BalanceStatus {
+    // net exposure, debts are subtracted
     pub total: Decimal,
+    // sum of absolute values
+    pub gross: Decimal,
     pub available_count: u32,
     pub available: Decimal,
     pub frozen_count: u32,
     pub frozen: Decimal,
+    pub debt_count: u32,
+    pub debt: Decimal,
+    pub frozen_by_purpose: BTreeMap<FreezePurpose, Decimal>,
 }
 
 impl BalanceManager {
     pub fn new(asset_config: &[config::Asset]) -> Result<BalanceManager> {
+        Self::with_capacity(asset_config, BALANCE_MAP_INIT_SIZE_ASSET)
+    }
+    pub fn with_capacity(asset_config: &[config::Asset], capacity: usize) -> Result<BalanceManager> {
         let asset_manager = AssetManager::new(asset_config)?;
         Ok(BalanceManager {
             asset_manager,
-            balances: HashMap::new(),
+            balances: HashMap::with_capacity(capacity),
+            allow_negative: false,
+            credit_limits: HashMap::new(),
+            holds: HashMap::new(),
+            fee_account: None,
+            fee_asset: None,
+            init_capacity: capacity,
+            balance_subscribers: HashMap::new(),
         })
     }
+    pub fn new_with_margin(asset_config: &[config::Asset]) -> Result<BalanceManager> {
+        let mut balance_manager = Self::new(asset_config)?;
+        balance_manager.allow_negative = true;
+        Ok(balance_manager)
+    }
+    pub fn set_fee_account(&mut self, config: &config::FeeAccountConfig) -> Result<()> {
+        if let Some(fee_asset) = &config.fee_asset {
+            if !self.asset_manager.asset_exist(fee_asset) {
+                return Err(anyhow!("invalid fee asset {}", fee_asset));
+            }
+        }
+        self.fee_account = config.user_id;
+        self.fee_asset = config.fee_asset.clone();
+        Ok(())
+    }
+    pub fn is_fee_account(&self, user_id: u32) -> bool {
+        self.fee_account == Some(user_id)
+    }
+    // Take a fee from the AVAILABLE balance of a user, to the fee account if there is one.
+    // Returns the new balance of the fee account.
+    pub fn charge_fee(&mut self, user_id: u32, asset: &str, fee: &Decimal) -> std::result::Result<Option<Decimal>, BalanceUpdateError> {
+        if let Some(fee_account) = self.fee_account {
+            if !self.can_add(fee_account, BalanceType::AVAILABLE, asset, fee) {
+                return Err(BalanceUpdateError::Overflow);
+            }
+        }
+        self.sub(user_id, BalanceType::AVAILABLE, asset, fee)?;
+        match self.fee_account {
+            Some(fee_account) => Ok(Some(self.add(fee_account, BalanceType::AVAILABLE, asset, fee)?)),
+            None => Ok(None),
+        }
+    }
+    // The changes are applied in order on a copy of the balances they touch, and written back only
+    // if none of them overflows or takes a balance below its floor. On an error nothing is changed.
+    // Returns the balance after each change.
+    pub fn commit(&mut self, transaction: &BalanceTransaction) -> std::result::Result<Vec<Decimal>, BalanceUpdateError> {
+        let mut staged: HashMap<&BalanceMapKey, Decimal> = HashMap::new();
+        let mut balances = Vec::with_capacity(transaction.len());
+        for (key, change) in &transaction.changes {
+            let change = self.round_asset(&key.asset, change);
+            let old_value = staged.get(key).copied().unwrap_or_else(|| self.get_by_key(key));
+            let new_value = old_value.checked_add(change).ok_or(BalanceUpdateError::Overflow)?;
+            let floor = if key.balance_type == BalanceType::AVAILABLE {
+                -self.credit_limit(key.user_id)
+            } else {
+                Decimal::zero()
+            };
+            if change.is_sign_negative() && new_value < floor {
+                return Err(BalanceUpdateError::BalanceNotEnough);
+            }
+            staged.insert(key, new_value);
+            balances.push(new_value);
+        }
+        for (key, value) in staged {
+            self.set_by_key(key.clone(), &value);
+        }
+        Ok(balances)
+    }
+    pub fn set_credit_limit(&mut self, user_id: u32, limit: &Decimal) {
+        debug_assert!(limit.is_sign_positive());
+        self.credit_limits.insert(user_id, *limit);
+    }
+    // the lowest value the AVAILABLE balance of a user can reach
+    pub fn credit_limit(&self, user_id: u32) -> Decimal {
+        if self.allow_negative {
+            *self.credit_limits.get(&user_id).unwrap_or(&Decimal::zero())
+        } else {
+            Decimal::zero()
+        }
+    }
+    // the memory of a large state is given back, rather than kept by `clear`
     pub fn reset(&mut self) {
-        self.balances.clear()
+        self.balances = HashMap::with_capacity(self.init_capacity);
+        self.holds = HashMap::new();
+        // the subscribers will find their streams closed and subscribe again
+        self.balance_subscribers.clear();
+    }
+    pub fn subscribe_balances(&mut self, user_id: u32) -> futures_channel::mpsc::Receiver<BalanceChange> {
+        self.balance_subscribers.entry(user_id).or_default().subscribe(None)
+    }
+    fn publish_balance(&mut self, user_id: u32, asset: &str) {
+        if !self.balance_subscribers.contains_key(&user_id) {
+            return;
+        }
+        let change = BalanceChange {
+            user_id,
+            asset: asset.to_string(),
+            available: self.get(user_id, BalanceType::AVAILABLE, asset),
+            frozen: self.get(user_id, BalanceType::FREEZE, asset),
+        };
+        let subscribers = self.balance_subscribers.get_mut(&user_id).unwrap();
+        subscribers.publish(&change);
+        if subscribers.is_empty() {
+            self.balance_subscribers.remove(&user_id);
+        }
+    }
+    // round to the save precision of the asset, with its configured strategy
+    pub fn round_asset(&self, asset: &str, value: &Decimal) -> Decimal {
+        let asset_info = self.asset_manager.asset_get(asset).unwrap();
+        value.round_dp_with_strategy(asset_info.prec_save, asset_info.rounding.into())
     }
     pub fn get(&self, user_id: u32, balance_type: BalanceType, asset: &str) -> Decimal {
         self.get_by_key(&BalanceMapKey {
@@ -108,14 +354,7 @@ impl BalanceManager {
     }
     pub fn get_with_round(&self, user_id: u32, balance_type: BalanceType, asset: &str) -> Decimal {
         let balance: Decimal = self.get(user_id, balance_type, asset);
-        let prec_save = self.asset_manager.asset_prec(asset);
-        let prec_show = self.asset_manager.asset_prec_show(asset);
-        let balance_show = if prec_save == prec_show {
-            balance
-        } else {
-            balance.round_dp(prec_show)
-        };
-        balance_show
+        self.asset_manager.round_show(asset, balance)
     }
     pub fn get_by_key(&self, key: &BalanceMapKey) -> Decimal {
         *self.balances.get(key).unwrap_or(&Decimal::zero())
@@ -126,6 +365,7 @@ impl BalanceManager {
             balance_type,
             asset: asset.to_owned(),
         });
+        self.publish_balance(user_id, asset);
     }
     pub fn set(&mut self, user_id: u32, balance_type: BalanceType, asset: &str, amount: &Decimal) {
         let key = BalanceMapKey {
@@ -136,35 +376,61 @@ impl BalanceManager {
         self.set_by_key(key, amount);
     }
     pub fn set_by_key(&mut self, key: BalanceMapKey, amount: &Decimal) {
-        debug_assert!(amount.is_sign_positive());
-        let amount = amount.round_dp(self.asset_manager.asset_prec(&key.asset));
-        //log::debug!("set balance: {:?}, {}", key, amount);
+        if !self.allow_negative || key.balance_type == BalanceType::FREEZE {
+            debug_assert!(amount.is_sign_positive());
+        }
+        let amount = self.round_asset(&key.asset, amount);
+        //tracing::debug!("set balance: {:?}, {}", key, amount);
+        let (user_id, asset) = (key.user_id, key.asset.clone());
         self.balances.insert(key, amount);
+        self.publish_balance(user_id, &asset);
+    }
+    // whether `add` would succeed
+    pub fn can_add(&self, user_id: u32, balance_type: BalanceType, asset: &str, amount: &Decimal) -> bool {
+        let amount = self.round_asset(asset, amount);
+        self.get(user_id, balance_type, asset).checked_add(amount).is_some()
     }
-    pub fn add(&mut self, user_id: u32, balance_type: BalanceType, asset: &str, amount: &Decimal) -> Decimal {
+    // returns the new balance, the balance is unchanged on overflow
+    pub fn add(
+        &mut self,
+        user_id: u32,
+        balance_type: BalanceType,
+        asset: &str,
+        amount: &Decimal,
+    ) -> std::result::Result<Decimal, BalanceUpdateError> {
         debug_assert!(amount.is_sign_positive());
-        let amount = amount.round_dp(self.asset_manager.asset_prec(asset));
+        let amount = self.round_asset(asset, amount);
         let key = BalanceMapKey {
             user_id,
             balance_type,
             asset: asset.to_owned(),
         };
         let old_value = self.get_by_key(&key);
-        let new_value = old_value + amount;
+        let new_value = old_value.checked_add(amount).ok_or(BalanceUpdateError::Overflow)?;
         self.set_by_key(key, &new_value);
-        new_value
+        Ok(new_value)
     }
-    pub fn sub(&mut self, user_id: u32, balance_type: BalanceType, asset: &str, amount: &Decimal) -> Decimal {
+    pub fn sub(
+        &mut self,
+        user_id: u32,
+        balance_type: BalanceType,
+        asset: &str,
+        amount: &Decimal,
+    ) -> std::result::Result<Decimal, BalanceUpdateError> {
         debug_assert!(amount.is_sign_positive());
-        let amount = amount.round_dp(self.asset_manager.asset_prec(asset));
+        let amount = self.round_asset(asset, amount);
         let key = BalanceMapKey {
             user_id,
             balance_type,
             asset: asset.to_owned(),
         };
         let old_value = self.get_by_key(&key);
-        debug_assert!(old_value.ge(&amount));
-        let new_value = old_value - amount;
+        if self.allow_negative && balance_type == BalanceType::AVAILABLE {
+            debug_assert!((old_value + self.credit_limit(user_id)).ge(&amount));
+        } else {
+            debug_assert!(old_value.ge(&amount));
+        }
+        let new_value = old_value.checked_sub(amount).ok_or(BalanceUpdateError::Overflow)?;
         // TODO don't remove it. Skip when sql insert
         /*
         if result.is_zero() {
@@ -174,38 +440,160 @@ impl BalanceManager {
         }
         */
         self.set_by_key(key, &new_value);
-        new_value
+        Ok(new_value)
+    }
+    pub fn frozen(&mut self, user_id: u32, asset: &str, amount: &Decimal) -> Result<()> {
+        self.freeze_with_purpose(user_id, asset, amount, FreezePurpose::Order)
     }
-    pub fn frozen(&mut self, user_id: u32, asset: &str, amount: &Decimal) {
+    pub fn unfrozen(&mut self, user_id: u32, asset: &str, amount: &Decimal) {
+        let result = self.unfreeze_with_purpose(user_id, asset, amount, FreezePurpose::Order);
+        debug_assert!(result.is_ok(), "{:?}", result);
+    }
+    pub fn freeze_with_purpose(&mut self, user_id: u32, asset: &str, amount: &Decimal, purpose: FreezePurpose) -> Result<()> {
         debug_assert!(amount.is_sign_positive());
-        let amount = amount.round_dp(self.asset_manager.asset_prec(asset));
+        let amount = self.round_asset(asset, amount);
         let key = BalanceMapKey {
             user_id,
             balance_type: BalanceType::AVAILABLE,
             asset: asset.to_owned(),
         };
         let old_available_value = self.get_by_key(&key);
-        debug_assert!(old_available_value.ge(&amount));
-        self.sub(user_id, BalanceType::AVAILABLE, asset, &amount);
-        self.add(user_id, BalanceType::FREEZE, asset, &amount);
+        debug_assert!((old_available_value + self.credit_limit(user_id)).ge(&amount));
+        // checked first, so an overflow leaves both balances unchanged
+        if !self.can_add(user_id, BalanceType::FREEZE, asset, &amount) {
+            return Err(BalanceUpdateError::Overflow.into());
+        }
+        self.sub(user_id, BalanceType::AVAILABLE, asset, &amount)?;
+        self.add(user_id, BalanceType::FREEZE, asset, &amount)?;
+        if purpose != FreezePurpose::Order {
+            *self
+                .holds
+                .entry((user_id, asset.to_owned()))
+                .or_default()
+                .entry(purpose)
+                .or_insert_with(Decimal::zero) += amount;
+        }
+        Ok(())
     }
-    pub fn unfrozen(&mut self, user_id: u32, asset: &str, amount: &Decimal) {
-        debug_assert!(amount.is_sign_positive());
-        let amount = amount.round_dp(self.asset_manager.asset_prec(asset));
-        let key = BalanceMapKey {
-            user_id,
-            balance_type: BalanceType::FREEZE,
-            asset: asset.to_owned(),
-        };
-        let old_frozen_value = self.get_by_key(&key);
-        debug_assert!(
-            old_frozen_value.ge(&amount),
-            "unfreeze larger than frozen {} > {}",
-            amount,
-            old_frozen_value
-        );
-        self.add(user_id, BalanceType::AVAILABLE, asset, &amount);
-        self.sub(user_id, BalanceType::FREEZE, asset, &amount);
+    // release part or all of the balance frozen for `purpose`, the other purposes are untouched
+    pub fn unfreeze_with_purpose(&mut self, user_id: u32, asset: &str, amount: &Decimal, purpose: FreezePurpose) -> Result<()> {
+        if amount.is_sign_negative() {
+            return Err(anyhow!("invalid unfreeze amount {}", amount));
+        }
+        let amount = self.round_asset(asset, amount);
+        let held = self.frozen_for_purpose(user_id, asset, &purpose);
+        if held.lt(&amount) {
+            return Err(anyhow!(
+                "unfreeze larger than frozen for {}: {} > {}",
+                purpose.as_str(),
+                amount,
+                held
+            ));
+        }
+        if !self.can_add(user_id, BalanceType::AVAILABLE, asset, &amount) {
+            return Err(BalanceUpdateError::Overflow.into());
+        }
+        self.add(user_id, BalanceType::AVAILABLE, asset, &amount)?;
+        self.sub(user_id, BalanceType::FREEZE, asset, &amount)?;
+        if purpose != FreezePurpose::Order {
+            let key = (user_id, asset.to_owned());
+            let holds = self.holds.get_mut(&key).unwrap();
+            if amount == held {
+                holds.remove(&purpose);
+            } else {
+                *holds.get_mut(&purpose).unwrap() -= amount;
+            }
+            if holds.is_empty() {
+                self.holds.remove(&key);
+            }
+        }
+        Ok(())
+    }
+    // Only the purposes other than orders are tracked one by one, since trades take the frozen
+    // balance of orders directly. The order part is what the others leave of the FREEZE balance.
+    pub fn frozen_for_purpose(&self, user_id: u32, asset: &str, purpose: &FreezePurpose) -> Decimal {
+        let holds = self.holds.get(&(user_id, asset.to_owned()));
+        if *purpose == FreezePurpose::Order {
+            let other_holds: Decimal = holds.map(|holds| holds.values().sum()).unwrap_or_default();
+            self.get(user_id, BalanceType::FREEZE, asset) - other_holds
+        } else {
+            holds.and_then(|holds| holds.get(purpose)).copied().unwrap_or_default()
+        }
+    }
+    // the non-zero parts of the FREEZE balance of a user
+    pub fn frozen_by_purpose(&self, user_id: u32, asset: &str) -> BTreeMap<FreezePurpose, Decimal> {
+        let mut result = self.holds.get(&(user_id, asset.to_owned())).cloned().unwrap_or_default();
+        let order_frozen = self.frozen_for_purpose(user_id, asset, &FreezePurpose::Order);
+        if !order_frozen.is_zero() {
+            result.insert(FreezePurpose::Order, order_frozen);
+        }
+        result
+    }
+    // load a hold from a slice, after the FREEZE balance of the orders
+    pub fn restore_hold(&mut self, user_id: u32, asset: &str, amount: &Decimal, purpose: FreezePurpose) -> Result<()> {
+        self.add(user_id, BalanceType::FREEZE, asset, amount)?;
+        if purpose != FreezePurpose::Order {
+            self.holds.entry((user_id, asset.to_owned())).or_default().insert(purpose, *amount);
+        }
+        Ok(())
+    }
+    // all non-zero balances of a user, grouped by asset
+    pub fn get_all_for_user(&self, user_id: u32) -> HashMap<String, BalanceStatus> {
+        let mut result: HashMap<String, BalanceStatus> = HashMap::new();
+        for (k, amount) in self.balances.iter() {
+            if k.user_id != user_id || amount.is_zero() {
+                continue;
+            }
+            let status = result.entry(k.asset.clone()).or_insert_with(BalanceStatus::default);
+            status.total += amount;
+            status.gross += amount.abs();
+            if amount.is_sign_negative() {
+                status.debt_count += 1;
+                status.debt -= amount;
+            } else if k.balance_type == BalanceType::AVAILABLE {
+                status.available_count += 1;
+                status.available += amount;
+            } else {
+                status.frozen_count += 1;
+                status.frozen += amount;
+            }
+        }
+        for (asset, status) in result.iter_mut() {
+            status.frozen_by_purpose = self.frozen_by_purpose(user_id, asset);
+        }
+        result
+    }
+    // move `amount` of AVAILABLE balance from one user to another, both legs or nothing
+    pub fn transfer(&mut self, from: u32, to: u32, asset: &str, amount: &Decimal) -> Result<(Decimal, Decimal)> {
+        if !amount.is_sign_positive() || amount.is_zero() {
+            return Err(anyhow!("invalid transfer amount {}", amount));
+        }
+        if from == to {
+            return Err(anyhow!("cannot transfer to oneself"));
+        }
+        let amount = self.round_asset(asset, amount);
+        let from_available = self.get(from, BalanceType::AVAILABLE, asset);
+        if (from_available + self.credit_limit(from)).lt(&amount) {
+            return Err(anyhow!("balance not enough: balance({}) < amount({})", from_available, amount));
+        }
+        if !self.can_add(to, BalanceType::AVAILABLE, asset, &amount) {
+            return Err(BalanceUpdateError::Overflow.into());
+        }
+        let from_balance = self.sub(from, BalanceType::AVAILABLE, asset, &amount)?;
+        let to_balance = self.add(to, BalanceType::AVAILABLE, asset, &amount)?;
+        Ok((from_balance, to_balance))
+    }
+    // AVAILABLE + FREEZE of all the users by asset, the debts of margin users are subtracted.
+    // The fee account is the exchange's own, it is not a liability.
+    pub fn total_liabilities(&self) -> HashMap<String, Decimal> {
+        let mut result: HashMap<String, Decimal> = HashMap::new();
+        for (k, amount) in self.balances.iter() {
+            if self.is_fee_account(k.user_id) {
+                continue;
+            }
+            *result.entry(k.asset.clone()).or_insert_with(Decimal::zero) += amount;
+        }
+        result
     }
     pub fn total(&self, user_id: u32, asset: &str) -> Decimal {
         self.get(user_id, BalanceType::AVAILABLE, asset) + self.get(user_id, BalanceType::FREEZE, asset)
@@ -215,7 +603,11 @@ impl BalanceManager {
         for (k, amount) in self.balances.iter() {
             if k.asset.eq(asset) && !amount.is_zero() {
                 result.total += amount;
-                if k.balance_type == BalanceType::AVAILABLE {
+                result.gross += amount.abs();
+                if amount.is_sign_negative() {
+                    result.debt_count += 1;
+                    result.debt -= amount;
+                } else if k.balance_type == BalanceType::AVAILABLE {
                     result.available_count += 1;
                     result.available += amount;
                 } else {
@@ -224,20 +616,204 @@ impl BalanceManager {
                 }
             }
         }
+        let mut order_frozen = result.frozen;
+        for ((_, hold_asset), holds) in self.holds.iter() {
+            if hold_asset != asset {
+                continue;
+            }
+            for (purpose, amount) in holds {
+                *result.frozen_by_purpose.entry(purpose.clone()).or_insert_with(Decimal::zero) += amount;
+                order_frozen -= amount;
+            }
+        }
+        if !order_frozen.is_zero() {
+            result.frozen_by_purpose.insert(FreezePurpose::Order, order_frozen);
+        }
         result
     }
 }
 
+// A balance to deposit when seeding a test environment. Entries without a business id are
+// numbered by their position in the file, so loading the same file again is deduplicated.
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+pub struct BalanceSeedEntry {
+    pub user_id: u32,
+    pub asset: String,
+    pub amount: Decimal,
+    #[serde(default)]
+    pub business_id: Option<u64>,
+}
+
+// A json array of entries, or csv lines of `user_id,asset,amount[,business_id]` with an optional
+// header. Empty lines and lines starting with `#` are skipped.
+pub fn parse_balance_seed(content: &str, is_json: bool) -> Result<Vec<BalanceSeedEntry>> {
+    let mut entries: Vec<BalanceSeedEntry> = if is_json {
+        serde_json::from_str(content)?
+    } else {
+        let mut entries = Vec::new();
+        for (idx, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || (idx == 0 && line.starts_with("user_id")) {
+                continue;
+            }
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            let invalid = || anyhow!("invalid seed line {}: {}", idx + 1, line);
+            if fields.len() != 3 && fields.len() != 4 {
+                return Err(invalid());
+            }
+            entries.push(BalanceSeedEntry {
+                user_id: fields[0].parse().map_err(|_| invalid())?,
+                asset: fields[1].to_string(),
+                amount: fields[2].parse().map_err(|_| invalid())?,
+                business_id: match fields.get(3) {
+                    Some(id) => Some(id.parse().map_err(|_| invalid())?),
+                    None => None,
+                },
+            });
+        }
+        entries
+    };
+    for (idx, entry) in entries.iter_mut().enumerate() {
+        if entry.business_id.is_none() {
+            entry.business_id = Some(idx as u64 + 1);
+        }
+    }
+    Ok(entries)
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum BalanceUpdateError {
+    #[error("deposit amount {0} is below the minimum {1}")]
+    DepositTooSmall(Decimal, Decimal),
+    #[error("withdrawal amount {0} exceeds the maximum {1}")]
+    WithdrawalTooLarge(Decimal, Decimal),
+    #[error("withdrawals of {0} within the day would exceed the daily limit {1}")]
+    DailyWithdrawalLimitExceeded(Decimal, Decimal),
+    #[error("user {0} is reserved")]
+    ReservedUser(u32),
+    #[error("balance not enough")]
+    BalanceNotEnough,
+    #[error("balance overflow")]
+    Overflow,
+}
+
 #[derive(PartialEq, Eq, Hash)]
 struct BalanceUpdateKey {
     pub user_id: u32,
     pub asset: String,
-    pub business: String,
+    pub business: BusinessKind,
     pub business_id: u64,
 }
 
+const DAY_SECS: f64 = 86400.0;
+
+// The withdrawals of the assets with a daily limit, by user and asset, oldest first.
+// Replayed withdrawals are recorded at the time they were journaled.
+struct WithdrawalTracker {
+    window: config::WithdrawalWindow,
+    withdrawals: HashMap<(u32, String), VecDeque<(f64, Decimal)>>,
+}
+
+impl WithdrawalTracker {
+    fn window_start(&self, now: f64) -> f64 {
+        match self.window {
+            config::WithdrawalWindow::Rolling => now - DAY_SECS,
+            config::WithdrawalWindow::CalendarDay => (now / DAY_SECS).floor() * DAY_SECS,
+        }
+    }
+    // the total withdrawn within the window ending at `now`
+    fn withdrawn(&mut self, user_id: u32, asset: &str, now: f64) -> Decimal {
+        let start = self.window_start(now);
+        match self.withdrawals.get_mut(&(user_id, asset.to_string())) {
+            Some(entries) => {
+                // drop the withdrawals which have slid out of the window
+                while entries.front().map_or(false, |(timestamp, _)| *timestamp < start) {
+                    entries.pop_front();
+                }
+                entries.iter().fold(Decimal::zero(), |sum, (_, amount)| sum + amount)
+            }
+            None => Decimal::zero(),
+        }
+    }
+    fn record(&mut self, user_id: u32, asset: &str, timestamp: f64, amount: Decimal) {
+        self.withdrawn(user_id, asset, timestamp);
+        self.withdrawals
+            .entry((user_id, asset.to_string()))
+            .or_insert_with(VecDeque::new)
+            .push_back((timestamp, amount));
+    }
+}
+
+// far beyond any uptime, so the lru strategy only drops the entries beyond the capacity
+const LRU_ENTRY_TTL: Duration = Duration::from_secs(100 * 365 * 86400);
+
+// The dedup cache of the balance updates, the operations from requests are counted in the metrics.
+struct DedupCache {
+    cache: TtlCache<BalanceUpdateKey, ()>,
+    strategy: config::DedupStrategy,
+    capacity: usize,
+    entry_ttl: Duration,
+    // entries inserted since the last clear, up to the capacity. Expired entries stay counted, so
+    // with the ttl strategy, the evictions are an upper bound.
+    size: usize,
+    metrics: Metrics,
+}
+
+impl DedupCache {
+    fn new(config: &config::BalanceUpdateConfig, metrics: Metrics) -> DedupCache {
+        DedupCache {
+            cache: TtlCache::new(config.capacity),
+            strategy: config.strategy,
+            capacity: config.capacity,
+            entry_ttl: match config.strategy {
+                config::DedupStrategy::Ttl => config.entry_ttl,
+                config::DedupStrategy::Lru => LRU_ENTRY_TTL,
+            },
+            size: 0,
+            metrics,
+        }
+    }
+    fn contains(&mut self, key: &BalanceUpdateKey, real: bool) -> bool {
+        let hit = self.cache.get(key).is_some();
+        if real {
+            if hit {
+                self.metrics.balance_cache_hits.inc();
+            } else {
+                self.metrics.balance_cache_misses.inc();
+            }
+        }
+        hit
+    }
+    fn insert(&mut self, key: BalanceUpdateKey, real: bool) {
+        if self.size < self.capacity {
+            self.size += 1;
+            self.metrics.balance_cache_size.set(self.size as i64);
+        } else if real {
+            self.metrics.balance_cache_evictions.inc();
+        }
+        self.cache.insert(key, (), self.entry_ttl);
+        if real {
+            self.metrics.balance_cache_inserts.inc();
+        }
+    }
+    fn clear(&mut self) {
+        self.cache.clear();
+        self.size = 0;
+        self.metrics.balance_cache_size.set(0);
+    }
+    fn on_timer(&mut self) {
+        if self.strategy == config::DedupStrategy::Ttl {
+            self.clear();
+        }
+    }
+}
+
 pub struct BalanceUpdateController {
-    cache: TtlCache<BalanceUpdateKey, bool>,
+    cache: DedupCache,
+    timer_interval: Duration,
+    withdrawals: WithdrawalTracker,
+    reserved_users: HashSet<u32>,
+    clock: Rc<dyn Clock>,
     balance_manager: Rc<RefCell<BalanceManager>>,
     message_manager: Rc<RefCell<dyn MessageManager>>,
     history_writer: Rc<RefCell<dyn HistoryWriter>>,
@@ -248,78 +824,982 @@ impl BalanceUpdateController {
         balance_manager: Rc<RefCell<BalanceManager>>,
         message_manager: Rc<RefCell<dyn MessageManager>>,
         history_writer: Rc<RefCell<dyn HistoryWriter>>,
-    ) -> BalanceUpdateController {
-        let capacity = 1_000_000;
-        BalanceUpdateController {
-            cache: TtlCache::new(capacity),
+        config: &config::BalanceUpdateConfig,
+        metrics: Metrics,
+    ) -> Result<BalanceUpdateController> {
+        if config.capacity == 0 {
+            return Err(anyhow!("invalid balance update cache capacity"));
+        }
+        Ok(BalanceUpdateController {
+            cache: DedupCache::new(config, metrics),
+            timer_interval: config.timer_interval,
+            withdrawals: WithdrawalTracker {
+                window: config.withdrawal_window,
+                withdrawals: HashMap::new(),
+            },
+            reserved_users: HashSet::new(),
+            // the balance history is kept in order when the system clock steps back
+            clock: Rc::new(MonotonicClock::new(Rc::new(SystemClock))),
             balance_manager,
             message_manager,
             history_writer,
+        })
+    }
+    pub fn set_clock(&mut self, clock: Rc<dyn Clock>) {
+        self.clock = clock;
+    }
+    // the fee account, when there is one, must be among the reserved users
+    pub fn set_reserved_users(&mut self, user_ids: &[u32]) -> Result<()> {
+        let reserved_users: HashSet<u32> = user_ids.iter().copied().collect();
+        if reserved_users.len() != user_ids.len() {
+            return Err(anyhow!("duplicate reserved user ids"));
+        }
+        if let Some(fee_account) = self.balance_manager.borrow().fee_account {
+            if !reserved_users.contains(&fee_account) {
+                return Err(anyhow!("the fee account {} is not a reserved user", fee_account));
+            }
         }
+        self.reserved_users = reserved_users;
+        Ok(())
+    }
+    pub fn is_reserved_user(&self, user_id: u32) -> bool {
+        self.reserved_users.contains(&user_id)
     }
     pub fn reset(&mut self) {
-        self.cache.clear()
+        self.cache.clear();
+        self.withdrawals.withdrawals.clear();
     }
     pub fn on_timer(&mut self) {
-        self.cache.clear()
+        self.cache.on_timer();
     }
     pub fn timer_interval(&self) -> Duration {
-        Duration::from_secs(60)
+        self.timer_interval
     }
-    // return false if duplicate
+    // return Ok(false) if duplicate
+    // limits are only checked for new operations, replayed ones have been accepted before
     pub fn update_user_balance(
         &mut self,
         real: bool,
         user_id: u32,
         asset: &str,
-        business: String,
+        business: BusinessKind,
         business_id: u64,
         change: Decimal,
-        mut detail: serde_json::Value,
-    ) -> bool {
+        detail: serde_json::Value,
+    ) -> std::result::Result<bool, BalanceUpdateError> {
+        let timestamp = self.clock.now();
+        self.update_user_balance_at(real, user_id, asset, business, business_id, change, detail, timestamp)
+    }
+    // `timestamp` is when the update is journaled, the withdrawals count toward the daily limits from then
+    pub fn update_user_balance_at(
+        &mut self,
+        real: bool,
+        user_id: u32,
+        asset: &str,
+        business: BusinessKind,
+        business_id: u64,
+        change: Decimal,
+        detail: serde_json::Value,
+        timestamp: f64,
+    ) -> std::result::Result<bool, BalanceUpdateError> {
         let cache_key = BalanceUpdateKey {
             user_id,
             asset: asset.to_string(),
             business: business.clone(),
             business_id,
         };
-        if self.cache.contains_key(&cache_key) {
-            return false;
+        if self.cache.contains(&cache_key, real) {
+            return Ok(false);
+        }
+        if real {
+            // only an operator can touch the balances of the system accounts
+            if business != BusinessKind::Adjustment && self.is_reserved_user(user_id) {
+                return Err(BalanceUpdateError::ReservedUser(user_id));
+            }
+            self.check_limit(user_id, asset, &business, &change, timestamp)?;
         }
         let abs_change = change.abs();
-        let new_balance = if abs_change.is_sign_positive() || abs_change.is_zero() {
+        let new_balance = if change.is_sign_positive() || change.is_zero() {
             self.balance_manager
                 .borrow_mut()
-                .add(user_id, BalanceType::AVAILABLE, &asset, &abs_change)
+                .add(user_id, BalanceType::AVAILABLE, &asset, &abs_change)?
         } else {
-            self.balance_manager
-                .borrow_mut()
-                .sub(user_id, BalanceType::AVAILABLE, &asset, &abs_change)
+            let mut balance_manager = self.balance_manager.borrow_mut();
+            let available = balance_manager.get(user_id, BalanceType::AVAILABLE, &asset);
+            if (available + balance_manager.credit_limit(user_id)).lt(&abs_change) {
+                return Err(BalanceUpdateError::BalanceNotEnough);
+            }
+            balance_manager.sub(user_id, BalanceType::AVAILABLE, &asset, &abs_change)?
+        };
+        tracing::debug!("change user balance: {} {} {}", user_id, asset, change);
+        if business == BusinessKind::Withdraw && change.is_sign_negative() {
+            self.record_withdrawal(user_id, asset, timestamp, abs_change);
+        }
+        self.cache.insert(cache_key, real);
+        if real {
+            self.emit_balance_change(user_id, asset, business, business_id, change, new_balance, detail);
+        }
+        Ok(true)
+    }
+    // A manual correction, the operator and the reason are kept in the history detail.
+    // A debit is checked against the balance like any other update.
+    pub fn adjust_balance(
+        &mut self,
+        real: bool,
+        user_id: u32,
+        asset: &str,
+        business_id: u64,
+        change: Decimal,
+        operator: &str,
+        reason: &str,
+    ) -> std::result::Result<bool, BalanceUpdateError> {
+        let detail = serde_json::json!({ "operator": operator, "reason": reason });
+        self.update_user_balance(real, user_id, asset, BusinessKind::Adjustment, business_id, change, detail)
+    }
+    // Deposit the seed entries, every entry is checked before any is applied.
+    // Returns the applied entries, the ones found in the dedup cache are skipped.
+    pub fn apply_balance_seed<'a>(&mut self, entries: &'a [BalanceSeedEntry]) -> Result<Vec<&'a BalanceSeedEntry>> {
+        {
+            let balance_manager = self.balance_manager.borrow();
+            for entry in entries {
+                if !balance_manager.asset_manager.asset_exist(&entry.asset) {
+                    return Err(anyhow!("invalid asset {} in seed", entry.asset));
+                }
+                if !entry.amount.is_sign_positive() || entry.amount.is_zero() {
+                    return Err(anyhow!("invalid amount {} in seed", entry.amount));
+                }
+                if self.is_reserved_user(entry.user_id) {
+                    return Err(anyhow!("reserved user {} in seed", entry.user_id));
+                }
+            }
+        }
+        let mut applied = Vec::new();
+        for entry in entries {
+            let business_id = entry.business_id.unwrap_or_default();
+            let detail = serde_json::json!({ "seed": true });
+            if self.update_user_balance(
+                true,
+                entry.user_id,
+                &entry.asset,
+                BusinessKind::Deposit,
+                business_id,
+                entry.amount,
+                detail,
+            )? {
+                applied.push(entry);
+            }
+        }
+        Ok(applied)
+    }
+    pub fn has_withdrawal_limits(&self) -> bool {
+        self.balance_manager
+            .borrow()
+            .asset_manager
+            .assets
+            .values()
+            .any(|asset_info| asset_info.daily_withdrawal_limit.is_some())
+    }
+    // only the withdrawals of the assets with a daily limit are kept
+    pub fn record_withdrawal(&mut self, user_id: u32, asset: &str, timestamp: f64, amount: Decimal) {
+        let limited = self
+            .balance_manager
+            .borrow()
+            .asset_manager
+            .asset_get(asset)
+            .map_or(false, |asset_info| asset_info.daily_withdrawal_limit.is_some());
+        if limited {
+            self.withdrawals.record(user_id, asset, timestamp, amount);
+        }
+    }
+    fn check_limit(
+        &mut self,
+        user_id: u32,
+        asset: &str,
+        business: &BusinessKind,
+        change: &Decimal,
+        timestamp: f64,
+    ) -> std::result::Result<(), BalanceUpdateError> {
+        let balance_manager = self.balance_manager.borrow();
+        let asset_info = match balance_manager.asset_manager.asset_get(asset) {
+            Some(asset_info) => asset_info,
+            None => return Ok(()),
+        };
+        let amount = change.abs();
+        match business {
+            BusinessKind::Deposit => {
+                if let Some(min_deposit) = asset_info.min_deposit {
+                    if amount.lt(&min_deposit) {
+                        return Err(BalanceUpdateError::DepositTooSmall(amount, min_deposit));
+                    }
+                }
+            }
+            BusinessKind::Withdraw => {
+                if let Some(max_withdrawal) = asset_info.max_withdrawal {
+                    if amount.gt(&max_withdrawal) {
+                        return Err(BalanceUpdateError::WithdrawalTooLarge(amount, max_withdrawal));
+                    }
+                }
+                if let Some(daily_limit) = asset_info.daily_withdrawal_limit {
+                    let total = self.withdrawals.withdrawn(user_id, asset, timestamp) + amount;
+                    if total.gt(&daily_limit) {
+                        return Err(BalanceUpdateError::DailyWithdrawalLimitExceeded(total, daily_limit));
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+    // return Ok(false) if duplicate
+    // both legs share the same business and business_id
+    pub fn transfer(
+        &mut self,
+        real: bool,
+        from: u32,
+        to: u32,
+        asset: &str,
+        business: BusinessKind,
+        business_id: u64,
+        amount: Decimal,
+        mut detail: serde_json::Value,
+    ) -> Result<bool> {
+        let from_key = BalanceUpdateKey {
+            user_id: from,
+            asset: asset.to_string(),
+            business: business.clone(),
+            business_id,
         };
-        log::debug!("change user balance: {} {} {}", user_id, asset, change);
-        self.cache.insert(cache_key, true, Duration::from_secs(3600));
+        let to_key = BalanceUpdateKey {
+            user_id: to,
+            asset: asset.to_string(),
+            business: business.clone(),
+            business_id,
+        };
+        if self.cache.contains(&from_key, real) || self.cache.contains(&to_key, real) {
+            return Ok(false);
+        }
+        // the same guard as a single update, on both sides
+        if real && business != BusinessKind::Adjustment {
+            for user_id in &[from, to] {
+                if self.is_reserved_user(*user_id) {
+                    return Err(BalanceUpdateError::ReservedUser(*user_id).into());
+                }
+            }
+        }
+        let (from_balance, to_balance) = self.balance_manager.borrow_mut().transfer(from, to, asset, &amount)?;
+        tracing::debug!("transfer user balance: {} -> {} {} {}", from, to, asset, amount);
+        self.cache.insert(from_key, real);
+        self.cache.insert(to_key, real);
         if real {
-            detail["id"] = serde_json::Value::from(business_id);
-            let balance_history = BalanceHistory {
-                time: FTimestamp(utils::current_timestamp()).into(),
-                user_id: user_id as i32,
-                asset: asset.to_string(),
-                business: business.clone(),
-                change,
-                balance: new_balance,
-                detail: detail.to_string(),
+            detail["from"] = serde_json::Value::from(from);
+            detail["to"] = serde_json::Value::from(to);
+            self.emit_balance_change(from, asset, business.clone(), business_id, -amount, from_balance, detail.clone());
+            self.emit_balance_change(to, asset, business, business_id, amount, to_balance, detail);
+        }
+        Ok(true)
+    }
+    fn emit_balance_change(
+        &mut self,
+        user_id: u32,
+        asset: &str,
+        business: BusinessKind,
+        business_id: u64,
+        change: Decimal,
+        new_balance: Decimal,
+        mut detail: serde_json::Value,
+    ) {
+        detail["id"] = serde_json::Value::from(business_id);
+        let now = self.clock.now();
+        let balance_history = BalanceHistory {
+            time: FTimestamp(now).into(),
+            user_id: user_id as i32,
+            asset: asset.to_string(),
+            business: business.clone(),
+            change,
+            balance: new_balance,
+            detail: detail.to_string(),
+        };
+        self.history_writer.borrow_mut().append_balance_history(balance_history);
+
+        let message = BalanceMessage::new(FTimestamp(now).into(), user_id, asset.to_string(), business.to_string(), change);
+        self.message_manager.borrow_mut().push_balance_message(&message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::DummyHistoryWriter;
+    use crate::message::DummyMessageManager;
+    use crate::utils::MockClock;
+    use rust_decimal_macros::*;
+    use serde_json::json;
+
+    fn usdt() -> String {
+        String::from("USDT")
+    }
+    fn get_simple_asset_config() -> Vec<config::Asset> {
+        vec![config::Asset {
+            name: usdt(),
+            prec_save: 8,
+            prec_show: 8,
+            ..Default::default()
+        }]
+    }
+    fn get_update_controller(asset_config: &[config::Asset]) -> BalanceUpdateController {
+        BalanceUpdateController::new(
+            Rc::new(RefCell::new(BalanceManager::new(asset_config).unwrap())),
+            Rc::new(RefCell::new(DummyMessageManager)),
+            Rc::new(RefCell::new(DummyHistoryWriter)),
+            &Default::default(),
+            Metrics::default(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_margin_balance() {
+        let mut balance_manager = BalanceManager::new_with_margin(&get_simple_asset_config()).unwrap();
+        balance_manager.set_credit_limit(101, &dec!(100));
+        balance_manager.add(101, BalanceType::AVAILABLE, &usdt(), &dec!(30)).unwrap();
+        balance_manager.add(102, BalanceType::AVAILABLE, &usdt(), &dec!(50)).unwrap();
+        let new_value = balance_manager.sub(101, BalanceType::AVAILABLE, &usdt(), &dec!(80)).unwrap();
+        assert_eq!(new_value, dec!(-50));
+        assert_eq!(balance_manager.get(101, BalanceType::AVAILABLE, &usdt()), dec!(-50));
+
+        let status = balance_manager.status(&usdt());
+        assert_eq!(status.total, dec!(0));
+        assert_eq!(status.gross, dec!(100));
+        assert_eq!(status.available_count, 1);
+        assert_eq!(status.available, dec!(50));
+        assert_eq!(status.debt_count, 1);
+        assert_eq!(status.debt, dec!(50));
+    }
+
+    #[test]
+    fn test_invalid_asset_precision() {
+        let asset_config = vec![config::Asset {
+            name: usdt(),
+            prec_save: 4,
+            prec_show: 6,
+            ..Default::default()
+        }];
+        let err = AssetManager::new(&asset_config).err().unwrap();
+        assert_eq!(format!("{}", err), "invalid precision for asset USDT: prec_show(6) > prec_save(4)");
+    }
+
+    #[test]
+    fn test_register_asset() {
+        let mut balance_manager = BalanceManager::new(&get_simple_asset_config()).unwrap();
+        let eth = config::Asset {
+            name: String::from("ETH"),
+            prec_save: 8,
+            prec_show: 6,
+            ..Default::default()
+        };
+        balance_manager.asset_manager.register_asset(&eth).unwrap();
+        // idempotent
+        balance_manager.asset_manager.register_asset(&eth).unwrap();
+        assert_eq!(balance_manager.get(101, BalanceType::AVAILABLE, "ETH"), dec!(0));
+        balance_manager.add(101, BalanceType::AVAILABLE, "ETH", &dec!(1.5)).unwrap();
+        assert_eq!(balance_manager.get(101, BalanceType::AVAILABLE, "ETH"), dec!(1.5));
+
+        let conflict = config::Asset { prec_save: 4, ..eth };
+        assert!(balance_manager.asset_manager.register_asset(&conflict).is_err());
+        let bad_prec = config::Asset {
+            name: String::from("BTC"),
+            prec_save: 4,
+            prec_show: 8,
+            ..Default::default()
+        };
+        assert!(balance_manager.asset_manager.register_asset(&bad_prec).is_err());
+        assert!(!balance_manager.asset_manager.asset_exist("BTC"));
+    }
+
+    #[test]
+    fn test_round_asset() {
+        let expected = vec![
+            (config::RoundingStrategy::Bankers, dec!(1.00), dec!(1.00)),
+            (config::RoundingStrategy::HalfUp, dec!(1.01), dec!(1.00)),
+            (config::RoundingStrategy::Truncate, dec!(1.00), dec!(1.00)),
+            (config::RoundingStrategy::AwayFromZero, dec!(1.01), dec!(1.01)),
+        ];
+        for (rounding, rounded_half, rounded_small) in expected {
+            let asset_config = vec![config::Asset {
+                name: usdt(),
+                prec_save: 2,
+                prec_show: 2,
+                rounding,
+                ..Default::default()
+            }];
+            let mut balance_manager = BalanceManager::new(&asset_config).unwrap();
+            assert_eq!(balance_manager.round_asset(&usdt(), &dec!(1.005)), rounded_half);
+            assert_eq!(balance_manager.round_asset(&usdt(), &dec!(1.001)), rounded_small);
+            assert_eq!(
+                balance_manager.add(101, BalanceType::AVAILABLE, &usdt(), &dec!(1.005)).unwrap(),
+                rounded_half
+            );
+        }
+    }
+
+    #[test]
+    fn test_get_all_for_user() {
+        let asset_config = vec![
+            config::Asset {
+                name: usdt(),
+                prec_save: 8,
+                prec_show: 8,
+                ..Default::default()
+            },
+            config::Asset {
+                name: String::from("ETH"),
+                prec_save: 8,
+                prec_show: 8,
+                ..Default::default()
+            },
+        ];
+        let mut balance_manager = BalanceManager::new(&asset_config).unwrap();
+        balance_manager.add(101, BalanceType::AVAILABLE, &usdt(), &dec!(30)).unwrap();
+        balance_manager.frozen(101, &usdt(), &dec!(10)).unwrap();
+        balance_manager.add(101, BalanceType::AVAILABLE, "ETH", &dec!(1)).unwrap();
+        balance_manager.sub(101, BalanceType::AVAILABLE, "ETH", &dec!(1)).unwrap();
+        balance_manager.add(102, BalanceType::AVAILABLE, &usdt(), &dec!(50)).unwrap();
+
+        let balances = balance_manager.get_all_for_user(101);
+        assert_eq!(balances.len(), 1);
+        let usdt_status = balances.get(&usdt()).unwrap();
+        assert_eq!(usdt_status.available, dec!(20));
+        assert_eq!(usdt_status.frozen, dec!(10));
+        assert_eq!(usdt_status.total, dec!(30));
+    }
+
+    #[test]
+    fn test_freeze_with_purpose() {
+        let mut balance_manager = BalanceManager::new(&get_simple_asset_config()).unwrap();
+        balance_manager.add(101, BalanceType::AVAILABLE, &usdt(), &dec!(100)).unwrap();
+        balance_manager.frozen(101, &usdt(), &dec!(30)).unwrap();
+        balance_manager
+            .freeze_with_purpose(101, &usdt(), &dec!(20), FreezePurpose::Withdrawal)
+            .unwrap();
+        balance_manager
+            .freeze_with_purpose(101, &usdt(), &dec!(5), FreezePurpose::from("margin"))
+            .unwrap();
+        assert_eq!(balance_manager.get(101, BalanceType::FREEZE, &usdt()), dec!(55));
+
+        // a trade takes the frozen balance of an order directly
+        balance_manager.sub(101, BalanceType::FREEZE, &usdt(), &dec!(10)).unwrap();
+        assert_eq!(balance_manager.frozen_for_purpose(101, &usdt(), &FreezePurpose::Order), dec!(20));
+
+        // order holds can't be released from the withdrawal hold
+        assert!(balance_manager
+            .unfreeze_with_purpose(101, &usdt(), &dec!(25), FreezePurpose::Order)
+            .is_err());
+        balance_manager.unfrozen(101, &usdt(), &dec!(20));
+        assert_eq!(
+            balance_manager.frozen_for_purpose(101, &usdt(), &FreezePurpose::Withdrawal),
+            dec!(20)
+        );
+
+        balance_manager
+            .unfreeze_with_purpose(101, &usdt(), &dec!(8), FreezePurpose::Withdrawal)
+            .unwrap();
+        assert!(balance_manager
+            .unfreeze_with_purpose(101, &usdt(), &dec!(13), FreezePurpose::Withdrawal)
+            .is_err());
+        assert_eq!(balance_manager.get(101, BalanceType::AVAILABLE, &usdt()), dec!(73));
+
+        let status = balance_manager.status(&usdt());
+        assert_eq!(status.frozen, dec!(17));
+        let by_purpose: Vec<(FreezePurpose, Decimal)> = status.frozen_by_purpose.into_iter().collect();
+        assert_eq!(
+            by_purpose,
+            vec![
+                (FreezePurpose::Withdrawal, dec!(12)),
+                (FreezePurpose::Custom("margin".to_string()), dec!(5)),
+            ]
+        );
+        balance_manager
+            .unfreeze_with_purpose(101, &usdt(), &dec!(12), FreezePurpose::Withdrawal)
+            .unwrap();
+        assert!(balance_manager.holds[&(101, usdt())].get(&FreezePurpose::Withdrawal).is_none());
+    }
+
+    #[test]
+    fn test_transaction_all_or_nothing() {
+        let mut balance_manager = BalanceManager::new(&get_simple_asset_config()).unwrap();
+        balance_manager.add(101, BalanceType::AVAILABLE, &usdt(), &dec!(100)).unwrap();
+        balance_manager.add(102, BalanceType::AVAILABLE, &usdt(), &dec!(10)).unwrap();
+
+        let mut transaction = BalanceTransaction::default();
+        transaction.sub(101, BalanceType::AVAILABLE, &usdt(), &dec!(30));
+        transaction.add(102, BalanceType::AVAILABLE, &usdt(), &dec!(30));
+        // 101 goes below zero with the second debit
+        transaction.sub(101, BalanceType::AVAILABLE, &usdt(), &dec!(80));
+        assert_eq!(balance_manager.commit(&transaction), Err(BalanceUpdateError::BalanceNotEnough));
+        assert_eq!(balance_manager.get(101, BalanceType::AVAILABLE, &usdt()), dec!(100));
+        assert_eq!(balance_manager.get(102, BalanceType::AVAILABLE, &usdt()), dec!(10));
+
+        let mut transaction = BalanceTransaction::default();
+        transaction.add(102, BalanceType::AVAILABLE, &usdt(), &dec!(1));
+        transaction.add(103, BalanceType::FREEZE, &usdt(), &Decimal::max_value());
+        transaction.add(103, BalanceType::FREEZE, &usdt(), &dec!(1));
+        assert_eq!(balance_manager.commit(&transaction), Err(BalanceUpdateError::Overflow));
+        assert_eq!(balance_manager.get(102, BalanceType::AVAILABLE, &usdt()), dec!(10));
+        assert_eq!(balance_manager.get(103, BalanceType::FREEZE, &usdt()), dec!(0));
+
+        let mut transaction = BalanceTransaction::default();
+        let first = transaction.sub(101, BalanceType::AVAILABLE, &usdt(), &dec!(30));
+        transaction.add(102, BalanceType::AVAILABLE, &usdt(), &dec!(30));
+        let second = transaction.sub(101, BalanceType::AVAILABLE, &usdt(), &dec!(70));
+        let balances = balance_manager.commit(&transaction).unwrap();
+        assert_eq!((balances[first], balances[second]), (dec!(70), dec!(0)));
+        assert_eq!(balance_manager.get(102, BalanceType::AVAILABLE, &usdt()), dec!(40));
+    }
+
+    #[test]
+    fn test_total_liabilities() {
+        let asset_config = vec![
+            config::Asset {
+                name: usdt(),
+                prec_save: 8,
+                prec_show: 8,
+                ..Default::default()
+            },
+            config::Asset {
+                name: String::from("ETH"),
+                prec_save: 8,
+                prec_show: 8,
+                ..Default::default()
+            },
+        ];
+        let mut balance_manager = BalanceManager::new(&asset_config).unwrap();
+        balance_manager
+            .set_fee_account(&config::FeeAccountConfig {
+                user_id: Some(1),
+                fee_asset: None,
+            })
+            .unwrap();
+        balance_manager.add(101, BalanceType::AVAILABLE, &usdt(), &dec!(100)).unwrap();
+        balance_manager.frozen(101, &usdt(), &dec!(40)).unwrap();
+        balance_manager.add(102, BalanceType::AVAILABLE, &usdt(), &dec!(50.5)).unwrap();
+        balance_manager.add(102, BalanceType::AVAILABLE, "ETH", &dec!(3)).unwrap();
+        balance_manager.add(103, BalanceType::AVAILABLE, "ETH", &dec!(0.25)).unwrap();
+        balance_manager.charge_fee(102, &usdt(), &dec!(0.5)).unwrap();
+
+        let liabilities = balance_manager.total_liabilities();
+        assert_eq!(liabilities.len(), 2);
+        assert_eq!(liabilities[&usdt()], dec!(150));
+        assert_eq!(liabilities["ETH"], dec!(3.25));
+        assert_eq!(balance_manager.get(1, BalanceType::AVAILABLE, &usdt()), dec!(0.5));
+    }
+
+    #[test]
+    fn test_reset_reclaims_capacity() {
+        let mut balance_manager = BalanceManager::with_capacity(&get_simple_asset_config(), 16).unwrap();
+        assert!(balance_manager.balances.capacity() >= 16);
+        for user_id in 0..10000 {
+            balance_manager.add(user_id, BalanceType::AVAILABLE, &usdt(), &dec!(1)).unwrap();
+        }
+        assert!(balance_manager.balances.capacity() >= 10000);
+        balance_manager.reset();
+        assert!(balance_manager.balances.is_empty());
+        let capacity = balance_manager.balances.capacity();
+        assert!((16..10000).contains(&capacity), "{}", capacity);
+    }
+
+    #[test]
+    fn test_transfer() {
+        let mut balance_manager = BalanceManager::new(&get_simple_asset_config()).unwrap();
+        balance_manager.add(101, BalanceType::AVAILABLE, &usdt(), &dec!(30)).unwrap();
+        assert!(balance_manager.transfer(101, 102, &usdt(), &dec!(40)).is_err());
+        assert_eq!(
+            balance_manager.transfer(101, 102, &usdt(), &dec!(10)).unwrap(),
+            (dec!(20), dec!(10))
+        );
+        assert_eq!(balance_manager.get(101, BalanceType::AVAILABLE, &usdt()), dec!(20));
+        assert_eq!(balance_manager.get(102, BalanceType::AVAILABLE, &usdt()), dec!(10));
+    }
+
+    #[test]
+    fn test_balance_overflow() {
+        let near_max = Decimal::max_value() - dec!(1);
+        let mut controller = get_update_controller(&get_simple_asset_config());
+        let deposit = |controller: &mut BalanceUpdateController, business_id: u64, change: Decimal| {
+            controller.update_user_balance(true, 101, &usdt(), BusinessKind::Deposit, business_id, change, json!({}))
+        };
+        assert_eq!(deposit(&mut controller, 1, near_max), Ok(true));
+        assert_eq!(deposit(&mut controller, 2, dec!(10)), Err(BalanceUpdateError::Overflow));
+        assert_eq!(
+            controller.balance_manager.borrow().get(101, BalanceType::AVAILABLE, &usdt()),
+            near_max
+        );
+
+        let mut balance_manager = controller.balance_manager.borrow_mut();
+        assert!(!balance_manager.can_add(101, BalanceType::AVAILABLE, &usdt(), &dec!(10)));
+        balance_manager.add(102, BalanceType::AVAILABLE, &usdt(), &dec!(10)).unwrap();
+        // neither leg of a failed transfer is applied
+        assert!(balance_manager.transfer(102, 101, &usdt(), &dec!(10)).is_err());
+        assert_eq!(balance_manager.get(102, BalanceType::AVAILABLE, &usdt()), dec!(10));
+        balance_manager.frozen(101, &usdt(), &near_max).unwrap();
+        balance_manager.add(101, BalanceType::AVAILABLE, &usdt(), &near_max).unwrap();
+        assert!(balance_manager.frozen(101, &usdt(), &dec!(10)).is_err());
+        assert_eq!(balance_manager.get(101, BalanceType::FREEZE, &usdt()), near_max);
+        assert_eq!(balance_manager.get(101, BalanceType::AVAILABLE, &usdt()), near_max);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_spot_balance_cannot_be_negative() {
+        let mut balance_manager = BalanceManager::new(&get_simple_asset_config()).unwrap();
+        balance_manager.set_credit_limit(101, &dec!(100));
+        balance_manager.add(101, BalanceType::AVAILABLE, &usdt(), &dec!(30)).unwrap();
+        balance_manager.sub(101, BalanceType::AVAILABLE, &usdt(), &dec!(80)).unwrap();
+    }
+
+    #[test]
+    fn test_deposit_withdrawal_limit() {
+        let mut asset_config = get_simple_asset_config();
+        asset_config[0].min_deposit = Some(dec!(10));
+        asset_config[0].max_withdrawal = Some(dec!(50));
+        let mut controller = get_update_controller(&asset_config);
+        let deposit = |controller: &mut BalanceUpdateController, business_id: u64, change: Decimal| {
+            controller.update_user_balance(true, 101, &usdt(), BusinessKind::Deposit, business_id, change, json!({}))
+        };
+        assert_eq!(
+            deposit(&mut controller, 1, dec!(5)),
+            Err(BalanceUpdateError::DepositTooSmall(dec!(5), dec!(10)))
+        );
+        assert_eq!(deposit(&mut controller, 2, dec!(100)), Ok(true));
+        // duplicate check runs before the limit check
+        assert_eq!(deposit(&mut controller, 2, dec!(100)), Ok(false));
+        assert_eq!(
+            controller.update_user_balance(true, 101, &usdt(), BusinessKind::Withdraw, 3, dec!(-60), json!({})),
+            Err(BalanceUpdateError::WithdrawalTooLarge(dec!(60), dec!(50)))
+        );
+        assert_eq!(
+            controller.update_user_balance(true, 101, &usdt(), BusinessKind::Withdraw, 4, dec!(-40), json!({})),
+            Ok(true)
+        );
+        assert_eq!(
+            controller.balance_manager.borrow().get(101, BalanceType::AVAILABLE, &usdt()),
+            dec!(60)
+        );
+    }
+
+    #[test]
+    fn test_reserved_user() {
+        let mut controller = get_update_controller(&get_simple_asset_config());
+        controller
+            .balance_manager
+            .borrow_mut()
+            .set_fee_account(&config::FeeAccountConfig {
+                user_id: Some(1),
+                ..Default::default()
+            })
+            .unwrap();
+        assert!(controller.set_reserved_users(&[0]).is_err());
+        assert!(controller.set_reserved_users(&[0, 1, 1]).is_err());
+        controller.set_reserved_users(&[0, 1]).unwrap();
+
+        let deposit = |controller: &mut BalanceUpdateController, real: bool, user_id: u32, business_id: u64| {
+            controller.update_user_balance(real, user_id, &usdt(), BusinessKind::Deposit, business_id, dec!(100), json!({}))
+        };
+        assert_eq!(deposit(&mut controller, true, 0, 1), Err(BalanceUpdateError::ReservedUser(0)));
+        assert_eq!(deposit(&mut controller, true, 1, 2), Err(BalanceUpdateError::ReservedUser(1)));
+        assert_eq!(deposit(&mut controller, true, 101, 3), Ok(true));
+        // the operation log was accepted before the user was reserved
+        assert_eq!(deposit(&mut controller, false, 0, 4), Ok(true));
+        // an operator still can
+        assert_eq!(
+            controller.adjust_balance(true, 0, &usdt(), 5, dec!(-100), "admin", "cleanup"),
+            Ok(true)
+        );
+        assert_eq!(controller.balance_manager.borrow().get(0, BalanceType::AVAILABLE, &usdt()), dec!(0));
+    }
+
+    #[test]
+    fn test_reserved_user_transfer() {
+        let mut controller = get_update_controller(&get_simple_asset_config());
+        controller.set_reserved_users(&[0]).unwrap();
+        let transfer =
+            |controller: &mut BalanceUpdateController, real: bool, from: u32, to: u32, business: BusinessKind, business_id: u64| {
+                controller.transfer(real, from, to, &usdt(), business, business_id, dec!(10), json!({}))
             };
-            self.history_writer.borrow_mut().append_balance_history(balance_history);
-
-            let message = BalanceMessage {
-                timestamp: FTimestamp(utils::current_timestamp()).into(),
-                user_id,
-                asset: asset.to_string(),
-                business,
-                change: change.to_string(),
+        for user_id in &[0, 101] {
+            controller
+                .balance_manager
+                .borrow_mut()
+                .add(*user_id, BalanceType::AVAILABLE, &usdt(), &dec!(100))
+                .unwrap();
+        }
+        for (from, to) in &[(0, 101), (101, 0)] {
+            let err = transfer(&mut controller, true, *from, *to, BusinessKind::Transfer, 3).unwrap_err();
+            assert_eq!(err.downcast_ref::<BalanceUpdateError>(), Some(&BalanceUpdateError::ReservedUser(0)));
+        }
+        assert_eq!(
+            controller.balance_manager.borrow().get(0, BalanceType::AVAILABLE, &usdt()),
+            dec!(100)
+        );
+        assert_eq!(
+            controller.balance_manager.borrow().get(101, BalanceType::AVAILABLE, &usdt()),
+            dec!(100)
+        );
+        // replayed and operator transfers go through
+        assert!(transfer(&mut controller, false, 0, 101, BusinessKind::Transfer, 4).unwrap());
+        assert!(transfer(&mut controller, true, 101, 0, BusinessKind::Adjustment, 5).unwrap());
+    }
+
+    #[test]
+    fn test_daily_withdrawal_limit() {
+        let mut asset_config = get_simple_asset_config();
+        asset_config[0].daily_withdrawal_limit = Some(dec!(100));
+        let mut controller = get_update_controller(&asset_config);
+        let t = 1_600_000_000.0;
+        let update = |controller: &mut BalanceUpdateController, real: bool, business_id: u64, change: Decimal, timestamp: f64| {
+            let business = if change.is_sign_negative() {
+                BusinessKind::Withdraw
+            } else {
+                BusinessKind::Deposit
             };
-            self.message_manager.borrow_mut().push_balance_message(&message);
+            controller.update_user_balance_at(real, 101, &usdt(), business, business_id, change, json!({}), timestamp)
+        };
+        assert_eq!(update(&mut controller, true, 1, dec!(500), t), Ok(true));
+        assert_eq!(update(&mut controller, true, 2, dec!(-60), t), Ok(true));
+        // up to the limit
+        assert_eq!(update(&mut controller, true, 3, dec!(-40), t + 3600.0), Ok(true));
+        assert_eq!(
+            update(&mut controller, true, 4, dec!(-1), t + 7200.0),
+            Err(BalanceUpdateError::DailyWithdrawalLimitExceeded(dec!(101), dec!(100)))
+        );
+        // the first withdrawal slides out of the window, the second is still counted
+        assert_eq!(
+            update(&mut controller, true, 5, dec!(-70), t + DAY_SECS + 1.0),
+            Err(BalanceUpdateError::DailyWithdrawalLimitExceeded(dec!(110), dec!(100)))
+        );
+        assert_eq!(update(&mut controller, true, 6, dec!(-60), t + DAY_SECS + 1.0), Ok(true));
+        assert_eq!(
+            controller.balance_manager.borrow().get(101, BalanceType::AVAILABLE, &usdt()),
+            dec!(340)
+        );
+
+        // replayed withdrawals are counted at their journaled time, without being checked
+        let mut controller = get_update_controller(&asset_config);
+        assert_eq!(update(&mut controller, false, 1, dec!(500), t), Ok(true));
+        assert_eq!(update(&mut controller, false, 2, dec!(-60), t), Ok(true));
+        assert_eq!(update(&mut controller, false, 3, dec!(-40), t + 3600.0), Ok(true));
+        assert!(update(&mut controller, true, 4, dec!(-1), t + 7200.0).is_err());
+
+        // a calendar day resets at 00:00 UTC
+        let mut controller = BalanceUpdateController::new(
+            Rc::new(RefCell::new(BalanceManager::new(&asset_config).unwrap())),
+            Rc::new(RefCell::new(DummyMessageManager)),
+            Rc::new(RefCell::new(DummyHistoryWriter)),
+            &config::BalanceUpdateConfig {
+                withdrawal_window: config::WithdrawalWindow::CalendarDay,
+                ..Default::default()
+            },
+            Metrics::default(),
+        )
+        .unwrap();
+        let midnight = (t / DAY_SECS).ceil() * DAY_SECS;
+        assert_eq!(update(&mut controller, true, 1, dec!(500), t), Ok(true));
+        assert_eq!(update(&mut controller, true, 2, dec!(-100), midnight - 1.0), Ok(true));
+        assert!(update(&mut controller, true, 3, dec!(-1), midnight - 1.0).is_err());
+        assert_eq!(update(&mut controller, true, 4, dec!(-100), midnight), Ok(true));
+    }
+
+    #[test]
+    fn test_withdrawal_window_with_clock() {
+        let mut asset_config = get_simple_asset_config();
+        asset_config[0].daily_withdrawal_limit = Some(dec!(100));
+        let mut controller = get_update_controller(&asset_config);
+        let clock = Rc::new(MockClock::new(1_600_000_000.0));
+        controller.set_clock(clock.clone());
+        let mut update = |business: BusinessKind, business_id: u64, change: Decimal| {
+            controller.update_user_balance(true, 101, &usdt(), business, business_id, change, json!({}))
+        };
+        assert_eq!(update(BusinessKind::Deposit, 1, dec!(500)), Ok(true));
+        assert_eq!(update(BusinessKind::Withdraw, 2, dec!(-100)), Ok(true));
+        // a full day later the withdrawal is still in the window
+        clock.advance(DAY_SECS);
+        assert!(update(BusinessKind::Withdraw, 3, dec!(-1)).is_err());
+        clock.advance(1.0);
+        assert_eq!(update(BusinessKind::Withdraw, 4, dec!(-100)), Ok(true));
+    }
+
+    #[test]
+    fn test_dedup_cache_metrics() {
+        let metrics = Metrics::default();
+        let new_controller = |strategy: config::DedupStrategy| {
+            BalanceUpdateController::new(
+                Rc::new(RefCell::new(BalanceManager::new(&get_simple_asset_config()).unwrap())),
+                Rc::new(RefCell::new(DummyMessageManager)),
+                Rc::new(RefCell::new(DummyHistoryWriter)),
+                &config::BalanceUpdateConfig {
+                    capacity: 2,
+                    strategy,
+                    ..Default::default()
+                },
+                metrics.clone(),
+            )
+            .unwrap()
+        };
+        let deposit = |controller: &mut BalanceUpdateController, real: bool, business_id: u64| {
+            controller
+                .update_user_balance(real, 101, &usdt(), BusinessKind::Deposit, business_id, dec!(10), json!({}))
+                .unwrap()
+        };
+        let counts = || {
+            (
+                metrics.balance_cache_hits.get(),
+                metrics.balance_cache_misses.get(),
+                metrics.balance_cache_inserts.get(),
+                metrics.balance_cache_evictions.get(),
+            )
+        };
+
+        let mut controller = new_controller(config::DedupStrategy::Ttl);
+        assert!(deposit(&mut controller, true, 1));
+        assert!(!deposit(&mut controller, true, 1));
+        assert!(deposit(&mut controller, true, 2));
+        assert_eq!(counts(), (1, 2, 2, 0));
+        assert_eq!(metrics.balance_cache_size.get(), 2);
+        // replayed operations are deduplicated but not counted
+        assert!(!deposit(&mut controller, false, 2));
+        assert_eq!(counts(), (1, 2, 2, 0));
+        // the full cache evicts an entry for the new one
+        assert!(deposit(&mut controller, true, 3));
+        assert_eq!(counts(), (1, 3, 3, 1));
+        controller.on_timer();
+        assert_eq!(metrics.balance_cache_size.get(), 0);
+        assert!(deposit(&mut controller, true, 1));
+
+        // the lru cache is not cleared by the timer
+        let mut controller = new_controller(config::DedupStrategy::Lru);
+        assert!(deposit(&mut controller, true, 1));
+        assert!(deposit(&mut controller, true, 2));
+        controller.on_timer();
+        assert!(!deposit(&mut controller, true, 1));
+        assert_eq!(metrics.balance_cache_size.get(), 2);
+        let evictions = metrics.balance_cache_evictions.get();
+        assert!(deposit(&mut controller, true, 3));
+        assert_eq!(metrics.balance_cache_evictions.get(), evictions + 1);
+    }
+
+    #[test]
+    fn test_balance_seed() {
+        let asset_config = vec![
+            config::Asset {
+                name: usdt(),
+                prec_save: 8,
+                prec_show: 8,
+                ..Default::default()
+            },
+            config::Asset {
+                name: String::from("ETH"),
+                prec_save: 8,
+                prec_show: 8,
+                ..Default::default()
+            },
+        ];
+        let mut controller = get_update_controller(&asset_config);
+        let csv = "user_id,asset,amount\n101,USDT,1000\n# a comment\n\n101, ETH, 2.5\n102,USDT,500,7\n";
+        let entries = parse_balance_seed(csv, false).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(
+            entries.iter().map(|entry| entry.business_id.unwrap()).collect::<Vec<_>>(),
+            vec![1, 2, 7]
+        );
+        assert_eq!(controller.apply_balance_seed(&entries).unwrap().len(), 3);
+        {
+            let balance_manager = controller.balance_manager.borrow();
+            assert_eq!(balance_manager.get(101, BalanceType::AVAILABLE, &usdt()), dec!(1000));
+            assert_eq!(balance_manager.get(101, BalanceType::AVAILABLE, "ETH"), dec!(2.5));
+            assert_eq!(balance_manager.get(102, BalanceType::AVAILABLE, &usdt()), dec!(500));
         }
-        true
+        // loading the same seed again is deduplicated
+        assert!(controller.apply_balance_seed(&entries).unwrap().is_empty());
+        assert_eq!(
+            controller.balance_manager.borrow().get(101, BalanceType::AVAILABLE, &usdt()),
+            dec!(1000)
+        );
+
+        let json = r#"[{"user_id": 103, "asset": "ETH", "amount": "1"}, {"user_id": 103, "asset": "BTC", "amount": "1"}]"#;
+        let entries = parse_balance_seed(json, true).unwrap();
+        assert!(controller.apply_balance_seed(&entries).is_err());
+        // nothing is applied when an asset is unknown
+        assert_eq!(controller.balance_manager.borrow().get(103, BalanceType::AVAILABLE, "ETH"), dec!(0));
+        assert!(parse_balance_seed("101,USDT", false).is_err());
+    }
+
+    #[test]
+    fn test_adjustment_debit() {
+        let mut controller = get_update_controller(&get_simple_asset_config());
+        controller
+            .balance_manager
+            .borrow_mut()
+            .add(101, BalanceType::AVAILABLE, &usdt(), &dec!(30))
+            .unwrap();
+        assert_eq!(
+            controller.adjust_balance(true, 101, &usdt(), 1, dec!(-50), "alice", "reconciliation"),
+            Err(BalanceUpdateError::BalanceNotEnough)
+        );
+        assert_eq!(
+            controller.adjust_balance(true, 101, &usdt(), 2, dec!(-20), "alice", "reconciliation"),
+            Ok(true)
+        );
+        assert_eq!(
+            controller.adjust_balance(true, 101, &usdt(), 2, dec!(-20), "alice", "reconciliation"),
+            Ok(false)
+        );
+        assert_eq!(
+            controller.balance_manager.borrow().get(101, BalanceType::AVAILABLE, &usdt()),
+            dec!(10)
+        );
+
+        // with margin the debit may go down to the credit limit
+        let balance_manager = Rc::new(RefCell::new(BalanceManager::new_with_margin(&get_simple_asset_config()).unwrap()));
+        balance_manager.borrow_mut().set_credit_limit(101, &dec!(100));
+        balance_manager
+            .borrow_mut()
+            .add(101, BalanceType::AVAILABLE, &usdt(), &dec!(30))
+            .unwrap();
+        let mut controller = BalanceUpdateController::new(
+            balance_manager.clone(),
+            Rc::new(RefCell::new(DummyMessageManager)),
+            Rc::new(RefCell::new(DummyHistoryWriter)),
+            &Default::default(),
+            Metrics::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            controller.adjust_balance(true, 101, &usdt(), 1, dec!(-50), "alice", "reconciliation"),
+            Ok(true)
+        );
+        assert_eq!(balance_manager.borrow().get(101, BalanceType::AVAILABLE, &usdt()), dec!(-20));
+        assert_eq!(
+            controller.adjust_balance(true, 101, &usdt(), 2, dec!(-90), "alice", "reconciliation"),
+            Err(BalanceUpdateError::BalanceNotEnough)
+        );
+    }
+
+    #[test]
+    fn test_limit_change_not_applied_on_replay() {
+        // the deposit was accepted with no limit, then the limit is raised and the log is replayed
+        let mut asset_config = get_simple_asset_config();
+        asset_config[0].min_deposit = Some(dec!(10));
+        let mut controller = get_update_controller(&asset_config);
+        assert_eq!(
+            controller.update_user_balance(false, 101, &usdt(), BusinessKind::Deposit, 1, dec!(5), json!({})),
+            Ok(true)
+        );
+        assert_eq!(
+            controller.balance_manager.borrow().get(101, BalanceType::AVAILABLE, &usdt()),
+            dec!(5)
+        );
+        assert_eq!(
+            controller.update_user_balance(true, 101, &usdt(), BusinessKind::Deposit, 2, dec!(5), json!({})),
+            Err(BalanceUpdateError::DepositTooSmall(dec!(5), dec!(10)))
+        );
     }
 }
@@ -0,0 +1,236 @@
+use crate::config::{self, HistoryOverflow};
+use crate::history::{self, HistoryWriter, SpillSink};
+use crate::kline::Candle;
+use crate::market;
+use crate::models::{self, tablenames};
+use crate::types::{SimpleResult, Trade};
+use anyhow::{anyhow, Result};
+use futures::future::LocalBoxFuture;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Request};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+// on shutdown the failed inserts are retried this many times, then the rows are spilled
+const CLOSE_RETRIES: u32 = 3;
+const CLOSE_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+enum ClickHouseMsg {
+    Row(&'static str, serde_json::Value),
+    Exit,
+}
+
+// The history rows go to a task inserting them in batches by table, into the tables of the same
+// names as on postgres. A failed insert keeps its rows for the next flush. The rows queued here
+// are bounded by `queue_limit` like the ones of each postgres table.
+pub struct ClickHouseHistoryWriter {
+    sender: Option<mpsc::UnboundedSender<ClickHouseMsg>>,
+    task: Option<JoinHandle<()>>,
+    // rows appended and not inserted yet
+    queued: Arc<AtomicUsize>,
+    queue_limit: usize,
+    overflow: HistoryOverflow,
+    spill: SpillSink,
+}
+
+impl ClickHouseHistoryWriter {
+    pub fn new(config: &config::HistoryWriterConfig) -> ClickHouseHistoryWriter {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let queued = Arc::new(AtomicUsize::new(0));
+        let inserter = Inserter {
+            client: Client::new(),
+            config: config.clickhouse.clone(),
+            batch_size: config.batch_size.max(1),
+            queued: queued.clone(),
+            buffers: BTreeMap::new(),
+            healthy: true,
+        };
+        let task = tokio::spawn(inserter.run(receiver, config.flush_interval));
+        ClickHouseHistoryWriter {
+            sender: Some(sender),
+            task: Some(task),
+            queued,
+            queue_limit: config.queue_limit,
+            overflow: config.overflow,
+            spill: SpillSink::new(&config.clickhouse.spill_path),
+        }
+    }
+    pub fn queue_depth(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+    fn append<T: Serialize>(&mut self, table: &'static str, row: &T) {
+        let row = match serde_json::to_value(row) {
+            Ok(row) => row,
+            Err(e) => {
+                tracing::error!("fail to encode history row of {}: {}", table, e);
+                return;
+            }
+        };
+        if let Err(row) = self.try_enqueue(table, row) {
+            history::overflow_row(self.overflow, &mut self.spill, table, &row);
+        }
+    }
+    fn try_enqueue(&self, table: &'static str, row: serde_json::Value) -> Result<(), serde_json::Value> {
+        let limit = self.queue_limit;
+        if self
+            .queued
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| if n < limit { Some(n + 1) } else { None })
+            .is_err()
+        {
+            return Err(row);
+        }
+        let result = match &self.sender {
+            Some(sender) => sender.send(ClickHouseMsg::Row(table, row)).map_err(|e| match e.0 {
+                ClickHouseMsg::Row(_, row) => row,
+                ClickHouseMsg::Exit => unreachable!(),
+            }),
+            None => Err(row),
+        };
+        if result.is_err() {
+            self.queued.fetch_sub(1, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+impl HistoryWriter for ClickHouseHistoryWriter {
+    fn is_block(&self) -> bool {
+        if self.overflow == HistoryOverflow::Spill {
+            return false;
+        }
+        self.sender.is_none() || ((self.queue_limit as f64 * 0.9) as usize) < self.queue_depth()
+    }
+    fn append_balance_history(&mut self, data: models::BalanceHistory) {
+        self.append(tablenames::BALANCEHISTORY, &data);
+    }
+    fn append_order_history(&mut self, order: &market::Order) {
+        self.append(tablenames::ORDERHISTORY, &history::order_history_row(order));
+    }
+    fn append_trade_history(&mut self, trade: &Trade) {
+        for data in history::trade_history_rows(trade).iter() {
+            self.append(tablenames::TRADEHISTORY, data);
+        }
+    }
+    fn append_kline(&mut self, market: &str, interval: u64, candle: &Candle) {
+        self.append(tablenames::KLINE, &history::kline_row(market, interval, candle));
+    }
+    fn close(&mut self) -> LocalBoxFuture<'_, SimpleResult> {
+        Box::pin(async move {
+            let sender = self.sender.take().ok_or_else(|| anyhow!("Not inited"))?;
+            sender
+                .send(ClickHouseMsg::Exit)
+                .map_err(|_| anyhow!("clickhouse inserter has exited"))?;
+            if let Some(task) = self.task.take() {
+                task.await.map_err(|e| anyhow!("Wait clickhouse inserter exit fail: {}", e))?;
+            }
+            Ok(())
+        })
+    }
+}
+
+struct Inserter {
+    client: Client<HttpConnector>,
+    config: config::ClickHouseConfig,
+    batch_size: usize,
+    queued: Arc<AtomicUsize>,
+    // rows waiting for an insert, by table
+    buffers: BTreeMap<&'static str, Vec<serde_json::Value>>,
+    // a full batch is only inserted at once while the inserts succeed, otherwise on the next tick
+    healthy: bool,
+}
+
+impl Inserter {
+    async fn run(mut self, mut receiver: mpsc::UnboundedReceiver<ClickHouseMsg>, flush_interval: Duration) {
+        let mut ticker = tokio::time::interval(flush_interval.max(Duration::from_millis(1)));
+        loop {
+            tokio::select! {
+                msg = receiver.recv() => match msg {
+                    Some(ClickHouseMsg::Row(table, row)) => {
+                        let buffer = self.buffers.entry(table).or_insert_with(Vec::new);
+                        buffer.push(row);
+                        if buffer.len() >= self.batch_size && self.healthy {
+                            self.flush(table).await;
+                        }
+                    }
+                    Some(ClickHouseMsg::Exit) | None => break,
+                },
+                _ = ticker.tick() => {
+                    self.flush_all().await;
+                }
+            }
+        }
+        for _ in 0..CLOSE_RETRIES {
+            if self.flush_all().await {
+                tracing::info!("clickhouse inserter exit");
+                return;
+            }
+            tokio::time::sleep(CLOSE_RETRY_DELAY).await;
+        }
+        let mut spill = SpillSink::new(&self.config.spill_path);
+        for (table, rows) in std::mem::take(&mut self.buffers) {
+            for row in rows {
+                history::overflow_row(HistoryOverflow::Spill, &mut spill, table, &row);
+            }
+        }
+        tracing::error!(
+            "clickhouse inserter exit with rows not inserted, spilled to {}",
+            self.config.spill_path
+        );
+    }
+
+    // returns whether all the buffered rows are inserted
+    async fn flush_all(&mut self) -> bool {
+        let tables: Vec<&'static str> = self.buffers.keys().copied().collect();
+        let mut ok = true;
+        for table in tables {
+            ok &= self.flush(table).await;
+        }
+        ok
+    }
+
+    async fn flush(&mut self, table: &'static str) -> bool {
+        let rows = match self.buffers.get(table) {
+            Some(rows) if !rows.is_empty() => rows,
+            _ => return true,
+        };
+        let count = rows.len();
+        match insert(&self.client, &self.config, table, rows).await {
+            Ok(()) => {
+                self.buffers.get_mut(table).unwrap().clear();
+                self.queued.fetch_sub(count, Ordering::Relaxed);
+                self.healthy = true;
+                true
+            }
+            Err(e) => {
+                tracing::error!("fail to insert {} rows into clickhouse {}: {}. retry", count, table, e);
+                self.healthy = false;
+                false
+            }
+        }
+    }
+}
+
+// the query leads the body, followed by a json row on each line
+async fn insert(client: &Client<HttpConnector>, config: &config::ClickHouseConfig, table: &str, rows: &[serde_json::Value]) -> Result<()> {
+    let mut body = format!("INSERT INTO {}.{} FORMAT JSONEachRow\n", config.database, table);
+    for row in rows {
+        body.push_str(&row.to_string());
+        body.push('\n');
+    }
+    let request = Request::post(format!("{}/?date_time_input_format=best_effort", config.url.trim_end_matches('/')))
+        .header("X-ClickHouse-User", config.user.as_str())
+        .header("X-ClickHouse-Key", config.password.as_str())
+        .body(Body::from(body))?;
+    let response = client.request(request).await?;
+    if !response.status().is_success() {
+        let status = response.status();
+        let message = hyper::body::to_bytes(response.into_body()).await?;
+        return Err(anyhow!("{}: {}", status, String::from_utf8_lossy(&message)));
+    }
+    Ok(())
+}
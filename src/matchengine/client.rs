@@ -0,0 +1,153 @@
+use crate::dto::matchengine_client::MatchengineClient;
+use anyhow::Result;
+use std::time::Duration;
+use tonic::metadata::{Ascii, MetadataKey, MetadataValue};
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity};
+
+// Connects the typed client of the matchengine for the services written in rust, e.g.
+// `ClientBuilder::new("http://127.0.0.1:50051").api_key("x-api-key", key).connect().await`.
+// Only connecting is retried, the calls are not, as placing an order twice is not the same as
+// placing it once. Set an idempotency key on the orders to retry them.
+#[derive(Clone)]
+pub struct ClientBuilder {
+    url: String,
+    connect_timeout: Duration,
+    // of each call
+    timeout: Duration,
+    tls: Option<ClientTlsConfig>,
+    api_key: Option<(String, String)>,
+    connect_retries: usize,
+    // doubled after each failed attempt
+    retry_backoff: Duration,
+}
+
+impl ClientBuilder {
+    pub fn new(url: &str) -> ClientBuilder {
+        ClientBuilder {
+            url: url.to_string(),
+            connect_timeout: Duration::from_secs(5),
+            timeout: Duration::from_secs(10),
+            tls: None,
+            api_key: None,
+            connect_retries: 3,
+            retry_backoff: Duration::from_millis(200),
+        }
+    }
+    pub fn connect_timeout(mut self, timeout: Duration) -> ClientBuilder {
+        self.connect_timeout = timeout;
+        self
+    }
+    pub fn timeout(mut self, timeout: Duration) -> ClientBuilder {
+        self.timeout = timeout;
+        self
+    }
+    // the roots of the system are trusted without `ca_pem`
+    pub fn tls(mut self, domain: &str, ca_pem: Option<&[u8]>) -> ClientBuilder {
+        let mut tls = ClientTlsConfig::new().domain_name(domain);
+        if let Some(ca_pem) = ca_pem {
+            tls = tls.ca_certificate(Certificate::from_pem(ca_pem));
+        }
+        self.tls = Some(tls);
+        self
+    }
+    // the client certificate presented under mutual tls, after `tls`
+    pub fn identity(mut self, cert_pem: &[u8], key_pem: &[u8]) -> ClientBuilder {
+        let tls = self.tls.take().unwrap_or_else(ClientTlsConfig::new);
+        self.tls = Some(tls.identity(Identity::from_pem(cert_pem, key_pem)));
+        self
+    }
+    // sent in the `header` metadata of every call, see the `auth` config of the matchengine
+    pub fn api_key(mut self, header: &str, key: &str) -> ClientBuilder {
+        self.api_key = Some((header.to_string(), key.to_string()));
+        self
+    }
+    pub fn connect_retries(mut self, retries: usize, backoff: Duration) -> ClientBuilder {
+        self.connect_retries = retries;
+        self.retry_backoff = backoff;
+        self
+    }
+
+    fn endpoint(&self) -> Result<Endpoint> {
+        let mut endpoint = Endpoint::from_shared(self.url.clone())?
+            .connect_timeout(self.connect_timeout)
+            .timeout(self.timeout)
+            .tcp_nodelay(true);
+        if let Some(tls) = &self.tls {
+            endpoint = endpoint.tls_config(tls.clone())?;
+        }
+        Ok(endpoint)
+    }
+
+    fn client(&self, channel: Channel) -> Result<MatchengineClient<Channel>> {
+        match &self.api_key {
+            None => Ok(MatchengineClient::new(channel)),
+            Some((header, key)) => {
+                let header = MetadataKey::<Ascii>::from_bytes(header.to_lowercase().as_bytes())?;
+                let key: MetadataValue<Ascii> = key.parse()?;
+                Ok(MatchengineClient::with_interceptor(
+                    channel,
+                    move |mut request: tonic::Request<()>| {
+                        request.metadata_mut().insert(header.clone(), key.clone());
+                        Ok(request)
+                    },
+                ))
+            }
+        }
+    }
+
+    pub async fn connect(&self) -> Result<MatchengineClient<Channel>> {
+        let endpoint = self.endpoint()?;
+        let mut backoff = self.retry_backoff;
+        let mut attempt = 0;
+        loop {
+            match endpoint.connect().await {
+                Ok(channel) => return self.client(channel),
+                Err(err) if attempt < self.connect_retries => {
+                    tracing::warn!(
+                        "connecting to the matchengine at {} failed: {}, retry in {:?}",
+                        self.url,
+                        err,
+                        backoff
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    // connected on the first call, and reconnected when the connection is lost
+    pub fn connect_lazy(&self) -> Result<MatchengineClient<Channel>> {
+        self.client(self.endpoint()?.connect_lazy()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_connect_retries() {
+        assert!(ClientBuilder::new("not a url").connect_lazy().is_err());
+        assert!(ClientBuilder::new("http://127.0.0.1:50051")
+            .api_key("x-api-key", "bad\nkey")
+            .connect_lazy()
+            .is_err());
+        assert!(ClientBuilder::new("http://127.0.0.1:50051")
+            .api_key("x-api-key", "key")
+            .connect_lazy()
+            .is_ok());
+
+        // nothing listens on the port
+        let started_at = std::time::Instant::now();
+        let result = ClientBuilder::new("http://127.0.0.1:1")
+            .connect_retries(2, Duration::from_millis(10))
+            .connect()
+            .await;
+        assert!(result.is_err());
+        // waited 10ms then 20ms before giving up
+        assert!(started_at.elapsed() >= Duration::from_millis(30));
+    }
+}
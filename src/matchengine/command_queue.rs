@@ -0,0 +1,145 @@
+use crate::config;
+use crate::controller::Controller;
+use crate::metrics::Metrics;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+use tonic::Status;
+
+// which commands are shed first when the engine falls behind
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandKind {
+    // new orders, including the amendments and the trigger orders
+    Place,
+    // the cancels let the users reduce their risk, they get in until the queue is full
+    Cancel,
+    Admin,
+}
+
+type Command = Box<dyn FnOnce(&mut Controller) + Send>;
+
+// The writing calls are queued here by the grpc handlers and executed one at a time by
+// `CommandReceiver`, in the order they are queued.
+pub struct CommandQueue {
+    sender: mpsc::Sender<Command>,
+    depth: Arc<AtomicUsize>,
+    capacity: usize,
+    placement_limit: usize,
+    metrics: Metrics,
+}
+
+pub struct CommandReceiver {
+    receiver: mpsc::Receiver<Command>,
+    depth: Arc<AtomicUsize>,
+    metrics: Metrics,
+}
+
+impl CommandQueue {
+    pub fn new(config: &config::CommandQueueConfig, metrics: Metrics) -> (CommandQueue, CommandReceiver) {
+        let capacity = config.capacity.max(1);
+        let placement_limit = ((capacity as f64 * config.shed_ratio) as usize).min(capacity);
+        let (sender, receiver) = mpsc::channel(capacity);
+        let depth = Arc::new(AtomicUsize::new(0));
+        (
+            CommandQueue {
+                sender,
+                depth: depth.clone(),
+                capacity,
+                placement_limit,
+                metrics: metrics.clone(),
+            },
+            CommandReceiver { receiver, depth, metrics },
+        )
+    }
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::SeqCst)
+    }
+    fn admit(&self, kind: CommandKind) -> Result<(), Status> {
+        let limit = match kind {
+            CommandKind::Place => self.placement_limit,
+            CommandKind::Cancel | CommandKind::Admin => self.capacity,
+        };
+        if self.depth() >= limit {
+            self.metrics.commands_shed.inc();
+            return Err(Status::resource_exhausted("engine busy, retry later"));
+        }
+        Ok(())
+    }
+    // queue the command, or reject it at once if the queue is too long for its kind
+    pub fn enqueue<T, F>(&self, kind: CommandKind, command: F) -> Result<oneshot::Receiver<Result<T, Status>>, Status>
+    where
+        F: FnOnce(&mut Controller) -> Result<T, Status> + Send + 'static,
+        T: Send + 'static,
+    {
+        self.admit(kind)?;
+        let (result_sender, result_receiver) = oneshot::channel();
+        let depth = self.depth.fetch_add(1, Ordering::SeqCst) + 1;
+        self.metrics.command_queue_depth.set(depth as i64);
+        let command: Command = Box::new(move |controller| {
+            result_sender.send(command(controller)).ok();
+        });
+        if self.sender.try_send(command).is_err() {
+            let depth = self.depth.fetch_sub(1, Ordering::SeqCst) - 1;
+            self.metrics.command_queue_depth.set(depth as i64);
+            self.metrics.commands_shed.inc();
+            return Err(Status::resource_exhausted("engine busy, retry later"));
+        }
+        Ok(result_receiver)
+    }
+    pub async fn execute<T, F>(&self, kind: CommandKind, command: F) -> Result<T, Status>
+    where
+        F: FnOnce(&mut Controller) -> Result<T, Status> + Send + 'static,
+        T: Send + 'static,
+    {
+        self.enqueue(kind, command)?
+            .await
+            .map_err(|_| Status::unavailable("engine stopped"))?
+    }
+}
+
+impl CommandReceiver {
+    // None after all the queues are dropped
+    pub async fn next(&mut self) -> Option<Command> {
+        let command = self.receiver.recv().await?;
+        let depth = self.depth.fetch_sub(1, Ordering::SeqCst) - 1;
+        self.metrics.command_queue_depth.set(depth as i64);
+        Some(command)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    #[test]
+    fn test_shed_placements() {
+        let metrics = Metrics::default();
+        let (queue, mut receiver) = CommandQueue::new(
+            &config::CommandQueueConfig {
+                capacity: 4,
+                shed_ratio: 0.5,
+            },
+            metrics.clone(),
+        );
+        let command = |_: &mut Controller| Ok(());
+        assert!(queue.enqueue(CommandKind::Place, command).is_ok());
+        assert!(queue.enqueue(CommandKind::Place, command).is_ok());
+        // saturated for the new orders, the cancels are still admitted
+        let status = queue.enqueue(CommandKind::Place, command).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+        assert!(queue.enqueue(CommandKind::Cancel, command).is_ok());
+        assert!(queue.enqueue(CommandKind::Cancel, command).is_ok());
+        assert!(queue.enqueue(CommandKind::Cancel, command).is_err());
+        assert_eq!(queue.depth(), 4);
+        assert_eq!(metrics.command_queue_depth.get(), 4);
+        assert_eq!(metrics.commands_shed.get(), 2);
+
+        // the engine catches up
+        block_on(receiver.next()).unwrap();
+        block_on(receiver.next()).unwrap();
+        block_on(receiver.next()).unwrap();
+        assert_eq!(metrics.command_queue_depth.get(), 1);
+        assert!(queue.enqueue(CommandKind::Place, command).is_ok());
+    }
+}
@@ -1,27 +1,34 @@
-use crate::asset::{AssetManager, BalanceManager, BalanceType, BalanceUpdateController};
+use crate::asset::{self, AssetManager, BalanceManager, BalanceType, BalanceUpdateController};
+use crate::config;
 use crate::database::OperationLogSender;
+use crate::fee::FeeTierManager;
+use crate::idempotency::OrderPutCache;
 use crate::market;
+use crate::metrics::Metrics;
+use crate::reserves::{self, LiabilityTree};
 use crate::sequencer::Sequencer;
-use crate::utils::FTimestamp;
-use crate::{config, utils};
+use crate::utils::{Clock, FTimestamp, MonotonicClock, SystemClock};
 use anyhow::anyhow;
 use rust_decimal::Decimal;
 use serde_json::json;
 use std::cell::RefCell;
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 use tonic::{self, Status};
 
 //use rust_decimal::Decimal;
 use crate::models::{self};
 use crate::types;
-use types::{ConnectionType, DbType, SimpleResult};
+use types::{BusinessKind, ConnectionType, DbType, SimpleResult};
 
 use crate::dto::*;
 
-use crate::database::DatabaseWriterConfig;
-use crate::message::{new_message_manager_with_kafka_backend, ChannelMessageManager};
+use crate::database::{connect_pool_lazy, DatabaseWriterConfig, DbPools, INSERT_LIMIT};
+use crate::message::{dead_letter_count, new_message_manager, MessageManager, OrderMessage};
+use crate::sqlxextend::StorageError;
+use crate::subscription::SUBSCRIPTION_BUFFER_SIZE;
 
-use crate::history::DatabaseHistoryWriter;
+use crate::history::new_history_writer;
 use crate::history::HistoryWriter;
 use rust_decimal::prelude::Zero;
 use std::collections::HashMap;
@@ -29,7 +36,7 @@ use std::collections::HashMap;
 use sqlx::Connection;
 use sqlx::Executor;
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::str::FromStr;
 
 pub struct Controller {
@@ -37,71 +44,217 @@ pub struct Controller {
     pub sequencer: Rc<RefCell<Sequencer>>,
     pub balance_manager: Rc<RefCell<BalanceManager>>,
     pub asset_manager: AssetManager,
+    // the assets registered at runtime, in the order they are registered
+    pub registered_assets: Vec<String>,
     pub update_controller: Rc<RefCell<BalanceUpdateController>>,
+    pub order_put_cache: OrderPutCache,
+    pub open_order_limits: market::OpenOrderLimits,
+    pub fee_tier_manager: Rc<RefCell<FeeTierManager>>,
     pub markets: HashMap<String, market::Market>,
     pub log_handler: OperationLogSender,
-    pub history_writer: Rc<RefCell<DatabaseHistoryWriter>>,
-    pub message_manager: Rc<RefCell<ChannelMessageManager>>,
+    pub history_writer: Rc<RefCell<dyn HistoryWriter>>,
+    // for the queries of the history, which don't touch the engine state
+    pub history_pools: DbPools,
+    pub message_manager: Rc<RefCell<dyn MessageManager>>,
+    pub metrics: Metrics,
+    pub engine_status: EngineStatus,
+    // set after replaying to `settings.replay_until`, only queries are served then
+    pub read_only: bool,
+    // the last published liability tree and when it was built
+    pub liability_commitment: Option<(f64, LiabilityTree)>,
+    // the time of the operations, the balance updates and the markets read the same clock
+    pub clock: Rc<dyn Clock>,
     pub(crate) rt: tokio::runtime::Handle,
 }
 
 const ORDER_LIST_MAX_LEN: usize = 100;
+const ORDER_BATCH_MAX_LEN: usize = 100;
+const MY_TRADES_DEFAULT_LIMIT: usize = 20;
+const MY_TRADES_MAX_LIMIT: usize = 100;
+const DEPTH_DEFAULT_LIMIT: usize = 20;
+const DEPTH_MAX_LIMIT: usize = 500;
+const OPERATION_ASSET_REGISTER: &str = "asset_register";
+const OPERATION_BALANCE_ADJUST: &str = "balance_adjust";
 const OPERATION_BALANCE_UPDATE: &str = "balance_update";
+const OPERATION_ORDER_AMEND: &str = "order_amend";
 const OPERATION_ORDER_CANCEL: &str = "order_cancel";
 const OPERATION_ORDER_CANCEL_ALL: &str = "order_cancel_all";
+const OPERATION_MARKET_HALT: &str = "market_halt";
+const OPERATION_MARKET_RESUME: &str = "market_resume";
+const OPERATION_MARKET_SET_STATE: &str = "market_set_state";
+const OPERATION_ORDER_EXPIRE: &str = "order_expire";
 const OPERATION_ORDER_PUT: &str = "order_put";
+const OPERATION_TRIGGER_ORDER_PUT: &str = "trigger_order_put";
+const OPERATION_TRIGGER_ORDER_CANCEL: &str = "trigger_order_cancel";
+
+// logged when a circuit breaker trips, since the price window is measured in wall-clock time
+#[derive(Serialize, Deserialize)]
+struct MarketHalt {
+    market: String,
+    until: f64,
+}
+
+// The operation log record of an order put, the request with what the engine decided for it,
+// which the replay takes as it is
+#[derive(Serialize, Deserialize)]
+struct OrderPutOperation {
+    #[serde(flatten)]
+    req: OrderPutRequest,
+    // the band edge a market order is bounded by, empty without a band
+    #[serde(default)]
+    protection_price: String,
+    // when the order is placed, the fee tiers of its trades are looked up at it
+    #[serde(default)]
+    timestamp: f64,
+}
+
+impl OrderPutOperation {
+    fn new(req: OrderPutRequest) -> Self {
+        OrderPutOperation {
+            req,
+            protection_price: String::new(),
+            timestamp: 0.0,
+        }
+    }
+}
+
+// The engine is not ready while it is replaying the operation log, on startup or reload,
+// or once it is shutting down
+#[derive(Debug, Clone, Copy)]
+pub struct EngineStatus {
+    started_at: Instant,
+    replaying: bool,
+    shutting_down: bool,
+    // the slice loaded on startup or made by `make_snapshot`, forked slices are not seen here
+    pub last_slice_operation_log_id: u64,
+}
+
+impl EngineStatus {
+    pub fn new() -> EngineStatus {
+        EngineStatus {
+            started_at: Instant::now(),
+            replaying: true,
+            shutting_down: false,
+            last_slice_operation_log_id: 0,
+        }
+    }
+    pub fn uptime(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+    pub fn is_ready(&self) -> bool {
+        !self.replaying && !self.shutting_down
+    }
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down
+    }
+    pub fn start_shutdown(&mut self) {
+        self.shutting_down = true;
+    }
+    pub fn start_replay(&mut self) {
+        self.replaying = true;
+    }
+    pub fn finish_replay(&mut self) {
+        self.replaying = false;
+    }
+}
+
+impl Default for EngineStatus {
+    fn default() -> Self {
+        EngineStatus::new()
+    }
+}
+
+// A slice captured by `begin_snapshot`, committed while the engine goes on matching.
+// It covers the operation log up to the end of the command which captured it.
+pub struct SnapshotJob {
+    slice: crate::persist::SliceData,
+    db_log: String,
+    compaction: config::OperationLogCompactionConfig,
+    started_at: std::time::Instant,
+}
+
+impl SnapshotJob {
+    pub async fn run(&self) -> SimpleResult {
+        crate::persist::commit_slice(&self.db_log, &self.compaction, &self.slice).await
+    }
+}
 
 impl Controller {
     pub fn new(settings: config::Settings) -> Controller {
-        let balance_manager = Rc::new(RefCell::new(BalanceManager::new(&settings.assets).unwrap()));
-        let message_manager = Rc::new(RefCell::new(new_message_manager_with_kafka_backend(&settings.brokers).unwrap()));
-        let history_writer = Rc::new(RefCell::new(
-            DatabaseHistoryWriter::new(
-                &DatabaseWriterConfig {
-                    spawn_limit: 4,
-                    apply_benchmark: true,
-                    capability_limit: 8192,
-                },
-                &sqlx::Pool::<DbType>::connect_lazy(&settings.db_history).unwrap(),
+        Controller::with_clock(settings, Rc::new(MonotonicClock::new(Rc::new(SystemClock))))
+    }
+    pub fn with_clock(settings: config::Settings, clock: Rc<dyn Clock>) -> Controller {
+        let mut balance_manager = BalanceManager::with_capacity(&settings.assets, settings.balance_map_capacity).unwrap();
+        balance_manager.set_fee_account(&settings.fee_account).unwrap();
+        let balance_manager = Rc::new(RefCell::new(balance_manager));
+        let message_manager = new_message_manager(&settings).unwrap();
+        let metrics = Metrics::default();
+        let history_pools = DbPools::connect_lazy(&settings.db_pool, &settings.db_history, &settings.db_history_replica).unwrap();
+        let history_writer = new_history_writer(&settings.history_writer, history_pools.primary(), metrics.clone()).unwrap();
+        let update_controller = Rc::new(RefCell::new(
+            BalanceUpdateController::new(
+                balance_manager.clone(),
+                message_manager.clone(),
+                history_writer.clone(),
+                &settings.balance_update,
+                metrics.clone(),
             )
             .unwrap(),
         ));
-        let update_controller = Rc::new(RefCell::new(BalanceUpdateController::new(
-            balance_manager.clone(),
-            message_manager.clone(),
-            history_writer.clone(),
-        )));
+        update_controller.borrow_mut().set_clock(clock.clone());
+        update_controller
+            .borrow_mut()
+            .set_reserved_users(&settings.reserved_user_ids)
+            .unwrap();
+        let order_put_cache = OrderPutCache::new(&settings.order_idempotency);
+        let open_order_limits = market::OpenOrderLimits::new(&settings.open_order_limit);
         let asset_manager = AssetManager::new(&settings.assets).unwrap();
         let sequencer = Rc::new(RefCell::new(Sequencer::default()));
+        let fee_tier_manager = Rc::new(RefCell::new(FeeTierManager::new(&settings.fee_tier).unwrap()));
         let mut markets = HashMap::new();
         for entry in &settings.markets {
-            let market = market::Market::new(
+            let mut market = market::Market::new(
                 entry,
                 balance_manager.clone(),
                 sequencer.clone(),
+                fee_tier_manager.clone(),
                 history_writer.clone(),
                 message_manager.clone(),
+                metrics.clone(),
             )
             .unwrap();
+            market.set_clock(clock.clone());
             markets.insert(entry.name.clone(), market);
         }
         let log_handler = OperationLogSender::new(&DatabaseWriterConfig {
             spawn_limit: 4,
             apply_benchmark: true,
             capability_limit: 8192,
+            batch_size: INSERT_LIMIT as usize,
+            flush_interval: Duration::default(),
         })
-        .start_schedule(&sqlx::Pool::<DbType>::connect_lazy(&settings.db_log).unwrap())
+        .start_schedule(&connect_pool_lazy(&settings.db_pool, &settings.db_log).unwrap())
         .unwrap();
         Controller {
             settings,
             sequencer,
             asset_manager,
+            registered_assets: Vec::new(),
             balance_manager,
             update_controller,
+            order_put_cache,
+            open_order_limits,
+            fee_tier_manager,
             markets,
             log_handler,
             history_writer,
+            history_pools,
             message_manager,
+            metrics,
+            engine_status: EngineStatus::new(),
+            read_only: false,
+            liability_commitment: None,
+            clock,
             rt: tokio::runtime::Handle::current(),
         }
     }
@@ -131,6 +284,103 @@ impl Controller {
         };
         Ok(result)
     }
+    pub fn total_liabilities(&self, _req: TotalLiabilitiesRequest) -> Result<TotalLiabilitiesResponse, Status> {
+        let mut totals = self.balance_manager.borrow().total_liabilities();
+        let mut liabilities: Vec<total_liabilities_response::AssetLiability> = self
+            .asset_manager
+            .assets
+            .keys()
+            .map(|asset| total_liabilities_response::AssetLiability {
+                asset: asset.clone(),
+                amount: totals.remove(asset).unwrap_or_default().to_string(),
+            })
+            .collect();
+        liabilities.sort_by(|a, b| a.asset.cmp(&b.asset));
+        Ok(TotalLiabilitiesResponse { liabilities })
+    }
+    // the tree stays the same until the next commitment, so the proofs match the published root
+    pub fn commit_liabilities(&mut self, _req: CommitLiabilitiesRequest) -> Result<CommitLiabilitiesResponse, Status> {
+        let tree = LiabilityTree::new(reserves::liability_leaves(&self.balance_manager.borrow()));
+        let timestamp = self.clock.now();
+        let response = CommitLiabilitiesResponse {
+            root: reserves::to_hex(&tree.root()),
+            leaf_count: tree.len() as u64,
+            timestamp,
+        };
+        tracing::info!("liabilities committed, root {} of {} leaves", response.root, response.leaf_count);
+        self.liability_commitment = Some((timestamp, tree));
+        Ok(response)
+    }
+    pub fn get_inclusion_proof(&self, req: GetInclusionProofRequest) -> Result<GetInclusionProofResponse, Status> {
+        let (timestamp, tree) = self
+            .liability_commitment
+            .as_ref()
+            .ok_or_else(|| Status::failed_precondition("no liabilities committed yet"))?;
+        let leaves = tree
+            .inclusion_proof(req.user_id)
+            .into_iter()
+            .map(|(index, leaf, path)| get_inclusion_proof_response::LeafProof {
+                asset: leaf.asset.clone(),
+                balance: leaf.balance.to_string(),
+                index: index as u64,
+                path: path
+                    .iter()
+                    .map(|step| get_inclusion_proof_response::ProofStep {
+                        sibling: reserves::to_hex(&step.sibling),
+                        is_left: step.is_left,
+                    })
+                    .collect(),
+            })
+            .collect();
+        Ok(GetInclusionProofResponse {
+            root: reserves::to_hex(&tree.root()),
+            timestamp: *timestamp,
+            leaves,
+        })
+    }
+    pub fn asset_register(&mut self, real: bool, req: AssetRegisterRequest) -> Result<AssetRegisterResponse, Status> {
+        if !self.check_service_available() {
+            return Err(Status::unavailable(""));
+        }
+        let limit = |value: &str, name: &str| -> Result<Option<Decimal>, Status> {
+            if value.is_empty() {
+                Ok(None)
+            } else {
+                Decimal::from_str(value)
+                    .map(Some)
+                    .map_err(|_| Status::invalid_argument(format!("invalid {}", name)))
+            }
+        };
+        let asset = config::Asset {
+            name: req.name.clone(),
+            prec_save: req.prec_save,
+            prec_show: req.prec_show,
+            rounding: if req.rounding.is_empty() {
+                config::RoundingStrategy::default()
+            } else {
+                req.rounding.parse().map_err(|e| Status::invalid_argument(format!("{}", e)))?
+            },
+            min_deposit: limit(&req.min_deposit, "min_deposit")?,
+            max_withdrawal: limit(&req.max_withdrawal, "max_withdrawal")?,
+            daily_withdrawal_limit: limit(&req.daily_withdrawal_limit, "daily_withdrawal_limit")?,
+        };
+        let is_new = self.register_asset(asset).map_err(|e| Status::invalid_argument(format!("{}", e)))?;
+        if is_new && real {
+            self.append_operation_log(OPERATION_ASSET_REGISTER, &req);
+        }
+        Ok(AssetRegisterResponse {})
+    }
+    // true if the asset is new, it is dumped in the slices then as it is missing in the config
+    pub fn register_asset(&mut self, asset: config::Asset) -> anyhow::Result<bool> {
+        let is_new = !self.asset_manager.asset_exist(&asset.name);
+        self.asset_manager.register_asset(&asset)?;
+        self.balance_manager.borrow_mut().asset_manager.register_asset(&asset)?;
+        if is_new {
+            self.registered_assets.push(asset.name.clone());
+            self.settings.assets.push(asset);
+        }
+        Ok(is_new)
+    }
     pub fn balance_query(&self, req: BalanceQueryRequest) -> Result<BalanceQueryResponse, Status> {
         let all_asset_param_valid = req
             .assets
@@ -139,76 +389,150 @@ impl Controller {
         if !all_asset_param_valid {
             return Err(Status::invalid_argument("invalid asset"));
         }
-        let query_assets = if req.assets.is_empty() {
-            self.settings.assets.iter().map(|asset| asset.name.clone()).collect()
-        } else {
-            req.assets
-        };
         let user_id = req.user_id;
         let balance_manager = self.balance_manager.borrow_mut();
-        let balances = query_assets
-            .into_iter()
-            .map(|asset_name| {
-                let available = balance_manager
-                    .get_with_round(user_id, BalanceType::AVAILABLE, &asset_name)
-                    .to_string();
-                let frozen = balance_manager
-                    .get_with_round(user_id, BalanceType::FREEZE, &asset_name)
-                    .to_string();
-                balance_query_response::AssetBalance {
+        // the same strategy aware rounding as `get_with_round` for the listed assets
+        let round =
+            |asset_name: &str, balance: Decimal| -> String { balance_manager.asset_manager.round_show(asset_name, balance).to_string() };
+        // an empty asset list means all the non-zero balances of the user
+        let balances = if req.assets.is_empty() {
+            balance_manager
+                .get_all_for_user(user_id)
+                .into_iter()
+                .map(|(asset_name, status)| balance_query_response::AssetBalance {
+                    available: round(&asset_name, status.available - status.debt),
+                    frozen: round(&asset_name, status.frozen),
                     asset_name,
-                    available,
-                    frozen,
-                }
-            })
-            .collect();
+                })
+                .collect()
+        } else {
+            req.assets
+                .into_iter()
+                .map(|asset_name| {
+                    let available = balance_manager
+                        .get_with_round(user_id, BalanceType::AVAILABLE, &asset_name)
+                        .to_string();
+                    let frozen = balance_manager
+                        .get_with_round(user_id, BalanceType::FREEZE, &asset_name)
+                        .to_string();
+                    balance_query_response::AssetBalance {
+                        asset_name,
+                        available,
+                        frozen,
+                    }
+                })
+                .collect()
+        };
         Ok(BalanceQueryResponse { balances })
     }
+    pub fn fee_tier_query(&self, req: FeeTierQueryRequest) -> Result<FeeTierQueryResponse, Status> {
+        let mut fee_tier_manager = self.fee_tier_manager.borrow_mut();
+        let now = self.clock.now();
+        let volume = fee_tier_manager.volume(req.user_id, now);
+        let result = match fee_tier_manager.tier(req.user_id, now) {
+            Some((level, tier)) => FeeTierQueryResponse {
+                level: level as u32,
+                volume: volume.to_string(),
+                maker_fee: tier.maker_fee.to_string(),
+                taker_fee: tier.taker_fee.to_string(),
+            },
+            None => FeeTierQueryResponse {
+                level: 0,
+                volume: volume.to_string(),
+                maker_fee: String::new(),
+                taker_fee: String::new(),
+            },
+        };
+        Ok(result)
+    }
     pub fn order_query(&self, req: OrderQueryRequest) -> Result<OrderQueryResponse, Status> {
-        if !self.markets.contains_key(&req.market) {
-            return Err(Status::invalid_argument("invalid market"));
-        }
+        let market = self
+            .markets
+            .get(&req.market)
+            .ok_or_else(|| Status::invalid_argument("invalid market"))?;
         if req.user_id == 0 {
             return Err(Status::invalid_argument("invalid user_id"));
         }
-        // TODO: magic number
-        let max_order_num = 100;
-        let default_order_num = 10;
         let limit = if req.limit <= 0 {
-            default_order_num
-        } else if req.limit > max_order_num {
-            max_order_num
+            self.settings.order_query_default_limit
         } else {
-            req.limit
+            (req.limit as usize).min(ORDER_LIST_MAX_LEN)
         };
-        let market = self
-            .markets
-            .get(&req.market)
-            .ok_or_else(|| Status::invalid_argument("invalid market"))?;
-        let total_order_count = market.users.get(&req.user_id).map(|order_map| order_map.len()).unwrap_or(0);
-        let orders = market
+        // the settings are checked on startup, but a page of nothing has no last order to index
+        if limit == 0 {
+            return Err(Status::invalid_argument("invalid limit"));
+        }
+        let sort = OrderQuerySort::from_i32(req.sort).ok_or_else(|| Status::invalid_argument("invalid sort"))?;
+        let mut orders: Vec<market::Order> = market
             .users
             .get(&req.user_id)
-            .map(|order_map| {
-                order_map
-                    .values()
-                    .rev()
-                    .skip(req.offset as usize)
-                    .take(limit as usize)
-                    .map(|order_rc| {
-                        let order = *order_rc.borrow_mut();
-                        order_to_proto(&order)
+            .map(|order_map| order_map.values().map(|order_rc| *order_rc.borrow()).collect())
+            .unwrap_or_default();
+        let total_order_count = orders.len();
+        let offset = if req.cursor.is_empty() { req.offset.max(0) as usize } else { 0 };
+        let page: Vec<market::Order> = match sort {
+            OrderQuerySort::Time => {
+                orders.reverse();
+                let after: Option<u64> = if req.cursor.is_empty() {
+                    None
+                } else {
+                    Some(req.cursor.parse().map_err(|_| Status::invalid_argument("invalid cursor"))?)
+                };
+                orders
+                    .into_iter()
+                    .filter(|order| after.map_or(true, |id| order.id < id))
+                    .skip(offset)
+                    .take(limit + 1)
+                    .collect()
+            }
+            OrderQuerySort::Price => {
+                orders.sort_by(|a, b| book_priority_cmp(&book_priority(a), &book_priority(b)));
+                let after = if req.cursor.is_empty() {
+                    None
+                } else {
+                    Some(parse_book_priority_cursor(&req.cursor)?)
+                };
+                orders
+                    .into_iter()
+                    .filter(|order| {
+                        after.as_ref().map_or(true, |after| {
+                            book_priority_cmp(&book_priority(order), after) == std::cmp::Ordering::Greater
+                        })
                     })
+                    .skip(offset)
+                    .take(limit + 1)
                     .collect()
-            })
-            .unwrap_or_else(Vec::new);
-        let result = OrderQueryResponse {
+            }
+        };
+        // one more order to know whether there is a next page
+        let next_cursor = if page.len() > limit {
+            let last = &page[limit - 1];
+            match sort {
+                OrderQuerySort::Time => last.id.to_string(),
+                OrderQuerySort::Price => book_priority_cursor(last),
+            }
+        } else {
+            String::new()
+        };
+        Ok(OrderQueryResponse {
             offset: req.offset,
-            limit,
+            limit: limit as i32,
             total: total_order_count as i32,
-            orders,
-        };
-        Ok(result)
+            orders: page.iter().take(limit).map(order_to_proto).collect(),
+            next_cursor,
+        })
+    }
+    pub fn order_book_snapshot(&self, req: OrderBookSnapshotRequest) -> Result<OrderBookSnapshotResponse, Status> {
+        let market = self
+            .markets
+            .get(&req.market)
+            .ok_or_else(|| Status::invalid_argument("invalid market"))?;
+        let snapshot = market.book_snapshot();
+        Ok(OrderBookSnapshotResponse {
+            version: snapshot.version,
+            order_count: snapshot.orders.len() as u64,
+            snapshot: snapshot.to_json(),
+        })
     }
     pub fn order_book_depth(&self, req: OrderBookDepthRequest) -> Result<OrderBookDepthResponse, Status> {
         // TODO cache
@@ -216,13 +540,20 @@ impl Controller {
             .markets
             .get(&req.market)
             .ok_or_else(|| Status::invalid_argument("invalid market"))?;
-        // TODO check interval
         let interval = if req.interval.is_empty() {
             Decimal::zero()
         } else {
             Decimal::from_str(&req.interval).map_err(|_| Status::invalid_argument("invalid interval"))?
         };
-        let depth = market.depth(req.limit as usize, &interval);
+        market
+            .check_depth_interval(&interval)
+            .map_err(|e| Status::invalid_argument(format!("{}", e)))?;
+        let limit = if req.limit <= 0 {
+            DEPTH_DEFAULT_LIMIT
+        } else {
+            std::cmp::min(req.limit as usize, DEPTH_MAX_LIMIT)
+        };
+        let depth = market.depth(limit, &interval);
         let convert = |price_info: &Vec<market::PriceInfo>| {
             price_info
                 .iter()
@@ -238,15 +569,54 @@ impl Controller {
         })
     }
 
-    pub fn order_detail(&self, req: OrderDetailRequest) -> Result<OrderInfo, Status> {
+    pub fn order_book_subscribe(
+        &mut self,
+        req: OrderBookSubscribeRequest,
+    ) -> Result<futures_channel::mpsc::Receiver<market::BookUpdate>, Status> {
         let market = self
             .markets
-            .get(&req.market)
+            .get_mut(&req.market)
             .ok_or_else(|| Status::invalid_argument("invalid market"))?;
-        let order = market
-            .get(req.order_id)
-            .ok_or_else(|| Status::invalid_argument("invalid order_id"))?;
-        Ok(order_to_proto(&order))
+        Ok(market.subscribe_book())
+    }
+
+    pub fn subscribe_trades(&mut self, req: SubscribeTradesRequest) -> Result<futures_channel::mpsc::Receiver<types::Trade>, Status> {
+        let market = self
+            .markets
+            .get_mut(&req.market)
+            .ok_or_else(|| Status::invalid_argument("invalid market"))?;
+        Ok(market.subscribe_trades())
+    }
+
+    // one receiver attached to the order events of the user in every market
+    pub fn subscribe_orders(&mut self, req: SubscribeOrdersRequest) -> Result<futures_channel::mpsc::Receiver<OrderMessage>, Status> {
+        let (sender, receiver) = futures_channel::mpsc::channel(SUBSCRIPTION_BUFFER_SIZE - 1);
+        for market in self.markets.values_mut() {
+            market.subscribe_orders(req.user_id, sender.clone());
+        }
+        Ok(receiver)
+    }
+
+    pub fn subscribe_balances(
+        &mut self,
+        req: SubscribeBalancesRequest,
+    ) -> Result<futures_channel::mpsc::Receiver<asset::BalanceChange>, Status> {
+        Ok(self.balance_manager.borrow_mut().subscribe_balances(req.user_id))
+    }
+
+    // the orders on the book, `order_history_detail` has the finished ones
+    pub fn order_detail(&self, req: OrderDetailRequest) -> Result<OrderInfo, Status> {
+        let order = if req.market.is_empty() {
+            self.markets.values().find_map(|market| market.get(req.order_id))
+        } else {
+            self.markets
+                .get(&req.market)
+                .ok_or_else(|| Status::invalid_argument("invalid market"))?
+                .get(req.order_id)
+        };
+        order
+            .map(|order| order_to_proto(&order))
+            .ok_or_else(|| Status::not_found("order not found"))
     }
 
     pub fn market_list(&self, _req: MarketListRequest) -> Result<MarketListResponse, Status> {
@@ -262,12 +632,13 @@ impl Controller {
                 base_precision: market.base.prec,
                 quote_precision: market.quote.prec,
                 min_amount: market.min_amount.to_string(),
+                min_notional: market.min_notional.to_string(),
             })
             .collect();
         Ok(MarketListResponse { markets })
     }
 
-    pub fn market_summary(&self, req: MarketSummaryRequest) -> Result<MarketSummaryResponse, Status> {
+    pub fn market_summary(&mut self, req: MarketSummaryRequest) -> Result<MarketSummaryResponse, Status> {
         let markets: Vec<String> = if req.markets.is_empty() {
             self.markets.keys().cloned().collect()
         } else {
@@ -278,10 +649,13 @@ impl Controller {
             }
             req.markets
         };
+        let now = self.clock.now();
         let market_summaries = markets
             .iter()
             .map(|market| {
-                let status = self.markets.get(market).unwrap().status();
+                let market = self.markets.get_mut(market).unwrap();
+                let status = market.status();
+                let ticker = market.ticker(now);
                 market_summary_response::MarketSummary {
                     name: status.name,
                     ask_count: status.ask_count as i32,
@@ -289,29 +663,105 @@ impl Controller {
                     bid_count: status.bid_count as i32,
                     bid_amount: status.bid_amount.to_string(),
                     trade_count: status.trade_count,
+                    halted: status.halted,
+                    state: market_state_to_proto(status.state) as i32,
+                    last_price: ticker.last_price.to_string(),
+                    open_24h: ticker.open.to_string(),
+                    high_24h: ticker.high.to_string(),
+                    low_24h: ticker.low.to_string(),
+                    base_volume_24h: ticker.base_volume.to_string(),
+                    quote_volume_24h: ticker.quote_volume.to_string(),
+                    price_change_percent_24h: ticker.price_change_percent.to_string(),
                 }
             })
             .collect();
         Ok(MarketSummaryResponse { market_summaries })
     }
 
+    pub fn get_klines(&self, req: GetKlinesRequest) -> Result<GetKlinesResponse, Status> {
+        let market = self
+            .markets
+            .get(&req.market)
+            .ok_or_else(|| Status::invalid_argument("invalid market"))?;
+        let now = self.clock.now();
+        let to = if req.to == 0 { i64::MAX } else { req.to };
+        let limit = if req.limit == 0 { usize::MAX } else { req.limit as usize };
+        let klines = market
+            .klines
+            .query(req.interval, req.from, to, limit, now)
+            .map_err(|e| Status::invalid_argument(format!("{}", e)))?
+            .iter()
+            .map(|candle| get_klines_response::Kline {
+                time: candle.start,
+                open: candle.open.to_string(),
+                high: candle.high.to_string(),
+                low: candle.low.to_string(),
+                close: candle.close.to_string(),
+                volume: candle.volume.to_string(),
+                quote_volume: candle.quote_volume.to_string(),
+            })
+            .collect();
+        Ok(GetKlinesResponse { klines })
+    }
+
+    pub fn health(&self, _req: HealthRequest) -> Result<HealthResponse, Status> {
+        let sequencer = self.sequencer.borrow();
+        Ok(HealthResponse {
+            available: self.check_service_available(),
+            operation_log_id: sequencer.get_operation_log_id(),
+            order_id: sequencer.get_order_id(),
+            trade_id: sequencer.get_trade_id(),
+            dead_lettered_messages: dead_letter_count(),
+        })
+    }
+
+    pub fn health_check(&self, _req: HealthCheckRequest) -> Result<HealthCheckResponse, Status> {
+        Ok(HealthCheckResponse {
+            ready: self.engine_status.is_ready(),
+            uptime_seconds: self.engine_status.uptime().as_secs(),
+            operation_log_id: self.sequencer.borrow().get_operation_log_id(),
+            last_slice_operation_log_id: self.engine_status.last_slice_operation_log_id,
+            operation_log_backlog: self.log_handler.status().pending_count as u64,
+            message_broker_connected: self.message_manager.borrow().is_connected(),
+        })
+    }
+
+    // read only, so it can be run after a replay to a target as well
+    pub fn verify_integrity(&self, _req: VerifyIntegrityRequest) -> Result<VerifyIntegrityResponse, Status> {
+        let violations = market::verify_integrity(&self.markets, &self.balance_manager.borrow());
+        for violation in &violations {
+            tracing::warn!("integrity violation: {}", violation);
+        }
+        Ok(VerifyIntegrityResponse {
+            violations: violations.iter().map(integrity_violation_to_proto).collect(),
+        })
+    }
+
     fn check_service_available(&self) -> bool {
+        if self.engine_status.is_shutting_down() {
+            tracing::warn!("shutting down");
+            return false;
+        }
+        if self.read_only {
+            tracing::warn!("read-only after replaying to a target");
+            return false;
+        }
         if self.log_handler.is_block() {
-            log::warn!("log_handler full");
+            tracing::warn!("log_handler full");
             return false;
         }
         if self.message_manager.borrow_mut().is_block() {
-            log::warn!("message_manager full");
+            tracing::warn!("message_manager full");
             return false;
         }
         if self.history_writer.borrow_mut().is_block() {
-            log::warn!("history_writer full");
+            tracing::warn!("history_writer full");
             return false;
         }
         true
     }
 
-    pub fn update_balance(&mut self, real: bool, req: BalanceUpdateRequest) -> std::result::Result<BalanceUpdateResponse, Status> {
+    pub fn update_balance(&mut self, real: bool, mut req: BalanceUpdateRequest) -> std::result::Result<BalanceUpdateResponse, Status> {
         if !self.check_service_available() {
             return Err(Status::unavailable(""));
         }
@@ -326,15 +776,24 @@ impl Controller {
         } else {
             serde_json::from_str(req.detail.as_str()).map_err(|_| Status::invalid_argument("invalid detail"))?
         };
-        let _is_valid = self.update_controller.borrow_mut().update_user_balance(
-            real,
-            req.user_id,
-            req.asset.as_str(),
-            req.business.clone(),
-            req.business_id,
-            change,
-            detail_json,
-        );
+        // the replayed withdrawals count toward the daily limits from when they were journaled
+        if real {
+            req.timestamp = self.clock.now();
+        }
+        let _is_valid = self
+            .update_controller
+            .borrow_mut()
+            .update_user_balance_at(
+                real,
+                req.user_id,
+                req.asset.as_str(),
+                BusinessKind::from(req.business.as_str()),
+                req.business_id,
+                change,
+                detail_json,
+                req.timestamp,
+            )
+            .map_err(|e| Status::invalid_argument(format!("{}", e)))?;
 
         // TODO how to handle this error?
         // TODO operation_log after exec or before exec?
@@ -344,28 +803,279 @@ impl Controller {
         Ok(BalanceUpdateResponse::default())
     }
 
+    // deposit the balances of a seed file, returns the number of the applied entries
+    pub fn load_balance_seed(&mut self, path: &str) -> anyhow::Result<usize> {
+        let content = std::fs::read_to_string(path)?;
+        let entries = asset::parse_balance_seed(&content, path.ends_with(".json"))?;
+        let applied = self.update_controller.borrow_mut().apply_balance_seed(&entries)?;
+        for entry in &applied {
+            let req = BalanceUpdateRequest {
+                user_id: entry.user_id,
+                asset: entry.asset.clone(),
+                business: BusinessKind::Deposit.to_string(),
+                business_id: entry.business_id.unwrap_or_default(),
+                delta: entry.amount.to_string(),
+                detail: json!({ "seed": true }).to_string(),
+                timestamp: self.clock.now(),
+            };
+            self.append_operation_log(OPERATION_BALANCE_UPDATE, &req);
+        }
+        tracing::info!("balance seed {}: {} of {} entries applied", path, applied.len(), entries.len());
+        Ok(applied.len())
+    }
+
+    pub fn adjust_balance(&mut self, real: bool, req: AdjustBalanceRequest) -> std::result::Result<AdjustBalanceResponse, Status> {
+        if !self.check_service_available() {
+            return Err(Status::unavailable(""));
+        }
+        if !self.asset_manager.asset_exist(&req.asset) {
+            return Err(Status::invalid_argument("invalid asset"));
+        }
+        if req.operator.is_empty() {
+            return Err(Status::invalid_argument("unknown operator"));
+        }
+        if req.reason.is_empty() {
+            return Err(Status::invalid_argument("a reason is required"));
+        }
+        let prec = self.asset_manager.asset_prec_show(&req.asset);
+        let change = Decimal::from_str(req.delta.as_str())
+            .map_err(|_| Status::invalid_argument("invalid amount"))?
+            .round_dp(prec);
+        if change.is_zero() {
+            return Err(Status::invalid_argument("invalid amount"));
+        }
+        self.update_controller
+            .borrow_mut()
+            .adjust_balance(real, req.user_id, &req.asset, req.business_id, change, &req.operator, &req.reason)
+            .map_err(|e| Status::invalid_argument(format!("{}", e)))?;
+        if real {
+            self.append_operation_log(OPERATION_BALANCE_ADJUST, &req);
+        }
+        Ok(AdjustBalanceResponse::default())
+    }
+
     pub fn order_put(&mut self, real: bool, req: OrderPutRequest) -> Result<OrderInfo, Status> {
+        self.order_put_operation(real, OrderPutOperation::new(req))
+    }
+
+    fn order_put_operation(&mut self, real: bool, mut operation: OrderPutOperation) -> Result<OrderInfo, Status> {
         if !self.check_service_available() {
             return Err(Status::unavailable(""));
         }
-        if !self.markets.contains_key(&req.market) {
-            return Err(Status::invalid_argument("invalid market"));
+        // placement, matching and settlement are logged in this span, `sequence` is the operation log id
+        let span = tracing::info_span!(
+            "order_put",
+            user_id = operation.req.user_id,
+            market = %operation.req.market,
+            real,
+            order_id = tracing::field::Empty,
+            sequence = tracing::field::Empty,
+        );
+        let _enter = span.enter();
+        // the replayed trades take the fee tiers of when the order was journaled, and the
+        // idempotency keys expire by it
+        if real {
+            operation.timestamp = self.clock.now();
         }
+        let now = if operation.timestamp > 0.0 {
+            operation.timestamp
+        } else {
+            self.clock.now()
+        };
+        if let Some(order) = self
+            .order_put_cache
+            .check(operation.req.user_id, &operation.req.idempotency_key, now)?
+        {
+            tracing::debug!(order_id = order.id, "duplicate order put");
+            return Ok(order);
+        }
+        let order_input = self.order_input_checked(real, &mut operation, 0)?;
+        let req = &operation.req;
         let market = self.markets.get_mut(&req.market).unwrap();
+        let order = market.put_order(real, order_input).map_err(|e| {
+            tracing::debug!("order rejected: {}", e);
+            Status::unknown(format!("{}", e))
+        })?;
+        span.record("order_id", &order.id);
+        if real {
+            self.append_operation_log(OPERATION_ORDER_PUT, &operation);
+            span.record("sequence", &self.sequencer.borrow().get_operation_log_id());
+            let market = self.markets.get_mut(&req.market).unwrap();
+            if let Some(until) = market.check_circuit_breaker(self.clock.now()) {
+                let halt = MarketHalt {
+                    market: req.market.clone(),
+                    until,
+                };
+                self.append_operation_log(OPERATION_MARKET_HALT, &halt);
+            }
+        }
+        let order = order_to_proto(&order);
+        self.order_put_cache.record(req.user_id, &req.idempotency_key, &order, now);
+        Ok(order)
+    }
 
-        let order_input = order_input_from_proto(&req).map_err(|e| Status::invalid_argument(format!("invalid decimal {}", e)))?;
+    // The checks of the controller before the ones of the market, `pending_orders` of the user in
+    // the market are to be placed before this one.
+    fn order_input_checked(
+        &self,
+        real: bool,
+        operation: &mut OrderPutOperation,
+        pending_orders: usize,
+    ) -> Result<market::OrderInput, Status> {
+        let req = &operation.req;
+        let market = self
+            .markets
+            .get(&req.market)
+            .ok_or_else(|| Status::invalid_argument("invalid market"))?;
+        if self.balance_manager.borrow().is_fee_account(req.user_id) {
+            return Err(Status::invalid_argument("the fee account can't place orders"));
+        }
+        // a changed list must not reject the orders in the operation log
+        if real && self.update_controller.borrow().is_reserved_user(req.user_id) {
+            return Err(Status::invalid_argument("reserved users can't place orders"));
+        }
+        let mut order_input = order_input_from_proto(req).map_err(|e| Status::invalid_argument(format!("invalid decimal {}", e)))?;
+        order_input.timestamp = if operation.timestamp > 0.0 {
+            Some(operation.timestamp)
+        } else {
+            None
+        };
+        // a changed size limit must not reject the orders in the operation log
+        if real {
+            self.open_order_limits
+                .check(market, req.user_id, pending_orders)
+                .map_err(|e| Status::resource_exhausted(format!("{}", e)))?;
+            market
+                .check_order_size(&order_input)
+                .map_err(|e| Status::invalid_argument(format!("{}", e)))?;
+            market
+                .check_fee_rates(&order_input)
+                .map_err(|e| Status::invalid_argument(format!("{}", e)))?;
+            check_increments(market.tick_size, market.lot_size, &order_input)?;
+            // the band moves with the last price, the protection price it gives is journaled
+            if let Some(band) = market.price_band() {
+                apply_price_band(band, market.quote_prec, &mut order_input)?;
+                if order_input.type_ == market::OrderType::MARKET {
+                    operation.protection_price = order_input.protection_price.to_string();
+                }
+            }
+        } else if !operation.protection_price.is_empty() {
+            order_input.protection_price =
+                Decimal::from_str(&operation.protection_price).map_err(|e| Status::invalid_argument(format!("invalid decimal {}", e)))?;
+        }
+        Ok(order_input)
+    }
+
+    // Places the orders one by one as `order_put`, so each of them is journaled on its own.
+    // In the all-or-nothing mode, the orders are checked together first, and the batch is rejected
+    // with the index of the first order that would fail.
+    pub fn place_orders(
+        &mut self,
+        real: bool,
+        mode: market::BatchMode,
+        orders: Vec<OrderPutRequest>,
+    ) -> Result<Vec<Result<OrderInfo, Status>>, Status> {
+        if !self.check_service_available() {
+            return Err(Status::unavailable(""));
+        }
+        if orders.len() > ORDER_BATCH_MAX_LEN {
+            return Err(Status::invalid_argument(format!(
+                "at most {} orders in a batch",
+                ORDER_BATCH_MAX_LEN
+            )));
+        }
+        if mode == market::BatchMode::AllOrNothing {
+            let reject = |idx: usize, status: Status| Status::new(status.code(), format!("order {} rejected: {}", idx, status.message()));
+            let mut inputs = Vec::new();
+            // the orders of the batch count toward the open order limits too
+            let mut pending: HashMap<(u32, &str), usize> = HashMap::new();
+            for (idx, req) in orders.iter().enumerate() {
+                // a retried order gets its earlier result, it takes no more balance
+                if self
+                    .order_put_cache
+                    .check(req.user_id, &req.idempotency_key, self.clock.now())
+                    .map_err(|e| reject(idx, e))?
+                    .is_some()
+                {
+                    continue;
+                }
+                let pending_orders = pending.entry((req.user_id, req.market.as_str())).or_insert(0);
+                let order_input = self
+                    .order_input_checked(real, &mut OrderPutOperation::new(req.clone()), *pending_orders)
+                    .map_err(|e| reject(idx, e))?;
+                *pending_orders += 1;
+                inputs.push((idx, order_input));
+            }
+            let (indexes, inputs): (Vec<usize>, Vec<market::OrderInput>) = inputs.into_iter().unzip();
+            market::check_order_batch(&self.markets, &inputs)
+                .map_err(|(i, e)| reject(indexes[i], Status::invalid_argument(format!("{}", e))))?;
+        }
+        Ok(orders.into_iter().map(|req| self.order_put(real, req)).collect())
+    }
 
-        let order = market.put_order(real, order_input).map_err(|e| Status::unknown(format!("{}", e)))?;
+    pub fn trigger_order_put(&mut self, real: bool, req: TriggerOrderPutRequest) -> Result<TriggerOrderInfo, Status> {
+        if !self.check_service_available() {
+            return Err(Status::unavailable(""));
+        }
+        let order_req = req.order.as_ref().ok_or_else(|| Status::invalid_argument("invalid order"))?;
+        // The order is placed without the checks of `order_put` when triggered, so they are done now.
+        // The band only gives a protection price to the orders placed now, not to the later ones.
+        self.order_input_checked(real, &mut OrderPutOperation::new(order_req.clone()), 0)?;
+        let market = self
+            .markets
+            .get_mut(&order_req.market)
+            .ok_or_else(|| Status::invalid_argument("invalid market"))?;
+        let order_input = order_input_from_proto(order_req).map_err(|e| Status::invalid_argument(format!("invalid decimal {}", e)))?;
+        let trigger_price = Decimal::from_str(&req.trigger_price).map_err(|_| Status::invalid_argument("invalid trigger price"))?;
+        let direction = if req.direction == TriggerDirection::Above as i32 {
+            market::TriggerDirection::ABOVE
+        } else {
+            market::TriggerDirection::BELOW
+        };
+        let trigger_order = market
+            .put_trigger_order(trigger_price, direction, order_input)
+            .map_err(|e| Status::invalid_argument(format!("{}", e)))?;
+        let result = trigger_order_to_proto(market.name, &trigger_order);
         if real {
-            self.append_operation_log(OPERATION_ORDER_PUT, &req);
+            self.append_operation_log(OPERATION_TRIGGER_ORDER_PUT, &req);
         }
-        Ok(order_to_proto(&order))
+        Ok(result)
+    }
+
+    pub fn trigger_order_cancel(&mut self, real: bool, req: OrderCancelRequest) -> Result<TriggerOrderInfo, Status> {
+        if !self.check_service_available() {
+            return Err(Status::unavailable(""));
+        }
+        let market = self
+            .markets
+            .get_mut(&req.market)
+            .ok_or_else(|| Status::invalid_argument("invalid market"))?;
+        match market.trigger_orders.get(&req.order_id) {
+            None => return Err(Status::invalid_argument("invalid order_id")),
+            Some(trigger_order) if trigger_order.user != req.user_id => return Err(Status::invalid_argument("invalid user")),
+            _ => {}
+        }
+        let trigger_order = market.cancel_trigger_order(req.order_id).unwrap();
+        let result = trigger_order_to_proto(market.name, &trigger_order);
+        if real {
+            self.append_operation_log(OPERATION_TRIGGER_ORDER_CANCEL, &req);
+        }
+        Ok(result)
     }
 
     pub fn order_cancel(&mut self, real: bool, req: OrderCancelRequest) -> Result<OrderInfo, tonic::Status> {
         if !self.check_service_available() {
             return Err(Status::unavailable(""));
         }
+        let span = tracing::info_span!(
+            "order_cancel",
+            user_id = req.user_id,
+            market = %req.market,
+            order_id = req.order_id,
+            real,
+            sequence = tracing::field::Empty,
+        );
+        let _enter = span.enter();
         let market = self
             .markets
             .get_mut(&req.market)
@@ -379,29 +1089,269 @@ impl Controller {
         market.cancel(real, order.id);
         if real {
             self.append_operation_log(OPERATION_ORDER_CANCEL, &req);
+            span.record("sequence", &self.sequencer.borrow().get_operation_log_id());
+        }
+        Ok(OrderInfo {
+            status: OrderStatus::Cancelled as i32,
+            ..order_to_proto(&order)
+        })
+    }
+
+    // each cancelled order is journaled as a single cancel
+    pub fn order_batch_cancel(&mut self, real: bool, req: OrderBatchCancelRequest) -> Result<OrderBatchCancelResponse, Status> {
+        if !self.check_service_available() {
+            return Err(Status::unavailable(""));
+        }
+        if req.order_ids.len() > ORDER_BATCH_MAX_LEN {
+            return Err(Status::invalid_argument(format!(
+                "at most {} orders in a batch",
+                ORDER_BATCH_MAX_LEN
+            )));
+        }
+        let market = self
+            .markets
+            .get_mut(&req.market)
+            .ok_or_else(|| Status::invalid_argument("invalid market"))?;
+        let cancel_results = market.cancel_orders(real, req.user_id, &req.order_ids);
+        let mut results = Vec::with_capacity(cancel_results.len());
+        for (order_id, result) in req.order_ids.iter().zip(cancel_results) {
+            let (status, order) = match result {
+                market::CancelResult::Cancelled(order) => (OrderCancelStatus::CancelOk, Some(order)),
+                market::CancelResult::NotFound => (OrderCancelStatus::CancelNotFound, None),
+                market::CancelResult::NotOwner => (OrderCancelStatus::CancelNotOwner, None),
+            };
+            if real && order.is_some() {
+                let cancel = OrderCancelRequest {
+                    user_id: req.user_id,
+                    market: req.market.clone(),
+                    order_id: *order_id,
+                };
+                self.append_operation_log(OPERATION_ORDER_CANCEL, &cancel);
+            }
+            results.push(order_batch_cancel_response::CancelResult {
+                order_id: *order_id,
+                status: status as i32,
+                order: order.map(|order| OrderInfo {
+                    status: OrderStatus::Cancelled as i32,
+                    ..order_to_proto(&order)
+                }),
+            });
+        }
+        Ok(OrderBatchCancelResponse { results })
+    }
+
+    pub fn order_amend(&mut self, real: bool, req: OrderAmendRequest) -> Result<OrderInfo, tonic::Status> {
+        if !self.check_service_available() {
+            return Err(Status::unavailable(""));
+        }
+        let market = self
+            .markets
+            .get_mut(&req.market)
+            .ok_or_else(|| Status::invalid_argument("invalid market"))?;
+        let order = market
+            .get(req.order_id)
+            .ok_or_else(|| Status::invalid_argument("invalid order_id"))?;
+        if order.user != req.user_id {
+            return Err(Status::invalid_argument("invalid user"));
+        }
+        let parse = |value: &str, name: &str| -> Result<Option<Decimal>, Status> {
+            if value.is_empty() {
+                Ok(None)
+            } else {
+                Decimal::from_str(value)
+                    .map(Some)
+                    .map_err(|_| Status::invalid_argument(format!("invalid {}", name)))
+            }
+        };
+        let price = parse(&req.price, "price")?;
+        let amount = parse(&req.amount, "amount")?;
+        let order = market
+            .amend_order(real, order.id, price, amount)
+            .map_err(|e| Status::invalid_argument(format!("{}", e)))?;
+        if real {
+            self.append_operation_log(OPERATION_ORDER_AMEND, &req);
         }
         Ok(order_to_proto(&order))
     }
 
-    pub fn order_cancel_all(&mut self, real: bool, req: OrderCancelAllRequest) -> Result<OrderCancelAllResponse, tonic::Status> {
+    pub fn market_resume(&mut self, real: bool, req: MarketResumeRequest) -> Result<MarketResumeResponse, Status> {
+        if !self.check_service_available() {
+            return Err(Status::unavailable(""));
+        }
+        let market = self
+            .markets
+            .get_mut(&req.market)
+            .ok_or_else(|| Status::invalid_argument("invalid market"))?;
+        if !market.is_halted() {
+            return Err(Status::failed_precondition("market not halted"));
+        }
+        market.resume();
+        if real {
+            tracing::info!("market {} resumed", req.market);
+            self.append_operation_log(OPERATION_MARKET_RESUME, &req);
+        }
+        Ok(MarketResumeResponse {})
+    }
+
+    // The open orders canceled by delisting are logged on their own after the state, as by
+    // `order_cancel_all`, so the replayed state change cancels nothing itself.
+    pub fn market_set_state(&mut self, real: bool, req: MarketSetStateRequest) -> Result<MarketSetStateResponse, Status> {
         if !self.check_service_available() {
             return Err(Status::unavailable(""));
         }
+        let state = market_state_from_proto(req.state).ok_or_else(|| Status::invalid_argument("invalid state"))?;
         let market = self
             .markets
             .get_mut(&req.market)
             .ok_or_else(|| Status::invalid_argument("invalid market"))?;
-        let total = market.cancel_all_for_user(real, req.user_id) as u32;
+        market.set_state(state);
+        if !real {
+            return Ok(MarketSetStateResponse::default());
+        }
+        let mut canceled = Vec::new();
+        let mut canceled_triggers = Vec::new();
+        if state == market::MarketState::Delisted {
+            for order in market.cancel_all(true) {
+                canceled.push(OrderCancelRequest {
+                    user_id: order.user,
+                    market: req.market.clone(),
+                    order_id: order.id,
+                });
+            }
+            let trigger_ids: Vec<u64> = market.trigger_orders.keys().copied().collect();
+            for trigger_order in trigger_ids.into_iter().filter_map(|id| market.cancel_trigger_order(id)) {
+                canceled_triggers.push(OrderCancelRequest {
+                    user_id: trigger_order.user,
+                    market: req.market.clone(),
+                    order_id: trigger_order.id,
+                });
+            }
+        }
+        self.append_operation_log(OPERATION_MARKET_SET_STATE, &req);
+        for cancel_req in canceled.iter() {
+            self.append_operation_log(OPERATION_ORDER_CANCEL, cancel_req);
+        }
+        for cancel_req in canceled_triggers.iter() {
+            self.append_operation_log(OPERATION_TRIGGER_ORDER_CANCEL, cancel_req);
+        }
+        Ok(MarketSetStateResponse {
+            canceled: canceled.len() as u32,
+        })
+    }
+
+    // cancel the expired orders and resume the markets after their cooldown,
+    // both are logged so replay reproduces them
+    pub fn on_timer(&mut self) {
+        if !self.check_service_available() {
+            return;
+        }
+        let now = self.clock.now();
+        let cooled_down: Vec<String> = self
+            .markets
+            .values()
+            .filter(|market| market.halted_until().map_or(false, |until| until <= now))
+            .map(|market| market.name.to_string())
+            .collect();
+        for market in cooled_down {
+            self.market_resume(true, MarketResumeRequest { market }).ok();
+        }
+        let mut expired = Vec::new();
+        for market in self.markets.values_mut() {
+            for order in market.expire_orders(true, now) {
+                expired.push(OrderCancelRequest {
+                    user_id: order.user,
+                    market: market.name.to_string(),
+                    order_id: order.id,
+                });
+            }
+        }
+        for req in expired {
+            tracing::debug!("order {} of market {} expired", req.order_id, req.market);
+            self.append_operation_log(OPERATION_ORDER_EXPIRE, &req);
+        }
+    }
+
+    pub fn order_cancel_all(&mut self, real: bool, req: OrderCancelAllRequest) -> Result<OrderCancelAllResponse, tonic::Status> {
+        if !self.check_service_available() {
+            return Err(Status::unavailable(""));
+        }
+        // an empty market means all the markets
+        if !req.market.is_empty() && !self.markets.contains_key(&req.market) {
+            return Err(Status::invalid_argument("invalid market"));
+        }
+        let mut canceled = Vec::new();
+        for market in self.markets.values_mut() {
+            if req.market.is_empty() || req.market == market.name {
+                for order in market.cancel_all_for_user(real, req.user_id) {
+                    canceled.push(OrderCancelRequest {
+                        user_id: order.user,
+                        market: market.name.to_string(),
+                        order_id: order.id,
+                    });
+                }
+            }
+        }
+        // each cancellation is logged on its own, so replay doesn't depend on the orders at that time
         if real {
-            self.append_operation_log(OPERATION_ORDER_CANCEL_ALL, &req);
+            for cancel_req in canceled.iter() {
+                self.append_operation_log(OPERATION_ORDER_CANCEL, cancel_req);
+            }
+        }
+        Ok(OrderCancelAllResponse {
+            total: canceled.len() as u32,
+        })
+    }
+
+    // The state is copied at once, the returned job writes it to the db without the engine
+    pub fn begin_snapshot(&mut self, _req: MakeSnapshotRequest) -> Result<SnapshotJob, Status> {
+        // a slice of the past state would be taken as the latest one
+        if self.read_only {
+            return Err(Status::failed_precondition("read-only after replaying to a target"));
+        }
+        let slice = crate::persist::capture_slice(self.clock.now() as i64, self).map_err(|err| Status::unknown(format!("{}", err)))?;
+        Ok(SnapshotJob {
+            slice,
+            db_log: self.settings.db_log.clone(),
+            compaction: self.settings.operation_log_compaction.clone(),
+            started_at: std::time::Instant::now(),
+        })
+    }
+
+    pub fn finish_snapshot(&mut self, job: &SnapshotJob) -> MakeSnapshotResponse {
+        self.metrics.snapshot_duration.set(job.started_at.elapsed().as_secs_f64());
+        let operation_log_id = job.slice.end_operation_log_id();
+        // a later slice may have finished first
+        if operation_log_id > self.engine_status.last_slice_operation_log_id {
+            self.engine_status.last_slice_operation_log_id = operation_log_id;
+        }
+        MakeSnapshotResponse {
+            slice_id: job.slice.slice_id(),
+            operation_log_id,
+        }
+    }
+
+    // Called once no request is running. The operation log and the history are written before
+    // the final slice, so restarting from the slice replays nothing and loses nothing.
+    pub async fn shutdown(&mut self) -> SimpleResult {
+        self.engine_status.start_shutdown();
+        self.log_handler.close().await?;
+        self.history_writer.borrow_mut().close().await?;
+        if !self.read_only {
+            let slice_id = crate::persist::make_slice(self).await?;
+            self.engine_status.last_slice_operation_log_id = self.sequencer.borrow().get_operation_log_id();
+            tracing::info!("final slice {} made", slice_id);
         }
-        Ok(OrderCancelAllResponse { total })
+        self.message_manager.borrow_mut().finish();
+        Ok(())
     }
 
     pub async fn debug_dump(&self, _req: DebugDumpRequest) -> Result<DebugDumpResponse, Status> {
+        if self.read_only {
+            return Err(Status::failed_precondition("read-only after replaying to a target"));
+        }
         async {
             let mut connection = ConnectionType::connect(&self.settings.db_log).await?;
-            crate::persist::dump_to_db(&mut connection, utils::current_timestamp() as i64, self).await
+            crate::persist::dump_to_db(&mut connection, self.clock.now() as i64, self).await
         }
         .await
         .map_err(|err| Status::unknown(format!("{}", err)))?;
@@ -415,13 +1365,16 @@ impl Controller {
         }
         //self.log_handler.reset();
         self.update_controller.borrow_mut().reset();
+        self.order_put_cache.reset();
+        self.fee_tier_manager.borrow_mut().reset();
         self.balance_manager.borrow_mut().reset();
+        self.read_only = false;
         //Ok(())
     }
 
     pub async fn debug_reset(&mut self, _req: DebugResetRequest) -> Result<DebugResetResponse, Status> {
         async {
-            println!("do full reset: memory and db");
+            tracing::warn!("do full reset: memory and db");
             self.reset_state();
             // waiting for pending db writes
             tokio::time::sleep(std::time::Duration::from_secs(1)).await;
@@ -484,17 +1437,49 @@ impl Controller {
     // reload 1000 in batch and replay
     pub fn replay(&mut self, method: &str, params: &str) -> SimpleResult {
         match method {
+            OPERATION_ASSET_REGISTER => {
+                self.asset_register(false, serde_json::from_str(params)?)?;
+            }
             OPERATION_BALANCE_UPDATE => {
                 self.update_balance(false, serde_json::from_str(params)?)?;
             }
+            OPERATION_BALANCE_ADJUST => {
+                self.adjust_balance(false, serde_json::from_str(params)?)?;
+            }
+            OPERATION_ORDER_AMEND => {
+                self.order_amend(false, serde_json::from_str(params)?)?;
+            }
             OPERATION_ORDER_CANCEL => {
                 self.order_cancel(false, serde_json::from_str(params)?)?;
             }
+            // only written by the older versions
             OPERATION_ORDER_CANCEL_ALL => {
                 self.order_cancel_all(false, serde_json::from_str(params)?)?;
             }
+            OPERATION_ORDER_EXPIRE => {
+                self.order_cancel(false, serde_json::from_str(params)?)?;
+            }
             OPERATION_ORDER_PUT => {
-                self.order_put(false, serde_json::from_str(params)?)?;
+                self.order_put_operation(false, serde_json::from_str(params)?)?;
+            }
+            OPERATION_TRIGGER_ORDER_PUT => {
+                self.trigger_order_put(false, serde_json::from_str(params)?)?;
+            }
+            OPERATION_TRIGGER_ORDER_CANCEL => {
+                self.trigger_order_cancel(false, serde_json::from_str(params)?)?;
+            }
+            OPERATION_MARKET_HALT => {
+                let halt: MarketHalt = serde_json::from_str(params)?;
+                self.markets
+                    .get_mut(&halt.market)
+                    .ok_or_else(|| anyhow!("invalid market {}", halt.market))?
+                    .halt(halt.until);
+            }
+            OPERATION_MARKET_RESUME => {
+                self.market_resume(false, serde_json::from_str(params)?)?;
+            }
+            OPERATION_MARKET_SET_STATE => {
+                self.market_set_state(false, serde_json::from_str(params)?)?;
             }
             _ => return Err(anyhow!("invalid operation {}", method)),
         }
@@ -505,13 +1490,16 @@ impl Controller {
         Operation: Serialize,
     {
         let params = serde_json::to_string(req).unwrap();
+        let id = self.sequencer.borrow_mut().next_operation_log_id();
+        self.message_manager.borrow_mut().set_operation_log_id(id);
         let operation_log = models::OperationLog {
-            id: self.sequencer.borrow_mut().next_operation_log_id() as i64,
-            time: FTimestamp(utils::current_timestamp()).into(),
+            id: id as i64,
+            time: FTimestamp(self.clock.now()).into(),
             method: method.to_owned(),
             params,
         };
         self.log_handler.append(operation_log).ok();
+        self.metrics.operation_log_lag.set(self.log_handler.status().pending_count as i64);
     }
 }
 
@@ -520,6 +1508,674 @@ fn sqlverf_clear_slice() {
     sqlx::query!("drop table if exists balance_history, balance_slice");
 }
 
+// Limit orders priced outside the band are rejected before touching the book or balances,
+// market orders get the band edge as their protection price.
+fn apply_price_band(band: (Decimal, Decimal), quote_prec: u32, order_input: &mut market::OrderInput) -> Result<(), Status> {
+    let (low, high) = band;
+    if order_input.type_ == market::OrderType::LIMIT {
+        let price = order_input.price.round_dp(quote_prec);
+        if price < low || price > high {
+            return Err(Status::out_of_range(format!("price outside the price band {} - {}", low, high)));
+        }
+        return Ok(());
+    }
+    order_input.protection_price = if order_input.side == market::OrderSide::ASK { low } else { high };
+    Ok(())
+}
+
+// Off-grid prices and amounts are rejected rather than rounded, so the book doesn't fragment into
+// levels between the ticks. The input is checked as given, so an extra digit beyond the precision
+// is rejected too.
+fn check_increments(tick_size: Decimal, lot_size: Decimal, order_input: &market::OrderInput) -> Result<(), Status> {
+    if !tick_size.is_zero() && order_input.type_ == market::OrderType::LIMIT && !(order_input.price % tick_size).is_zero() {
+        return Err(Status::invalid_argument(format!(
+            "price {} is not a multiple of the tick size {}",
+            order_input.price, tick_size
+        )));
+    }
+    // market bids by quote amount have no amount
+    if !lot_size.is_zero() && !order_input.amount.is_zero() && !(order_input.amount % lot_size).is_zero() {
+        return Err(Status::invalid_argument(format!(
+            "amount {} is not a multiple of the lot size {}",
+            order_input.amount, lot_size
+        )));
+    }
+    Ok(())
+}
+
+// side, price and time priority of an order on the book
+type BookPriority = (market::OrderSide, Decimal, u64);
+
+fn book_priority(order: &market::Order) -> BookPriority {
+    (order.side, order.price, order.priority)
+}
+
+// the order the book matches in, asks before bids
+fn book_priority_cmp(a: &BookPriority, b: &BookPriority) -> std::cmp::Ordering {
+    use market::OrderSide::{ASK, BID};
+    use std::cmp::Ordering;
+    match (a.0, b.0) {
+        (ASK, ASK) => (a.1, a.2).cmp(&(b.1, b.2)),
+        (BID, BID) => b.1.cmp(&a.1).then(a.2.cmp(&b.2)),
+        (ASK, BID) => Ordering::Less,
+        (BID, ASK) => Ordering::Greater,
+    }
+}
+
+// `priority` is unique in a market and keeps the time priority of the order
+fn book_priority_cursor(order: &market::Order) -> String {
+    let side = if order.side == market::OrderSide::ASK { "ask" } else { "bid" };
+    format!("{}_{}_{}", side, order.price, order.priority)
+}
+
+fn parse_book_priority_cursor(cursor: &str) -> Result<BookPriority, Status> {
+    let invalid = || Status::invalid_argument("invalid cursor");
+    let mut parts = cursor.splitn(3, '_');
+    let side = match parts.next() {
+        Some("ask") => market::OrderSide::ASK,
+        Some("bid") => market::OrderSide::BID,
+        _ => return Err(invalid()),
+    };
+    let price = parts.next().and_then(|price| Decimal::from_str(price).ok()).ok_or_else(invalid)?;
+    let priority = parts.next().and_then(|priority| priority.parse().ok()).ok_or_else(invalid)?;
+    Ok((side, price, priority))
+}
+
+// The fills of a user are listed by (trade_id, side) descending, trade ids grow with time and
+// the side tells apart the two fills of a self-matched trade. A page starts right after the cursor.
+fn parse_trade_cursor(cursor: &str) -> Result<(i64, i16), Status> {
+    let invalid = || Status::invalid_argument("invalid cursor");
+    let mut parts = cursor.splitn(2, '_');
+    let trade_id = parts.next().and_then(|id| id.parse().ok()).ok_or_else(invalid)?;
+    let side = parts.next().and_then(|side| side.parse().ok()).ok_or_else(invalid)?;
+    Ok((trade_id, side))
+}
+
+#[cfg(sqlxverf)]
+fn sqlverf_my_trades() {
+    let time = chrono::NaiveDateTime::from_timestamp(0, 0);
+    sqlx::query_as!(
+        models::TradeHistory,
+        "select * from trade_history
+        where user_id = $1 and market = $2 and time >= $3 and time < $4 and (trade_id, side) < ($5, $6)
+        order by trade_id desc, side desc limit 21",
+        101,
+        "ETH_USDT",
+        time,
+        time,
+        10000,
+        1,
+    );
+}
+
+// the filters are bound in the order of user_id, market, from, to and cursor
+fn my_trades_query(market: bool, from: bool, to: bool, cursor: bool, limit: usize) -> String {
+    let mut conditions = vec!["user_id = $1".to_string()];
+    if market {
+        conditions.push(format!("market = ${}", conditions.len() + 1));
+    }
+    if from {
+        conditions.push(format!("time >= ${}", conditions.len() + 1));
+    }
+    if to {
+        conditions.push(format!("time < ${}", conditions.len() + 1));
+    }
+    if cursor {
+        let n = conditions.len();
+        conditions.push(format!("(trade_id, side) < (${}, ${})", n + 1, n + 2));
+    }
+    format!(
+        "select * from {} where {} order by trade_id desc, side desc limit {}",
+        models::tablenames::TRADEHISTORY,
+        conditions.join(" and "),
+        limit
+    )
+}
+
+fn my_trade_to_proto(trade: &models::TradeHistory) -> my_trades_response::Trade {
+    my_trades_response::Trade {
+        trade_id: trade.trade_id as u64,
+        timestamp: FTimestamp::from(&trade.time).0,
+        market: trade.market.clone(),
+        side: if trade.side == market::OrderSide::ASK as i16 {
+            OrderSide::Ask as i32
+        } else {
+            OrderSide::Bid as i32
+        },
+        role: if trade.role == types::MarketRole::MAKER as i16 {
+            MarketRole::Maker as i32
+        } else {
+            MarketRole::Taker as i32
+        },
+        price: trade.price.to_string(),
+        amount: trade.amount.to_string(),
+        quote_amount: trade.quote_amount.to_string(),
+        fee: trade.fee.to_string(),
+        order_id: trade.order_id as u64,
+        counter_order_id: trade.counter_order_id as u64,
+    }
+}
+
+// read from the trade history, so the trades not written to the db yet are not seen
+pub async fn my_trades(pool: &sqlx::Pool<DbType>, req: MyTradesRequest) -> Result<MyTradesResponse, Status> {
+    let limit = match req.limit as usize {
+        0 => MY_TRADES_DEFAULT_LIMIT,
+        limit => limit.min(MY_TRADES_MAX_LIMIT),
+    };
+    let cursor = if req.cursor.is_empty() {
+        None
+    } else {
+        Some(parse_trade_cursor(&req.cursor)?)
+    };
+    // one more row to know whether there is a next page
+    let trades_query = my_trades_query(!req.market.is_empty(), req.from > 0, req.to > 0, cursor.is_some(), limit + 1);
+    let mut query = sqlx::query_as::<_, models::TradeHistory>(&trades_query).bind(req.user_id as i32);
+    if !req.market.is_empty() {
+        query = query.bind(&req.market);
+    }
+    if req.from > 0 {
+        query = query.bind(chrono::NaiveDateTime::from_timestamp(req.from, 0));
+    }
+    if req.to > 0 {
+        query = query.bind(chrono::NaiveDateTime::from_timestamp(req.to, 0));
+    }
+    if let Some((trade_id, side)) = cursor {
+        query = query.bind(trade_id).bind(side);
+    }
+    let mut rows = query.fetch_all(pool).await.map_err(StorageError::from)?;
+    let next_cursor = if rows.len() > limit {
+        rows.truncate(limit);
+        rows.last().map(|row| format!("{}_{}", row.trade_id, row.side)).unwrap()
+    } else {
+        String::new()
+    };
+    Ok(MyTradesResponse {
+        trades: rows.iter().map(my_trade_to_proto).collect(),
+        next_cursor,
+    })
+}
+
+// read from the order history, so the orders finished but not written to the db yet are not seen
+pub async fn order_history_detail(pool: &sqlx::Pool<DbType>, req: OrderDetailRequest) -> Result<OrderInfo, Status> {
+    let order_query = if req.market.is_empty() {
+        format!("select * from {} where id = $1", models::tablenames::ORDERHISTORY)
+    } else {
+        format!("select * from {} where id = $1 and market = $2", models::tablenames::ORDERHISTORY)
+    };
+    let mut query = sqlx::query_as::<_, models::OrderHistory>(&order_query).bind(req.order_id as i64);
+    if !req.market.is_empty() {
+        query = query.bind(&req.market);
+    }
+    let order = query.fetch_optional(pool).await.map_err(StorageError::from)?;
+    order
+        .map(|order| order_history_to_proto(&order))
+        .ok_or_else(|| Status::not_found("order not found"))
+}
+
+pub fn init_order_expire_timer() {
+    let interval = unsafe { G_STUB.as_ref().unwrap() }.settings.order_expire_interval;
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            unsafe { G_STUB.as_mut().unwrap() }.on_timer();
+        }
+    });
+}
+
 //use the ownership should make us has no dangling pointer
 pub(crate) static mut G_STUB: Option<Controller> = None;
 pub(crate) static mut G_RT: *const tokio::runtime::Runtime = std::ptr::null();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // as `persist::init_from_db` drives it on startup and reload
+    #[test]
+    fn test_ready_after_replay() {
+        let mut status = EngineStatus::new();
+        assert!(!status.is_ready());
+        status.start_replay();
+        status.last_slice_operation_log_id = 100;
+        assert!(!status.is_ready());
+        status.finish_replay();
+        assert!(status.is_ready());
+
+        status.start_replay();
+        assert!(!status.is_ready());
+        status.finish_replay();
+        assert!(status.is_ready());
+        assert_eq!(status.last_slice_operation_log_id, 100);
+        assert!(status.uptime() < Duration::from_secs(60));
+
+        status.start_shutdown();
+        assert!(!status.is_ready());
+    }
+
+    fn order_input(type_: market::OrderType, side: market::OrderSide, price: Decimal) -> market::OrderInput {
+        market::OrderInput {
+            user_id: 101,
+            side,
+            type_,
+            amount: Decimal::new(1, 0),
+            price,
+            taker_fee: Decimal::zero(),
+            maker_fee: Decimal::zero(),
+            market: "ETH_USDT".to_string(),
+            time_in_force: market::TimeInForce::GTC,
+            post_only: false,
+            self_trade_prevention: market::SelfTradePrevention::Allow,
+            quote_amount: Decimal::zero(),
+            display_qty: Decimal::zero(),
+            expire_at: None,
+            protection_price: Decimal::zero(),
+            timestamp: None,
+        }
+    }
+
+    #[test]
+    fn test_order_detail_status() {
+        let order = market::Order {
+            id: 7,
+            market: "ETH_USDT",
+            type_: market::OrderType::LIMIT,
+            side: market::OrderSide::BID,
+            user: 101,
+            create_time: 1000.0,
+            update_time: 1000.0,
+            price: Decimal::new(100, 0),
+            amount: Decimal::new(2, 0),
+            taker_fee: Decimal::zero(),
+            maker_fee: Decimal::zero(),
+            remain: Decimal::new(2, 0),
+            frozen: Decimal::new(200, 0),
+            finished_base: Decimal::zero(),
+            finished_quote: Decimal::zero(),
+            finished_fee: Decimal::zero(),
+            finished_paid_fee: Decimal::zero(),
+            display_qty: Decimal::zero(),
+            visible: Decimal::zero(),
+            priority: 7,
+            expire_at: None,
+        };
+        let open = order_to_proto(&order);
+        assert_eq!((open.status, open.remain.as_str()), (OrderStatus::Open as i32, "2"));
+        let partially_filled = market::Order {
+            remain: Decimal::new(1, 0),
+            finished_base: Decimal::new(1, 0),
+            ..order
+        };
+        assert_eq!(order_status(&partially_filled), OrderStatus::PartiallyFilled);
+
+        let history = |finished_base: Decimal| models::OrderHistory {
+            id: 7,
+            create_time: FTimestamp(1000.0).into(),
+            finish_time: FTimestamp(1060.0).into(),
+            user_id: 101,
+            market: "ETH_USDT".to_string(),
+            order_type: market::OrderType::LIMIT,
+            order_side: market::OrderSide::BID,
+            price: Decimal::new(100, 0),
+            amount: Decimal::new(2, 0),
+            taker_fee: Decimal::zero(),
+            maker_fee: Decimal::zero(),
+            finished_base,
+            finished_quote: finished_base * Decimal::new(100, 0),
+            finished_fee: Decimal::zero(),
+            finished_paid_fee: Decimal::zero(),
+        };
+        let filled = order_history_to_proto(&history(Decimal::new(2, 0)));
+        assert_eq!(filled.status, OrderStatus::Filled as i32);
+        assert_eq!((filled.remain.as_str(), filled.finished_quote.as_str()), ("0", "200"));
+        assert_eq!(filled.update_time, 1060.0);
+        let cancelled = order_history_to_proto(&history(Decimal::new(5, 1)));
+        assert_eq!(cancelled.status, OrderStatus::Cancelled as i32);
+        assert_eq!(cancelled.remain, "1.5");
+    }
+
+    #[test]
+    fn test_order_query_book_priority() {
+        use market::{MarketKeyAsk, MarketKeyBid, OrderSide};
+        use std::collections::BTreeMap;
+        let order = |id: u64, side: OrderSide, price: u64, priority: u64| market::Order {
+            id,
+            market: "ETH_USDT",
+            type_: market::OrderType::LIMIT,
+            side,
+            user: 101,
+            create_time: id as f64,
+            update_time: id as f64,
+            price: Decimal::from(price),
+            amount: Decimal::new(1, 0),
+            taker_fee: Decimal::zero(),
+            maker_fee: Decimal::zero(),
+            remain: Decimal::new(1, 0),
+            frozen: Decimal::zero(),
+            finished_base: Decimal::zero(),
+            finished_quote: Decimal::zero(),
+            finished_fee: Decimal::zero(),
+            finished_paid_fee: Decimal::zero(),
+            display_qty: Decimal::zero(),
+            visible: Decimal::zero(),
+            priority,
+            expire_at: None,
+        };
+        // order 1 is a refreshed iceberg, queued behind order 4
+        let orders = vec![
+            order(1, OrderSide::ASK, 101, 6),
+            order(2, OrderSide::BID, 99, 2),
+            order(3, OrderSide::BID, 98, 3),
+            order(4, OrderSide::ASK, 101, 4),
+            order(5, OrderSide::ASK, 100, 5),
+            order(7, OrderSide::BID, 99, 7),
+        ];
+        let mut asks = BTreeMap::new();
+        let mut bids = BTreeMap::new();
+        for o in &orders {
+            match o.side {
+                OrderSide::ASK => asks.insert(
+                    MarketKeyAsk {
+                        order_price: o.price,
+                        priority: o.priority,
+                    },
+                    o.id,
+                ),
+                OrderSide::BID => bids.insert(
+                    MarketKeyBid {
+                        order_price: o.price,
+                        priority: o.priority,
+                    },
+                    o.id,
+                ),
+            };
+        }
+        let book: Vec<u64> = asks.values().chain(bids.values()).copied().collect();
+        assert_eq!(book, vec![5, 4, 1, 2, 7, 3]);
+
+        let mut sorted = orders.clone();
+        sorted.sort_by(|a, b| book_priority_cmp(&book_priority(a), &book_priority(b)));
+        assert_eq!(sorted.iter().map(|o| o.id).collect::<Vec<u64>>(), book);
+
+        // a page starts right after the cursor
+        let cursor = book_priority_cursor(&sorted[2]);
+        assert_eq!(cursor, "ask_101_6");
+        let after = parse_book_priority_cursor(&cursor).unwrap();
+        let next_page: Vec<u64> = sorted
+            .iter()
+            .filter(|o| book_priority_cmp(&book_priority(o), &after) == std::cmp::Ordering::Greater)
+            .map(|o| o.id)
+            .collect();
+        assert_eq!(next_page, vec![2, 7, 3]);
+        assert!(parse_book_priority_cursor("bid_99").is_err());
+        assert!(parse_book_priority_cursor("buy_99_2").is_err());
+    }
+
+    #[test]
+    fn test_price_band() {
+        use market::{OrderSide, OrderType};
+        // ±10% around 100
+        let band = (Decimal::new(90, 0), Decimal::new(110, 0));
+
+        for price in &[Decimal::new(90, 0), Decimal::new(110, 0), Decimal::new(1100004, 4)] {
+            let mut order = order_input(OrderType::LIMIT, OrderSide::BID, *price);
+            assert!(apply_price_band(band, 2, &mut order).is_ok(), "{}", price);
+        }
+        for price in &[Decimal::new(8999, 2), Decimal::new(11001, 2)] {
+            let mut order = order_input(OrderType::LIMIT, OrderSide::ASK, *price);
+            let status = apply_price_band(band, 2, &mut order).unwrap_err();
+            assert_eq!(status.code(), tonic::Code::OutOfRange, "{}", price);
+        }
+
+        let mut order = order_input(OrderType::MARKET, OrderSide::BID, Decimal::zero());
+        apply_price_band(band, 2, &mut order).unwrap();
+        assert_eq!(order.protection_price, Decimal::new(110, 0));
+        let mut order = order_input(OrderType::MARKET, OrderSide::ASK, Decimal::zero());
+        apply_price_band(band, 2, &mut order).unwrap();
+        assert_eq!(order.protection_price, Decimal::new(90, 0));
+    }
+
+    #[test]
+    fn test_order_put_operation() {
+        let req = OrderPutRequest {
+            user_id: 1,
+            market: "ETH_USDT".to_owned(),
+            order_type: OrderType::Market as i32,
+            amount: "1".to_owned(),
+            ..Default::default()
+        };
+        let mut operation = OrderPutOperation::new(req.clone());
+        operation.protection_price = "110".to_owned();
+        operation.timestamp = 1_615_379_696.0;
+        let params = serde_json::to_string(&operation).unwrap();
+        // the record sits beside the fields of the request, as in the older operation logs
+        let value: serde_json::Value = serde_json::from_str(&params).unwrap();
+        assert_eq!(value["market"], "ETH_USDT");
+        assert_eq!(value["protection_price"], "110");
+        assert_eq!(value["timestamp"], 1_615_379_696.0);
+        let replayed: OrderPutOperation = serde_json::from_str(&params).unwrap();
+        assert_eq!(replayed.req, req);
+        assert_eq!(replayed.protection_price, "110");
+        assert_eq!(replayed.timestamp, 1_615_379_696.0);
+        // the requests journaled by the older versions have neither, the order is placed at the replay time
+        let replayed: OrderPutOperation = serde_json::from_str(&serde_json::to_string(&req).unwrap()).unwrap();
+        assert_eq!(replayed.protection_price, "");
+        assert_eq!(replayed.timestamp, 0.0);
+    }
+
+    #[test]
+    fn test_increments() {
+        use market::{OrderSide, OrderType};
+        // ETH_USDT at quote prec 2 and base prec 4
+        let tick_size = Decimal::new(5, 2);
+        let lot_size = Decimal::new(10, 4);
+
+        for (price, amount) in &[
+            (Decimal::new(10005, 2), Decimal::new(1, 0)),
+            (Decimal::new(100, 0), Decimal::new(12340, 4)),
+            (Decimal::new(1000000, 4), Decimal::new(1, 3)),
+        ] {
+            let mut order = order_input(OrderType::LIMIT, OrderSide::BID, *price);
+            order.amount = *amount;
+            assert!(check_increments(tick_size, lot_size, &order).is_ok(), "{} {}", price, amount);
+        }
+        for (price, amount) in &[
+            (Decimal::new(10003, 2), Decimal::new(1, 0)),
+            (Decimal::new(100051, 3), Decimal::new(1, 0)),
+            (Decimal::new(100, 0), Decimal::new(12345, 4)),
+            (Decimal::new(100, 0), Decimal::new(10001, 4)),
+        ] {
+            let mut order = order_input(OrderType::LIMIT, OrderSide::ASK, *price);
+            order.amount = *amount;
+            let status = check_increments(tick_size, lot_size, &order).unwrap_err();
+            assert_eq!(status.code(), tonic::Code::InvalidArgument, "{} {}", price, amount);
+        }
+
+        // market orders have no price, and no amount when spending a quote amount
+        let mut order = order_input(OrderType::MARKET, OrderSide::BID, Decimal::zero());
+        assert!(check_increments(tick_size, lot_size, &order).is_ok());
+        order.amount = Decimal::zero();
+        order.quote_amount = Decimal::new(10001, 2);
+        assert!(check_increments(tick_size, lot_size, &order).is_ok());
+        // zero disables the checks
+        let order = order_input(OrderType::LIMIT, OrderSide::BID, Decimal::new(10003, 2));
+        assert!(check_increments(Decimal::zero(), Decimal::zero(), &order).is_ok());
+    }
+
+    #[test]
+    fn utest_my_trades_query() {
+        assert_eq!(
+            my_trades_query(false, false, false, false, 21),
+            "select * from trade_history where user_id = $1 order by trade_id desc, side desc limit 21"
+        );
+        assert_eq!(
+            my_trades_query(true, false, true, true, 21),
+            "select * from trade_history where user_id = $1 and market = $2 and time < $3 \
+            and (trade_id, side) < ($4, $5) order by trade_id desc, side desc limit 21"
+        );
+        assert_eq!(parse_trade_cursor("42_1").unwrap(), (42, 1));
+        assert!(parse_trade_cursor("42").is_err());
+    }
+
+    // with self trade prevention off, a user can be both sides of a trade
+    #[test]
+    fn test_self_match_roles() {
+        let trade = types::Trade {
+            id: 42,
+            timestamp: 1_615_379_696.0,
+            market: "ETH_USDT".to_string(),
+            base: "ETH".to_string(),
+            quote: "USDT".to_string(),
+            price: Decimal::new(100, 0),
+            amount: Decimal::new(2, 0),
+            quote_amount: Decimal::new(200, 0),
+            ask_user_id: 101,
+            ask_order_id: 1,
+            ask_role: types::MarketRole::MAKER,
+            ask_fee: Decimal::new(2, 1),
+            ask_fee_asset: "USDT".to_string(),
+            bid_user_id: 101,
+            bid_order_id: 2,
+            bid_role: types::MarketRole::TAKER,
+            bid_fee: Decimal::new(4, 3),
+            bid_fee_asset: "ETH".to_string(),
+        };
+        let rows = crate::history::trade_history_rows(&trade);
+        assert!(rows.iter().all(|row| row.user_id == 101));
+        // newest first, as the query lists them
+        let fills: Vec<my_trades_response::Trade> = rows.iter().rev().map(my_trade_to_proto).collect();
+
+        assert_eq!(fills[0].side, OrderSide::Bid as i32);
+        assert_eq!(fills[0].role, MarketRole::Taker as i32);
+        assert_eq!((fills[0].order_id, fills[0].counter_order_id), (2, 1));
+        assert_eq!(fills[0].fee, "0.004");
+
+        assert_eq!(fills[1].side, OrderSide::Ask as i32);
+        assert_eq!(fills[1].role, MarketRole::Maker as i32);
+        assert_eq!((fills[1].order_id, fills[1].counter_order_id), (1, 2));
+        assert_eq!(fills[1].fee, "0.2");
+        assert!(fills.iter().all(|fill| fill.trade_id == 42 && fill.timestamp == 1_615_379_696.0));
+    }
+
+    // an ETH_USDT market, the pools of the engine connect to `url` lazily
+    fn utest_settings(url: &str, market: config::Market) -> config::Settings {
+        let asset = |name: &str| config::Asset {
+            name: name.to_string(),
+            prec_save: 8,
+            prec_show: 8,
+            ..Default::default()
+        };
+        let unit = |name: &str, prec| config::MarketUnit {
+            name: name.to_string(),
+            prec,
+        };
+        config::Settings {
+            db_log: url.to_string(),
+            db_history: url.to_string(),
+            assets: vec![asset("ETH"), asset("USDT")],
+            markets: vec![config::Market {
+                name: "ETH_USDT".to_string(),
+                base: unit("ETH", 4),
+                quote: unit("USDT", 2),
+                ..market
+            }],
+            ..Default::default()
+        }
+    }
+
+    // A retry within the ttl gets the first order, a reused key after it places another, both
+    // live and on a replay long after. Needs a postgres at DATABASE_URL.
+    #[tokio::test]
+    #[ignore]
+    async fn utest_order_put_idempotency_replay() {
+        use crate::utils::MockClock;
+        use rust_decimal_macros::dec;
+        let url = std::env::var("DATABASE_URL").unwrap();
+        let settings = || config::Settings {
+            order_idempotency: config::OrderIdempotencyConfig {
+                capacity: 100,
+                entry_ttl: Duration::from_secs(60),
+            },
+            ..utest_settings(&url, Default::default())
+        };
+        let order = || OrderPutRequest {
+            user_id: 101,
+            market: "ETH_USDT".to_string(),
+            order_side: OrderSide::Ask as i32,
+            order_type: OrderType::Limit as i32,
+            amount: "1".to_string(),
+            price: "100".to_string(),
+            idempotency_key: "order-1".to_string(),
+            ..Default::default()
+        };
+        let open_orders = |controller: &Controller| controller.markets["ETH_USDT"].users[&101].keys().copied().collect::<Vec<u64>>();
+        let t0 = 1_600_000_000.0;
+
+        let clock = Rc::new(MockClock::new(t0));
+        let mut controller = Controller::with_clock(settings(), clock.clone());
+        controller
+            .balance_manager
+            .borrow_mut()
+            .add(101, BalanceType::AVAILABLE, "ETH", &dec!(10))
+            .unwrap();
+        let first = controller.order_put(true, order()).unwrap();
+        clock.advance(30.0);
+        assert_eq!(controller.order_put(true, order()).unwrap(), first);
+        clock.advance(30.0);
+        let second = controller.order_put(true, order()).unwrap();
+        assert_ne!(second.id, first.id);
+        assert_eq!(open_orders(&controller), vec![first.id, second.id]);
+
+        // the two orders as journaled, replayed at once
+        let clock = Rc::new(MockClock::new(t0 + 90.0));
+        let mut restarted = Controller::with_clock(settings(), clock.clone());
+        restarted
+            .balance_manager
+            .borrow_mut()
+            .add(101, BalanceType::AVAILABLE, "ETH", &dec!(10))
+            .unwrap();
+        for timestamp in &[t0, t0 + 60.0] {
+            let mut operation = OrderPutOperation::new(order());
+            operation.timestamp = *timestamp;
+            restarted
+                .replay(OPERATION_ORDER_PUT, &serde_json::to_string(&operation).unwrap())
+                .unwrap();
+        }
+        assert_eq!(open_orders(&restarted), open_orders(&controller));
+        // and the replayed key still dedups the retries within its ttl
+        assert_eq!(restarted.order_put(true, order()).unwrap(), second);
+    }
+
+    // the trigger orders are checked as the placed ones when submitted, needs a postgres at DATABASE_URL
+    #[tokio::test]
+    #[ignore]
+    async fn utest_trigger_order_checks() {
+        use rust_decimal_macros::dec;
+        let url = std::env::var("DATABASE_URL").unwrap();
+        let market = config::Market {
+            tick_size: dec!(0.5),
+            ..Default::default()
+        };
+        let mut controller = Controller::new(utest_settings(&url, market));
+        let trigger_order = |price: &str| TriggerOrderPutRequest {
+            order: Some(OrderPutRequest {
+                user_id: 101,
+                market: "ETH_USDT".to_string(),
+                order_side: OrderSide::Ask as i32,
+                order_type: OrderType::Limit as i32,
+                amount: "1".to_string(),
+                price: price.to_string(),
+                ..Default::default()
+            }),
+            trigger_price: "90".to_string(),
+            direction: TriggerDirection::Below as i32,
+        };
+        let status = controller.trigger_order_put(true, trigger_order("100.3")).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert!(controller.markets["ETH_USDT"].trigger_orders.is_empty());
+        // the replay takes the journaled ones as they are
+        controller.trigger_order_put(false, trigger_order("100.3")).unwrap();
+        controller.trigger_order_put(true, trigger_order("100.5")).unwrap();
+        assert_eq!(controller.markets["ETH_USDT"].trigger_orders.len(), 2);
+    }
+}
@@ -1,10 +1,18 @@
+use crate::asset;
 use crate::market;
+use crate::message;
+use crate::models;
+use crate::types::{MarketRole, OrderEventType, Trade};
+use crate::utils::FTimestamp;
 use rust_decimal::Decimal;
 
 pub mod matchengine {
     tonic::include_proto!("matchengine");
 }
 
+// the descriptors of the protos, for the reflection service
+pub const FILE_DESCRIPTOR_SET: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/matchengine_descriptor.bin"));
+
 pub use matchengine::matchengine_server::*;
 pub use matchengine::*;
 use rust_decimal::prelude::Zero;
@@ -35,6 +43,58 @@ pub fn order_to_proto(o: &market::Order) -> OrderInfo {
         finished_base: o.finished_base.to_string(),
         finished_quote: o.finished_quote.to_string(),
         finished_fee: o.finished_fee.to_string(),
+        finished_paid_fee: o.finished_paid_fee.to_string(),
+        display_qty: o.display_qty.to_string(),
+        expire_at: o.expire_at.unwrap_or(0.0),
+        status: order_status(o) as i32,
+    }
+}
+
+// the status of an order still on the book, or just filled
+pub fn order_status(o: &market::Order) -> OrderStatus {
+    if o.remain.is_zero() {
+        OrderStatus::Filled
+    } else if o.finished_base.is_zero() {
+        OrderStatus::Open
+    } else {
+        OrderStatus::PartiallyFilled
+    }
+}
+
+// only finished orders are in the history, the ones not fully filled are cancelled
+pub fn order_history_to_proto(o: &models::OrderHistory) -> OrderInfo {
+    OrderInfo {
+        id: o.id as u64,
+        market: o.market.clone(),
+        order_type: if o.order_type == market::OrderType::LIMIT {
+            OrderType::Limit as i32
+        } else {
+            OrderType::Market as i32
+        },
+        order_side: if o.order_side == market::OrderSide::ASK {
+            OrderSide::Ask as i32
+        } else {
+            OrderSide::Bid as i32
+        },
+        user_id: o.user_id as u32,
+        create_time: FTimestamp::from(&o.create_time).0,
+        update_time: FTimestamp::from(&o.finish_time).0,
+        price: o.price.to_string(),
+        amount: o.amount.to_string(),
+        taker_fee: o.taker_fee.to_string(),
+        maker_fee: o.maker_fee.to_string(),
+        remain: (o.amount - o.finished_base).max(Decimal::zero()).to_string(),
+        finished_base: o.finished_base.to_string(),
+        finished_quote: o.finished_quote.to_string(),
+        finished_fee: o.finished_fee.to_string(),
+        finished_paid_fee: o.finished_paid_fee.to_string(),
+        display_qty: String::new(),
+        expire_at: 0.0,
+        status: if o.finished_base >= o.amount {
+            OrderStatus::Filled as i32
+        } else {
+            OrderStatus::Cancelled as i32
+        },
     }
 }
 
@@ -51,7 +111,11 @@ pub fn order_input_from_proto(req: &OrderPutRequest) -> Result<market::OrderInpu
         } else {
             market::OrderType::MARKET
         },
-        amount: Decimal::from_str(req.amount.as_str())?,
+        amount: if req.amount.is_empty() {
+            Decimal::zero()
+        } else {
+            Decimal::from_str(req.amount.as_str())?
+        },
         price: Decimal::from_str(req.price.as_str())?,
         taker_fee: if req.taker_fee.is_empty() {
             Decimal::zero()
@@ -64,5 +128,189 @@ pub fn order_input_from_proto(req: &OrderPutRequest) -> Result<market::OrderInpu
             Decimal::from_str(req.maker_fee.as_str())?
         },
         market: req.market.clone(),
+        time_in_force: if req.time_in_force == TimeInForce::Ioc as i32 {
+            market::TimeInForce::IOC
+        } else if req.time_in_force == TimeInForce::Fok as i32 {
+            market::TimeInForce::FOK
+        } else if req.time_in_force == TimeInForce::Gtt as i32 || req.expire_at > 0.0 {
+            // GTC with expire_at was accepted before GTT, keep it for the operation logs
+            market::TimeInForce::GTT
+        } else {
+            market::TimeInForce::GTC
+        },
+        post_only: req.post_only,
+        self_trade_prevention: if req.self_trade_prevention == SelfTradePrevention::CancelNewest as i32 {
+            market::SelfTradePrevention::CancelNewest
+        } else if req.self_trade_prevention == SelfTradePrevention::CancelOldest as i32 {
+            market::SelfTradePrevention::CancelOldest
+        } else if req.self_trade_prevention == SelfTradePrevention::CancelBoth as i32 {
+            market::SelfTradePrevention::CancelBoth
+        } else {
+            market::SelfTradePrevention::Allow
+        },
+        quote_amount: if req.quote_amount.is_empty() {
+            Decimal::zero()
+        } else {
+            Decimal::from_str(req.quote_amount.as_str())?
+        },
+        display_qty: if req.display_qty.is_empty() {
+            Decimal::zero()
+        } else {
+            Decimal::from_str(req.display_qty.as_str())?
+        },
+        expire_at: if req.expire_at > 0.0 { Some(req.expire_at) } else { None },
+        timestamp: None,
+        protection_price: Decimal::zero(),
     })
 }
+
+pub fn batch_mode_from_proto(mode: i32) -> market::BatchMode {
+    if mode == OrderBatchMode::AllOrNothing as i32 {
+        market::BatchMode::AllOrNothing
+    } else {
+        market::BatchMode::BestEffort
+    }
+}
+
+pub fn order_batch_put_result(result: Result<OrderInfo, tonic::Status>) -> OrderBatchPutResult {
+    match result {
+        Ok(order) => OrderBatchPutResult {
+            order: Some(order),
+            error: String::new(),
+        },
+        Err(status) => OrderBatchPutResult {
+            order: None,
+            error: status.message().to_string(),
+        },
+    }
+}
+
+pub fn market_state_to_proto(state: market::MarketState) -> MarketState {
+    match state {
+        market::MarketState::Active => MarketState::MarketActive,
+        market::MarketState::PostOnly => MarketState::MarketPostOnly,
+        market::MarketState::CancelOnly => MarketState::MarketCancelOnly,
+        market::MarketState::Delisted => MarketState::MarketDelisted,
+    }
+}
+
+pub fn market_state_from_proto(state: i32) -> Option<market::MarketState> {
+    match MarketState::from_i32(state)? {
+        MarketState::MarketActive => Some(market::MarketState::Active),
+        MarketState::MarketPostOnly => Some(market::MarketState::PostOnly),
+        MarketState::MarketCancelOnly => Some(market::MarketState::CancelOnly),
+        MarketState::MarketDelisted => Some(market::MarketState::Delisted),
+    }
+}
+
+pub fn integrity_violation_to_proto(violation: &market::IntegrityViolation) -> IntegrityViolation {
+    let mut result = IntegrityViolation {
+        description: violation.to_string(),
+        ..Default::default()
+    };
+    match violation {
+        market::IntegrityViolation::CrossedBook { market, .. } => result.market = market.clone(),
+        market::IntegrityViolation::FrozenMismatch { user_id, asset, .. } => {
+            result.user_id = *user_id;
+            result.asset = asset.clone();
+        }
+    }
+    result
+}
+
+pub fn trigger_order_to_proto(market: &str, o: &market::TriggerOrder) -> TriggerOrderInfo {
+    TriggerOrderInfo {
+        id: o.id,
+        market: String::from(market),
+        user_id: o.user,
+        create_time: o.create_time,
+        trigger_price: o.trigger_price.to_string(),
+        direction: if o.direction == market::TriggerDirection::ABOVE {
+            TriggerDirection::Above as i32
+        } else {
+            TriggerDirection::Below as i32
+        },
+        order_side: if o.order_input.side == market::OrderSide::ASK {
+            OrderSide::Ask as i32
+        } else {
+            OrderSide::Bid as i32
+        },
+        order_type: if o.order_input.type_ == market::OrderType::LIMIT {
+            OrderType::Limit as i32
+        } else {
+            OrderType::Market as i32
+        },
+        price: o.order_input.price.to_string(),
+        amount: o.order_input.amount.to_string(),
+    }
+}
+
+pub fn book_update_to_proto(u: &market::BookUpdate) -> OrderBookUpdate {
+    let convert = |levels: &Vec<market::PriceLevelUpdate>| {
+        levels
+            .iter()
+            .map(|level| order_book_update::PriceLevel {
+                update_type: match level.update_type {
+                    market::BookUpdateType::ADD => BookUpdateType::Add as i32,
+                    market::BookUpdateType::MODIFY => BookUpdateType::Modify as i32,
+                    market::BookUpdateType::REMOVE => BookUpdateType::Remove as i32,
+                },
+                price: level.price.to_string(),
+                amount: level.amount.to_string(),
+            })
+            .collect::<Vec<_>>()
+    };
+    OrderBookUpdate {
+        market: u.market.clone(),
+        seq: u.seq,
+        prev_seq: u.prev_seq,
+        snapshot: u.snapshot,
+        asks: convert(&u.asks),
+        bids: convert(&u.bids),
+    }
+}
+
+// a finished order not fully filled is cancelled
+pub fn order_message_to_proto(m: &message::OrderMessage) -> OrderUpdate {
+    let (event, status) = match m.event {
+        OrderEventType::PUT => (OrderEvent::OrderPlaced, order_status(&m.order)),
+        OrderEventType::UPDATE => (OrderEvent::OrderUpdated, order_status(&m.order)),
+        OrderEventType::FINISH if m.order.remain.is_zero() => (OrderEvent::OrderFinished, OrderStatus::Filled),
+        OrderEventType::FINISH => (OrderEvent::OrderFinished, OrderStatus::Cancelled),
+    };
+    OrderUpdate {
+        event: event as i32,
+        order: Some(OrderInfo {
+            status: status as i32,
+            ..order_to_proto(&m.order)
+        }),
+    }
+}
+
+pub fn balance_change_to_proto(c: &asset::BalanceChange) -> BalanceUpdate {
+    BalanceUpdate {
+        user_id: c.user_id,
+        asset: c.asset.clone(),
+        available: c.available.to_string(),
+        frozen: c.frozen.to_string(),
+    }
+}
+
+pub fn trade_to_proto(t: &Trade) -> TradeInfo {
+    let taker_is_ask = t.ask_role == MarketRole::TAKER;
+    TradeInfo {
+        id: t.id,
+        market: t.market.clone(),
+        timestamp: t.timestamp,
+        price: t.price.to_string(),
+        amount: t.amount.to_string(),
+        quote_amount: t.quote_amount.to_string(),
+        taker_side: if taker_is_ask {
+            OrderSide::Ask as i32
+        } else {
+            OrderSide::Bid as i32
+        },
+        taker_order_id: if taker_is_ask { t.ask_order_id } else { t.bid_order_id },
+        maker_order_id: if taker_is_ask { t.bid_order_id } else { t.ask_order_id },
+    }
+}
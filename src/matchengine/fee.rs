@@ -0,0 +1,240 @@
+use crate::config;
+use anyhow::{anyhow, Result};
+use rust_decimal::prelude::Zero;
+use rust_decimal::Decimal;
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+use ttl_cache::TtlCache;
+
+const SECONDS_PER_DAY: f64 = 86400.0;
+
+fn day_of(timestamp: f64) -> i64 {
+    (timestamp / SECONDS_PER_DAY).floor() as i64
+}
+
+// Any taker can meet any maker, so the lowest taker fee rate must cover the highest maker rebate,
+// both rates are of the same traded amount.
+// `max_maker_rebate` is none when the markets are not known yet.
+fn check_net_fee(tiers: &[config::FeeTier], max_maker_rebate: Option<&Decimal>) -> Result<()> {
+    // users below the lowest tier use the fee rates of their orders, bounded by `max_maker_rebate`
+    let order_rate = if tiers.iter().any(|tier| tier.min_volume.is_zero()) {
+        None
+    } else {
+        max_maker_rebate.copied()
+    };
+    let lowest_taker_fee = tiers.iter().map(|tier| tier.taker_fee).chain(order_rate).min();
+    let highest_rebate = tiers.iter().map(|tier| -tier.maker_fee).chain(order_rate).max();
+    match (lowest_taker_fee, highest_rebate) {
+        (Some(taker_fee), Some(rebate)) if taker_fee < rebate => {
+            Err(anyhow!("taker fee {} does not cover maker rebate {}", taker_fee, rebate))
+        }
+        _ => Ok(()),
+    }
+}
+
+// Volumes are measured in quote amount and summed over all the markets,
+// so the tier table should be configured in the unit of the main quote asset.
+pub struct FeeTierManager {
+    // sorted by min_volume, ascending
+    tiers: Vec<config::FeeTier>,
+    volume_window: Duration,
+    // user_id -> day -> traded quote amount of that day
+    daily_volumes: HashMap<u32, BTreeMap<i64, Decimal>>,
+    // user_id -> (first day of the window, volume), only valid for the same window, so the result
+    // doesn't depend on when the cache expires
+    volume_cache: TtlCache<u32, (i64, Decimal)>,
+    cache_ttl: Duration,
+}
+
+impl FeeTierManager {
+    pub fn new(config: &config::FeeTierConfig) -> Result<FeeTierManager> {
+        if config.cache_capacity == 0 {
+            return Err(anyhow!("invalid fee tier cache capacity"));
+        }
+        let mut tiers = config.tiers.clone();
+        for tier in &tiers {
+            if tier.min_volume.is_sign_negative() || tier.taker_fee.is_sign_negative() {
+                return Err(anyhow!("invalid fee tier {:?}", tier));
+            }
+        }
+        check_net_fee(&tiers, None)?;
+        tiers.sort_by(|a, b| a.min_volume.cmp(&b.min_volume));
+        if tiers.windows(2).any(|pair| pair[0].min_volume == pair[1].min_volume) {
+            return Err(anyhow!("duplicated fee tier min_volume"));
+        }
+        Ok(FeeTierManager {
+            tiers,
+            volume_window: config.volume_window,
+            daily_volumes: HashMap::new(),
+            volume_cache: TtlCache::new(config.cache_capacity),
+            cache_ttl: config.cache_ttl,
+        })
+    }
+    // `max_maker_rebate` bounds the fee rates of the orders, which apply below the tiers
+    pub fn check_maker_rebate(&self, max_maker_rebate: &Decimal) -> Result<()> {
+        if max_maker_rebate.is_sign_negative() {
+            return Err(anyhow!("invalid max maker rebate {}", max_maker_rebate));
+        }
+        check_net_fee(&self.tiers, Some(max_maker_rebate))
+    }
+    pub fn reset(&mut self) {
+        self.daily_volumes.clear();
+        self.volume_cache.clear();
+    }
+    pub fn is_enabled(&self) -> bool {
+        !self.tiers.is_empty()
+    }
+    pub fn add_daily_volume(&mut self, user_id: u32, day: i64, volume: &Decimal) {
+        *self
+            .daily_volumes
+            .entry(user_id)
+            .or_insert_with(BTreeMap::new)
+            .entry(day)
+            .or_insert_with(Decimal::zero) += volume;
+        self.volume_cache.remove(&user_id);
+    }
+    pub fn record_trade(&mut self, user_id: u32, timestamp: f64, quote_amount: &Decimal) {
+        self.add_daily_volume(user_id, day_of(timestamp), quote_amount);
+    }
+    // rolling volume of the user inside the window ending at `now`
+    pub fn volume(&mut self, user_id: u32, now: f64) -> Decimal {
+        let first_day = day_of(now - self.volume_window.as_secs_f64());
+        if let Some((cached_first_day, volume)) = self.volume_cache.get(&user_id) {
+            if *cached_first_day == first_day {
+                return *volume;
+            }
+        }
+        let volume = match self.daily_volumes.get_mut(&user_id) {
+            Some(days) => {
+                // drop the days which have slid out of the window
+                *days = days.split_off(&first_day);
+                days.values().fold(Decimal::zero(), |sum, volume| sum + volume)
+            }
+            None => Decimal::zero(),
+        };
+        self.volume_cache.insert(user_id, (first_day, volume), self.cache_ttl);
+        volume
+    }
+    // return the tier level (starting from 1) and the tier of the user,
+    // None if the user does not reach any tier
+    pub fn tier(&mut self, user_id: u32, now: f64) -> Option<(usize, config::FeeTier)> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let volume = self.volume(user_id, now);
+        self.tiers
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, tier)| volume >= tier.min_volume)
+            .map(|(idx, tier)| (idx + 1, tier.clone()))
+    }
+    // the fee rates of the order are used when the user does not reach any tier
+    pub fn maker_fee(&mut self, user_id: u32, now: f64, order_fee: &Decimal) -> Decimal {
+        self.tier(user_id, now).map(|(_, tier)| tier.maker_fee).unwrap_or(*order_fee)
+    }
+    pub fn taker_fee(&mut self, user_id: u32, now: f64, order_fee: &Decimal) -> Decimal {
+        self.tier(user_id, now).map(|(_, tier)| tier.taker_fee).unwrap_or(*order_fee)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::*;
+
+    fn get_fee_tier_manager() -> FeeTierManager {
+        FeeTierManager::new(&config::FeeTierConfig {
+            tiers: vec![
+                config::FeeTier {
+                    min_volume: dec!(1000),
+                    maker_fee: dec!(0.0008),
+                    taker_fee: dec!(0.001),
+                },
+                config::FeeTier {
+                    min_volume: dec!(0),
+                    maker_fee: dec!(0.001),
+                    taker_fee: dec!(0.002),
+                },
+            ],
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_net_fee_validation() {
+        let tier = |min_volume, maker_fee, taker_fee| config::FeeTier {
+            min_volume,
+            maker_fee,
+            taker_fee,
+        };
+        let new_manager = |tiers| {
+            FeeTierManager::new(&config::FeeTierConfig {
+                tiers,
+                ..Default::default()
+            })
+        };
+        // the rebate of the top tier is covered by the taker fee of every tier
+        let fee_tiers = new_manager(vec![
+            tier(dec!(0), dec!(0.001), dec!(0.002)),
+            tier(dec!(1000), dec!(-0.0002), dec!(0.0005)),
+        ])
+        .unwrap();
+        // the fee rates of the orders are never used
+        assert!(fee_tiers.check_maker_rebate(&dec!(0.01)).is_ok());
+        assert!(fee_tiers.check_maker_rebate(&dec!(-0.01)).is_err());
+        assert!(new_manager(vec![
+            tier(dec!(0), dec!(0.001), dec!(0.0001)),
+            tier(dec!(1000), dec!(-0.0002), dec!(0.0005))
+        ])
+        .is_err());
+
+        // below 1000, takers may pay the fee rate of their orders, down to the max maker rebate
+        let fee_tiers = new_manager(vec![tier(dec!(1000), dec!(-0.0002), dec!(0.0005))]).unwrap();
+        assert!(fee_tiers.check_maker_rebate(&dec!(0.0002)).is_ok());
+        assert!(fee_tiers.check_maker_rebate(&dec!(0.0001)).is_err());
+        assert!(fee_tiers.check_maker_rebate(&dec!(0.0006)).is_err());
+        assert!(new_manager(Vec::new()).unwrap().check_maker_rebate(&dec!(0.0006)).is_ok());
+    }
+
+    #[test]
+    fn test_fee_tier() {
+        let mut fee_tiers = get_fee_tier_manager();
+        let now = 100.0 * SECONDS_PER_DAY;
+        assert_eq!(fee_tiers.tier(101, now).unwrap().0, 1);
+        assert_eq!(fee_tiers.taker_fee(101, now, &dec!(0.005)), dec!(0.002));
+
+        fee_tiers.record_trade(101, now, &dec!(600));
+        fee_tiers.record_trade(101, now - 10.0 * SECONDS_PER_DAY, &dec!(500));
+        assert_eq!(fee_tiers.volume(101, now), dec!(1100));
+        assert_eq!(fee_tiers.tier(101, now).unwrap().0, 2);
+        assert_eq!(fee_tiers.maker_fee(101, now, &dec!(0.005)), dec!(0.0008));
+
+        // the older trade slides out of the 30-day window, the cached volume is of the earlier window
+        let later = now + 25.0 * SECONDS_PER_DAY;
+        assert_eq!(fee_tiers.volume(101, later), dec!(600));
+        assert_eq!(fee_tiers.tier(101, later).unwrap().0, 1);
+    }
+
+    #[test]
+    fn test_fee_tier_disabled() {
+        let mut fee_tiers = FeeTierManager::new(&Default::default()).unwrap();
+        assert!(fee_tiers.tier(101, 0.0).is_none());
+        assert_eq!(fee_tiers.maker_fee(101, 0.0, &dec!(0.003)), dec!(0.003));
+    }
+
+    #[test]
+    fn test_duplicated_fee_tier() {
+        let tier = config::FeeTier {
+            min_volume: dec!(0),
+            maker_fee: dec!(0.001),
+            taker_fee: dec!(0.002),
+        };
+        assert!(FeeTierManager::new(&config::FeeTierConfig {
+            tiers: vec![tier.clone(), tier],
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
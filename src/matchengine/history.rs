@@ -1,20 +1,38 @@
+use crate::clickhouse::ClickHouseHistoryWriter;
+use crate::config::{self, HistoryBackend, HistoryOverflow};
 use crate::database::{DatabaseWriter, DatabaseWriterConfig};
+use crate::kline::Candle;
 use crate::market;
-use crate::models;
+use crate::metrics::Metrics;
+use crate::models::{self, tablenames};
 use crate::types::Trade;
 
+use crate::types::SimpleResult;
 use crate::utils::FTimestamp;
 use anyhow::Result;
+use futures::future::LocalBoxFuture;
+use serde::Serialize;
+use std::cell::RefCell;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::rc::Rc;
 
 type BalanceWriter = DatabaseWriter<models::BalanceHistory>;
 type OrderWriter = DatabaseWriter<models::OrderHistory>;
 type TradeWriter = DatabaseWriter<models::TradeHistory>;
+type KlineWriter = DatabaseWriter<models::Kline>;
 
 pub trait HistoryWriter {
     fn is_block(&self) -> bool;
     fn append_balance_history(&mut self, data: models::BalanceHistory);
     fn append_order_history(&mut self, order: &market::Order);
     fn append_trade_history(&mut self, trade: &Trade);
+    fn append_kline(&mut self, market: &str, interval: u64, candle: &Candle);
+    // on shutdown, wait for all the history to be written
+    fn close(&mut self) -> LocalBoxFuture<'_, SimpleResult> {
+        Box::pin(async { Ok(()) })
+    }
 }
 
 pub struct DummyHistoryWriter;
@@ -22,56 +40,253 @@ impl HistoryWriter for DummyHistoryWriter {
     fn append_balance_history(&mut self, _data: models::BalanceHistory) {}
     fn append_order_history(&mut self, _order: &market::Order) {}
     fn append_trade_history(&mut self, _trade: &Trade) {}
+    fn append_kline(&mut self, _market: &str, _interval: u64, _candle: &Candle) {}
     fn is_block(&self) -> bool {
         false
     }
 }
 
+// one json line of the spill file, `row` can be inserted into `table` as it is later
+#[derive(Serialize)]
+struct SpilledRow<'a, T: Serialize> {
+    table: &'a str,
+    row: &'a T,
+}
+
+pub struct SpillSink {
+    path: PathBuf,
+    // opened on the first spilled row
+    file: Option<File>,
+}
+
+impl SpillSink {
+    pub fn new(path: &str) -> SpillSink {
+        SpillSink {
+            path: PathBuf::from(path),
+            file: None,
+        }
+    }
+    pub fn append<T: Serialize>(&mut self, table: &str, row: &T) -> Result<()> {
+        if self.file.is_none() {
+            self.file = Some(OpenOptions::new().create(true).append(true).open(&self.path)?);
+        }
+        let mut line = serde_json::to_string(&SpilledRow { table, row })?;
+        line.push('\n');
+        self.file.as_mut().unwrap().write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+// A row beyond the queue bound, returns whether it is spilled. Without spilling the log is the
+// last place the row can be found.
+pub fn overflow_row<T: Serialize>(overflow: HistoryOverflow, spill: &mut SpillSink, table: &str, row: &T) -> bool {
+    if overflow == HistoryOverflow::Spill {
+        match spill.append(table, row) {
+            Ok(()) => return true,
+            Err(e) => tracing::error!("fail to spill history row of {}: {}", table, e),
+        }
+    }
+    tracing::error!(
+        "history queue of {} full, row lost: {}",
+        table,
+        serde_json::to_string(row).unwrap_or_default()
+    );
+    false
+}
+
+// The queue of each table is bounded by `queue_limit`. With `HistoryOverflow::Block` the engine
+// refuses commands once a queue is nearly full, so only the rows of the commands taken just before
+// can overflow it, and they are lost with an error log. With `HistoryOverflow::Spill` the rows
+// beyond the bound go to the spill file instead.
 pub struct DatabaseHistoryWriter {
     pub balance_writer: BalanceWriter,
     pub trade_writer: TradeWriter,
     pub order_writer: OrderWriter,
+    pub kline_writer: KlineWriter,
+    overflow: HistoryOverflow,
+    spill: SpillSink,
+    metrics: Metrics,
 }
 
 impl DatabaseHistoryWriter {
-    pub fn new(config: &DatabaseWriterConfig, pool: &sqlx::Pool<crate::types::DbType>) -> Result<DatabaseHistoryWriter> {
+    pub fn new(
+        config: &config::HistoryWriterConfig,
+        pool: &sqlx::Pool<crate::types::DbType>,
+        metrics: Metrics,
+    ) -> Result<DatabaseHistoryWriter> {
+        let writer_config = DatabaseWriterConfig {
+            spawn_limit: 4,
+            apply_benchmark: true,
+            capability_limit: config.queue_limit,
+            batch_size: config.batch_size,
+            flush_interval: config.flush_interval,
+        };
         Ok(DatabaseHistoryWriter {
-            balance_writer: BalanceWriter::new(config).start_schedule(pool)?,
-            trade_writer: TradeWriter::new(config).start_schedule(pool)?,
-            order_writer: OrderWriter::new(config).start_schedule(pool)?,
+            balance_writer: BalanceWriter::new(&writer_config).start_schedule(pool)?,
+            trade_writer: TradeWriter::new(&writer_config).start_schedule(pool)?,
+            order_writer: OrderWriter::new(&writer_config).start_schedule(pool)?,
+            kline_writer: KlineWriter::new(&writer_config).start_schedule(pool)?,
+            overflow: config.overflow,
+            spill: SpillSink::new(&config.spill_path),
+            metrics,
         })
     }
+    pub fn queue_depth(&self) -> usize {
+        self.balance_writer.queue_depth()
+            + self.trade_writer.queue_depth()
+            + self.order_writer.queue_depth()
+            + self.kline_writer.queue_depth()
+    }
+    fn update_queue_depth(&self) {
+        self.metrics.history_queue_depth.set(self.queue_depth() as i64);
+    }
+    fn overflow<T: Serialize>(&mut self, table: &str, row: T) {
+        if overflow_row(self.overflow, &mut self.spill, table, &row) {
+            self.metrics.history_rows_spilled.inc();
+        }
+    }
 }
 
 impl HistoryWriter for DatabaseHistoryWriter {
     fn is_block(&self) -> bool {
-        self.balance_writer.is_block() || self.trade_writer.is_block() || self.order_writer.is_block()
+        self.update_queue_depth();
+        if self.overflow == HistoryOverflow::Spill {
+            return false;
+        }
+        self.balance_writer.is_block() || self.trade_writer.is_block() || self.order_writer.is_block() || self.kline_writer.is_block()
     }
     fn append_balance_history(&mut self, data: models::BalanceHistory) {
-        self.balance_writer.append(data).ok();
+        if let Err(data) = self.balance_writer.append(data) {
+            self.overflow(tablenames::BALANCEHISTORY, data);
+        }
+        self.update_queue_depth();
     }
     fn append_order_history(&mut self, order: &market::Order) {
-        let data = models::OrderHistory {
-            id: order.id as i64,
-            create_time: FTimestamp(order.create_time).into(),
-            finish_time: FTimestamp(order.update_time).into(),
-            user_id: order.user as i32,
-            market: order.market.to_string(),
-            order_type: order.type_,
-            order_side: order.side,
-            price: order.price,
-            amount: order.amount,
-            taker_fee: order.taker_fee,
-            maker_fee: order.maker_fee,
-            finished_base: order.finished_base,
-            finished_quote: order.finished_quote,
-            finished_fee: order.finished_fee,
-        };
-        self.order_writer.append(data).ok();
+        if let Err(data) = self.order_writer.append(order_history_row(order)) {
+            self.overflow(tablenames::ORDERHISTORY, data);
+        }
+        self.update_queue_depth();
+    }
+
+    fn append_trade_history(&mut self, trade: &Trade) {
+        for data in trade_history_rows(trade).iter().cloned() {
+            if let Err(data) = self.trade_writer.append(data) {
+                self.overflow(tablenames::TRADEHISTORY, data);
+            }
+        }
+        self.update_queue_depth();
+    }
+
+    fn append_kline(&mut self, market: &str, interval: u64, candle: &Candle) {
+        if let Err(data) = self.kline_writer.append(kline_row(market, interval, candle)) {
+            self.overflow(tablenames::KLINE, data);
+        }
+        self.update_queue_depth();
     }
+    fn close(&mut self) -> LocalBoxFuture<'_, SimpleResult> {
+        Box::pin(async move {
+            self.balance_writer.close().await?;
+            self.trade_writer.close().await?;
+            self.order_writer.close().await?;
+            self.kline_writer.close().await
+        })
+    }
+}
 
+// Fans the history out to two backends. Each of them buffers on its own, so a stalled or failing
+// one doesn't hold or lose the rows of the other. It blocks when either does, with
+// `HistoryOverflow::Spill` neither of them does.
+pub struct TeeHistoryWriter<A: HistoryWriter, B: HistoryWriter> {
+    pub first: A,
+    pub second: B,
+}
+
+impl<A: HistoryWriter, B: HistoryWriter> HistoryWriter for TeeHistoryWriter<A, B> {
+    fn is_block(&self) -> bool {
+        // both are asked, to keep their metrics up to date
+        let first = self.first.is_block();
+        let second = self.second.is_block();
+        first || second
+    }
+    fn append_balance_history(&mut self, data: models::BalanceHistory) {
+        self.first.append_balance_history(data.clone());
+        self.second.append_balance_history(data);
+    }
+    fn append_order_history(&mut self, order: &market::Order) {
+        self.first.append_order_history(order);
+        self.second.append_order_history(order);
+    }
     fn append_trade_history(&mut self, trade: &Trade) {
-        let ask_trade = models::TradeHistory {
+        self.first.append_trade_history(trade);
+        self.second.append_trade_history(trade);
+    }
+    fn append_kline(&mut self, market: &str, interval: u64, candle: &Candle) {
+        self.first.append_kline(market, interval, candle);
+        self.second.append_kline(market, interval, candle);
+    }
+    // the second one is closed even if the first one fails
+    fn close(&mut self) -> LocalBoxFuture<'_, SimpleResult> {
+        Box::pin(async move {
+            let first = self.first.close().await;
+            let second = self.second.close().await;
+            first.and(second)
+        })
+    }
+}
+
+pub fn new_history_writer(
+    config: &config::HistoryWriterConfig,
+    pool: &sqlx::Pool<crate::types::DbType>,
+    metrics: Metrics,
+) -> Result<Rc<RefCell<dyn HistoryWriter>>> {
+    Ok(match config.backend {
+        HistoryBackend::Postgres => Rc::new(RefCell::new(DatabaseHistoryWriter::new(config, pool, metrics)?)),
+        HistoryBackend::ClickHouse => Rc::new(RefCell::new(ClickHouseHistoryWriter::new(config))),
+        HistoryBackend::Both => Rc::new(RefCell::new(TeeHistoryWriter {
+            first: DatabaseHistoryWriter::new(config, pool, metrics)?,
+            second: ClickHouseHistoryWriter::new(config),
+        })),
+    })
+}
+
+pub fn order_history_row(order: &market::Order) -> models::OrderHistory {
+    models::OrderHistory {
+        id: order.id as i64,
+        create_time: FTimestamp(order.create_time).into(),
+        finish_time: FTimestamp(order.update_time).into(),
+        user_id: order.user as i32,
+        market: order.market.to_string(),
+        order_type: order.type_,
+        order_side: order.side,
+        price: order.price,
+        amount: order.amount,
+        taker_fee: order.taker_fee,
+        maker_fee: order.maker_fee,
+        finished_base: order.finished_base,
+        finished_quote: order.finished_quote,
+        finished_fee: order.finished_fee,
+        finished_paid_fee: order.finished_paid_fee,
+    }
+}
+
+pub fn kline_row(market: &str, interval: u64, candle: &Candle) -> models::Kline {
+    models::Kline {
+        time: FTimestamp(candle.start as f64).into(),
+        market: market.to_string(),
+        interval: interval as i64,
+        open: candle.open,
+        high: candle.high,
+        low: candle.low,
+        close: candle.close,
+        volume: candle.volume,
+        quote_volume: candle.quote_volume,
+    }
+}
+
+// the trade from the view of each side, ask first
+pub fn trade_history_rows(trade: &Trade) -> [models::TradeHistory; 2] {
+    [
+        models::TradeHistory {
             time: FTimestamp(trade.timestamp).into(),
             user_id: trade.ask_user_id as i32,
             market: trade.market.clone(),
@@ -85,8 +300,8 @@ impl HistoryWriter for DatabaseHistoryWriter {
             quote_amount: trade.quote_amount,
             fee: trade.ask_fee,
             counter_order_fee: trade.bid_fee, // counter order
-        };
-        let bid_trade = models::TradeHistory {
+        },
+        models::TradeHistory {
             time: FTimestamp(trade.timestamp).into(),
             user_id: trade.bid_user_id as i32,
             market: trade.market.clone(),
@@ -100,8 +315,78 @@ impl HistoryWriter for DatabaseHistoryWriter {
             quote_amount: trade.quote_amount,
             fee: trade.bid_fee,
             counter_order_fee: trade.ask_fee, // counter order
+        },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::BusinessKind;
+    use rust_decimal::Decimal;
+    use std::time::Duration;
+
+    #[derive(Default)]
+    struct HistoryRecorder {
+        balance_history: Vec<models::BalanceHistory>,
+    }
+    impl HistoryWriter for HistoryRecorder {
+        fn append_balance_history(&mut self, data: models::BalanceHistory) {
+            self.balance_history.push(data);
+        }
+        fn append_order_history(&mut self, _order: &market::Order) {}
+        fn append_trade_history(&mut self, _trade: &Trade) {}
+        fn append_kline(&mut self, _market: &str, _interval: u64, _candle: &Candle) {}
+        fn is_block(&self) -> bool {
+            false
+        }
+    }
+
+    fn balance_history(user_id: i32) -> models::BalanceHistory {
+        models::BalanceHistory {
+            time: FTimestamp(1_615_379_696.0).into(),
+            user_id,
+            asset: "USDT".to_string(),
+            business: BusinessKind::Deposit,
+            change: Decimal::new(100, 0),
+            balance: Decimal::new(100, 0),
+            detail: "{}".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tee_isolates_failing_backend() {
+        let spill_path = std::env::temp_dir().join(format!("clickhouse_spill_{}.log", std::process::id()));
+        std::fs::remove_file(&spill_path).ok();
+        let config = config::HistoryWriterConfig {
+            queue_limit: 10,
+            flush_interval: Duration::from_secs(3600),
+            clickhouse: config::ClickHouseConfig {
+                // nothing listens there
+                url: "http://127.0.0.1:1".to_string(),
+                spill_path: spill_path.to_str().unwrap().to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut tee = TeeHistoryWriter {
+            first: HistoryRecorder::default(),
+            second: ClickHouseHistoryWriter::new(&config),
         };
-        self.trade_writer.append(ask_trade).ok();
-        self.trade_writer.append(bid_trade).ok();
+        for user_id in 0..20 {
+            tee.append_balance_history(balance_history(user_id));
+        }
+        // the failing backend holds its bound and blocks, the other one gets every row
+        assert_eq!(tee.first.balance_history.len(), 20);
+        assert_eq!(tee.second.queue_depth(), 10);
+        assert!(!tee.first.is_block());
+        assert!(tee.is_block());
+
+        // the rows it could not insert are spilled on shutdown
+        tee.close().await.unwrap();
+        let spilled = std::fs::read_to_string(&spill_path).unwrap();
+        assert_eq!(spilled.lines().count(), 10);
+        assert!(spilled.lines().all(|line| line.starts_with("{\"table\":\"balance_history\"")));
+        std::fs::remove_file(&spill_path).ok();
     }
 }
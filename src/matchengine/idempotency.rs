@@ -0,0 +1,125 @@
+use crate::config;
+use crate::dto::OrderInfo;
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+use tonic::Status;
+
+// longer keys are rejected, so a client can't fill the cache with large ones
+pub const MAX_KEY_LEN: usize = 64;
+
+#[derive(PartialEq, Eq, Hash, Clone)]
+struct OrderPutKey {
+    user_id: u32,
+    key: String,
+}
+
+// The results of the orders placed with an idempotency key, so a retried request gets the order
+// placed by the first one instead of placing another. Failed requests are not recorded, they can
+// be retried with the same key. The keys are replayed with the operation log, which restores them
+// after a restart. They expire by the journaled time of the orders rather than the wall clock, so
+// the replay takes the same keys for retries as the live engine did, however fast it runs.
+pub struct OrderPutCache {
+    orders: HashMap<OrderPutKey, (f64, OrderInfo)>,
+    // the keys by the time they were recorded, oldest first
+    keys: VecDeque<(f64, OrderPutKey)>,
+    capacity: usize,
+    entry_ttl: Duration,
+}
+
+impl OrderPutCache {
+    pub fn new(config: &config::OrderIdempotencyConfig) -> OrderPutCache {
+        OrderPutCache {
+            orders: HashMap::new(),
+            keys: VecDeque::new(),
+            capacity: config.capacity,
+            entry_ttl: config.entry_ttl,
+        }
+    }
+    pub fn reset(&mut self) {
+        self.orders.clear();
+        self.keys.clear();
+    }
+    fn is_enabled(&self, key: &str) -> bool {
+        !key.is_empty() && self.capacity != 0
+    }
+    // the keys recorded at or before this are expired at `now`
+    fn expired_before(&self, now: f64) -> f64 {
+        now - self.entry_ttl.as_secs_f64()
+    }
+    // drops the oldest key, unless it was recorded again since
+    fn pop_oldest(&mut self) {
+        if let Some((timestamp, key)) = self.keys.pop_front() {
+            if self.orders.get(&key).map_or(false, |(recorded, _)| *recorded == timestamp) {
+                self.orders.remove(&key);
+            }
+        }
+    }
+    // the order placed by an earlier request with the key, none to place it now
+    pub fn check(&mut self, user_id: u32, key: &str, now: f64) -> Result<Option<OrderInfo>, Status> {
+        if !self.is_enabled(key) {
+            return Ok(None);
+        }
+        if key.len() > MAX_KEY_LEN {
+            return Err(Status::invalid_argument("idempotency key too long"));
+        }
+        let expired_before = self.expired_before(now);
+        while self.keys.front().map_or(false, |(timestamp, _)| *timestamp <= expired_before) {
+            self.pop_oldest();
+        }
+        let cache_key = OrderPutKey {
+            user_id,
+            key: key.to_string(),
+        };
+        Ok(self
+            .orders
+            .get(&cache_key)
+            .filter(|(recorded, _)| *recorded > expired_before)
+            .map(|(_, order)| order.clone()))
+    }
+    // `now` is the time the order is journaled at
+    pub fn record(&mut self, user_id: u32, key: &str, order: &OrderInfo, now: f64) {
+        if self.is_enabled(key) {
+            while self.orders.len() >= self.capacity {
+                self.pop_oldest();
+            }
+            let cache_key = OrderPutKey {
+                user_id,
+                key: key.to_string(),
+            };
+            self.orders.insert(cache_key.clone(), (now, order.clone()));
+            self.keys.push_back((now, cache_key));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_expiry() {
+        let mut cache = OrderPutCache::new(&config::OrderIdempotencyConfig {
+            capacity: 2,
+            entry_ttl: Duration::from_secs(60),
+        });
+        let order = |id| OrderInfo { id, ..Default::default() };
+        let t0 = 1_600_000_000.0;
+        cache.record(101, "order-1", &order(1), t0);
+        assert_eq!(cache.check(101, "order-1", t0 + 59.0).unwrap(), Some(order(1)));
+        // the keys are scoped by user
+        assert_eq!(cache.check(102, "order-1", t0 + 59.0).unwrap(), None);
+        assert_eq!(cache.check(101, "order-1", t0 + 60.0).unwrap(), None);
+        // recorded again once expired
+        cache.record(101, "order-1", &order(2), t0 + 60.0);
+        assert_eq!(cache.check(101, "order-1", t0 + 61.0).unwrap(), Some(order(2)));
+        // the oldest key goes beyond the capacity
+        cache.record(101, "order-2", &order(3), t0 + 62.0);
+        cache.record(101, "order-3", &order(4), t0 + 63.0);
+        assert_eq!(cache.check(101, "order-1", t0 + 64.0).unwrap(), None);
+        assert_eq!(cache.check(101, "order-2", t0 + 64.0).unwrap(), Some(order(3)));
+        // no key, no dedup
+        cache.record(101, "", &order(5), t0 + 64.0);
+        assert_eq!(cache.check(101, "", t0 + 64.0).unwrap(), None);
+        assert!(cache.check(101, &"k".repeat(MAX_KEY_LEN + 1), t0 + 64.0).is_err());
+    }
+}
@@ -0,0 +1,237 @@
+use crate::config;
+use anyhow::{anyhow, Result};
+use rust_decimal::prelude::Zero;
+use rust_decimal::Decimal;
+use std::collections::{BTreeMap, VecDeque};
+
+// `start` is a unix timestamp aligned to the interval, so every bucket starts at a UTC boundary
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Candle {
+    pub start: i64,
+    pub open: Decimal,
+    pub high: Decimal,
+    pub low: Decimal,
+    pub close: Decimal,
+    // base amount
+    pub volume: Decimal,
+    pub quote_volume: Decimal,
+}
+
+impl Candle {
+    fn new(start: i64, price: Decimal) -> Candle {
+        Candle {
+            start,
+            open: price,
+            high: price,
+            low: price,
+            close: price,
+            volume: Decimal::zero(),
+            quote_volume: Decimal::zero(),
+        }
+    }
+}
+
+pub fn bucket_start(timestamp: f64, interval: u64) -> i64 {
+    let interval = interval as i64;
+    (timestamp.floor() as i64).div_euclid(interval) * interval
+}
+
+#[derive(Default)]
+struct IntervalCandles {
+    // oldest first
+    completed: VecDeque<Candle>,
+    // the bucket of the last trade, it is completed by a trade in a later bucket
+    current: Option<Candle>,
+}
+
+// Candles of one market for every configured interval, updated on every real trade, since the
+// replayed ones would be stamped with the replay time. Only the recent `max_candles` of each interval are kept in memory, the completed ones are persisted.
+pub struct KlineAggregator {
+    max_candles: usize,
+    // interval in seconds -> candles
+    intervals: BTreeMap<u64, IntervalCandles>,
+}
+
+impl KlineAggregator {
+    pub fn new(config: &config::KlineConfig) -> KlineAggregator {
+        KlineAggregator {
+            max_candles: config.max_candles,
+            intervals: config
+                .intervals
+                .iter()
+                .filter(|interval| **interval > 0)
+                .map(|interval| (*interval, IntervalCandles::default()))
+                .collect(),
+        }
+    }
+    pub fn intervals(&self) -> impl Iterator<Item = u64> + '_ {
+        self.intervals.keys().copied()
+    }
+    pub fn reset(&mut self) {
+        for candles in self.intervals.values_mut() {
+            *candles = IntervalCandles::default();
+        }
+    }
+    // returns the candles completed by this trade, by interval
+    pub fn record(&mut self, timestamp: f64, price: Decimal, amount: Decimal, quote_amount: Decimal) -> Vec<(u64, Candle)> {
+        let mut completed = Vec::new();
+        let max_candles = self.max_candles;
+        for (interval, candles) in self.intervals.iter_mut() {
+            let start = bucket_start(timestamp, *interval);
+            // a trade behind the current bucket, by clock adjustment, is counted in the current one
+            if candles.current.map_or(true, |candle| candle.start < start) {
+                if let Some(candle) = candles.current.take() {
+                    completed.push((*interval, candle));
+                    candles.completed.push_back(candle);
+                    if candles.completed.len() > max_candles {
+                        candles.completed.pop_front();
+                    }
+                }
+                candles.current = Some(Candle::new(start, price));
+            }
+            let candle = candles.current.as_mut().unwrap();
+            candle.high = candle.high.max(price);
+            candle.low = candle.low.min(price);
+            candle.close = price;
+            candle.volume += amount;
+            candle.quote_volume += quote_amount;
+        }
+        completed
+    }
+    // restore the persisted candles on startup, oldest first
+    pub fn load(&mut self, interval: u64, history: Vec<Candle>) {
+        if let Some(candles) = self.intervals.get_mut(&interval) {
+            candles.completed = history.into_iter().collect();
+            while candles.completed.len() > self.max_candles {
+                candles.completed.pop_front();
+            }
+        }
+    }
+    // The candles starting in [from, to), the latest `limit` of them. A bucket without trades
+    // carries forward the last close with zero volume, nothing is returned before the first trade.
+    pub fn query(&self, interval: u64, from: i64, to: i64, limit: usize, now: f64) -> Result<Vec<Candle>> {
+        let candles = self
+            .intervals
+            .get(&interval)
+            .ok_or_else(|| anyhow!("invalid interval {}", interval))?;
+        let step = interval as i64;
+        // no bucket after the current one
+        let to = to.min(bucket_start(now, interval) + step);
+        let mut result = Vec::new();
+        let mut last_close: Option<Decimal> = None;
+        let mut next_start = bucket_start(from as f64, interval);
+        if next_start < from {
+            next_start += step;
+        }
+        for candle in candles.completed.iter().chain(candles.current.iter()) {
+            if candle.start >= to {
+                break;
+            }
+            if candle.start >= next_start {
+                if let Some(close) = last_close {
+                    while next_start < candle.start {
+                        result.push(Candle::new(next_start, close));
+                        next_start += step;
+                    }
+                }
+                result.push(*candle);
+                next_start = candle.start + step;
+            }
+            last_close = Some(candle.close);
+        }
+        if let Some(close) = last_close {
+            while next_start < to {
+                result.push(Candle::new(next_start, close));
+                next_start += step;
+            }
+        }
+        if result.len() > limit {
+            result.drain(..result.len() - limit);
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::*;
+
+    fn get_aggregator() -> KlineAggregator {
+        KlineAggregator::new(&config::KlineConfig {
+            intervals: vec![60, 300, 3600, 86400],
+            max_candles: 100,
+        })
+    }
+
+    #[test]
+    fn test_bucket_alignment() {
+        // 2021-03-10 12:34:56 UTC
+        let timestamp = 1_615_379_696.5;
+        assert_eq!(bucket_start(timestamp, 60), 1_615_379_640);
+        assert_eq!(bucket_start(timestamp, 300), 1_615_379_400);
+        assert_eq!(bucket_start(timestamp, 3600), 1_615_377_600);
+        // 2021-03-10 00:00:00 UTC
+        assert_eq!(bucket_start(timestamp, 86400), 1_615_334_400);
+        // the boundary itself starts the next bucket
+        assert_eq!(bucket_start(1_615_379_700.0, 300), 1_615_379_700);
+        assert_eq!(bucket_start(1_615_379_699.999, 300), 1_615_379_400);
+    }
+
+    #[test]
+    fn test_record_across_boundary() {
+        let mut klines = get_aggregator();
+        let minute = 1_615_379_580.0;
+        assert!(klines.record(minute + 1.0, dec!(10), dec!(1), dec!(10)).is_empty());
+        assert!(klines.record(minute + 30.0, dec!(12), dec!(2), dec!(24)).is_empty());
+        assert!(klines.record(minute + 59.0, dec!(9), dec!(1), dec!(9)).is_empty());
+        // the next minute completes only the 1m candle
+        let completed = klines.record(minute + 60.0, dec!(11), dec!(1), dec!(11));
+        assert_eq!(completed.len(), 1);
+        let (interval, candle) = completed[0];
+        assert_eq!(interval, 60);
+        assert_eq!(candle.start, 1_615_379_580);
+        assert_eq!(
+            (candle.open, candle.high, candle.low, candle.close),
+            (dec!(10), dec!(12), dec!(9), dec!(9))
+        );
+        assert_eq!((candle.volume, candle.quote_volume), (dec!(4), dec!(43)));
+
+        let candles = klines.query(300, 0, i64::MAX, 10, minute + 60.0).unwrap();
+        assert_eq!(candles.len(), 1);
+        assert_eq!(candles[0].start, 1_615_379_400);
+        assert_eq!(
+            (candles[0].open, candles[0].close, candles[0].volume),
+            (dec!(10), dec!(11), dec!(5))
+        );
+        assert!(klines.query(120, 0, i64::MAX, 10, minute).is_err());
+    }
+
+    #[test]
+    fn test_query_fills_gaps() {
+        let mut klines = get_aggregator();
+        let minute = 1_615_379_640;
+        klines.record(minute as f64, dec!(10), dec!(1), dec!(10));
+        klines.record((minute + 180) as f64, dec!(11), dec!(1), dec!(11));
+
+        let now = (minute + 330) as f64;
+        let candles = klines.query(60, minute - 600, minute + 600, 100, now).unwrap();
+        let closes: Vec<(i64, Decimal, Decimal)> = candles.iter().map(|c| (c.start - minute, c.close, c.volume)).collect();
+        assert_eq!(
+            closes,
+            vec![
+                (0, dec!(10), dec!(1)),
+                (60, dec!(10), dec!(0)),
+                (120, dec!(10), dec!(0)),
+                (180, dec!(11), dec!(1)),
+                (240, dec!(11), dec!(0)),
+                (300, dec!(11), dec!(0)),
+            ]
+        );
+        // the latest ones within the limit, `from` is rounded up to a boundary
+        let candles = klines.query(60, minute + 30, minute + 240, 2, now).unwrap();
+        assert_eq!(candles.iter().map(|c| c.start - minute).collect::<Vec<_>>(), vec![120, 180]);
+        let candles = klines.query(60, minute + 30, minute + 240, 100, now).unwrap();
+        assert_eq!(candles.iter().map(|c| c.start - minute).collect::<Vec<_>>(), vec![60, 120, 180]);
+    }
+}
@@ -1,43 +1,50 @@
-use crate::asset::{BalanceManager, BalanceType};
+use crate::asset::{BalanceManager, BalanceTransaction, BalanceType, BalanceUpdateError, FreezePurpose};
+use crate::fee::FeeTierManager;
 use crate::history::HistoryWriter;
-use crate::message::{MessageManager, OrderMessage};
+use crate::kline::KlineAggregator;
+use crate::message::{MessageManager, OrderFillMessage, OrderMessage};
+use crate::metrics::Metrics;
+use crate::models;
 use crate::sequencer::Sequencer;
-use crate::types::{self, MarketRole, OrderEventType, Trade};
-use crate::utils;
+use crate::subscription::SubscriptionHub;
+use crate::surveillance::{NoopSurveillance, SurveillanceHook, SurveillanceVerdict};
+use crate::types::{self, BusinessKind, MarketRole, OrderEventType, OrderFillStatus, Trade};
+use crate::utils::{Clock, FTimestamp, MonotonicTimestamps, SystemClock};
 use crate::{config, message};
 
 use std::cell::RefCell;
-use std::cmp::{min, Ordering};
-use std::collections::BTreeMap;
+use std::cmp::{max, min, Ordering};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
 use std::iter::Iterator;
 use std::rc::Rc;
 
 use anyhow::{anyhow, Result};
 use itertools::Itertools;
-use rust_decimal::prelude::Zero;
-use rust_decimal::Decimal;
+use rust_decimal::prelude::{One, Zero};
+use rust_decimal::{Decimal, RoundingStrategy};
 use serde::{Deserialize, Serialize};
 
-pub use types::{OrderSide, OrderType};
+pub use types::{BookUpdateType, OrderSide, OrderType, SelfTradePrevention, TimeInForce, TriggerDirection};
 
+// `priority` orders the entries of the same price, later entries have a greater one
 #[derive(PartialEq, PartialOrd, Eq, Ord)]
 pub struct MarketKeyAsk {
     pub order_price: Decimal,
-    pub order_id: u64,
+    pub priority: u64,
 }
 pub type MarketKey = MarketKeyAsk;
 
 #[derive(PartialEq, Eq)]
 pub struct MarketKeyBid {
     pub order_price: Decimal,
-    pub order_id: u64,
+    pub priority: u64,
 }
 
 impl Ord for MarketKeyBid {
     fn cmp(&self, other: &Self) -> Ordering {
         let price_order = self.order_price.cmp(&other.order_price);
         if price_order == Ordering::Equal {
-            self.order_id.cmp(&other.order_id).reverse()
+            self.priority.cmp(&other.priority).reverse()
         } else {
             price_order.reverse()
         }
@@ -68,30 +75,246 @@ pub struct Order {
     pub frozen: Decimal,
     pub finished_base: Decimal,
     pub finished_quote: Decimal,
+    // the fees charged in the received asset, and the ones converted to the paid asset
     pub finished_fee: Decimal,
+    pub finished_paid_fee: Decimal,
+    // iceberg orders only show `display_qty` on the book, zero for normal orders
+    pub display_qty: Decimal,
+    // the remaining amount of the displayed slice
+    pub visible: Decimal,
+    pub priority: u64,
+    // wall-clock time when a resting order is canceled
+    pub expire_at: Option<f64>,
 }
 
 impl Order {
     pub fn get_ask_key(&self) -> MarketKeyAsk {
         MarketKeyAsk {
             order_price: self.price,
-            order_id: self.id,
+            priority: self.priority,
         }
     }
     pub fn get_bid_key(&self) -> MarketKeyBid {
         MarketKeyBid {
             order_price: self.price,
-            order_id: self.id,
+            priority: self.priority,
+        }
+    }
+    pub fn is_iceberg(&self) -> bool {
+        !self.display_qty.is_zero()
+    }
+    // the amount shown on the book
+    pub fn visible_amount(&self) -> Decimal {
+        if self.is_iceberg() {
+            self.visible
+        } else {
+            self.remain
         }
     }
 }
 
 pub type OrderRc = Rc<RefCell<Order>>;
 
+// a conditional order kept off the book until the last trade price crosses `trigger_price`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TriggerOrder {
+    pub id: u64,
+    pub user: u32,
+    pub create_time: f64,
+    pub trigger_price: Decimal,
+    pub direction: TriggerDirection,
+    pub order_input: OrderInput,
+}
+
+impl TriggerOrder {
+    pub fn is_triggered(&self, last_price: &Decimal) -> bool {
+        match self.direction {
+            TriggerDirection::ABOVE => last_price.ge(&self.trigger_price),
+            TriggerDirection::BELOW => last_price.le(&self.trigger_price),
+        }
+    }
+}
+
 pub fn is_order_ask(order: &Order) -> bool {
     order.side == OrderSide::ASK
 }
 
+// whether a taker of `side` may trade at `price`, `price_limit` is its worst acceptable price
+fn within_price_limit(side: OrderSide, price_limit: &Option<Decimal>, price: &Decimal) -> bool {
+    match price_limit {
+        None => true,
+        Some(limit) if side == OrderSide::ASK => price >= limit,
+        Some(limit) => price <= limit,
+    }
+}
+
+// the base amount a market bid can buy at `price` with `quote_left`, rounded down
+fn market_bid_base_amount(quote_left: &Decimal, price: &Decimal, base_prec: u32) -> Decimal {
+    (quote_left / price).round_dp_with_strategy(base_prec, RoundingStrategy::RoundDown)
+}
+
+// Split `total` among the makers of a price level in proportion to their `sizes`, rounded down to `prec`.
+// The lots left by rounding go one at a time to the maker with the least fill so far, the earlier
+// maker on ties, so the allocations always sum up to `total`.
+fn pro_rata_allocation(total: Decimal, sizes: &[Decimal], prec: u32) -> Vec<Decimal> {
+    let level_amount: Decimal = sizes.iter().copied().sum();
+    if total >= level_amount {
+        return sizes.to_vec();
+    }
+    let mut allocation: Vec<Decimal> = sizes
+        .iter()
+        .map(|size| (total * size / level_amount).round_dp_with_strategy(prec, RoundingStrategy::RoundDown))
+        .collect();
+    let lot = Decimal::new(1, prec);
+    let mut left = total - allocation.iter().copied().sum::<Decimal>();
+    while left >= lot {
+        let idx = (0..sizes.len())
+            .filter(|&idx| allocation[idx] + lot <= sizes[idx])
+            .min_by_key(|&idx| (allocation[idx], idx))
+            .unwrap();
+        allocation[idx] += lot;
+        left -= lot;
+    }
+    allocation
+}
+
+// The trade prices of the recent window, the oldest one is the reference of the price move.
+// Only real trades are recorded, the halts and resumes are replayed from the operation log.
+struct CircuitBreaker {
+    config: config::CircuitBreakerConfig,
+    prices: VecDeque<(f64, Decimal)>,
+    // when the cooldown ends, the market is halted until it is resumed
+    halted_until: Option<f64>,
+}
+
+impl CircuitBreaker {
+    fn is_enabled(&self) -> bool {
+        !self.config.max_move.is_zero()
+    }
+    fn record(&mut self, timestamp: f64, price: Decimal) {
+        if !self.is_enabled() || self.halted_until.is_some() {
+            return;
+        }
+        let window_start = timestamp - self.config.window.as_secs_f64();
+        while self.prices.front().map_or(false, |(t, _)| *t < window_start) {
+            self.prices.pop_front();
+        }
+        self.prices.push_back((timestamp, price));
+    }
+    fn is_tripped(&self) -> bool {
+        match (self.prices.front(), self.prices.back()) {
+            (Some((_, reference)), Some((_, last))) if !reference.is_zero() => {
+                ((last - reference) / reference).abs() > self.config.max_move
+            }
+            _ => false,
+        }
+    }
+}
+
+const TICKER_WINDOW: f64 = 24.0 * 3600.0;
+
+// The trades of the last 24 hours, updated on every trade and expired from the front.
+// Like the circuit breaker only real trades are recorded, the window starts empty after a restart.
+#[derive(Default)]
+struct Ticker {
+    // timestamp, price, base amount, quote amount
+    trades: VecDeque<(f64, Decimal, Decimal, Decimal)>,
+    base_volume: Decimal,
+    quote_volume: Decimal,
+    // decreasing and increasing prices, the front is the high or the low of the window
+    highs: VecDeque<(f64, Decimal)>,
+    lows: VecDeque<(f64, Decimal)>,
+}
+
+impl Ticker {
+    fn record(&mut self, timestamp: f64, price: Decimal, base_amount: Decimal, quote_amount: Decimal) {
+        self.expire(timestamp);
+        self.trades.push_back((timestamp, price, base_amount, quote_amount));
+        self.base_volume += base_amount;
+        self.quote_volume += quote_amount;
+        while self.highs.back().map_or(false, |(_, high)| *high <= price) {
+            self.highs.pop_back();
+        }
+        self.highs.push_back((timestamp, price));
+        while self.lows.back().map_or(false, |(_, low)| *low >= price) {
+            self.lows.pop_back();
+        }
+        self.lows.push_back((timestamp, price));
+    }
+    fn expire(&mut self, now: f64) {
+        let window_start = now - TICKER_WINDOW;
+        while self.trades.front().map_or(false, |(t, ..)| *t < window_start) {
+            let (_, _, base_amount, quote_amount) = self.trades.pop_front().unwrap();
+            self.base_volume -= base_amount;
+            self.quote_volume -= quote_amount;
+        }
+        while self.highs.front().map_or(false, |(t, _)| *t < window_start) {
+            self.highs.pop_front();
+        }
+        while self.lows.front().map_or(false, |(t, _)| *t < window_start) {
+            self.lows.pop_front();
+        }
+    }
+    fn summary(&mut self, now: f64, last_price: Decimal) -> TickerSummary {
+        self.expire(now);
+        let open = self.trades.front().map_or(Decimal::zero(), |(_, price, ..)| *price);
+        let price_change_percent = if open.is_zero() {
+            Decimal::zero()
+        } else {
+            ((last_price - open) / open * Decimal::from(100)).round_dp(2)
+        };
+        TickerSummary {
+            last_price,
+            open,
+            high: self.highs.front().map_or(Decimal::zero(), |(_, price)| *price),
+            low: self.lows.front().map_or(Decimal::zero(), |(_, price)| *price),
+            base_volume: self.base_volume,
+            quote_volume: self.quote_volume,
+            price_change_percent,
+        }
+    }
+}
+
+// The trading state of a market, set by an operator to wind the market down before retiring it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MarketState {
+    Active,
+    // only the limit orders which rest on the book without matching are accepted
+    PostOnly,
+    // no new orders, the open ones can be canceled or reduced
+    CancelOnly,
+    // no new orders, the open ones have been canceled
+    Delisted,
+}
+
+impl MarketState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MarketState::Active => "active",
+            MarketState::PostOnly => "post_only",
+            MarketState::CancelOnly => "cancel_only",
+            MarketState::Delisted => "delisted",
+        }
+    }
+    pub fn accepts_orders(&self) -> bool {
+        matches!(self, MarketState::Active | MarketState::PostOnly)
+    }
+}
+
+impl std::str::FromStr for MarketState {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "active" => Ok(MarketState::Active),
+            "post_only" => Ok(MarketState::PostOnly),
+            "cancel_only" => Ok(MarketState::CancelOnly),
+            "delisted" => Ok(MarketState::Delisted),
+            _ => Err(anyhow!("invalid market state {}", s)),
+        }
+    }
+}
+
 pub struct Market {
     pub name: &'static str,
     pub base: String,
@@ -99,7 +322,15 @@ pub struct Market {
     pub base_prec: u32,
     pub quote_prec: u32,
     pub fee_prec: u32,
+    pub fee_rounding: Option<config::RoundingStrategy>,
+    pub fee_side: config::FeeSide,
     pub min_amount: Decimal,
+    pub min_notional: Decimal,
+    pub matching_mode: config::MatchingMode,
+    pub price_band: Decimal,
+    pub max_maker_rebate: Decimal,
+    pub tick_size: Decimal,
+    pub lot_size: Decimal,
 
     pub orders: BTreeMap<u64, OrderRc>,
     pub users: BTreeMap<u32, BTreeMap<u64, OrderRc>>,
@@ -108,42 +339,164 @@ pub struct Market {
     pub bids: BTreeMap<MarketKeyBid, OrderRc>,
 
     pub trade_count: u64,
+    pub last_price: Decimal,
+    pub trigger_orders: BTreeMap<u64, TriggerOrder>,
+    state: MarketState,
 
     pub sequencer: Rc<RefCell<Sequencer>>,
+    book_feed: BookFeed,
+    trade_subscribers: SubscriptionHub<Trade>,
     balance_manager: BalanceManagerWrapper,
+    fee_tiers: Rc<RefCell<FeeTierManager>>,
     pub history_writer: Rc<RefCell<dyn HistoryWriter>>,
     message_manager: MessageManagerWrapper,
+    metrics: Metrics,
+    circuit_breaker: CircuitBreaker,
+    ticker: Ticker,
+    pub klines: KlineAggregator,
+    surveillance: Box<dyn SurveillanceHook>,
+    clock: Rc<dyn Clock>,
+    // the times of the orders and the trades, in the order they are placed and made
+    timestamps: MonotonicTimestamps,
+    // when the current command is journaled, the fee tiers are looked up and the volumes recorded
+    // at it, so a replay charges the same fees whenever it runs
+    command_time: f64,
+    // the greatest priority on the book, kept apart from the order ids so requeued orders leave no gaps in them
+    last_priority: u64,
 }
 
 const MAP_INIT_CAPACITY: usize = 1024;
 
+// Publish the changed price levels to the order book subscribers.
+// Levels are tracked only while there are subscribers.
+#[derive(Default)]
+struct BookFeed {
+    subscribers: SubscriptionHub<BookUpdate>,
+    dirty_asks: BTreeSet<Decimal>,
+    dirty_bids: BTreeSet<Decimal>,
+    // the levels known by the subscribers, price -> amount
+    asks: BTreeMap<Decimal, Decimal>,
+    bids: BTreeMap<Decimal, Decimal>,
+    last_seq: u64,
+}
+
+impl BookFeed {
+    fn mark(&mut self, side: OrderSide, price: Decimal) {
+        if self.subscribers.is_empty() {
+            return;
+        }
+        if side == OrderSide::ASK {
+            self.dirty_asks.insert(price);
+        } else {
+            self.dirty_bids.insert(price);
+        }
+    }
+    fn reset(&mut self) {
+        // the subscribers will find their streams closed and subscribe again
+        *self = BookFeed::default();
+    }
+}
+
+fn snapshot_levels<'a>(levels: impl Iterator<Item = (&'a Decimal, &'a Decimal)>) -> Vec<PriceLevelUpdate> {
+    levels
+        .map(|(price, amount)| PriceLevelUpdate {
+            update_type: BookUpdateType::ADD,
+            price: *price,
+            amount: *amount,
+        })
+        .collect()
+}
+
+// update the published level and return the change of it
+fn diff_price_level(levels: &mut BTreeMap<Decimal, Decimal>, price: Decimal, amount: Decimal) -> Option<PriceLevelUpdate> {
+    let update_type = match (levels.get(&price), amount.is_zero()) {
+        (None, true) => return None,
+        (Some(old), false) if *old == amount => return None,
+        (None, false) => BookUpdateType::ADD,
+        (Some(_), false) => BookUpdateType::MODIFY,
+        (Some(_), true) => BookUpdateType::REMOVE,
+    };
+    if amount.is_zero() {
+        levels.remove(&price);
+    } else {
+        levels.insert(price, amount);
+    }
+    Some(PriceLevelUpdate {
+        update_type,
+        price,
+        amount,
+    })
+}
+
 struct MessageManagerWrapper {
     inner: Rc<RefCell<dyn MessageManager>>,
+    // the order events of each user, for the streaming subscribers
+    order_subscribers: HashMap<u32, SubscriptionHub<OrderMessage>>,
 }
 impl MessageManagerWrapper {
-    pub fn push_order_message(&self, message: &OrderMessage) {
-        self.inner.borrow_mut().push_order_message(message)
+    pub fn push_order_message(&mut self, message: &OrderMessage) {
+        self.inner.borrow_mut().push_order_message(message);
+        if let Some(subscribers) = self.order_subscribers.get_mut(&message.order.user) {
+            subscribers.publish(message);
+            if subscribers.is_empty() {
+                self.order_subscribers.remove(&message.order.user);
+            }
+        }
     }
     pub fn push_trade_message(&self, message: &Trade) {
         self.inner.borrow_mut().push_trade_message(message)
     }
+    pub fn push_order_fill_message(&self, message: &OrderFillMessage) {
+        self.inner.borrow_mut().push_order_fill_message(message)
+    }
 }
 
 struct BalanceManagerWrapper {
     inner: Rc<RefCell<BalanceManager>>,
 }
 
+// A failed change leaves the balances unchanged, the order is rejected or its remainder canceled.
 impl BalanceManagerWrapper {
-    pub fn balance_add(&self, user_id: u32, balance_type: BalanceType, asset: &str, amount: &Decimal) {
-        self.inner.borrow_mut().add(user_id, balance_type, asset, amount);
+    pub fn can_add(&self, user_id: u32, balance_type: BalanceType, asset: &str, amount: &Decimal) -> bool {
+        self.inner.borrow().can_add(user_id, balance_type, asset, amount)
     }
     pub fn balance_get(&self, user_id: u32, balance_type: BalanceType, asset: &str) -> Decimal {
         self.inner.borrow_mut().get(user_id, balance_type, asset)
     }
-    pub fn balance_sub(&self, user_id: u32, balance_type: BalanceType, asset: &str, amount: &Decimal) {
-        self.inner.borrow_mut().sub(user_id, balance_type, asset, amount);
+    // returns the balance after each change
+    pub fn settle(&self, transaction: &BalanceTransaction) -> std::result::Result<Vec<Decimal>, BalanceUpdateError> {
+        self.inner.borrow_mut().commit(transaction)
+    }
+    // The asset and the amount a fee is charged in. A fee is in the received asset, it is converted
+    // to the paid asset when that is `fee_asset` and the AVAILABLE balance left after `reserved`
+    // covers it.
+    pub fn fee_charge(
+        &self,
+        user_id: u32,
+        fee: &Decimal,
+        received: &str,
+        paid: &str,
+        paid_per_received: &Decimal,
+        reserved: &Decimal,
+        fee_asset: Option<&str>,
+        rounding: Option<config::RoundingStrategy>,
+    ) -> (String, Decimal) {
+        let inner = self.inner.borrow();
+        if fee_asset == Some(paid) {
+            let converted = self.round_fee(paid, &(fee * paid_per_received), rounding);
+            if !converted.is_zero() && inner.get(user_id, BalanceType::AVAILABLE, paid) >= reserved + converted {
+                return (paid.to_string(), converted);
+            }
+        }
+        (received.to_string(), *fee)
+    }
+    // to the precision the asset is saved at, `rounding` overrides the rounding of the asset
+    pub fn round_fee(&self, asset: &str, fee: &Decimal, rounding: Option<config::RoundingStrategy>) -> Decimal {
+        let inner = self.inner.borrow();
+        let rounding = rounding.unwrap_or_else(|| inner.asset_manager.asset_rounding(asset));
+        fee.round_dp_with_strategy(inner.asset_manager.asset_prec(asset), rounding.into())
     }
-    pub fn balance_frozen(&self, user_id: u32, asset: &str, amount: &Decimal) {
+    pub fn balance_frozen(&self, user_id: u32, asset: &str, amount: &Decimal) -> Result<()> {
         self.inner.borrow_mut().frozen(user_id, asset, amount)
     }
     pub fn balance_unfrozen(&self, user_id: u32, asset: &str, amount: &Decimal) {
@@ -152,6 +505,12 @@ impl BalanceManagerWrapper {
     pub fn asset_prec(&self, asset: &str) -> u32 {
         self.inner.borrow_mut().asset_manager.asset_prec(asset)
     }
+    pub fn fee_account(&self) -> Option<u32> {
+        self.inner.borrow().fee_account
+    }
+    pub fn fee_asset(&self) -> Option<String> {
+        self.inner.borrow().fee_asset.clone()
+    }
 }
 // TODO: is it ok to match with oneself's order?
 // TODO: precision
@@ -160,8 +519,10 @@ impl Market {
         market_conf: &config::Market,
         balance_manager: Rc<RefCell<BalanceManager>>,
         sequencer: Rc<RefCell<Sequencer>>,
+        fee_tiers: Rc<RefCell<FeeTierManager>>,
         history_writer: Rc<RefCell<dyn HistoryWriter>>,
         message_manager: Rc<RefCell<dyn MessageManager>>,
+        metrics: Metrics,
     ) -> Result<Market> {
         let asset_exist = |asset: &str| -> bool { balance_manager.borrow_mut().asset_manager.asset_exist(asset) };
         let asset_prec = |asset: &str| -> u32 { balance_manager.borrow_mut().asset_manager.asset_prec(asset) };
@@ -176,6 +537,15 @@ impl Market {
             return Err(anyhow!("invalid precision"));
         }
 
+        fee_tiers.borrow().check_maker_rebate(&market_conf.max_maker_rebate)?;
+        // an increment finer than the precision could never be met after rounding
+        if market_conf.tick_size.is_sign_negative() || market_conf.tick_size.round_dp(market_conf.quote.prec) != market_conf.tick_size {
+            return Err(anyhow!("invalid tick size {}", market_conf.tick_size));
+        }
+        if market_conf.lot_size.is_sign_negative() || market_conf.lot_size.round_dp(market_conf.base.prec) != market_conf.lot_size {
+            return Err(anyhow!("invalid lot size {}", market_conf.lot_size));
+        }
+
         let market = Market {
             name: Box::leak(market_conf.name.clone().into_boxed_str()),
             base: market_conf.base.name.clone(),
@@ -183,30 +553,82 @@ impl Market {
             base_prec: market_conf.base.prec,
             quote_prec: market_conf.quote.prec,
             fee_prec: market_conf.fee_prec,
+            fee_rounding: market_conf.fee_rounding,
+            fee_side: market_conf.fee_side,
             min_amount: market_conf.min_amount,
+            min_notional: market_conf.min_notional,
+            matching_mode: market_conf.matching_mode,
+            price_band: market_conf.price_band,
+            max_maker_rebate: market_conf.max_maker_rebate,
+            tick_size: market_conf.tick_size,
+            lot_size: market_conf.lot_size,
             sequencer,
+            book_feed: BookFeed::default(),
+            trade_subscribers: SubscriptionHub::default(),
             orders: BTreeMap::new(),
             users: BTreeMap::new(),
             asks: BTreeMap::new(),
             bids: BTreeMap::new(),
             trade_count: 0,
+            last_price: Decimal::zero(),
+            trigger_orders: BTreeMap::new(),
+            state: MarketState::Active,
             balance_manager: BalanceManagerWrapper { inner: balance_manager },
+            fee_tiers,
             history_writer,
-            message_manager: MessageManagerWrapper { inner: message_manager },
+            message_manager: MessageManagerWrapper {
+                inner: message_manager,
+                order_subscribers: HashMap::new(),
+            },
+            metrics,
+            circuit_breaker: CircuitBreaker {
+                config: market_conf.circuit_breaker.clone(),
+                prices: VecDeque::new(),
+                halted_until: None,
+            },
+            ticker: Ticker::default(),
+            klines: KlineAggregator::new(&market_conf.kline),
+            surveillance: Box::new(NoopSurveillance),
+            clock: Rc::new(SystemClock),
+            timestamps: MonotonicTimestamps::default(),
+            command_time: 0.0,
+            last_priority: 0,
         };
         Ok(market)
     }
+    // A fee is rounded here once, and this exact value is debited from the user, credited to the fee
+    // account and recorded with the trade and the order, so they always add up. A rebate likewise.
+    fn round_fee(&self, asset: &str, fee: &Decimal) -> Decimal {
+        self.balance_manager.round_fee(asset, fee, self.fee_rounding)
+    }
+    pub fn set_surveillance_hook(&mut self, hook: Box<dyn SurveillanceHook>) {
+        self.surveillance = hook;
+    }
+    pub fn set_clock(&mut self, clock: Rc<dyn Clock>) {
+        self.clock = clock;
+    }
     pub fn reset(&mut self) {
-        log::debug!("market {} reset", self.name);
+        tracing::debug!("market {} reset", self.name);
         self.bids.clear();
         self.asks.clear();
         self.users.clear();
         self.orders.clear();
+        self.trigger_orders.clear();
+        self.last_priority = 0;
+        self.state = MarketState::Active;
+        self.last_price = Decimal::zero();
+        self.circuit_breaker.prices.clear();
+        self.circuit_breaker.halted_until = None;
+        self.ticker = Ticker::default();
+        self.klines.reset();
+        self.book_feed.reset();
+        self.trade_subscribers.clear();
+        self.message_manager.order_subscribers.clear();
     }
-    pub fn frozen_balance(&self, order: &Order) {
+    pub fn frozen_balance(&self, order: &Order) -> Result<()> {
         let asset = if is_order_ask(order) { &self.base } else { &self.quote };
 
-        self.balance_manager.balance_frozen(order.user, asset, &order.frozen);
+        self.balance_manager.balance_frozen(order.user, asset, &order.frozen)
     }
     pub fn unfrozen_balance(&self, order: &Order) {
         debug_assert!(order.remain.is_sign_positive());
@@ -216,8 +638,31 @@ impl Market {
         let asset = if is_order_ask(&order) { &self.base } else { &self.quote };
         self.balance_manager.balance_unfrozen(order.user, asset, &order.frozen);
     }
+    // Ids from the sequencer are strictly increasing. A lower one means the sequencer is restored
+    // or replayed wrongly, and it would collide with the existing orders, so stop here.
+    fn next_order_id(&self) -> u64 {
+        let id = self.sequencer.borrow_mut().next_order_id();
+        let max_order_id = self.orders.keys().next_back().copied().unwrap_or(0);
+        let max_trigger_order_id = self.trigger_orders.keys().next_back().copied().unwrap_or(0);
+        if id <= max_order_id || id <= max_trigger_order_id {
+            panic!(
+                "order id {} is not greater than the existing order id {} in market {}, the sequencer is broken",
+                id,
+                max(max_order_id, max_trigger_order_id),
+                self.name
+            );
+        }
+        id
+    }
+    // queue behind every entry on the book
+    fn next_priority(&mut self) -> u64 {
+        self.last_priority += 1;
+        self.last_priority
+    }
     pub fn insert_order(&mut self, order_rc: OrderRc) -> Order {
         let mut order = order_rc.borrow_mut();
+        // restored orders bring their priorities
+        self.last_priority = max(self.last_priority, order.priority);
         if order.side == OrderSide::ASK {
             order.frozen = order.remain;
         } else {
@@ -230,6 +675,7 @@ impl Market {
         let user_map = self.users.entry(order.user).or_insert_with(BTreeMap::new);
         debug_assert!(!user_map.contains_key(&order.id));
         user_map.insert(order.id, order_rc.clone());
+        self.book_feed.mark(order.side, order.price);
         if order.side == OrderSide::ASK {
             let key = order.get_ask_key();
             debug_assert!(!self.asks.contains_key(&key));
@@ -243,6 +689,7 @@ impl Market {
     }
 
     fn order_finish(&mut self, real: bool, order: &Order) {
+        self.book_feed.mark(order.side, order.price);
         if order.side == OrderSide::ASK {
             let key = &order.get_ask_key();
             debug_assert!(self.asks.contains_key(key));
@@ -275,12 +722,86 @@ impl Market {
         }
     }
 
-    // the last parameter `quote_limit`, is only used for market bid order,
+    // show the next slice of an iceberg order from its hidden reserve,
+    // the slice loses time priority by queuing behind all the existing orders
+    fn refresh_iceberg_order(&mut self, order_id: u64) {
+        let order_rc = self.orders.get(&order_id).unwrap().clone();
+        let mut order = order_rc.borrow_mut();
+        if order.side == OrderSide::ASK {
+            self.asks.remove(&order.get_ask_key());
+        } else {
+            self.bids.remove(&order.get_bid_key());
+        }
+        order.visible = min(order.display_qty, order.remain);
+        self.book_feed.mark(order.side, order.price);
+        order.priority = self.next_priority();
+        order.update_time = self.clock.now();
+        tracing::debug!("refresh iceberg order {} with priority {}", order.id, order.priority);
+        if order.side == OrderSide::ASK {
+            self.asks.insert(order.get_ask_key(), order_rc.clone());
+        } else {
+            self.bids.insert(order.get_bid_key(), order_rc.clone());
+        }
+    }
+
+    // With pro-rata matching, the amount each maker trades with the taker, by maker id. Every level the
+    // taker reaches is planned from the current book, makers skipped by self trade prevention get nothing.
+    fn pro_rata_plan(
+        &self,
+        taker: &Order,
+        quote_left: &Decimal,
+        price_limit: &Option<Decimal>,
+        self_trade_prevention: SelfTradePrevention,
+    ) -> HashMap<u64, Decimal> {
+        let taker_is_ask = taker.side == OrderSide::ASK;
+        let is_market_bid = !taker_is_ask && taker.type_ == OrderType::MARKET;
+        let counter_orders: Box<dyn Iterator<Item = &OrderRc>> = if taker_is_ask {
+            Box::new(self.bids.values())
+        } else {
+            Box::new(self.asks.values())
+        };
+        let mut plan = HashMap::new();
+        let mut taker_left = taker.remain;
+        let mut quote_left = *quote_left;
+        for (price, level) in &counter_orders.map(|order_rc| *order_rc.borrow()).group_by(|order| order.price) {
+            if taker_left.is_zero() || !within_price_limit(taker.side, price_limit, &price) {
+                break;
+            }
+            let makers: Vec<Order> = level
+                .filter(|maker| maker.user != taker.user || self_trade_prevention == SelfTradePrevention::Allow)
+                .collect();
+            let sizes: Vec<Decimal> = makers.iter().map(Order::visible_amount).collect();
+            let mut level_total = min(taker_left, sizes.iter().copied().sum());
+            if is_market_bid {
+                level_total = min(level_total, market_bid_base_amount(&quote_left, &price, self.base_prec));
+            }
+            for (maker, amount) in makers.iter().zip(pro_rata_allocation(level_total, &sizes, self.base_prec)) {
+                plan.insert(maker.id, amount);
+            }
+            taker_left -= level_total;
+            quote_left -= level_total * price;
+        }
+        plan
+    }
+
+    // the parameter `quote_limit`, is only used for market bid order,
     // it indicates the `quote` balance of the user,
     // so the sum of all the trades' quote amount cannot exceed this value
-    pub fn execute_order(&mut self, real: bool, taker: OrderRc, quote_limit: &Decimal) {
-        log::debug!("execute_order {:?}", taker);
-        let taker_is_ask = taker.borrow_mut().side == OrderSide::ASK;
+    // return true if the remainder of the taker is canceled by self trade prevention
+    // `price_limit` is the price of a limit taker, or the protection price of a market taker
+    pub fn execute_order(
+        &mut self,
+        real: bool,
+        taker: OrderRc,
+        quote_limit: &Decimal,
+        price_limit: Option<Decimal>,
+        self_trade_prevention: SelfTradePrevention,
+    ) -> bool {
+        let match_span = tracing::debug_span!("match", order_id = taker.borrow().id, market = self.name);
+        let _match = match_span.enter();
+        tracing::debug!("execute_order {:?}", taker);
+        let taker_side = taker.borrow().side;
+        let taker_is_ask = taker_side == OrderSide::ASK;
         let taker_is_bid = !taker_is_ask;
         let maker_is_bid = taker_is_ask;
         let maker_is_ask = !maker_is_bid;
@@ -289,166 +810,560 @@ impl Market {
         //let mut quote_available = *quote_limit;
         let mut quote_sum = Decimal::zero();
 
-        let mut finished_orders = Vec::new();
+        let mut taker_canceled = false;
 
-        let counter_orders: Box<dyn Iterator<Item = &mut OrderRc>> = if maker_is_bid {
-            Box::new(self.bids.values_mut())
-        } else {
-            Box::new(self.asks.values_mut())
-        };
-        for maker in counter_orders {
-            let taker_mut = taker.borrow_mut();
-            let maker_mut = maker.borrow_mut();
-            if taker_mut.remain.is_zero() {
-                break;
-            }
-            let (ask_fee_rate, bid_fee_rate) = if taker_is_ask {
-                (taker_mut.taker_fee, maker_mut.maker_fee)
+        // matching is restarted after iceberg orders are refreshed, since the book can't be
+        // changed while it's being iterated
+        loop {
+            let mut finished_orders = Vec::new();
+            let mut refreshed_orders = Vec::new();
+            let pro_rata_plan = if self.matching_mode == config::MatchingMode::ProRata {
+                Some(self.pro_rata_plan(&taker.borrow(), &(*quote_limit - quote_sum), &price_limit, self_trade_prevention))
             } else {
-                (maker_mut.maker_fee, taker_mut.taker_fee)
+                None
             };
-            let price = maker_mut.price;
-            let (mut ask_order, mut bid_order) = if taker_is_ask {
-                (taker_mut, maker_mut)
+            let counter_orders: Box<dyn Iterator<Item = &mut OrderRc>> = if maker_is_bid {
+                Box::new(self.bids.values_mut())
             } else {
-                (maker_mut, taker_mut)
+                Box::new(self.asks.values_mut())
             };
-            if is_limit_order && ask_order.price.gt(&bid_order.price) {
-                break;
-            }
-            let traded_base_amount = min(ask_order.remain, bid_order.remain);
-            let traded_quote_amount = price * traded_base_amount;
-
-            quote_sum += traded_quote_amount;
-            if taker_is_bid && is_market_order {
-                if quote_sum.gt(quote_limit) {
-                    // Now user has not enough balance, stop here.
-                    // Notice: another approach here is to divide remain quote by price to get a base amount
-                    // to be traded, then all `quote_limit` will be consumed.
-                    // But division is prone to bugs in financial decimal calculation,
-                    // so we will not adapt tis method.
-                    // TODO: maybe another method is to make:
-                    // trade_base_amount = round_down(quote_limit - old_quote_sum / price)
-                    // so quote_limit will be `almost` fulfilled
+            for maker in counter_orders {
+                let taker_mut = taker.borrow_mut();
+                let maker_mut = maker.borrow_mut();
+                if taker_mut.remain.is_zero() {
                     break;
                 }
-            }
+                let price = maker_mut.price;
+                let (mut ask_order, mut bid_order) = if taker_is_ask {
+                    (taker_mut, maker_mut)
+                } else {
+                    (maker_mut, taker_mut)
+                };
+                if !within_price_limit(taker_side, &price_limit, &price) {
+                    break;
+                }
+                if ask_order.user == bid_order.user && self_trade_prevention != SelfTradePrevention::Allow {
+                    let maker_order = if taker_is_ask { *bid_order } else { *ask_order };
+                    match self_trade_prevention {
+                        SelfTradePrevention::CancelNewest => {
+                            taker_canceled = true;
+                            break;
+                        }
+                        SelfTradePrevention::CancelOldest => {
+                            finished_orders.push(maker_order);
+                            continue;
+                        }
+                        SelfTradePrevention::CancelBoth => {
+                            finished_orders.push(maker_order);
+                            taker_canceled = true;
+                            break;
+                        }
+                        SelfTradePrevention::Allow => unreachable!(),
+                    }
+                }
+                let (taker_order, maker_order) = if taker_is_ask {
+                    (&*ask_order, &*bid_order)
+                } else {
+                    (&*bid_order, &*ask_order)
+                };
+                match self.surveillance.check_match(taker_order, maker_order) {
+                    SurveillanceVerdict::Allow => {}
+                    SurveillanceVerdict::Flag(reason) => {
+                        tracing::warn!("match of order {} with {} flagged: {}", taker_order.id, maker_order.id, reason);
+                    }
+                    SurveillanceVerdict::Block(reason) => {
+                        tracing::warn!("match of order {} with {} blocked: {}", taker_order.id, maker_order.id, reason);
+                        taker_canceled = true;
+                        break;
+                    }
+                }
+                let maker_visible = if taker_is_ask {
+                    bid_order.visible_amount()
+                } else {
+                    ask_order.visible_amount()
+                };
+                let mut traded_base_amount = match &pro_rata_plan {
+                    None => min(if taker_is_ask { ask_order.remain } else { bid_order.remain }, maker_visible),
+                    // the makers after the planned levels are not reached
+                    Some(plan) => match plan.get(if taker_is_ask { &bid_order.id } else { &ask_order.id }) {
+                        Some(amount) => *amount,
+                        None => break,
+                    },
+                };
+                // a small maker may get nothing of a small taker
+                if traded_base_amount.is_zero() {
+                    continue;
+                }
+                let mut traded_quote_amount = price * traded_base_amount;
 
-            let ask_fee = traded_quote_amount * ask_fee_rate;
-            let bid_fee = traded_base_amount * bid_fee_rate;
+                if taker_is_bid && is_market_order && (quote_sum + traded_quote_amount).gt(quote_limit) {
+                    // Only part of this maker can be taken with the remaining quote.
+                    // The base amount is rounded down, so `quote_limit` is `almost` fulfilled but never exceeded.
+                    traded_base_amount = market_bid_base_amount(&(*quote_limit - quote_sum), &price, self.base_prec);
+                    if traded_base_amount.is_zero() {
+                        break;
+                    }
+                    traded_quote_amount = price * traded_base_amount;
+                }
+                // a trade that would overflow a received balance is not made, the taker is canceled
+                if !self
+                    .balance_manager
+                    .can_add(bid_order.user, BalanceType::AVAILABLE, &self.base, &traded_base_amount)
+                    || !self
+                        .balance_manager
+                        .can_add(ask_order.user, BalanceType::AVAILABLE, &self.quote, &traded_quote_amount)
+                {
+                    tracing::error!("balance overflow in trade of orders {} and {}", ask_order.id, bid_order.id);
+                    taker_canceled = true;
+                    break;
+                }
+                quote_sum += traded_quote_amount;
+                self.book_feed
+                    .mark(if maker_is_ask { OrderSide::ASK } else { OrderSide::BID }, price);
+                // the fees and the balances of both sides, until the next maker
+                let settle_span = tracing::debug_span!(
+                    "settle",
+                    maker_order_id = if taker_is_ask { bid_order.id } else { ask_order.id },
+                    price = %price,
+                    amount = %traded_base_amount,
+                    trade_id = tracing::field::Empty,
+                );
+                let _settle = settle_span.enter();
 
-            let timestamp = utils::current_timestamp();
-            ask_order.update_time = timestamp;
-            bid_order.update_time = timestamp;
+                let timestamp = self.timestamps.next(self.clock.now());
+                // the fee rates of the orders may be overridden by the volume based fee tiers
+                let (ask_fee_rate, bid_fee_rate) = {
+                    let mut fee_tiers = self.fee_tiers.borrow_mut();
+                    if taker_is_ask {
+                        (
+                            fee_tiers.taker_fee(ask_order.user, self.command_time, &ask_order.taker_fee),
+                            fee_tiers.maker_fee(bid_order.user, self.command_time, &bid_order.maker_fee),
+                        )
+                    } else {
+                        (
+                            fee_tiers.maker_fee(ask_order.user, self.command_time, &ask_order.maker_fee),
+                            fee_tiers.taker_fee(bid_order.user, self.command_time, &bid_order.taker_fee),
+                        )
+                    }
+                };
+                let ask_fee = self.round_fee(&self.quote, &(traded_quote_amount * ask_fee_rate));
+                let bid_fee = self.round_fee(&self.base, &(traded_base_amount * bid_fee_rate));
 
-            if real {
-                // emit the trade
-                let trade_id = self.sequencer.borrow_mut().next_trade_id();
-                let trade = types::Trade {
-                    id: trade_id,
-                    timestamp: utils::current_timestamp(),
-                    market: self.name.to_string(),
-                    base: self.base.clone(),
-                    quote: self.quote.clone(),
-                    price,
-                    amount: traded_base_amount,
-                    quote_amount: traded_quote_amount,
-                    ask_user_id: ask_order.user,
-                    ask_order_id: ask_order.id,
-                    ask_role: if taker_is_ask { MarketRole::TAKER } else { MarketRole::MAKER },
-                    ask_fee,
-                    bid_user_id: bid_order.user,
-                    bid_order_id: bid_order.id,
-                    bid_role: if taker_is_ask { MarketRole::MAKER } else { MarketRole::TAKER },
-                    bid_fee,
+                // A limit taker pays from the AVAILABLE balance, including the unfilled part it
+                // freezes later, makers and market takers pay from the frozen balance.
+                let fee_asset = match self.fee_side {
+                    config::FeeSide::Received => self.balance_manager.fee_asset(),
+                    config::FeeSide::Quote => Some(self.quote.clone()),
+                    config::FeeSide::Base => Some(self.base.clone()),
                 };
-                self.history_writer.borrow_mut().append_trade_history(&trade);
-                self.message_manager.push_trade_message(&trade);
-                self.trade_count += 1;
-            }
-            ask_order.remain -= traded_base_amount;
-            bid_order.remain -= traded_base_amount;
-            ask_order.finished_base += traded_base_amount;
-            bid_order.finished_base += traded_base_amount;
-            ask_order.finished_quote += traded_quote_amount;
-            bid_order.finished_quote += traded_quote_amount;
-            ask_order.finished_fee += ask_fee;
-            bid_order.finished_fee += bid_fee;
-
-            // TODO: change balance should emit a balance update history/event
-            // handle maker balance
-            let _balance_type = if maker_is_bid {
-                BalanceType::FREEZE
-            } else {
-                BalanceType::AVAILABLE
-            };
-            // handle base
-            self.balance_manager
-                .balance_add(bid_order.user, BalanceType::AVAILABLE, &self.base, &traded_base_amount);
-            self.balance_manager.balance_sub(
-                ask_order.user,
-                if maker_is_ask {
-                    BalanceType::FREEZE
+                let (ask_fee_asset, ask_fee_charged) = if ask_fee > Decimal::zero() {
+                    let reserved = if maker_is_ask || is_market_order {
+                        Decimal::zero()
+                    } else {
+                        ask_order.remain
+                    };
+                    self.balance_manager.fee_charge(
+                        ask_order.user,
+                        &ask_fee,
+                        &self.quote,
+                        &self.base,
+                        &(Decimal::one() / price),
+                        &reserved,
+                        fee_asset.as_deref(),
+                        self.fee_rounding,
+                    )
                 } else {
-                    BalanceType::AVAILABLE
-                },
-                &self.base,
-                &traded_base_amount,
-            );
-            // handle quote
-            self.balance_manager
-                .balance_add(ask_order.user, BalanceType::AVAILABLE, &self.quote, &traded_quote_amount);
-            self.balance_manager.balance_sub(
-                bid_order.user,
-                if maker_is_bid {
-                    BalanceType::FREEZE
+                    (self.quote.clone(), ask_fee)
+                };
+                let (bid_fee_asset, bid_fee_charged) = if bid_fee > Decimal::zero() {
+                    let reserved = if maker_is_bid || is_market_order {
+                        Decimal::zero()
+                    } else {
+                        bid_order.remain * bid_order.price
+                    };
+                    self.balance_manager.fee_charge(
+                        bid_order.user,
+                        &bid_fee,
+                        &self.base,
+                        &self.quote,
+                        &price,
+                        &reserved,
+                        fee_asset.as_deref(),
+                        self.fee_rounding,
+                    )
                 } else {
-                    BalanceType::AVAILABLE
-                },
-                &self.quote,
-                &traded_quote_amount,
-            );
+                    (self.base.clone(), bid_fee)
+                };
+
+                // All the balance changes of the trade are staged and applied at once, so a failure
+                // leaves none of them applied. The trade, the orders and the history follow.
+                let fee_account = self.balance_manager.fee_account();
+                let mut settlement = BalanceTransaction::default();
+                // handle base
+                settlement.add(bid_order.user, BalanceType::AVAILABLE, &self.base, &traded_base_amount);
+                // makers and market takers trade with the frozen balance
+                settlement.sub(
+                    ask_order.user,
+                    if maker_is_ask || is_market_order {
+                        BalanceType::FREEZE
+                    } else {
+                        BalanceType::AVAILABLE
+                    },
+                    &self.base,
+                    &traded_base_amount,
+                );
+                // handle quote
+                settlement.add(ask_order.user, BalanceType::AVAILABLE, &self.quote, &traded_quote_amount);
+                settlement.sub(
+                    bid_order.user,
+                    if maker_is_bid || is_market_order {
+                        BalanceType::FREEZE
+                    } else {
+                        BalanceType::AVAILABLE
+                    },
+                    &self.quote,
+                    &traded_quote_amount,
+                );
+                let ask_fee_change = stage_fee(&mut settlement, fee_account, ask_order.user, &ask_fee_asset, &ask_fee_charged);
+                let bid_fee_change = stage_fee(&mut settlement, fee_account, bid_order.user, &bid_fee_asset, &bid_fee_charged);
+                // a failed settlement is handled like an overflow above, nothing of this trade is applied
+                let settled_balances = match self.balance_manager.settle(&settlement) {
+                    Ok(settled_balances) => settled_balances,
+                    Err(err) => {
+                        tracing::error!("settlement of orders {} and {} failed: {}", ask_order.id, bid_order.id, err);
+                        taker_canceled = true;
+                        break;
+                    }
+                };
+                ask_order.update_time = timestamp;
+                bid_order.update_time = timestamp;
+
+                let mut executed_trade = None;
+                if real {
+                    // emit the trade
+                    let trade_id = self.sequencer.borrow_mut().next_trade_id();
+                    settle_span.record("trade_id", &trade_id);
+                    let trade = types::Trade {
+                        id: trade_id,
+                        timestamp,
+                        market: self.name.to_string(),
+                        base: self.base.clone(),
+                        quote: self.quote.clone(),
+                        price,
+                        amount: traded_base_amount,
+                        quote_amount: traded_quote_amount,
+                        ask_user_id: ask_order.user,
+                        ask_order_id: ask_order.id,
+                        ask_role: if taker_is_ask { MarketRole::TAKER } else { MarketRole::MAKER },
+                        ask_fee: ask_fee_charged,
+                        ask_fee_asset: ask_fee_asset.clone(),
+                        bid_user_id: bid_order.user,
+                        bid_order_id: bid_order.id,
+                        bid_role: if taker_is_ask { MarketRole::MAKER } else { MarketRole::TAKER },
+                        bid_fee: bid_fee_charged,
+                        bid_fee_asset: bid_fee_asset.clone(),
+                    };
+                    self.history_writer.borrow_mut().append_trade_history(&trade);
+                    self.message_manager.push_trade_message(&trade);
+                    self.trade_count += 1;
+                    self.metrics.trades_executed.inc();
+                    self.circuit_breaker.record(timestamp, price);
+                    self.ticker.record(timestamp, price, traded_base_amount, traded_quote_amount);
+                    for (interval, candle) in self.klines.record(timestamp, price, traded_base_amount, traded_quote_amount) {
+                        self.history_writer.borrow_mut().append_kline(self.name, interval, &candle);
+                    }
+                    executed_trade = Some(trade);
+                }
+                self.last_price = price;
+                {
+                    let mut fee_tiers = self.fee_tiers.borrow_mut();
+                    fee_tiers.record_trade(ask_order.user, self.command_time, &traded_quote_amount);
+                    fee_tiers.record_trade(bid_order.user, self.command_time, &traded_quote_amount);
+                }
+                ask_order.remain -= traded_base_amount;
+                bid_order.remain -= traded_base_amount;
+                ask_order.finished_base += traded_base_amount;
+                bid_order.finished_base += traded_base_amount;
+                ask_order.finished_quote += traded_quote_amount;
+                bid_order.finished_quote += traded_quote_amount;
+                // the amounts charged, in the asset they are charged in
+                if ask_fee_asset == self.quote {
+                    ask_order.finished_fee += ask_fee_charged;
+                } else {
+                    ask_order.finished_paid_fee += ask_fee_charged;
+                }
+                if bid_fee_asset == self.base {
+                    bid_order.finished_fee += bid_fee_charged;
+                } else {
+                    bid_order.finished_paid_fee += bid_fee_charged;
+                }
+                if let Some(trade) = &executed_trade {
+                    self.message_manager
+                        .push_order_fill_message(&order_fill_message(&ask_order, trade, trade.ask_role));
+                    self.message_manager
+                        .push_order_fill_message(&order_fill_message(&bid_order, trade, trade.bid_role));
+                }
+
+                if real {
+                    let ask_fee_detail = BalanceHistoryFromFee {
+                        market: self.name.to_string(),
+                        order_id: ask_order.id,
+                        price,
+                        amount: traded_base_amount,
+                        fee_rate: ask_fee_rate,
+                    };
+                    if let Some(change) = ask_fee_change {
+                        append_trade_fee_history(
+                            &self.history_writer,
+                            fee_account,
+                            ask_order.user,
+                            &ask_fee_asset,
+                            ask_fee_charged,
+                            settled_balances[change],
+                            &ask_fee_detail,
+                            timestamp,
+                        );
+                    }
+                    let bid_fee_detail = BalanceHistoryFromFee {
+                        market: self.name.to_string(),
+                        order_id: bid_order.id,
+                        price,
+                        amount: traded_base_amount,
+                        fee_rate: bid_fee_rate,
+                    };
+                    if let Some(change) = bid_fee_change {
+                        append_trade_fee_history(
+                            &self.history_writer,
+                            fee_account,
+                            bid_order.user,
+                            &bid_fee_asset,
+                            bid_fee_charged,
+                            settled_balances[change],
+                            &bid_fee_detail,
+                            timestamp,
+                        );
+                    }
+                }
+                // subscribers see the trade only after its balance changes are applied
+                if let Some(trade) = executed_trade {
+                    self.trade_subscribers.publish(&trade);
+                }
+
+                let (mut taker_mut, mut maker_mut) = if taker_is_ask {
+                    (ask_order, bid_order)
+                } else {
+                    (bid_order, ask_order)
+                };
+                maker_mut.frozen -= if maker_is_bid { traded_quote_amount } else { traded_base_amount };
+                if maker_mut.is_iceberg() {
+                    maker_mut.visible -= traded_base_amount;
+                }
+                if is_market_order {
+                    taker_mut.frozen -= if taker_is_bid { traded_quote_amount } else { traded_base_amount };
+                }
+
+                let maker_finished = maker_mut.remain.is_zero();
+                if maker_finished {
+                    finished_orders.push(*maker_mut);
+                } else if maker_mut.is_iceberg() && maker_mut.visible.is_zero() {
+                    refreshed_orders.push(maker_mut.id);
+                }
+                // When maker_finished, `order_finish` will send message.
+                // So we don't need to send the finish message here.
+                if real && !maker_finished {
+                    let order_message = message::OrderMessage {
+                        event: OrderEventType::UPDATE,
+                        order: *maker_mut,
+                        base: self.base.clone(),
+                        quote: self.quote.clone(),
+                    };
+                    self.message_manager.push_order_message(&order_message);
+                }
+            }
 
-            if ask_fee.is_sign_positive() {
-                self.balance_manager
-                    .balance_sub(ask_order.user, BalanceType::AVAILABLE, &self.quote, &ask_fee);
+            // orders canceled by self trade prevention are finished here as well
+            for item in finished_orders.iter() {
+                self.order_finish(real, item);
             }
-            if bid_fee.is_sign_positive() {
-                self.balance_manager
-                    .balance_sub(bid_order.user, BalanceType::AVAILABLE, &self.base, &bid_fee);
+            let refreshed = !refreshed_orders.is_empty();
+            for order_id in refreshed_orders {
+                self.refresh_iceberg_order(order_id);
+            }
+            // the refreshed slices may still match the taker
+            if !refreshed || taker_canceled {
+                break;
             }
+        }
+        taker_canceled
+    }
 
-            let (mut _taker_mut, mut maker_mut) = if taker_is_ask {
-                (ask_order, bid_order)
-            } else {
-                (bid_order, ask_order)
-            };
-            maker_mut.frozen -= if maker_is_bid { traded_quote_amount } else { traded_base_amount };
-
-            let maker_finished = maker_mut.remain.is_zero();
-            if maker_finished {
-                finished_orders.push(*maker_mut);
-            }
-            // When maker_finished, `order_finish` will send message.
-            // So we don't need to send the finish message here.
-            if real && !maker_finished {
-                let order_message = message::OrderMessage {
-                    event: OrderEventType::UPDATE,
-                    order: *maker_mut,
-                    base: self.base.clone(),
-                    quote: self.quote.clone(),
-                };
-                self.message_manager.push_order_message(&order_message);
+    pub fn put_order(&mut self, real: bool, order_input: OrderInput) -> Result<Order> {
+        // the wall-clock can only be checked for new orders
+        if real && order_input.expire_at.map_or(false, |expire_at| expire_at <= self.clock.now()) {
+            return Err(anyhow!("order already expired"));
+        }
+        if real && self.is_halted() {
+            return Err(anyhow!("market halted"));
+        }
+        if real {
+            self.check_state(&order_input)?;
+        }
+        // the triggered orders are placed in the same command
+        self.command_time = order_input.timestamp.unwrap_or_else(|| self.clock.now());
+        let started_at = std::time::Instant::now();
+        let order = self.place_order(real, order_input)?;
+        if real {
+            self.metrics.matching_latency.observe(started_at.elapsed().as_secs_f64());
+            self.metrics.orders_placed.inc();
+        }
+        // triggers are evaluated only when trades happen, and not in a halted market
+        if !order.finished_base.is_zero() && !self.is_halted() {
+            self.activate_triggers(real);
+        }
+        self.publish_book_update();
+        Ok(order)
+    }
+
+    // the prices new orders may have around the last trade price, none before the first trade
+    pub fn price_band(&self) -> Option<(Decimal, Decimal)> {
+        if self.price_band.is_zero() || self.last_price.is_zero() {
+            return None;
+        }
+        let low = self.last_price * (Decimal::from(1) - self.price_band);
+        let high = self.last_price * (Decimal::from(1) + self.price_band);
+        Some((
+            low.round_dp_with_strategy(self.quote_prec, RoundingStrategy::RoundUp),
+            high.round_dp_with_strategy(self.quote_prec, RoundingStrategy::RoundDown),
+        ))
+    }
+
+    pub fn state(&self) -> MarketState {
+        self.state
+    }
+    // The open orders are left to the caller, which cancels them when the market is delisted.
+    // Journaled by the caller as the halts are.
+    pub fn set_state(&mut self, state: MarketState) {
+        tracing::info!("market {} state {} -> {}", self.name, self.state.as_str(), state.as_str());
+        self.state = state;
+    }
+    fn check_state(&self, order_input: &OrderInput) -> Result<()> {
+        match self.state {
+            MarketState::Active => Ok(()),
+            MarketState::PostOnly => {
+                if order_input.type_ != OrderType::LIMIT
+                    || !order_input.time_in_force.is_resting()
+                    || self.would_cross(order_input.side, &order_input.price)
+                {
+                    Err(anyhow!("market is post-only: only the orders resting on the book are accepted"))
+                } else {
+                    Ok(())
+                }
             }
+            state => Err(anyhow!("market is {}: new orders are rejected", state.as_str())),
+        }
+    }
+    pub fn is_halted(&self) -> bool {
+        self.circuit_breaker.halted_until.is_some()
+    }
+    pub fn halted_until(&self) -> Option<f64> {
+        self.circuit_breaker.halted_until
+    }
+    // Halt the market if the price has moved too much, return when the cooldown ends.
+    // Only called for real orders, the caller logs the halt for replay.
+    pub fn check_circuit_breaker(&mut self, now: f64) -> Option<f64> {
+        if !self.circuit_breaker.is_enabled() || self.is_halted() || !self.circuit_breaker.is_tripped() {
+            return None;
         }
+        let until = now + self.circuit_breaker.config.cooldown.as_secs_f64();
+        tracing::warn!("market {} halted until {} at price {}", self.name, until, self.last_price);
+        self.halt(until);
+        Some(until)
+    }
+    pub fn halt(&mut self, until: f64) {
+        self.circuit_breaker.halted_until = Some(until);
+        self.circuit_breaker.prices.clear();
+    }
+    // the price move is measured again from the trades after resuming
+    pub fn resume(&mut self) {
+        self.circuit_breaker.halted_until = None;
+        self.circuit_breaker.prices.clear();
+    }
 
-        for item in finished_orders.iter() {
-            self.order_finish(real, item);
+    pub fn put_trigger_order(
+        &mut self,
+        trigger_price: Decimal,
+        direction: TriggerDirection,
+        order_input: OrderInput,
+    ) -> Result<TriggerOrder> {
+        if !trigger_price.is_sign_positive() || trigger_price.is_zero() {
+            return Err(anyhow!("invalid trigger price"));
+        }
+        if !self.state.accepts_orders() {
+            return Err(anyhow!("market is {}: new orders are rejected", self.state.as_str()));
+        }
+        order_input.check_flags()?;
+        if order_input.quote_amount.is_zero() && order_input.amount.lt(&self.min_amount) {
+            return Err(anyhow!("invalid amount"));
         }
+        if order_input.type_ == OrderType::MARKET && !order_input.price.is_zero() {
+            return Err(anyhow!("market order should not have a price"));
+        }
+        if order_input.type_ == OrderType::LIMIT && order_input.price.is_zero() {
+            return Err(anyhow!("invalid price for limit order"));
+        }
+        let trigger_order = TriggerOrder {
+            id: self.next_order_id(),
+            user: order_input.user_id,
+            create_time: self.clock.now(),
+            trigger_price: trigger_price.round_dp(self.quote_prec),
+            direction,
+            order_input,
+        };
+        tracing::debug!("put trigger order {:?}", trigger_order);
+        self.trigger_orders.insert(trigger_order.id, trigger_order.clone());
+        Ok(trigger_order)
     }
 
-    pub fn put_order(&mut self, real: bool, order_input: OrderInput) -> Result<Order> {
-        if order_input.amount.lt(&self.min_amount) {
+    pub fn cancel_trigger_order(&mut self, order_id: u64) -> Option<TriggerOrder> {
+        self.trigger_orders.remove(&order_id)
+    }
+
+    // submit triggered orders in id order, until no more triggers fire
+    fn activate_triggers(&mut self, real: bool) {
+        loop {
+            let last_price = self.last_price;
+            let triggered_id = self
+                .trigger_orders
+                .values()
+                .find(|trigger_order| trigger_order.is_triggered(&last_price))
+                .map(|trigger_order| trigger_order.id);
+            let trigger_order = match triggered_id {
+                Some(id) => self.trigger_orders.remove(&id).unwrap(),
+                None => break,
+            };
+            tracing::debug!("trigger order {} fired at {}", trigger_order.id, last_price);
+            if let Err(e) = self.place_order(real, trigger_order.order_input) {
+                tracing::warn!("triggered order {} fails: {}", trigger_order.id, e);
+            }
+        }
+    }
+
+    // the checks of an order on its own, returns it rounded to the precisions of the market
+    fn normalize_order_input(&self, order_input: OrderInput) -> Result<OrderInput> {
+        // a market bid order may spend a quote amount instead of buying a base amount
+        order_input.check_flags()?;
+        let by_quote = !order_input.quote_amount.is_zero();
+        if by_quote {
+            if order_input.type_ != OrderType::MARKET || order_input.side != OrderSide::BID {
+                return Err(anyhow!("quote amount is only valid for market bid orders"));
+            }
+            if !order_input.amount.is_zero() {
+                return Err(anyhow!("market bid order should have either amount or quote amount"));
+            }
+            if order_input.quote_amount.is_sign_negative() {
+                return Err(anyhow!("invalid quote amount"));
+            }
+        } else if order_input.amount.lt(&self.min_amount) {
             return Err(anyhow!("invalid amount"));
         }
         // TODO: refactor this
@@ -457,9 +1372,13 @@ impl Market {
         let amount = order_input.amount.round_dp(base_prec);
         let price = order_input.price.round_dp(quote_prec);
         //println!("decimal {} {} {} {} ", self.base, base_prec, self.quote, quote_prec);
+        let quote_amount = order_input.quote_amount.round_dp(self.balance_manager.asset_prec(&self.quote));
+        let display_qty = order_input.display_qty.round_dp(base_prec);
         let order_input = OrderInput {
             price,
             amount,
+            quote_amount,
+            display_qty,
             ..order_input
         };
         if order_input.type_ == OrderType::MARKET {
@@ -474,6 +1393,51 @@ impl Market {
                 return Err(anyhow!("invalid price for limit order"));
             }
         }
+        if !order_input.display_qty.is_zero() {
+            if order_input.display_qty.lt(&self.min_amount) || order_input.display_qty.ge(&order_input.amount) {
+                return Err(anyhow!("invalid display quantity"));
+            }
+        }
+        Ok(order_input)
+    }
+
+    // Checks an order as `put_order` does, without placing it. Returns the asset and the amount of
+    // the balance it needs, the balance itself is left to the caller.
+    pub fn check_order(&self, order_input: &OrderInput) -> Result<(String, Decimal)> {
+        if order_input.expire_at.map_or(false, |expire_at| expire_at <= self.clock.now()) {
+            return Err(anyhow!("order already expired"));
+        }
+        if self.is_halted() {
+            return Err(anyhow!("market halted"));
+        }
+        self.check_state(order_input)?;
+        let by_quote = !order_input.quote_amount.is_zero();
+        let order_input = self.normalize_order_input(order_input.clone())?;
+        let available = self
+            .balance_manager
+            .balance_get(order_input.user_id, BalanceType::AVAILABLE, &self.quote);
+        let (asset, required, quote_limit) = match (order_input.type_, order_input.side) {
+            (_, OrderSide::ASK) => (&self.base, order_input.amount, Decimal::zero()),
+            (OrderType::LIMIT, OrderSide::BID) => (&self.quote, order_input.amount * order_input.price, Decimal::zero()),
+            (_, OrderSide::BID) if by_quote => (&self.quote, order_input.quote_amount, order_input.quote_amount),
+            // the minimum against the top of the counter book, as `place_order` checks
+            (_, OrderSide::BID) => {
+                let top_counter_order_price = self.asks.values().next().unwrap().borrow().price;
+                (&self.quote, order_input.amount * top_counter_order_price, available)
+            }
+        };
+        if order_input.post_only && self.would_cross(order_input.side, &order_input.price) {
+            return Err(anyhow!("post-only order rejected: it would take liquidity"));
+        }
+        if order_input.time_in_force == TimeInForce::FOK && !self.can_fill_fully(&order_input, &quote_limit) {
+            return Err(anyhow!("fill-or-kill order cannot be fully filled"));
+        }
+        Ok((asset.clone(), required))
+    }
+
+    fn place_order(&mut self, real: bool, order_input: OrderInput) -> Result<Order> {
+        let by_quote = !order_input.quote_amount.is_zero();
+        let order_input = self.normalize_order_input(order_input)?;
         if order_input.side == OrderSide::ASK {
             if self
                 .balance_manager
@@ -496,6 +1460,10 @@ impl Market {
                         &order_input.price
                     ));
                 }
+            } else if by_quote {
+                if balance.lt(&order_input.quote_amount) {
+                    return Err(anyhow!("balance not enough"));
+                }
             } else {
                 // We have already checked that counter order book is not empty,
                 // so `unwrap` here is safe.
@@ -508,36 +1476,112 @@ impl Market {
                 }
             }
         }
-        let quote_limit = if order_input.type_ == OrderType::MARKET && order_input.side == OrderSide::BID {
+        let quote_limit = if by_quote {
+            order_input.quote_amount
+        } else if order_input.type_ == OrderType::MARKET && order_input.side == OrderSide::BID {
             self.balance_manager
                 .balance_get(order_input.user_id, BalanceType::AVAILABLE, &self.quote)
         } else {
             // not used
             Decimal::zero()
         };
+        // check before any id is consumed, so a rejected order leaves nothing behind
+        let (freeze_asset, max_frozen) = match (order_input.type_, order_input.side) {
+            (OrderType::LIMIT, OrderSide::ASK) => (&self.base, order_input.amount),
+            (OrderType::LIMIT, OrderSide::BID) => (&self.quote, order_input.amount * order_input.price),
+            (_, OrderSide::ASK) => (&self.base, order_input.amount),
+            (_, OrderSide::BID) => (&self.quote, quote_limit),
+        };
+        if !self
+            .balance_manager
+            .can_add(order_input.user_id, BalanceType::FREEZE, freeze_asset, &max_frozen)
+        {
+            return Err(anyhow!("balance overflow"));
+        }
+        if order_input.post_only && self.would_cross(order_input.side, &order_input.price) {
+            return Err(anyhow!("post-only order rejected: it would take liquidity"));
+        }
+        if order_input.time_in_force == TimeInForce::FOK && !self.can_fill_fully(&order_input, &quote_limit) {
+            return Err(anyhow!("fill-or-kill order cannot be fully filled"));
+        }
 
-        let t = utils::current_timestamp();
-        let order_rc = Rc::new(RefCell::new(Order {
-            id: self.sequencer.borrow_mut().next_order_id(),
-            type_: order_input.type_,
-            side: order_input.side,
-            create_time: t,
-            update_time: t,
-            market: &self.name,
+        // the base amount of a market bid by quote is unknown before execution,
+        // the whole ask book is an upper bound of it
+        let amount = if by_quote {
+            self.asks.values().fold(Decimal::zero(), |sum, ask| sum + ask.borrow().remain)
+        } else {
+            order_input.amount
+        };
+        // a market order freezes its worst case cost before execution,
+        // and the unused part is refunded after execution
+        let frozen = if order_input.type_ == OrderType::LIMIT {
+            Decimal::zero()
+        } else if order_input.side == OrderSide::ASK {
+            amount
+        } else {
+            quote_limit
+        };
+        if !frozen.is_zero() {
+            let asset = if order_input.side == OrderSide::ASK {
+                &self.base
+            } else {
+                &self.quote
+            };
+            self.balance_manager.balance_frozen(order_input.user_id, asset, &frozen)?;
+        }
+        let t = self.timestamps.next(self.clock.now());
+        let order_id = self.next_order_id();
+        let order_rc = Rc::new(RefCell::new(Order {
+            id: order_id,
+            type_: order_input.type_,
+            side: order_input.side,
+            create_time: t,
+            update_time: t,
+            market: &self.name,
             user: order_input.user_id,
             price: order_input.price,
-            amount: order_input.amount,
+            amount,
             taker_fee: order_input.taker_fee,
             maker_fee: order_input.maker_fee,
-            remain: order_input.amount,
-            frozen: Decimal::zero(),
+            remain: amount,
+            frozen,
             finished_base: Decimal::zero(),
             finished_quote: Decimal::zero(),
             finished_fee: Decimal::zero(),
+            finished_paid_fee: Decimal::zero(),
+            display_qty: order_input.display_qty,
+            visible: Decimal::zero(),
+            priority: self.next_priority(),
+            expire_at: order_input.expire_at,
         }));
-        self.execute_order(real, order_rc.clone(), &quote_limit);
+        let taker_canceled = self.execute_order(
+            real,
+            order_rc.clone(),
+            &quote_limit,
+            order_input.price_limit(),
+            order_input.self_trade_prevention,
+        );
+        if order_input.type_ == OrderType::LIMIT {
+            // the whole iceberg order can be taken while it is the taker, it is only hidden on the book
+            let mut order = order_rc.borrow_mut();
+            order.visible = min(order.display_qty, order.remain);
+        }
         let mut order = *order_rc.borrow_mut();
-        if order.type_ == OrderType::LIMIT && !order.remain.is_zero() {
+        let mut resting =
+            order.type_ == OrderType::LIMIT && order_input.time_in_force.is_resting() && !taker_canceled && !order.remain.is_zero();
+        if resting {
+            // the remainder is canceled when its balance can't be frozen
+            let (asset, frozen) = if is_order_ask(&order) {
+                (&self.base, order.remain)
+            } else {
+                (&self.quote, order.remain * order.price)
+            };
+            if let Err(err) = self.balance_manager.balance_frozen(order.user, asset, &frozen) {
+                tracing::error!("cancel the remainder of order {}: {}", order.id, err);
+                resting = false;
+            }
+        }
+        if resting {
             if real {
                 let order_message = OrderMessage {
                     event: OrderEventType::PUT,
@@ -548,8 +1592,17 @@ impl Market {
                 self.message_manager.push_order_message(&order_message);
             }
             order = self.insert_order(order_rc);
-            self.frozen_balance(&order);
         } else {
+            if !order.frozen.is_zero() {
+                let asset = if is_order_ask(&order) { &self.base } else { &self.quote };
+                self.balance_manager.balance_unfrozen(order.user, asset, &order.frozen);
+                order.frozen = Decimal::zero();
+            }
+            if by_quote {
+                // the remainder is canceled, only the executed part makes sense
+                order.amount = order.finished_base;
+                order.remain = Decimal::zero();
+            }
             if real {
                 self.history_writer.borrow_mut().append_order_history(&order);
                 let order_message = OrderMessage {
@@ -563,20 +1616,259 @@ impl Market {
         }
         Ok(order)
     }
+    // the order fee rates must not pay out more than the market allows, the taker fee is
+    // charged on the other side of any maker rebate
+    pub fn check_fee_rates(&self, order_input: &OrderInput) -> Result<()> {
+        if order_input.maker_fee < -self.max_maker_rebate {
+            return Err(anyhow!(
+                "maker fee {} exceeds max maker rebate {}",
+                order_input.maker_fee,
+                self.max_maker_rebate
+            ));
+        }
+        if order_input.taker_fee < self.max_maker_rebate {
+            return Err(anyhow!(
+                "taker fee {} does not cover max maker rebate {}",
+                order_input.taker_fee,
+                self.max_maker_rebate
+            ));
+        }
+        Ok(())
+    }
+    // reject dust orders, the amount and the price are rounded the same way as `put_order`
+    pub fn check_order_size(&self, order_input: &OrderInput) -> Result<()> {
+        let quote_prec_save = self.balance_manager.asset_prec(&self.quote);
+        let notional = if !order_input.quote_amount.is_zero() {
+            order_input.quote_amount.round_dp(quote_prec_save)
+        } else {
+            let amount = order_input.amount.round_dp(self.base_prec);
+            if amount.lt(&self.min_amount) {
+                return Err(anyhow!("order amount {} is less than min amount {}", amount, self.min_amount));
+            }
+            let price = if order_input.type_ == OrderType::LIMIT {
+                order_input.price.round_dp(self.quote_prec)
+            } else {
+                // market orders are estimated with the best counter price
+                let best_counter_order = if order_input.side == OrderSide::ASK {
+                    self.bids.values().next()
+                } else {
+                    self.asks.values().next()
+                };
+                match best_counter_order {
+                    Some(order) => order.borrow().price,
+                    // rejected by `put_order` later
+                    None => return Ok(()),
+                }
+            };
+            (amount * price).round_dp(quote_prec_save)
+        };
+        if notional.lt(&self.min_notional) {
+            return Err(anyhow!(
+                "order notional {} is less than min notional {}",
+                notional,
+                self.min_notional
+            ));
+        }
+        Ok(())
+    }
+    // whether the order would match the best counter order at once
+    fn would_cross(&self, side: OrderSide, price: &Decimal) -> bool {
+        if side == OrderSide::ASK {
+            self.bids
+                .values()
+                .next()
+                .map_or(false, |best_bid| price.le(&best_bid.borrow().price))
+        } else {
+            self.asks
+                .values()
+                .next()
+                .map_or(false, |best_ask| price.ge(&best_ask.borrow().price))
+        }
+    }
+    // walk the counter book the same way `execute_order` does, without changing anything
+    fn can_fill_fully(&self, order_input: &OrderInput, quote_limit: &Decimal) -> bool {
+        let is_limit_order = order_input.type_ == OrderType::LIMIT;
+        let counter_orders: Box<dyn Iterator<Item = &OrderRc>> = if order_input.side == OrderSide::ASK {
+            Box::new(self.bids.values())
+        } else {
+            Box::new(self.asks.values())
+        };
+        let mut remain = order_input.amount;
+        let mut quote_sum = Decimal::zero();
+        for maker in counter_orders {
+            if remain.is_zero() {
+                break;
+            }
+            let maker = maker.borrow();
+            if maker.user == order_input.user_id && order_input.self_trade_prevention != SelfTradePrevention::Allow {
+                if order_input.self_trade_prevention == SelfTradePrevention::CancelOldest {
+                    continue;
+                }
+                break;
+            }
+            if !within_price_limit(order_input.side, &order_input.price_limit(), &maker.price) {
+                break;
+            }
+            let mut traded_base_amount = min(remain, maker.remain);
+            if !is_limit_order && order_input.side == OrderSide::BID && (quote_sum + maker.price * traded_base_amount).gt(quote_limit) {
+                traded_base_amount = market_bid_base_amount(&(*quote_limit - quote_sum), &maker.price, self.base_prec);
+                remain -= traded_base_amount;
+                break;
+            }
+            quote_sum += maker.price * traded_base_amount;
+            remain -= traded_base_amount;
+        }
+        remain.is_zero()
+    }
     pub fn cancel(&mut self, real: bool, order_id: u64) -> Order {
         let order = self.orders.get(&order_id).unwrap();
         let order_struct = *order.borrow_mut();
         self.order_finish(real, &order_struct);
+        self.publish_book_update();
+        if real {
+            self.metrics.orders_cancelled.inc();
+        }
         order_struct
     }
-    pub fn cancel_all_for_user(&mut self, real: bool, user_id: u32) -> usize {
-        // TODO: can we mutate while iterate?
-        let order_ids: Vec<u64> = self.users.get(&user_id).unwrap_or(&BTreeMap::new()).keys().copied().collect();
-        let total = order_ids.len();
+    // Cancel the listed orders of a user, with a result for each id in the same order. The ids of
+    // finished orders are not found, and the orders of other users are left untouched.
+    pub fn cancel_orders(&mut self, real: bool, user_id: u32, order_ids: &[u64]) -> Vec<CancelResult> {
+        let mut results = Vec::with_capacity(order_ids.len());
         for order_id in order_ids {
-            self.cancel(real, order_id);
+            let order = match self.orders.get(order_id) {
+                Some(order) => *order.borrow(),
+                None => {
+                    results.push(CancelResult::NotFound);
+                    continue;
+                }
+            };
+            if order.user != user_id {
+                results.push(CancelResult::NotOwner);
+                continue;
+            }
+            self.order_finish(real, &order);
+            if real {
+                self.metrics.orders_cancelled.inc();
+            }
+            results.push(CancelResult::Cancelled(order));
+        }
+        self.publish_book_update();
+        results
+    }
+    // Change the price and/or the amount of a resting order, `None` keeps the old value.
+    // Only decreasing the amount keeps the time priority, otherwise the order queues again
+    // at its (new) price. The amended order is not matched, so a crossing price is rejected.
+    pub fn amend_order(&mut self, real: bool, order_id: u64, price: Option<Decimal>, amount: Option<Decimal>) -> Result<Order> {
+        let order_rc = self.orders.get(&order_id).ok_or_else(|| anyhow!("invalid order_id"))?.clone();
+        let old_order = *order_rc.borrow();
+        let price = price.map_or(old_order.price, |price| price.round_dp(self.quote_prec));
+        let amount = amount.map_or(old_order.amount, |amount| amount.round_dp(self.base_prec));
+        if price.is_sign_negative() || price.is_zero() {
+            return Err(anyhow!("invalid price for limit order"));
         }
-        total
+        if amount.lt(&self.min_amount) || amount.le(&old_order.finished_base) {
+            return Err(anyhow!("invalid amount"));
+        }
+        if old_order.is_iceberg() && old_order.display_qty.ge(&amount) {
+            return Err(anyhow!("invalid display quantity"));
+        }
+        if price != old_order.price && self.would_cross(old_order.side, &price) {
+            return Err(anyhow!("amended order would cross the book"));
+        }
+        // winding down, the orders can only give back their frozen balance
+        if real && !self.state.accepts_orders() && (price != old_order.price || amount > old_order.amount) {
+            return Err(anyhow!("market is {}: orders can only be reduced", self.state.as_str()));
+        }
+        let remain = amount - old_order.finished_base;
+        let frozen = if old_order.side == OrderSide::ASK { remain } else { remain * price };
+        let asset = if old_order.side == OrderSide::ASK {
+            &self.base
+        } else {
+            &self.quote
+        };
+        if frozen > old_order.frozen {
+            let delta = frozen - old_order.frozen;
+            if self
+                .balance_manager
+                .balance_get(old_order.user, BalanceType::AVAILABLE, asset)
+                .lt(&delta)
+            {
+                return Err(anyhow!("balance not enough"));
+            }
+            self.balance_manager.balance_frozen(old_order.user, asset, &delta)?;
+        } else if frozen < old_order.frozen {
+            self.balance_manager
+                .balance_unfrozen(old_order.user, asset, &(old_order.frozen - frozen));
+        }
+
+        let keep_priority = price == old_order.price && remain <= old_order.remain;
+        if !keep_priority {
+            if old_order.side == OrderSide::ASK {
+                self.asks.remove(&old_order.get_ask_key());
+            } else {
+                self.bids.remove(&old_order.get_bid_key());
+            }
+        }
+        let order = {
+            let mut order = order_rc.borrow_mut();
+            order.price = price;
+            order.amount = amount;
+            order.remain = remain;
+            order.frozen = frozen;
+            order.update_time = self.clock.now();
+            if order.is_iceberg() {
+                order.visible = if keep_priority {
+                    min(order.visible, remain)
+                } else {
+                    min(order.display_qty, remain)
+                };
+            }
+            if !keep_priority {
+                order.priority = self.next_priority();
+            }
+            *order
+        };
+        if !keep_priority {
+            if order.side == OrderSide::ASK {
+                self.asks.insert(order.get_ask_key(), order_rc.clone());
+            } else {
+                self.bids.insert(order.get_bid_key(), order_rc.clone());
+            }
+        }
+        self.book_feed.mark(order.side, old_order.price);
+        self.book_feed.mark(order.side, order.price);
+        if real {
+            let order_message = OrderMessage {
+                event: OrderEventType::UPDATE,
+                order,
+                base: self.base.clone(),
+                quote: self.quote.clone(),
+            };
+            self.message_manager.push_order_message(&order_message);
+        }
+        self.publish_book_update();
+        Ok(order)
+    }
+    // cancel the resting orders which have expired at `now`
+    pub fn expire_orders(&mut self, real: bool, now: f64) -> Vec<Order> {
+        let order_ids: Vec<u64> = self
+            .orders
+            .values()
+            .filter(|order_rc| order_rc.borrow().expire_at.map_or(false, |expire_at| expire_at <= now))
+            .map(|order_rc| order_rc.borrow().id)
+            .collect();
+        order_ids.into_iter().map(|order_id| self.cancel(real, order_id)).collect()
+    }
+    // the canceled orders of all the users, as a delisted market is cleared
+    pub fn cancel_all(&mut self, real: bool) -> Vec<Order> {
+        let order_ids: Vec<u64> = self.orders.keys().copied().collect();
+        order_ids.into_iter().map(|order_id| self.cancel(real, order_id)).collect()
+    }
+    // return the canceled orders, empty if the user has no open orders
+    pub fn cancel_all_for_user(&mut self, real: bool, user_id: u32) -> Vec<Order> {
+        // TODO: can we mutate while iterate?
+        let order_ids: Vec<u64> = self.users.get(&user_id).unwrap_or(&BTreeMap::new()).keys().copied().collect();
+        order_ids.into_iter().map(|order_id| self.cancel(real, order_id)).collect()
     }
     pub fn get(&self, order_id: u64) -> Option<Order> {
         self.orders.get(&order_id).map(|o| *o.borrow_mut())
@@ -595,16 +1887,43 @@ impl Market {
             println!("{}, {:?}", k, v.borrow())
         }
     }
+    // the best bid must be below the best ask, the crossing orders would have matched
+    pub fn verify_integrity(&self) -> Vec<IntegrityViolation> {
+        let mut violations = Vec::new();
+        if let (Some(best_bid), Some(best_ask)) = (self.bids.keys().next(), self.asks.keys().next()) {
+            if best_bid.order_price >= best_ask.order_price {
+                violations.push(IntegrityViolation::CrossedBook {
+                    market: self.name.to_string(),
+                    best_bid: best_bid.order_price,
+                    best_ask: best_ask.order_price,
+                });
+            }
+        }
+        violations
+    }
     pub fn status(&self) -> MarketStatus {
         MarketStatus {
             name: self.name.to_string(),
             ask_count: self.asks.len(),
-            ask_amount: self.asks.values().map(|item| item.borrow_mut().remain).sum(),
+            ask_amount: self.asks.values().map(|item| item.borrow_mut().visible_amount()).sum(),
             bid_count: self.bids.len(),
-            bid_amount: self.bids.values().map(|item| item.borrow_mut().remain).sum(),
+            bid_amount: self.bids.values().map(|item| item.borrow_mut().visible_amount()).sum(),
             trade_count: self.trade_count,
+            halted: self.is_halted(),
+            state: self.state,
         }
     }
+    pub fn ticker(&mut self, now: f64) -> TickerSummary {
+        self.ticker.summary(now, self.last_price)
+    }
+    // zero means no grouping, otherwise the interval should be a multiple of the price tick
+    pub fn check_depth_interval(&self, interval: &Decimal) -> Result<()> {
+        if interval.is_sign_negative() || interval.round_dp(self.quote_prec) != *interval {
+            return Err(anyhow!("invalid interval {} for price precision {}", interval, self.quote_prec));
+        }
+        Ok(())
+    }
+    // price levels grouped by `interval`, asks are rounded up and bids are rounded down
     pub fn depth(&self, limit: usize, interval: &Decimal) -> MarketDepth {
         if interval.is_zero() {
             let id_fn = |order: &Order| -> Decimal { order.price };
@@ -613,8 +1932,9 @@ impl Market {
                 bids: Self::group_ordebook_by_fn(&self.bids, limit, id_fn),
             }
         } else {
-            let ask_group_fn = |order: &Order| -> Decimal { (order.price / interval).ceil() * interval };
-            let bid_group_fn = |order: &Order| -> Decimal { (order.price / interval).floor() * interval };
+            let quote_prec = self.quote_prec;
+            let ask_group_fn = |order: &Order| -> Decimal { ((order.price / interval).ceil() * interval).round_dp(quote_prec) };
+            let bid_group_fn = |order: &Order| -> Decimal { ((order.price / interval).floor() * interval).round_dp(quote_prec) };
             MarketDepth {
                 asks: Self::group_ordebook_by_fn(&self.asks, limit, ask_group_fn),
                 bids: Self::group_ordebook_by_fn(&self.bids, limit, bid_group_fn),
@@ -622,6 +1942,199 @@ impl Market {
         }
     }
 
+    // the resting orders by price-time priority, asks first
+    pub fn book_snapshot(&self) -> BookSnapshot {
+        let orders = self
+            .asks
+            .values()
+            .chain(self.bids.values())
+            .map(|order| BookSnapshotOrder::from(&*order.borrow()))
+            .collect();
+        BookSnapshot {
+            version: BOOK_SNAPSHOT_VERSION,
+            market: self.name.to_string(),
+            time: self.clock.now(),
+            orders,
+        }
+    }
+    // Rebuild the book of an empty market from a snapshot, and freeze the balances of the orders
+    // again. Everything is checked before anything is changed, the snapshot is refused if the
+    // AVAILABLE balance of a user can't cover the orders. Returns the number of orders restored.
+    pub fn restore_book(&mut self, snapshot: &BookSnapshot) -> Result<usize> {
+        if snapshot.version != BOOK_SNAPSHOT_VERSION {
+            return Err(anyhow!("unsupported book snapshot version {}", snapshot.version));
+        }
+        if snapshot.market != self.name {
+            return Err(anyhow!("book snapshot of market {} is not for {}", snapshot.market, self.name));
+        }
+        if !self.orders.is_empty() {
+            return Err(anyhow!("the book of {} is not empty", self.name));
+        }
+        let mut ids = BTreeSet::new();
+        let mut priorities = BTreeSet::new();
+        let mut required: BTreeMap<(u32, &str), Decimal> = BTreeMap::new();
+        for order in &snapshot.orders {
+            if !ids.insert(order.id) || !priorities.insert(order.priority) {
+                return Err(anyhow!("duplicated order {} in book snapshot", order.id));
+            }
+            if !order.price.is_sign_positive()
+                || order.price.is_zero()
+                || order.price.round_dp(self.quote_prec) != order.price
+                || !order.remain.is_sign_positive()
+                || order.remain.is_zero()
+                || order.remain > order.amount
+                || order.visible > order.remain
+            {
+                return Err(anyhow!("invalid order {} in book snapshot", order.id));
+            }
+            let (asset, frozen) = if order.side == OrderSide::ASK {
+                (self.base.as_str(), order.remain)
+            } else {
+                (self.quote.as_str(), order.remain * order.price)
+            };
+            *required.entry((order.user, asset)).or_insert_with(Decimal::zero) += frozen;
+        }
+        let best_ask = snapshot.orders.iter().filter(|o| o.side == OrderSide::ASK).map(|o| o.price).min();
+        let best_bid = snapshot.orders.iter().filter(|o| o.side == OrderSide::BID).map(|o| o.price).max();
+        if let (Some(best_ask), Some(best_bid)) = (best_ask, best_bid) {
+            if best_bid >= best_ask {
+                return Err(anyhow!("book snapshot is crossed: bid {} >= ask {}", best_bid, best_ask));
+            }
+        }
+        for ((user_id, asset), frozen) in &required {
+            let available = self.balance_manager.balance_get(*user_id, BalanceType::AVAILABLE, asset);
+            if available < *frozen || !self.balance_manager.can_add(*user_id, BalanceType::FREEZE, asset, frozen) {
+                return Err(anyhow!(
+                    "balance of user {} doesn't reconcile with book snapshot: {} {} < {}",
+                    user_id,
+                    asset,
+                    available,
+                    frozen
+                ));
+            }
+        }
+
+        // the ids of the snapshot are taken, so new orders don't reuse them
+        let max_id = ids.iter().copied().max().unwrap_or(0);
+        if max_id > self.sequencer.borrow().get_order_id() {
+            self.sequencer.borrow_mut().set_order_id(max_id);
+        }
+        for order in &snapshot.orders {
+            let order_rc = Rc::new(RefCell::new(Order {
+                id: order.id,
+                market: self.name,
+                type_: OrderType::LIMIT,
+                side: order.side,
+                user: order.user,
+                create_time: order.create_time,
+                update_time: order.update_time,
+                price: order.price,
+                amount: order.amount,
+                taker_fee: order.taker_fee,
+                maker_fee: order.maker_fee,
+                remain: order.remain,
+                frozen: Decimal::zero(),
+                finished_base: order.finished_base,
+                finished_quote: order.finished_quote,
+                finished_fee: order.finished_fee,
+                finished_paid_fee: order.finished_paid_fee,
+                display_qty: order.display_qty,
+                visible: order.visible,
+                priority: order.priority,
+                expire_at: order.expire_at,
+            }));
+            let order = self.insert_order(order_rc);
+            // checked above, it doesn't fail
+            self.frozen_balance(&order)?;
+        }
+        tracing::info!("restored {} orders of {} from book snapshot", snapshot.orders.len(), self.name);
+        Ok(snapshot.orders.len())
+    }
+
+    // the current snapshot is delivered first, then the changed levels after each operation
+    pub fn subscribe_book(&mut self) -> futures_channel::mpsc::Receiver<BookUpdate> {
+        if self.book_feed.subscribers.is_empty() {
+            // levels are not tracked without subscribers, so rebuild them from the book
+            let depth = self.depth(usize::MAX, &Decimal::zero());
+            self.book_feed.asks = depth.asks.into_iter().map(|info| (info.price, info.amount)).collect();
+            self.book_feed.bids = depth.bids.into_iter().map(|info| (info.price, info.amount)).collect();
+        }
+        let snapshot = BookUpdate {
+            market: self.name.to_string(),
+            seq: self.book_feed.last_seq,
+            prev_seq: self.book_feed.last_seq,
+            snapshot: true,
+            asks: snapshot_levels(self.book_feed.asks.iter()),
+            bids: snapshot_levels(self.book_feed.bids.iter().rev()),
+        };
+        self.book_feed.subscribers.subscribe(Some(snapshot))
+    }
+
+    pub fn subscribe_trades(&mut self) -> futures_channel::mpsc::Receiver<Trade> {
+        self.trade_subscribers.subscribe(None)
+    }
+
+    // the events of the orders of a user, only the new ones are sent
+    pub fn subscribe_orders(&mut self, user_id: u32, sender: futures_channel::mpsc::Sender<OrderMessage>) {
+        self.message_manager.order_subscribers.entry(user_id).or_default().attach(sender);
+    }
+
+    fn level_amount(&self, side: OrderSide, price: Decimal) -> Decimal {
+        if side == OrderSide::ASK {
+            let range = MarketKeyAsk {
+                order_price: price,
+                priority: 0,
+            }..=MarketKeyAsk {
+                order_price: price,
+                priority: u64::MAX,
+            };
+            self.asks.range(range).map(|(_, order_rc)| order_rc.borrow().visible_amount()).sum()
+        } else {
+            // bids of the same price are sorted by priority descending
+            let range = MarketKeyBid {
+                order_price: price,
+                priority: u64::MAX,
+            }..=MarketKeyBid {
+                order_price: price,
+                priority: 0,
+            };
+            self.bids.range(range).map(|(_, order_rc)| order_rc.borrow().visible_amount()).sum()
+        }
+    }
+
+    // send the levels changed since the last update to the subscribers
+    fn publish_book_update(&mut self) {
+        if self.book_feed.subscribers.is_empty() {
+            return;
+        }
+        let dirty_asks = std::mem::take(&mut self.book_feed.dirty_asks);
+        let dirty_bids = std::mem::take(&mut self.book_feed.dirty_bids);
+        let mut asks = Vec::new();
+        for price in dirty_asks {
+            let amount = self.level_amount(OrderSide::ASK, price);
+            asks.extend(diff_price_level(&mut self.book_feed.asks, price, amount));
+        }
+        let mut bids = Vec::new();
+        for price in dirty_bids.into_iter().rev() {
+            let amount = self.level_amount(OrderSide::BID, price);
+            bids.extend(diff_price_level(&mut self.book_feed.bids, price, amount));
+        }
+        if asks.is_empty() && bids.is_empty() {
+            return;
+        }
+        let seq = self.sequencer.borrow_mut().next_book_update_id();
+        let update = BookUpdate {
+            market: self.name.to_string(),
+            seq,
+            prev_seq: self.book_feed.last_seq,
+            snapshot: false,
+            asks,
+            bids,
+        };
+        self.book_feed.last_seq = seq;
+        self.book_feed.subscribers.publish(&update);
+    }
+
     fn group_ordebook_by_fn<K, F>(orderbook: &BTreeMap<K, OrderRc>, limit: usize, f: F) -> Vec<PriceInfo>
     where
         F: Fn(&Order) -> Decimal,
@@ -633,7 +2146,7 @@ impl Market {
             .take(limit)
             .map(|(price, group)| PriceInfo {
                 price,
-                amount: group.map(|order_rc| order_rc.borrow_mut().remain).sum(),
+                amount: group.map(|order_rc| order_rc.borrow_mut().visible_amount()).sum(),
             })
             .collect::<Vec<PriceInfo>>()
     }
@@ -646,6 +2159,20 @@ pub struct MarketStatus {
     pub bid_count: usize,
     pub bid_amount: Decimal,
     pub trade_count: u64,
+    pub halted: bool,
+    pub state: MarketState,
+}
+
+// the 24 hours aggregates of the trades, zeros without any trade in the window
+pub struct TickerSummary {
+    pub last_price: Decimal,
+    pub open: Decimal,
+    pub high: Decimal,
+    pub low: Decimal,
+    pub base_volume: Decimal,
+    pub quote_volume: Decimal,
+    // from the open to the last price
+    pub price_change_percent: Decimal,
 }
 
 pub struct PriceInfo {
@@ -658,6 +2185,99 @@ pub struct MarketDepth {
     pub bids: Vec<PriceInfo>,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PriceLevelUpdate {
+    pub update_type: BookUpdateType,
+    pub price: Decimal,
+    // zero for removed levels
+    pub amount: Decimal,
+}
+
+// `seq` comes from the sequencer and is shared by all the markets, so it is increasing but not
+// continuous inside one market. A subscriber has missed updates if `prev_seq` is not the `seq` of
+// the last update it received, and it should subscribe again for a new snapshot.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BookUpdate {
+    pub market: String,
+    pub seq: u64,
+    pub prev_seq: u64,
+    pub snapshot: bool,
+    // asks are ascending and bids are descending by price
+    pub asks: Vec<PriceLevelUpdate>,
+    pub bids: Vec<PriceLevelUpdate>,
+}
+
+pub const BOOK_SNAPSHOT_VERSION: u32 = 1;
+
+// The resting orders of one market, without the rest of the engine state. Only limit orders rest
+// on the book, `priority` keeps their time priority.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BookSnapshot {
+    pub version: u32,
+    pub market: String,
+    pub time: f64,
+    pub orders: Vec<BookSnapshotOrder>,
+}
+
+impl BookSnapshot {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+    pub fn from_json(data: &str) -> Result<BookSnapshot> {
+        Ok(serde_json::from_str(data)?)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BookSnapshotOrder {
+    pub id: u64,
+    pub side: OrderSide,
+    pub user: u32,
+    pub create_time: f64,
+    pub update_time: f64,
+    pub price: Decimal,
+    pub amount: Decimal,
+    pub taker_fee: Decimal,
+    pub maker_fee: Decimal,
+    pub remain: Decimal,
+    pub finished_base: Decimal,
+    pub finished_quote: Decimal,
+    pub finished_fee: Decimal,
+    // snapshots taken before fees could be converted have none
+    #[serde(default)]
+    pub finished_paid_fee: Decimal,
+    pub display_qty: Decimal,
+    pub visible: Decimal,
+    pub priority: u64,
+    pub expire_at: Option<f64>,
+}
+
+impl From<&Order> for BookSnapshotOrder {
+    fn from(order: &Order) -> Self {
+        BookSnapshotOrder {
+            id: order.id,
+            side: order.side,
+            user: order.user,
+            create_time: order.create_time,
+            update_time: order.update_time,
+            price: order.price,
+            amount: order.amount,
+            taker_fee: order.taker_fee,
+            maker_fee: order.maker_fee,
+            remain: order.remain,
+            finished_base: order.finished_base,
+            finished_quote: order.finished_quote,
+            finished_fee: order.finished_fee,
+            finished_paid_fee: order.finished_paid_fee,
+            display_qty: order.display_qty,
+            visible: order.visible,
+            priority: order.priority,
+            expire_at: order.expire_at,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct OrderInput {
     pub user_id: u32,
     pub side: OrderSide,
@@ -667,6 +2287,309 @@ pub struct OrderInput {
     pub taker_fee: Decimal, // FIXME fee should be determined inside engine rather than take from input
     pub maker_fee: Decimal,
     pub market: String,
+    pub time_in_force: TimeInForce,
+    pub post_only: bool,
+    pub self_trade_prevention: SelfTradePrevention,
+    // only for market bid orders: spend up to this quote amount, `amount` should be zero then
+    pub quote_amount: Decimal,
+    // only for GTC/GTT limit orders: show at most this amount on the book, zero means no iceberg
+    pub display_qty: Decimal,
+    // only for GTT limit orders: cancel the order at this timestamp
+    pub expire_at: Option<f64>,
+    // only for market orders: don't trade beyond this price, zero means no limit
+    #[serde(default)]
+    pub protection_price: Decimal,
+    // when the order is journaled, none for the current time
+    #[serde(default)]
+    pub timestamp: Option<f64>,
+}
+
+impl OrderInput {
+    // the combinations of the time in force and the other flags, before any amount is checked
+    pub fn check_flags(&self) -> Result<()> {
+        let tif = self.time_in_force;
+        if tif == TimeInForce::GTT && self.expire_at.is_none() {
+            return Err(anyhow!("GTT orders require expire_at"));
+        }
+        if tif != TimeInForce::GTT && self.expire_at.is_some() {
+            return Err(anyhow!("expire_at is only valid for GTT orders, not {:?}", tif));
+        }
+        if self.type_ == OrderType::MARKET && tif == TimeInForce::GTT {
+            return Err(anyhow!("GTT is only valid for limit orders"));
+        }
+        let resting_limit = self.type_ == OrderType::LIMIT && tif.is_resting();
+        if self.post_only && !resting_limit {
+            return Err(anyhow!(
+                "post-only is only valid for GTC or GTT limit orders, not {:?} {:?}",
+                tif,
+                self.type_
+            ));
+        }
+        if !self.display_qty.is_zero() && !resting_limit {
+            return Err(anyhow!(
+                "iceberg is only valid for GTC or GTT limit orders, not {:?} {:?}",
+                tif,
+                self.type_
+            ));
+        }
+        if tif == TimeInForce::FOK && !self.quote_amount.is_zero() {
+            return Err(anyhow!("fill-or-kill is not supported for market orders with quote amount"));
+        }
+        Ok(())
+    }
+    // the worst price the order may trade at
+    pub fn price_limit(&self) -> Option<Decimal> {
+        if self.type_ == OrderType::LIMIT {
+            Some(self.price)
+        } else if !self.protection_price.is_zero() {
+            Some(self.protection_price)
+        } else {
+            None
+        }
+    }
+}
+
+// max open orders of a user in one market, configured per user
+pub struct OpenOrderLimits {
+    default_limit: usize,
+    user_limits: HashMap<u32, usize>,
+}
+
+impl OpenOrderLimits {
+    pub fn new(config: &config::OpenOrderLimitConfig) -> OpenOrderLimits {
+        OpenOrderLimits {
+            default_limit: config.max_open_orders_per_market,
+            user_limits: config
+                .user_overrides
+                .iter()
+                .map(|limit| (limit.user_id, limit.max_open_orders_per_market))
+                .collect(),
+        }
+    }
+    // zero for no limit
+    pub fn limit(&self, user_id: u32) -> usize {
+        *self.user_limits.get(&user_id).unwrap_or(&self.default_limit)
+    }
+    // `pending` orders of the user are to be placed before this one
+    pub fn check(&self, market: &Market, user_id: u32, pending: usize) -> Result<()> {
+        let limit = self.limit(user_id);
+        let open = market.users.get(&user_id).map_or(0, |orders| orders.len()) + pending;
+        if limit != 0 && open >= limit {
+            return Err(anyhow!(
+                "too many open orders in market {}: {} of at most {}, cancel some first",
+                market.name,
+                open,
+                limit
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum CancelResult {
+    Cancelled(Order),
+    NotFound,
+    // the order is of another user
+    NotOwner,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BatchMode {
+    // each order is placed or rejected on its own
+    BestEffort,
+    // the orders are checked together first, and none is placed if any is rejected
+    AllOrNothing,
+}
+
+// Checks the orders of a batch as if they were placed one after another, the balances they need
+// are summed by user and asset over all the markets. Returns the index of the first rejected order.
+// The orders are checked against the books before the batch, so orders of a batch crossing each
+// other may still be rejected when placed.
+pub fn check_order_batch(markets: &HashMap<String, Market>, orders: &[OrderInput]) -> std::result::Result<(), (usize, anyhow::Error)> {
+    let mut required: HashMap<(u32, String), Decimal> = HashMap::new();
+    for (idx, order_input) in orders.iter().enumerate() {
+        let market = markets.get(&order_input.market).ok_or_else(|| (idx, anyhow!("invalid market")))?;
+        let (asset, amount) = market.check_order(order_input).map_err(|e| (idx, e))?;
+        let total = required.entry((order_input.user_id, asset.clone())).or_insert_with(Decimal::zero);
+        *total += amount;
+        if market
+            .balance_manager
+            .balance_get(order_input.user_id, BalanceType::AVAILABLE, &asset)
+            .lt(total)
+        {
+            return Err((idx, anyhow!("balance not enough for the batch")));
+        }
+    }
+    Ok(())
+}
+
+// an invariant of the engine state which doesn't hold, found by `verify_integrity`
+#[derive(Debug, Clone, PartialEq)]
+pub enum IntegrityViolation {
+    // the best bid is not below the best ask
+    CrossedBook {
+        market: String,
+        best_bid: Decimal,
+        best_ask: Decimal,
+    },
+    // the FREEZE balance held for orders is not the sum frozen by the open orders
+    FrozenMismatch {
+        user_id: u32,
+        asset: String,
+        frozen: Decimal,
+        reserved: Decimal,
+    },
+}
+
+impl std::fmt::Display for IntegrityViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IntegrityViolation::CrossedBook {
+                market,
+                best_bid,
+                best_ask,
+            } => {
+                write!(f, "book of {} is crossed: best bid {} >= best ask {}", market, best_bid, best_ask)
+            }
+            IntegrityViolation::FrozenMismatch {
+                user_id,
+                asset,
+                frozen,
+                reserved,
+            } => write!(
+                f,
+                "user {} has {} {} frozen for orders, but the open orders reserve {}",
+                user_id, frozen, asset, reserved
+            ),
+        }
+    }
+}
+
+// The checks of every market, then the frozen balances of every user against the open orders
+// of all the markets. Read only, the state is reported as it is.
+pub fn verify_integrity(markets: &HashMap<String, Market>, balance_manager: &BalanceManager) -> Vec<IntegrityViolation> {
+    let mut violations: Vec<IntegrityViolation> = markets.keys().sorted().flat_map(|name| markets[name].verify_integrity()).collect();
+    let mut reserved: BTreeMap<(u32, String), Decimal> = BTreeMap::new();
+    for market in markets.values() {
+        for order_rc in market.orders.values() {
+            let order = order_rc.borrow();
+            let asset = if is_order_ask(&order) { &market.base } else { &market.quote };
+            *reserved.entry((order.user, asset.clone())).or_insert_with(Decimal::zero) += order.frozen;
+        }
+    }
+    // the frozen balances without any open order
+    for key in balance_manager.balances.keys() {
+        if key.balance_type == BalanceType::FREEZE {
+            reserved.entry((key.user_id, key.asset.clone())).or_insert_with(Decimal::zero);
+        }
+    }
+    for ((user_id, asset), reserved) in reserved {
+        let frozen = balance_manager.frozen_for_purpose(user_id, &asset, &FreezePurpose::Order);
+        if frozen != reserved {
+            violations.push(IntegrityViolation::FrozenMismatch {
+                user_id,
+                asset,
+                frozen,
+                reserved,
+            });
+        }
+    }
+    violations
+}
+
+fn order_fill_message(order: &Order, trade: &Trade, role: MarketRole) -> OrderFillMessage {
+    OrderFillMessage {
+        timestamp: trade.timestamp,
+        market: trade.market.clone(),
+        order_id: order.id,
+        user_id: order.user,
+        trade_id: trade.id,
+        side: order.side,
+        role,
+        price: trade.price,
+        filled: trade.amount,
+        cumulative_filled: order.finished_base,
+        remain: order.remain,
+        status: if order.remain.is_zero() {
+            OrderFillStatus::Filled
+        } else {
+            OrderFillStatus::PartiallyFilled
+        },
+    }
+}
+
+// Stage the fee of one side of a trade, `fee` is negative for a rebate. Returns the index of the
+// change whose balance is recorded in the fee history: the credit of the fee account for a fee, of
+// the user for a rebate. A rebate is paid to the maker with the trade and recorded as a balance
+// change of its own.
+fn stage_fee(settlement: &mut BalanceTransaction, fee_account: Option<u32>, user_id: u32, asset: &str, fee: &Decimal) -> Option<usize> {
+    if *fee > Decimal::zero() {
+        settlement.sub(user_id, BalanceType::AVAILABLE, asset, fee);
+        fee_account.map(|fee_account| settlement.add(fee_account, BalanceType::AVAILABLE, asset, fee))
+    } else if fee.is_sign_negative() {
+        Some(settlement.add(user_id, BalanceType::AVAILABLE, asset, &-fee))
+    } else {
+        None
+    }
+}
+
+// the history row of a change staged by `stage_fee`
+fn append_trade_fee_history(
+    history_writer: &Rc<RefCell<dyn HistoryWriter>>,
+    fee_account: Option<u32>,
+    user_id: u32,
+    asset: &str,
+    fee: Decimal,
+    balance: Decimal,
+    detail: &BalanceHistoryFromFee,
+    timestamp: f64,
+) {
+    if fee > Decimal::zero() {
+        append_fee_history(
+            history_writer,
+            fee_account.unwrap(),
+            asset,
+            BusinessKind::Fee,
+            fee,
+            balance,
+            detail,
+            timestamp,
+        );
+    } else {
+        append_fee_history(
+            history_writer,
+            user_id,
+            asset,
+            BusinessKind::Rebate,
+            -fee,
+            balance,
+            detail,
+            timestamp,
+        );
+    }
+}
+
+fn append_fee_history(
+    history_writer: &Rc<RefCell<dyn HistoryWriter>>,
+    user_id: u32,
+    asset: &str,
+    business: BusinessKind,
+    change: Decimal,
+    balance: Decimal,
+    detail: &BalanceHistoryFromFee,
+    timestamp: f64,
+) {
+    let balance_history = models::BalanceHistory {
+        time: FTimestamp(timestamp).into(),
+        user_id: user_id as i32,
+        asset: asset.to_string(),
+        business,
+        change,
+        balance,
+        detail: serde_json::to_string(detail).unwrap(),
+    };
+    history_writer.borrow_mut().append_balance_history(balance_history);
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -691,7 +2614,8 @@ mod tests {
     use super::*;
     use crate::asset::AssetManager;
     use crate::history::DummyHistoryWriter;
-    use crate::message::DummyMessageManager;
+    use crate::message::{DummyMessageManager, NullMessageManager};
+    use crate::utils::{self, MockClock};
     use rust_decimal_macros::*;
 
     fn get_simple_market_config() -> config::Market {
@@ -700,7 +2624,17 @@ mod tests {
             base: config::MarketUnit { name: eth(), prec: 4 },   // amount: xx.xxxx
             quote: config::MarketUnit { name: usdt(), prec: 2 }, // price xx.xx
             fee_prec: 3,
+            fee_rounding: None,
+            fee_side: config::FeeSide::Received,
             min_amount: dec!(0.01),
+            min_notional: dec!(0),
+            matching_mode: config::MatchingMode::PriceTime,
+            circuit_breaker: Default::default(),
+            price_band: dec!(0),
+            kline: Default::default(),
+            max_maker_rebate: dec!(0),
+            tick_size: dec!(0),
+            lot_size: dec!(0),
         }
     }
     fn get_simple_asset_config() -> Vec<config::Asset> {
@@ -709,11 +2643,13 @@ mod tests {
                 name: usdt(),
                 prec_save: 8,
                 prec_show: 8,
+                ..Default::default()
             },
             config::Asset {
                 name: eth(),
                 prec_show: 8,
                 prec_save: 8,
+                ..Default::default()
             },
         ]
     }
@@ -729,11 +2665,14 @@ mod tests {
     fn get_simple_balance_manager() -> BalanceManager {
         BalanceManager::new(&get_simple_asset_config()).unwrap()
     }
+    fn get_fee_tier_manager() -> Rc<RefCell<FeeTierManager>> {
+        Rc::new(RefCell::new(FeeTierManager::new(&Default::default()).unwrap()))
+    }
     fn init_balance(balance_manager: &mut BalanceManager) {
-        balance_manager.add(101, BalanceType::AVAILABLE, &usdt(), &dec!(300));
-        balance_manager.add(102, BalanceType::AVAILABLE, &usdt(), &dec!(300));
-        balance_manager.add(101, BalanceType::AVAILABLE, &eth(), &dec!(1000));
-        balance_manager.add(102, BalanceType::AVAILABLE, &eth(), &dec!(1000));
+        balance_manager.add(101, BalanceType::AVAILABLE, &usdt(), &dec!(300)).unwrap();
+        balance_manager.add(102, BalanceType::AVAILABLE, &usdt(), &dec!(300)).unwrap();
+        balance_manager.add(101, BalanceType::AVAILABLE, &eth(), &dec!(1000)).unwrap();
+        balance_manager.add(102, BalanceType::AVAILABLE, &eth(), &dec!(1000)).unwrap();
     }
 
     #[test]
@@ -748,8 +2687,10 @@ mod tests {
             &get_simple_market_config(),
             balance_manager_rc.clone(),
             sequencer,
+            get_fee_tier_manager(),
             Rc::new(RefCell::new(DummyHistoryWriter)),
             Rc::new(RefCell::new(DummyMessageManager)),
+            Metrics::default(),
         )
         .unwrap();
         let ask_order_input = OrderInput {
@@ -761,6 +2702,14 @@ mod tests {
             taker_fee: dec!(0.001),
             maker_fee: dec!(0.001),
             market: market.name.to_string(),
+            time_in_force: TimeInForce::GTC,
+            post_only: false,
+            self_trade_prevention: SelfTradePrevention::Allow,
+            quote_amount: Decimal::zero(),
+            display_qty: Decimal::zero(),
+            expire_at: None,
+            protection_price: Decimal::zero(),
+            timestamp: None,
         };
         let ask_order = market.put_order(false, ask_order_input).unwrap();
         assert_eq!(ask_order.id, 1);
@@ -776,6 +2725,14 @@ mod tests {
             taker_fee: dec!(0.001),
             maker_fee: dec!(0.001),
             market: market.name.to_string(),
+            time_in_force: TimeInForce::GTC,
+            post_only: false,
+            self_trade_prevention: SelfTradePrevention::Allow,
+            quote_amount: Decimal::zero(),
+            display_qty: Decimal::zero(),
+            expire_at: None,
+            protection_price: Decimal::zero(),
+            timestamp: None,
         };
         let bid_order = market.put_order(false, bid_order_input).unwrap();
         // trade: price: 0.10 amount: 10
@@ -807,4 +2764,2013 @@ mod tests {
         assert_eq!(balance_manager.get(bid_user_id, BalanceType::AVAILABLE, &usdt()), dec!(299));
         assert_eq!(balance_manager.get(bid_user_id, BalanceType::FREEZE, &usdt()), dec!(0));
     }
+
+    fn get_simple_market(balance_manager_rc: Rc<RefCell<BalanceManager>>) -> Market {
+        Market::new(
+            &get_simple_market_config(),
+            balance_manager_rc,
+            Rc::new(RefCell::new(Sequencer::default())),
+            get_fee_tier_manager(),
+            Rc::new(RefCell::new(DummyHistoryWriter)),
+            Rc::new(RefCell::new(DummyMessageManager)),
+            Metrics::default(),
+        )
+        .unwrap()
+    }
+
+    fn limit_order_input(user_id: u32, side: OrderSide, amount: Decimal, price: Decimal, time_in_force: TimeInForce) -> OrderInput {
+        OrderInput {
+            user_id,
+            side,
+            type_: OrderType::LIMIT,
+            amount,
+            price,
+            taker_fee: dec!(0),
+            maker_fee: dec!(0),
+            market: String::from("ETH_USDT"),
+            time_in_force,
+            post_only: false,
+            self_trade_prevention: SelfTradePrevention::Allow,
+            quote_amount: Decimal::zero(),
+            display_qty: Decimal::zero(),
+            expire_at: None,
+            protection_price: Decimal::zero(),
+            timestamp: None,
+        }
+    }
+
+    #[test]
+    fn test_fok_cannot_fully_fill() {
+        let mut balance_manager = get_simple_balance_manager();
+        init_balance(&mut balance_manager);
+        let balance_manager_rc = Rc::new(RefCell::new(balance_manager));
+        let mut market = get_simple_market(balance_manager_rc.clone());
+        market
+            .put_order(false, limit_order_input(101, OrderSide::ASK, dec!(10), dec!(1), TimeInForce::GTC))
+            .unwrap();
+        let result = market.put_order(false, limit_order_input(102, OrderSide::BID, dec!(20), dec!(1), TimeInForce::FOK));
+        assert!(result.is_err());
+
+        // nothing is changed
+        assert_eq!(market.asks.len(), 1);
+        assert_eq!(market.bids.len(), 0);
+        assert_eq!(market.asks.values().next().unwrap().borrow().remain, dec!(10));
+        assert_eq!(market.sequencer.borrow().get_order_id(), 1);
+        let balance_manager = balance_manager_rc.borrow();
+        assert_eq!(balance_manager.get(101, BalanceType::FREEZE, &eth()), dec!(10));
+        assert_eq!(balance_manager.get(102, BalanceType::AVAILABLE, &usdt()), dec!(300));
+        assert_eq!(balance_manager.get(102, BalanceType::AVAILABLE, &eth()), dec!(1000));
+    }
+
+    #[test]
+    fn test_fok_fully_filled() {
+        let mut balance_manager = get_simple_balance_manager();
+        init_balance(&mut balance_manager);
+        let balance_manager_rc = Rc::new(RefCell::new(balance_manager));
+        let mut market = get_simple_market(balance_manager_rc.clone());
+        market
+            .put_order(false, limit_order_input(101, OrderSide::ASK, dec!(10), dec!(1), TimeInForce::GTC))
+            .unwrap();
+        let order = market
+            .put_order(false, limit_order_input(102, OrderSide::BID, dec!(10), dec!(1), TimeInForce::FOK))
+            .unwrap();
+        assert_eq!(order.remain, dec!(0));
+        assert_eq!(market.asks.len(), 0);
+        assert_eq!(balance_manager_rc.borrow().get(102, BalanceType::AVAILABLE, &eth()), dec!(1010));
+    }
+
+    #[test]
+    fn test_ioc_partial_fill() {
+        let mut balance_manager = get_simple_balance_manager();
+        init_balance(&mut balance_manager);
+        let balance_manager_rc = Rc::new(RefCell::new(balance_manager));
+        let mut market = get_simple_market(balance_manager_rc.clone());
+        market
+            .put_order(false, limit_order_input(101, OrderSide::ASK, dec!(10), dec!(1), TimeInForce::GTC))
+            .unwrap();
+        let order = market
+            .put_order(false, limit_order_input(102, OrderSide::BID, dec!(20), dec!(1), TimeInForce::IOC))
+            .unwrap();
+        assert_eq!(order.finished_base, dec!(10));
+        assert_eq!(order.remain, dec!(10));
+        // the remainder does not rest on the book
+        assert_eq!(market.asks.len(), 0);
+        assert_eq!(market.bids.len(), 0);
+        assert!(market.get(order.id).is_none());
+        let balance_manager = balance_manager_rc.borrow();
+        assert_eq!(balance_manager.get(102, BalanceType::AVAILABLE, &eth()), dec!(1010));
+        assert_eq!(balance_manager.get(102, BalanceType::AVAILABLE, &usdt()), dec!(290));
+        assert_eq!(balance_manager.get(102, BalanceType::FREEZE, &usdt()), dec!(0));
+    }
+
+    #[test]
+    fn test_post_only() {
+        let mut balance_manager = get_simple_balance_manager();
+        init_balance(&mut balance_manager);
+        let balance_manager_rc = Rc::new(RefCell::new(balance_manager));
+        let mut market = get_simple_market(balance_manager_rc.clone());
+        market
+            .put_order(false, limit_order_input(101, OrderSide::ASK, dec!(10), dec!(1), TimeInForce::GTC))
+            .unwrap();
+
+        let crossing = OrderInput {
+            post_only: true,
+            ..limit_order_input(102, OrderSide::BID, dec!(10), dec!(1), TimeInForce::GTC)
+        };
+        assert!(market.put_order(false, crossing).is_err());
+        assert_eq!(market.sequencer.borrow().get_order_id(), 1);
+        assert_eq!(market.asks.len(), 1);
+        assert_eq!(market.bids.len(), 0);
+        assert_eq!(balance_manager_rc.borrow().get(102, BalanceType::AVAILABLE, &usdt()), dec!(300));
+
+        let resting = OrderInput {
+            post_only: true,
+            ..limit_order_input(102, OrderSide::BID, dec!(10), dec!(0.9), TimeInForce::GTC)
+        };
+        let order = market.put_order(false, resting).unwrap();
+        assert_eq!(order.id, 2);
+        assert_eq!(order.remain, dec!(10));
+        assert_eq!(market.bids.len(), 1);
+        assert_eq!(balance_manager_rc.borrow().get(102, BalanceType::FREEZE, &usdt()), dec!(9));
+    }
+
+    #[test]
+    fn test_trigger_order() {
+        let mut balance_manager = get_simple_balance_manager();
+        init_balance(&mut balance_manager);
+        let balance_manager_rc = Rc::new(RefCell::new(balance_manager));
+        let mut market = get_simple_market(balance_manager_rc.clone());
+        market
+            .put_order(false, limit_order_input(101, OrderSide::BID, dec!(10), dec!(0.9), TimeInForce::GTC))
+            .unwrap();
+        market
+            .put_order(false, limit_order_input(101, OrderSide::BID, dec!(10), dec!(0.8), TimeInForce::GTC))
+            .unwrap();
+        // stop loss: sell 10 at market when the price falls to 0.9
+        let stop_loss = OrderInput {
+            type_: OrderType::MARKET,
+            ..limit_order_input(102, OrderSide::ASK, dec!(10), dec!(0), TimeInForce::GTC)
+        };
+        let trigger_order = market.put_trigger_order(dec!(0.9), TriggerDirection::BELOW, stop_loss).unwrap();
+        assert_eq!(trigger_order.id, 3);
+        assert_eq!(market.trigger_orders.len(), 1);
+
+        // trade at 0.9 fires the trigger, which then trades at 0.8
+        let order = market
+            .put_order(false, limit_order_input(102, OrderSide::ASK, dec!(10), dec!(0.9), TimeInForce::GTC))
+            .unwrap();
+        assert_eq!(order.remain, dec!(0));
+        assert_eq!(market.trigger_orders.len(), 0);
+        assert_eq!(market.bids.len(), 0);
+        assert_eq!(market.last_price, dec!(0.8));
+        let balance_manager = balance_manager_rc.borrow();
+        assert_eq!(balance_manager.get(102, BalanceType::AVAILABLE, &eth()), dec!(980));
+        assert_eq!(balance_manager.get(102, BalanceType::AVAILABLE, &usdt()), dec!(317));
+    }
+
+    fn put_self_trade_orders(market: &mut Market, self_trade_prevention: SelfTradePrevention) -> Order {
+        market
+            .put_order(true, limit_order_input(101, OrderSide::ASK, dec!(10), dec!(1), TimeInForce::GTC))
+            .unwrap();
+        market
+            .put_order(true, limit_order_input(102, OrderSide::ASK, dec!(10), dec!(1.1), TimeInForce::GTC))
+            .unwrap();
+        let taker = OrderInput {
+            self_trade_prevention,
+            taker_fee: dec!(0.001),
+            ..limit_order_input(101, OrderSide::BID, dec!(10), dec!(1.1), TimeInForce::GTC)
+        };
+        market.put_order(true, taker).unwrap()
+    }
+
+    #[test]
+    fn test_self_trade_cancel_newest() {
+        let mut balance_manager = get_simple_balance_manager();
+        init_balance(&mut balance_manager);
+        let balance_manager_rc = Rc::new(RefCell::new(balance_manager));
+        let mut market = get_simple_market(balance_manager_rc.clone());
+        let order = put_self_trade_orders(&mut market, SelfTradePrevention::CancelNewest);
+        assert_eq!(market.trade_count, 0);
+        assert_eq!(order.finished_fee, dec!(0));
+        assert!(market.get(order.id).is_none());
+        assert_eq!(market.asks.len(), 2);
+        assert_eq!(market.bids.len(), 0);
+        let balance_manager = balance_manager_rc.borrow();
+        assert_eq!(balance_manager.get(101, BalanceType::AVAILABLE, &usdt()), dec!(300));
+        assert_eq!(balance_manager.get(101, BalanceType::FREEZE, &eth()), dec!(10));
+    }
+
+    #[test]
+    fn test_self_trade_cancel_oldest() {
+        let mut balance_manager = get_simple_balance_manager();
+        init_balance(&mut balance_manager);
+        let balance_manager_rc = Rc::new(RefCell::new(balance_manager));
+        let mut market = get_simple_market(balance_manager_rc.clone());
+        let order = put_self_trade_orders(&mut market, SelfTradePrevention::CancelOldest);
+        // the resting ask of the same user is canceled, then the taker matches user 102
+        assert_eq!(market.trade_count, 1);
+        assert_eq!(order.remain, dec!(0));
+        assert_eq!(order.finished_quote, dec!(11));
+        assert_eq!(market.asks.len(), 0);
+        let balance_manager = balance_manager_rc.borrow();
+        assert_eq!(balance_manager.get(101, BalanceType::FREEZE, &eth()), dec!(0));
+        assert_eq!(balance_manager.get(101, BalanceType::AVAILABLE, &eth()), dec!(1009.99));
+        assert_eq!(balance_manager.get(101, BalanceType::AVAILABLE, &usdt()), dec!(289));
+    }
+
+    #[test]
+    fn test_self_trade_cancel_both() {
+        let mut balance_manager = get_simple_balance_manager();
+        init_balance(&mut balance_manager);
+        let balance_manager_rc = Rc::new(RefCell::new(balance_manager));
+        let mut market = get_simple_market(balance_manager_rc.clone());
+        let order = put_self_trade_orders(&mut market, SelfTradePrevention::CancelBoth);
+        assert_eq!(market.trade_count, 0);
+        assert_eq!(order.finished_fee, dec!(0));
+        assert!(market.get(order.id).is_none());
+        assert_eq!(market.asks.len(), 1);
+        assert_eq!(market.bids.len(), 0);
+        let balance_manager = balance_manager_rc.borrow();
+        assert_eq!(balance_manager.get(101, BalanceType::FREEZE, &eth()), dec!(0));
+        assert_eq!(balance_manager.get(101, BalanceType::AVAILABLE, &eth()), dec!(1000));
+        assert_eq!(balance_manager.get(101, BalanceType::AVAILABLE, &usdt()), dec!(300));
+    }
+
+    #[test]
+    fn test_fee_tier_override() {
+        let mut balance_manager = get_simple_balance_manager();
+        init_balance(&mut balance_manager);
+        let balance_manager_rc = Rc::new(RefCell::new(balance_manager));
+        let fee_tiers = FeeTierManager::new(&config::FeeTierConfig {
+            tiers: vec![config::FeeTier {
+                min_volume: dec!(0),
+                maker_fee: dec!(0.001),
+                taker_fee: dec!(0.002),
+            }],
+            ..Default::default()
+        })
+        .unwrap();
+        let mut market = Market::new(
+            &get_simple_market_config(),
+            balance_manager_rc.clone(),
+            Rc::new(RefCell::new(Sequencer::default())),
+            Rc::new(RefCell::new(fee_tiers)),
+            Rc::new(RefCell::new(DummyHistoryWriter)),
+            Rc::new(RefCell::new(DummyMessageManager)),
+            Metrics::default(),
+        )
+        .unwrap();
+        market
+            .put_order(true, limit_order_input(101, OrderSide::ASK, dec!(10), dec!(1), TimeInForce::GTC))
+            .unwrap();
+        let bid_order = market
+            .put_order(true, limit_order_input(102, OrderSide::BID, dec!(10), dec!(1), TimeInForce::GTC))
+            .unwrap();
+        assert_eq!(bid_order.finished_fee, dec!(0.02));
+        let balance_manager = balance_manager_rc.borrow();
+        assert_eq!(balance_manager.get(101, BalanceType::AVAILABLE, &usdt()), dec!(309.99));
+        assert_eq!(balance_manager.get(102, BalanceType::AVAILABLE, &eth()), dec!(1009.98));
+    }
+
+    // the trades of a replay get the fee tiers of when their orders were journaled, not of when it runs
+    #[test]
+    fn test_replay_fee_tiers() {
+        let t0 = 1_600_000_000.0;
+        let run = |real: bool, timestamps: &[Option<f64>]| {
+            let mut balance_manager = get_simple_balance_manager();
+            init_balance(&mut balance_manager);
+            let balance_manager_rc = Rc::new(RefCell::new(balance_manager));
+            let tier = |min_volume, maker_fee, taker_fee| config::FeeTier {
+                min_volume,
+                maker_fee,
+                taker_fee,
+            };
+            let fee_tiers = FeeTierManager::new(&config::FeeTierConfig {
+                tiers: vec![tier(dec!(0), dec!(0), dec!(0)), tier(dec!(10), dec!(0.001), dec!(0.002))],
+                volume_window: std::time::Duration::from_secs(30 * 86400),
+                ..Default::default()
+            })
+            .unwrap();
+            let mut market = Market::new(
+                &get_simple_market_config(),
+                balance_manager_rc.clone(),
+                Rc::new(RefCell::new(Sequencer::default())),
+                Rc::new(RefCell::new(fee_tiers)),
+                Rc::new(RefCell::new(DummyHistoryWriter)),
+                Rc::new(RefCell::new(DummyMessageManager)),
+                Metrics::default(),
+            )
+            .unwrap();
+            market.set_clock(Rc::new(MockClock::new(t0 + 100.0 * 86400.0)));
+            for timestamp in timestamps {
+                for (user_id, side) in &[(101, OrderSide::ASK), (102, OrderSide::BID)] {
+                    let input = OrderInput {
+                        timestamp: *timestamp,
+                        ..limit_order_input(*user_id, *side, dec!(10), dec!(1), TimeInForce::GTC)
+                    };
+                    market.put_order(real, input).unwrap();
+                }
+            }
+            let eth = balance_manager_rc.borrow().get(102, BalanceType::AVAILABLE, &eth());
+            eth
+        };
+        // the volume of the first trade has slid out of the window at the second one
+        let journaled = [Some(t0), Some(t0 + 40.0 * 86400.0)];
+        let original = run(true, &journaled);
+        assert_eq!(original, dec!(1020));
+        assert_eq!(run(false, &journaled), original);
+        // at the time of the replay, the first trade would count toward the tier of the second one
+        assert_eq!(run(false, &[None, None]), dec!(1019.98));
+    }
+
+    #[derive(Default)]
+    struct HistoryRecorder {
+        balance_history: Vec<models::BalanceHistory>,
+        orders: Vec<Order>,
+        trades: Vec<Trade>,
+    }
+    impl HistoryWriter for HistoryRecorder {
+        fn append_balance_history(&mut self, data: models::BalanceHistory) {
+            self.balance_history.push(data);
+        }
+        fn append_order_history(&mut self, order: &Order) {
+            self.orders.push(*order);
+        }
+        fn append_trade_history(&mut self, trade: &Trade) {
+            self.trades.push(trade.clone());
+        }
+        fn append_kline(&mut self, _market: &str, _interval: u64, _candle: &crate::kline::Candle) {}
+        fn is_block(&self) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn test_maker_rebate() {
+        let mut balance_manager = get_simple_balance_manager();
+        init_balance(&mut balance_manager);
+        let balance_manager_rc = Rc::new(RefCell::new(balance_manager));
+        let history_writer = Rc::new(RefCell::new(HistoryRecorder::default()));
+        let mut market_conf = get_simple_market_config();
+        market_conf.max_maker_rebate = dec!(0.0005);
+        let mut market = Market::new(
+            &market_conf,
+            balance_manager_rc.clone(),
+            Rc::new(RefCell::new(Sequencer::default())),
+            get_fee_tier_manager(),
+            history_writer.clone(),
+            Rc::new(RefCell::new(DummyMessageManager)),
+            Metrics::default(),
+        )
+        .unwrap();
+
+        let mut ask_input = limit_order_input(101, OrderSide::ASK, dec!(10), dec!(1), TimeInForce::GTC);
+        ask_input.maker_fee = dec!(-0.0002);
+        ask_input.taker_fee = dec!(0.001);
+        let mut bid_input = limit_order_input(102, OrderSide::BID, dec!(10), dec!(1), TimeInForce::GTC);
+        bid_input.taker_fee = dec!(0.001);
+        assert!(market.check_fee_rates(&ask_input).is_ok());
+        assert!(market.check_fee_rates(&bid_input).is_ok());
+        let ask_order = market.put_order(true, ask_input).unwrap();
+        market.put_order(true, bid_input).unwrap();
+
+        assert_eq!(market.orders.len(), 0);
+        let balance_manager = balance_manager_rc.borrow();
+        assert_eq!(balance_manager.get(101, BalanceType::AVAILABLE, &usdt()), dec!(310.002));
+        assert_eq!(balance_manager.get(102, BalanceType::AVAILABLE, &eth()), dec!(1009.99));
+        let history = &history_writer.borrow().balance_history;
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].user_id, 101);
+        assert_eq!(history[0].business, BusinessKind::Rebate);
+        assert_eq!((history[0].change, history[0].balance), (dec!(0.002), dec!(310.002)));
+        let detail: BalanceHistoryFromFee = serde_json::from_str(&history[0].detail).unwrap();
+        assert_eq!((detail.order_id, detail.fee_rate), (ask_order.id, dec!(-0.0002)));
+
+        let mut order_input = limit_order_input(101, OrderSide::ASK, dec!(10), dec!(1), TimeInForce::GTC);
+        order_input.maker_fee = dec!(-0.001);
+        order_input.taker_fee = dec!(0.001);
+        assert!(market.check_fee_rates(&order_input).is_err());
+        order_input.maker_fee = dec!(0);
+        order_input.taker_fee = dec!(0.0001);
+        assert!(market.check_fee_rates(&order_input).is_err());
+    }
+
+    fn get_fee_account_market(
+        balance_manager: BalanceManager,
+        fee_asset: Option<String>,
+    ) -> (Market, Rc<RefCell<BalanceManager>>, Rc<RefCell<HistoryRecorder>>) {
+        get_fee_account_market_with_config(&get_simple_market_config(), balance_manager, fee_asset)
+    }
+
+    fn get_fee_account_market_with_config(
+        market_conf: &config::Market,
+        balance_manager: BalanceManager,
+        fee_asset: Option<String>,
+    ) -> (Market, Rc<RefCell<BalanceManager>>, Rc<RefCell<HistoryRecorder>>) {
+        let mut balance_manager = balance_manager;
+        balance_manager
+            .set_fee_account(&config::FeeAccountConfig {
+                user_id: Some(1),
+                fee_asset,
+            })
+            .unwrap();
+        let balance_manager_rc = Rc::new(RefCell::new(balance_manager));
+        let history_writer = Rc::new(RefCell::new(HistoryRecorder::default()));
+        let market = Market::new(
+            market_conf,
+            balance_manager_rc.clone(),
+            Rc::new(RefCell::new(Sequencer::default())),
+            get_fee_tier_manager(),
+            history_writer.clone(),
+            Rc::new(RefCell::new(DummyMessageManager)),
+            Metrics::default(),
+        )
+        .unwrap();
+        (market, balance_manager_rc, history_writer)
+    }
+
+    fn fee_order_input(user_id: u32, side: OrderSide, amount: Decimal, price: Decimal) -> OrderInput {
+        let mut order_input = limit_order_input(user_id, side, amount, price, TimeInForce::GTC);
+        order_input.maker_fee = dec!(0.001);
+        order_input.taker_fee = dec!(0.002);
+        order_input
+    }
+
+    #[test]
+    fn test_fee_account_collects_fees() {
+        let mut balance_manager = get_simple_balance_manager();
+        init_balance(&mut balance_manager);
+        let (mut market, balance_manager_rc, history_writer) = get_fee_account_market(balance_manager, None);
+        market
+            .put_order(true, fee_order_input(101, OrderSide::ASK, dec!(10), dec!(1)))
+            .unwrap();
+        market
+            .put_order(true, fee_order_input(102, OrderSide::BID, dec!(4), dec!(1)))
+            .unwrap();
+        market
+            .put_order(true, fee_order_input(102, OrderSide::BID, dec!(6), dec!(1.5)))
+            .unwrap();
+        market
+            .put_order(true, fee_order_input(102, OrderSide::ASK, dec!(5), dec!(2)))
+            .unwrap();
+        market
+            .put_order(true, fee_order_input(101, OrderSide::BID, dec!(5), dec!(2)))
+            .unwrap();
+
+        let history = history_writer.borrow();
+        assert_eq!(history.trades.len(), 3);
+        let mut fees: HashMap<String, Decimal> = HashMap::new();
+        for trade in &history.trades {
+            *fees.entry(trade.ask_fee_asset.clone()).or_default() += trade.ask_fee;
+            *fees.entry(trade.bid_fee_asset.clone()).or_default() += trade.bid_fee;
+        }
+        let balance_manager = balance_manager_rc.borrow();
+        assert_eq!(fees[&usdt()], balance_manager.get(1, BalanceType::AVAILABLE, &usdt()));
+        assert_eq!(fees[&eth()], balance_manager.get(1, BalanceType::AVAILABLE, &eth()));
+        assert_eq!(fees[&usdt()], dec!(0.02));
+        assert_eq!(fees[&eth()], dec!(0.03));
+        // one fee history row per charged fee, with the running balance of the fee account
+        assert!(history
+            .balance_history
+            .iter()
+            .all(|row| row.user_id == 1 && row.business == BusinessKind::Fee));
+        assert_eq!(history.balance_history.len(), 6);
+        let last_usdt = history.balance_history.iter().filter(|row| row.asset == usdt()).last().unwrap();
+        assert_eq!(last_usdt.balance, fees[&usdt()]);
+    }
+
+    #[test]
+    fn test_fee_charged_in_fee_asset() {
+        let mut balance_manager = get_simple_balance_manager();
+        init_balance(&mut balance_manager);
+        balance_manager.add(103, BalanceType::AVAILABLE, &usdt(), &dec!(20)).unwrap();
+        let (mut market, balance_manager_rc, history_writer) = get_fee_account_market(balance_manager, Some(usdt()));
+        market
+            .put_order(true, fee_order_input(101, OrderSide::ASK, dec!(10), dec!(2)))
+            .unwrap();
+        market
+            .put_order(true, fee_order_input(102, OrderSide::BID, dec!(10), dec!(2)))
+            .unwrap();
+        {
+            let trade = &history_writer.borrow().trades[0];
+            // the seller receives usdt and pays it, the buyer pays 0.02 eth as 0.04 usdt
+            assert_eq!((trade.ask_fee_asset.as_str(), trade.ask_fee), ("USDT", dec!(0.02)));
+            assert_eq!((trade.bid_fee_asset.as_str(), trade.bid_fee), ("USDT", dec!(0.04)));
+            let balance_manager = balance_manager_rc.borrow();
+            assert_eq!(balance_manager.get(102, BalanceType::AVAILABLE, &usdt()), dec!(279.96));
+            assert_eq!(balance_manager.get(102, BalanceType::AVAILABLE, &eth()), dec!(1010));
+            assert_eq!(balance_manager.get(1, BalanceType::AVAILABLE, &usdt()), dec!(0.06));
+        }
+
+        // 103 can't afford the fee in usdt besides its order, it pays in eth
+        market
+            .put_order(true, fee_order_input(101, OrderSide::ASK, dec!(10), dec!(2)))
+            .unwrap();
+        market
+            .put_order(true, fee_order_input(103, OrderSide::BID, dec!(10), dec!(2)))
+            .unwrap();
+        let trade = &history_writer.borrow().trades[1];
+        assert_eq!((trade.bid_fee_asset.as_str(), trade.bid_fee), ("ETH", dec!(0.02)));
+        let balance_manager = balance_manager_rc.borrow();
+        assert_eq!(balance_manager.get(103, BalanceType::AVAILABLE, &usdt()), dec!(0));
+        assert_eq!(balance_manager.get(103, BalanceType::AVAILABLE, &eth()), dec!(9.98));
+        assert_eq!(balance_manager.get(1, BalanceType::AVAILABLE, &eth()), dec!(0.02));
+    }
+
+    #[test]
+    fn test_fee_side() {
+        // (fee side, taker side, the ask fee, the bid fee), maker fee 0.001 and taker fee 0.002 of a trade of 10 ETH at 2
+        let expected = vec![
+            (config::FeeSide::Received, OrderSide::ASK, ("USDT", dec!(0.04)), ("ETH", dec!(0.01))),
+            (config::FeeSide::Received, OrderSide::BID, ("USDT", dec!(0.02)), ("ETH", dec!(0.02))),
+            (config::FeeSide::Quote, OrderSide::ASK, ("USDT", dec!(0.04)), ("USDT", dec!(0.02))),
+            (config::FeeSide::Quote, OrderSide::BID, ("USDT", dec!(0.02)), ("USDT", dec!(0.04))),
+            (config::FeeSide::Base, OrderSide::ASK, ("ETH", dec!(0.02)), ("ETH", dec!(0.01))),
+            (config::FeeSide::Base, OrderSide::BID, ("ETH", dec!(0.01)), ("ETH", dec!(0.02))),
+        ];
+        for (fee_side, taker_side, ask_fee, bid_fee) in expected {
+            let mut balance_manager = get_simple_balance_manager();
+            init_balance(&mut balance_manager);
+            let mut market_conf = get_simple_market_config();
+            market_conf.fee_side = fee_side;
+            let (mut market, balance_manager_rc, history_writer) = get_fee_account_market_with_config(&market_conf, balance_manager, None);
+            let (maker, taker) = match taker_side {
+                OrderSide::ASK => (
+                    fee_order_input(102, OrderSide::BID, dec!(10), dec!(2)),
+                    fee_order_input(101, OrderSide::ASK, dec!(10), dec!(2)),
+                ),
+                OrderSide::BID => (
+                    fee_order_input(101, OrderSide::ASK, dec!(10), dec!(2)),
+                    fee_order_input(102, OrderSide::BID, dec!(10), dec!(2)),
+                ),
+            };
+            market.put_order(true, maker).unwrap();
+            market.put_order(true, taker).unwrap();
+
+            let case = format!("{:?} with a taker {:?}", fee_side, taker_side);
+            let trade = &history_writer.borrow().trades[0];
+            assert_eq!((trade.ask_fee_asset.as_str(), trade.ask_fee), ask_fee, "{}", case);
+            assert_eq!((trade.bid_fee_asset.as_str(), trade.bid_fee), bid_fee, "{}", case);
+            let balance_manager = balance_manager_rc.borrow();
+            for asset in &[usdt(), eth()] {
+                let charged: Decimal = vec![ask_fee, bid_fee]
+                    .into_iter()
+                    .filter(|(fee_asset, _)| fee_asset == asset)
+                    .map(|(_, fee)| fee)
+                    .sum();
+                assert_eq!(balance_manager.get(1, BalanceType::AVAILABLE, asset), charged, "{}", case);
+            }
+            // the seller 101 gets 20 USDT for 10 ETH, the buyer 102 the other way round
+            let paid = |fee: (&str, Decimal), asset: &str| if fee.0 == asset { fee.1 } else { Decimal::zero() };
+            assert_eq!(
+                balance_manager.get(101, BalanceType::AVAILABLE, &usdt()),
+                dec!(320) - paid(ask_fee, "USDT"),
+                "{}",
+                case
+            );
+            assert_eq!(
+                balance_manager.get(101, BalanceType::AVAILABLE, &eth()),
+                dec!(990) - paid(ask_fee, "ETH"),
+                "{}",
+                case
+            );
+            assert_eq!(
+                balance_manager.get(102, BalanceType::AVAILABLE, &usdt()),
+                dec!(280) - paid(bid_fee, "USDT"),
+                "{}",
+                case
+            );
+            assert_eq!(
+                balance_manager.get(102, BalanceType::AVAILABLE, &eth()),
+                dec!(1010) - paid(bid_fee, "ETH"),
+                "{}",
+                case
+            );
+        }
+    }
+
+    // a buyer without the quote to pay its fee in pays it in what it receives
+    #[test]
+    fn test_fee_side_fallback() {
+        let mut balance_manager = get_simple_balance_manager();
+        balance_manager.add(101, BalanceType::AVAILABLE, &eth(), &dec!(10)).unwrap();
+        balance_manager.add(102, BalanceType::AVAILABLE, &usdt(), &dec!(20)).unwrap();
+        let mut market_conf = get_simple_market_config();
+        market_conf.fee_side = config::FeeSide::Quote;
+        let (mut market, balance_manager_rc, history_writer) = get_fee_account_market_with_config(&market_conf, balance_manager, None);
+        market
+            .put_order(true, fee_order_input(101, OrderSide::ASK, dec!(10), dec!(2)))
+            .unwrap();
+        market
+            .put_order(true, fee_order_input(102, OrderSide::BID, dec!(10), dec!(2)))
+            .unwrap();
+        let trade = &history_writer.borrow().trades[0];
+        assert_eq!((trade.bid_fee_asset.as_str(), trade.bid_fee), ("ETH", dec!(0.02)));
+        let balance_manager = balance_manager_rc.borrow();
+        assert_eq!(balance_manager.get(102, BalanceType::AVAILABLE, &usdt()), dec!(0));
+        assert_eq!(balance_manager.get(102, BalanceType::AVAILABLE, &eth()), dec!(9.98));
+    }
+
+    // the fees have more decimals than the assets keep, what the users pay is still what the fee account gets
+    #[test]
+    fn test_fee_rounding_reconciles() {
+        let assets = vec![
+            config::Asset {
+                name: usdt(),
+                prec_save: 4,
+                prec_show: 4,
+                ..Default::default()
+            },
+            config::Asset {
+                name: eth(),
+                prec_save: 4,
+                prec_show: 4,
+                rounding: config::RoundingStrategy::HalfUp,
+                ..Default::default()
+            },
+        ];
+        let users = [101, 102, 103, 104, 105];
+        // with the fees charged in the quote asset, the ones of the bids are converted
+        for &(fee_rounding, fee_side) in &[
+            (None, config::FeeSide::Received),
+            (Some(config::RoundingStrategy::Truncate), config::FeeSide::Received),
+            (Some(config::RoundingStrategy::AwayFromZero), config::FeeSide::Received),
+            (None, config::FeeSide::Quote),
+            (Some(config::RoundingStrategy::Truncate), config::FeeSide::Quote),
+        ] {
+            let mut balance_manager = BalanceManager::new(&assets).unwrap();
+            for &user_id in &users {
+                balance_manager
+                    .add(user_id, BalanceType::AVAILABLE, &usdt(), &dec!(1000000))
+                    .unwrap();
+                balance_manager.add(user_id, BalanceType::AVAILABLE, &eth(), &dec!(10000)).unwrap();
+            }
+            let mut market_conf = get_simple_market_config();
+            market_conf.base.prec = 2;
+            market_conf.fee_prec = 2;
+            market_conf.fee_rounding = fee_rounding;
+            market_conf.fee_side = fee_side;
+            let (mut market, balance_manager_rc, history_writer) = get_fee_account_market_with_config(&market_conf, balance_manager, None);
+
+            for i in 0..300u32 {
+                let price = Decimal::new(10000 + (i * 37 % 100) as i64, 2);
+                let amount = Decimal::new((i * 13 % 50 + 1) as i64, 2);
+                let (maker_side, taker_side) = if i % 2 == 0 {
+                    (OrderSide::ASK, OrderSide::BID)
+                } else {
+                    (OrderSide::BID, OrderSide::ASK)
+                };
+                let mut maker = limit_order_input(users[(i % 3) as usize], maker_side, amount, price, TimeInForce::GTC);
+                maker.maker_fee = dec!(0.0007);
+                let mut taker = limit_order_input(users[3 + (i % 2) as usize], taker_side, amount, price, TimeInForce::GTC);
+                taker.taker_fee = dec!(0.0013);
+                market.put_order(true, maker).unwrap();
+                market.put_order(true, taker).unwrap();
+            }
+            assert_eq!(market.orders.len(), 0);
+
+            let history = history_writer.borrow();
+            assert_eq!(history.trades.len(), 300);
+            let balance_manager = balance_manager_rc.borrow();
+            for (asset, deposited) in &[(usdt(), dec!(1000000)), (eth(), dec!(10000))] {
+                let recorded: Decimal = history
+                    .trades
+                    .iter()
+                    .flat_map(|trade| vec![(&trade.ask_fee_asset, trade.ask_fee), (&trade.bid_fee_asset, trade.bid_fee)])
+                    .filter(|(fee_asset, _)| *fee_asset == asset)
+                    .map(|(_, fee)| fee)
+                    .sum();
+                // the finished orders record the same fees as their trades
+                let ordered: Decimal = history
+                    .orders
+                    .iter()
+                    .map(|order| {
+                        let (received, paid) = if order.side == OrderSide::ASK {
+                            (usdt(), eth())
+                        } else {
+                            (eth(), usdt())
+                        };
+                        if *asset == received {
+                            order.finished_fee
+                        } else {
+                            debug_assert_eq!(*asset, paid);
+                            order.finished_paid_fee
+                        }
+                    })
+                    .sum();
+                let left: Decimal = users
+                    .iter()
+                    .map(|&user_id| {
+                        balance_manager.get(user_id, BalanceType::AVAILABLE, asset)
+                            + balance_manager.get(user_id, BalanceType::FREEZE, asset)
+                    })
+                    .sum();
+                let debited = deposited * Decimal::from(users.len() as u32) - left;
+                let credited = balance_manager.get(1, BalanceType::AVAILABLE, asset);
+                assert!(credited > Decimal::zero());
+                assert_eq!(debited, credited, "{} with {:?}", asset, fee_rounding);
+                assert_eq!(recorded, credited, "{} with {:?}", asset, fee_rounding);
+                assert_eq!(ordered, credited, "{} with {:?} {:?}", asset, fee_rounding, fee_side);
+            }
+            assert_eq!(history.orders.len(), 600);
+            assert!(history
+                .trades
+                .iter()
+                .all(|trade| trade.ask_fee.scale() <= 4 && trade.bid_fee.scale() <= 4));
+            if fee_side == config::FeeSide::Quote {
+                assert!(history.orders.iter().any(|order| !order.finished_paid_fee.is_zero()));
+            }
+        }
+    }
+
+    // the fee account can't take the fee, so the trade fails as a whole and leaves nothing applied
+    #[test]
+    fn test_settlement_failure_changes_nothing() {
+        let mut balance_manager = get_simple_balance_manager();
+        init_balance(&mut balance_manager);
+        balance_manager.add(102, BalanceType::AVAILABLE, &usdt(), &dec!(20000)).unwrap();
+        balance_manager
+            .add(1, BalanceType::AVAILABLE, &usdt(), &(Decimal::max_value() - dec!(10)))
+            .unwrap();
+        let (mut market, balance_manager_rc, history_writer) = get_fee_account_market(balance_manager, None);
+        let maker = market
+            .put_order(true, fee_order_input(102, OrderSide::BID, dec!(100), dec!(100)))
+            .unwrap();
+        let balances = balance_manager_rc.borrow().balances.clone();
+
+        // the taker pays 20 USDT of fee, credited last after both sides of the trade
+        let taker = market
+            .put_order(true, fee_order_input(101, OrderSide::ASK, dec!(100), dec!(100)))
+            .unwrap();
+        // the taker is canceled without a trade, like on an overflow of a received balance
+        assert_eq!(taker.finished_base, dec!(0));
+        assert!(market.get(taker.id).is_none());
+        assert_eq!(balance_manager_rc.borrow().balances, balances);
+        assert!(history_writer.borrow().trades.is_empty());
+        assert!(history_writer.borrow().balance_history.is_empty());
+        assert_eq!(market.orders[&maker.id].borrow().remain, dec!(100));
+    }
+
+    #[test]
+    fn test_freeze_overflow_rejected() {
+        let mut balance_manager = get_simple_balance_manager();
+        init_balance(&mut balance_manager);
+        balance_manager.set(101, BalanceType::FREEZE, &eth(), &(Decimal::max_value() - dec!(10)));
+        let balance_manager_rc = Rc::new(RefCell::new(balance_manager));
+        let mut market = get_simple_market(balance_manager_rc.clone());
+        let order = market
+            .put_order(true, limit_order_input(101, OrderSide::ASK, dec!(5), dec!(1), TimeInForce::GTC))
+            .unwrap();
+        let balances = balance_manager_rc.borrow().balances.clone();
+        // the amended order would freeze 15 more, it is rejected as a whole
+        assert!(market.amend_order(true, order.id, None, Some(dec!(20))).is_err());
+        assert!(market
+            .put_order(true, limit_order_input(101, OrderSide::ASK, dec!(10), dec!(1), TimeInForce::GTC))
+            .is_err());
+        assert_eq!(balance_manager_rc.borrow().balances, balances);
+        assert_eq!(market.get(order.id).unwrap().amount, dec!(5));
+        assert_eq!(market.orders.len(), 1);
+    }
+
+    #[test]
+    fn test_rebate_not_covered_by_fee_tiers() {
+        let balance_manager_rc = Rc::new(RefCell::new(get_simple_balance_manager()));
+        let fee_tiers = FeeTierManager::new(&config::FeeTierConfig {
+            tiers: vec![config::FeeTier {
+                min_volume: dec!(1000),
+                maker_fee: dec!(0),
+                taker_fee: dec!(0.0005),
+            }],
+            ..Default::default()
+        })
+        .unwrap();
+        let mut market_conf = get_simple_market_config();
+        market_conf.max_maker_rebate = dec!(0.001);
+        let market = Market::new(
+            &market_conf,
+            balance_manager_rc,
+            Rc::new(RefCell::new(Sequencer::default())),
+            Rc::new(RefCell::new(fee_tiers)),
+            Rc::new(RefCell::new(DummyHistoryWriter)),
+            Rc::new(RefCell::new(DummyMessageManager)),
+            Metrics::default(),
+        );
+        assert!(market.is_err());
+    }
+
+    #[test]
+    fn test_market_bid_by_quote_amount() {
+        let mut balance_manager = get_simple_balance_manager();
+        init_balance(&mut balance_manager);
+        let balance_manager_rc = Rc::new(RefCell::new(balance_manager));
+        let mut market = get_simple_market(balance_manager_rc.clone());
+        market
+            .put_order(true, limit_order_input(102, OrderSide::ASK, dec!(10), dec!(1), TimeInForce::GTC))
+            .unwrap();
+        market
+            .put_order(true, limit_order_input(102, OrderSide::ASK, dec!(10), dec!(2), TimeInForce::GTC))
+            .unwrap();
+        let bid_order_input = OrderInput {
+            type_: OrderType::MARKET,
+            amount: dec!(0),
+            price: dec!(0),
+            quote_amount: dec!(15),
+            ..limit_order_input(101, OrderSide::BID, dec!(0), dec!(0), TimeInForce::GTC)
+        };
+        let bid_order = market.put_order(true, bid_order_input).unwrap();
+        // 10 at price 1, then the remaining 5 quote buys 2.5 at price 2
+        assert_eq!(bid_order.finished_base, dec!(12.5));
+        assert_eq!(bid_order.finished_quote, dec!(15));
+        assert_eq!(bid_order.amount, dec!(12.5));
+        assert_eq!(bid_order.remain, dec!(0));
+        assert_eq!(market.asks.values().next().unwrap().borrow().remain, dec!(7.5));
+        let balance_manager = balance_manager_rc.borrow();
+        assert_eq!(balance_manager.get(101, BalanceType::AVAILABLE, &usdt()), dec!(285));
+        assert_eq!(balance_manager.get(101, BalanceType::FREEZE, &usdt()), dec!(0));
+        assert_eq!(balance_manager.get(101, BalanceType::AVAILABLE, &eth()), dec!(1012.5));
+    }
+
+    #[test]
+    fn test_market_order_protection_price() {
+        let mut balance_manager = get_simple_balance_manager();
+        init_balance(&mut balance_manager);
+        let balance_manager_rc = Rc::new(RefCell::new(balance_manager));
+        let mut market = get_simple_market(balance_manager_rc.clone());
+        market.price_band = dec!(0.1);
+        assert_eq!(market.price_band(), None);
+        market
+            .put_order(true, limit_order_input(102, OrderSide::ASK, dec!(10), dec!(1), TimeInForce::GTC))
+            .unwrap();
+        market
+            .put_order(true, limit_order_input(102, OrderSide::ASK, dec!(10), dec!(1.05), TimeInForce::GTC))
+            .unwrap();
+        market
+            .put_order(true, limit_order_input(102, OrderSide::ASK, dec!(10), dec!(2), TimeInForce::GTC))
+            .unwrap();
+        let bid_order_input = OrderInput {
+            type_: OrderType::MARKET,
+            price: dec!(0),
+            protection_price: dec!(1.05),
+            ..limit_order_input(101, OrderSide::BID, dec!(25), dec!(0), TimeInForce::GTC)
+        };
+        let bid_order = market.put_order(true, bid_order_input).unwrap();
+        // the asks at 2 are beyond the protection price, the rest of the market order is canceled
+        assert_eq!(bid_order.finished_base, dec!(20));
+        assert_eq!(bid_order.finished_quote, dec!(20.5));
+        assert_eq!(market.asks.len(), 1);
+        assert_eq!(market.asks.values().next().unwrap().borrow().remain, dec!(10));
+        assert_eq!(market.price_band(), Some((dec!(0.95), dec!(1.15))));
+    }
+
+    #[test]
+    fn test_market_ask_refund() {
+        let mut balance_manager = get_simple_balance_manager();
+        init_balance(&mut balance_manager);
+        let balance_manager_rc = Rc::new(RefCell::new(balance_manager));
+        let mut market = get_simple_market(balance_manager_rc.clone());
+        market
+            .put_order(true, limit_order_input(102, OrderSide::BID, dec!(5), dec!(1), TimeInForce::GTC))
+            .unwrap();
+        let ask_order_input = OrderInput {
+            type_: OrderType::MARKET,
+            price: dec!(0),
+            ..limit_order_input(101, OrderSide::ASK, dec!(10), dec!(0), TimeInForce::GTC)
+        };
+        let ask_order = market.put_order(true, ask_order_input).unwrap();
+        assert_eq!(ask_order.finished_base, dec!(5));
+        assert_eq!(ask_order.frozen, dec!(0));
+        // the unfilled 5 ETH frozen before execution is refunded
+        let balance_manager = balance_manager_rc.borrow();
+        assert_eq!(balance_manager.get(101, BalanceType::AVAILABLE, &eth()), dec!(995));
+        assert_eq!(balance_manager.get(101, BalanceType::FREEZE, &eth()), dec!(0));
+        assert_eq!(balance_manager.get(101, BalanceType::AVAILABLE, &usdt()), dec!(305));
+    }
+
+    #[test]
+    fn test_order_size() {
+        let mut balance_manager = get_simple_balance_manager();
+        init_balance(&mut balance_manager);
+        let balance_manager_rc = Rc::new(RefCell::new(balance_manager));
+        let mut market = get_simple_market(balance_manager_rc);
+        market.min_notional = dec!(10);
+        // exactly at the minimum
+        let order_input = limit_order_input(101, OrderSide::BID, dec!(0.01), dec!(1000), TimeInForce::GTC);
+        assert!(market.check_order_size(&order_input).is_ok());
+        // one tick below the min amount
+        let order_input = limit_order_input(101, OrderSide::BID, dec!(0.0099), dec!(2000), TimeInForce::GTC);
+        assert!(market.check_order_size(&order_input).is_err());
+        // one tick below the min notional
+        let order_input = limit_order_input(101, OrderSide::BID, dec!(0.01), dec!(999.99), TimeInForce::GTC);
+        assert!(market.check_order_size(&order_input).is_err());
+        // market orders use the best counter price
+        market
+            .put_order(true, limit_order_input(102, OrderSide::ASK, dec!(1), dec!(1000), TimeInForce::GTC))
+            .unwrap();
+        let order_input = OrderInput {
+            type_: OrderType::MARKET,
+            price: dec!(0),
+            ..limit_order_input(101, OrderSide::BID, dec!(0.0099), dec!(0), TimeInForce::GTC)
+        };
+        assert!(market.check_order_size(&order_input).is_err());
+        let order_input = OrderInput {
+            amount: dec!(0.01),
+            ..order_input
+        };
+        assert!(market.check_order_size(&order_input).is_ok());
+    }
+
+    #[test]
+    fn test_order_size_respects_precision() {
+        let mut balance_manager = get_simple_balance_manager();
+        init_balance(&mut balance_manager);
+        let balance_manager_rc = Rc::new(RefCell::new(balance_manager));
+        let mut market = get_simple_market(balance_manager_rc);
+        market.min_notional = dec!(10);
+        // 0.00995 is rounded to 0.0100 before the check, as it will be traded
+        let order_input = limit_order_input(101, OrderSide::BID, dec!(0.00995), dec!(1000), TimeInForce::GTC);
+        assert!(market.check_order_size(&order_input).is_ok());
+        // the quote amount is rounded to the prec_save(8) of USDT: 9.999999995 -> 10.00000000
+        let order_input = OrderInput {
+            type_: OrderType::MARKET,
+            price: dec!(0),
+            amount: dec!(0),
+            quote_amount: dec!(9.999999995),
+            ..limit_order_input(101, OrderSide::BID, dec!(0), dec!(0), TimeInForce::GTC)
+        };
+        assert!(market.check_order_size(&order_input).is_ok());
+        let order_input = OrderInput {
+            quote_amount: dec!(9.99999999),
+            ..order_input
+        };
+        assert!(market.check_order_size(&order_input).is_err());
+    }
+
+    fn iceberg_order_input(user_id: u32, side: OrderSide, amount: Decimal, display_qty: Decimal, price: Decimal) -> OrderInput {
+        OrderInput {
+            display_qty,
+            ..limit_order_input(user_id, side, amount, price, TimeInForce::GTC)
+        }
+    }
+
+    #[test]
+    fn test_iceberg_order_hidden_in_depth() {
+        let mut balance_manager = get_simple_balance_manager();
+        init_balance(&mut balance_manager);
+        let balance_manager_rc = Rc::new(RefCell::new(balance_manager));
+        let mut market = get_simple_market(balance_manager_rc.clone());
+        let ask_order = market
+            .put_order(true, iceberg_order_input(102, OrderSide::ASK, dec!(10), dec!(2), dec!(1)))
+            .unwrap();
+        assert_eq!(ask_order.visible, dec!(2));
+        let depth = market.depth(10, &dec!(0));
+        assert_eq!(depth.asks[0].amount, dec!(2));
+        // the hidden reserve still executes, slice by slice
+        let bid_order = market
+            .put_order(true, limit_order_input(101, OrderSide::BID, dec!(5), dec!(1), TimeInForce::GTC))
+            .unwrap();
+        assert_eq!(bid_order.finished_base, dec!(5));
+        assert_eq!(market.trade_count, 3);
+        assert_eq!(market.bids.len(), 0);
+        let ask_order = market.get(ask_order.id).unwrap();
+        assert_eq!(ask_order.remain, dec!(5));
+        assert_eq!(ask_order.visible, dec!(1));
+        let depth = market.depth(10, &dec!(0));
+        assert_eq!(depth.asks[0].amount, dec!(1));
+        let balance_manager = balance_manager_rc.borrow();
+        assert_eq!(balance_manager.get(102, BalanceType::FREEZE, &eth()), dec!(5));
+    }
+
+    #[test]
+    fn test_book_snapshot_round_trip() {
+        let mut balance_manager = get_simple_balance_manager();
+        init_balance(&mut balance_manager);
+        let balance_manager_rc = Rc::new(RefCell::new(balance_manager));
+        let mut market = get_simple_market(balance_manager_rc.clone());
+        market
+            .put_order(true, limit_order_input(101, OrderSide::ASK, dec!(3), dec!(1.5), TimeInForce::GTC))
+            .unwrap();
+        market
+            .put_order(true, iceberg_order_input(102, OrderSide::ASK, dec!(10), dec!(2), dec!(1.5)))
+            .unwrap();
+        market
+            .put_order(true, limit_order_input(101, OrderSide::BID, dec!(4), dec!(1.2), TimeInForce::GTC))
+            .unwrap();
+        market
+            .put_order(true, limit_order_input(102, OrderSide::BID, dec!(5), dec!(1.25), TimeInForce::GTC))
+            .unwrap();
+        let snapshot = market.book_snapshot();
+        let ids: Vec<u64> = snapshot.orders.iter().map(|order| order.id).collect();
+        assert_eq!(ids, vec![1, 2, 4, 3]);
+        let data = snapshot.to_json();
+
+        let mut balance_manager = get_simple_balance_manager();
+        init_balance(&mut balance_manager);
+        let restored_balance_manager_rc = Rc::new(RefCell::new(balance_manager));
+        let mut restored = get_simple_market(restored_balance_manager_rc.clone());
+        let restored_snapshot = BookSnapshot::from_json(&data).unwrap();
+        assert_eq!(restored.restore_book(&restored_snapshot).unwrap(), 4);
+        assert_eq!(
+            BookSnapshot {
+                time: snapshot.time,
+                ..restored.book_snapshot()
+            },
+            snapshot
+        );
+        for user_id in &[101, 102] {
+            for asset in &[eth(), usdt()] {
+                for balance_type in &[BalanceType::AVAILABLE, BalanceType::FREEZE] {
+                    assert_eq!(
+                        restored_balance_manager_rc.borrow().get(*user_id, *balance_type, asset),
+                        balance_manager_rc.borrow().get(*user_id, *balance_type, asset)
+                    );
+                }
+            }
+        }
+        // the time priority holds, and new orders don't reuse the restored ids
+        let order = restored
+            .put_order(true, limit_order_input(101, OrderSide::BID, dec!(2), dec!(1.5), TimeInForce::GTC))
+            .unwrap();
+        assert_eq!(order.id, 5);
+        assert_eq!(restored.get(1).unwrap().remain, dec!(1));
+        assert_eq!(restored.get(2).unwrap().remain, dec!(10));
+        assert!(restored.restore_book(&restored_snapshot).is_err());
+    }
+
+    #[test]
+    fn test_book_snapshot_refused() {
+        let mut balance_manager = get_simple_balance_manager();
+        init_balance(&mut balance_manager);
+        let mut market = get_simple_market(Rc::new(RefCell::new(balance_manager)));
+        market
+            .put_order(true, limit_order_input(101, OrderSide::BID, dec!(100), dec!(2), TimeInForce::GTC))
+            .unwrap();
+        let snapshot = market.book_snapshot();
+
+        // 200 USDT frozen by the book, while the user has 100 only
+        let mut balance_manager = get_simple_balance_manager();
+        balance_manager.add(101, BalanceType::AVAILABLE, &usdt(), &dec!(100)).unwrap();
+        let balance_manager_rc = Rc::new(RefCell::new(balance_manager));
+        let mut restored = get_simple_market(balance_manager_rc.clone());
+        assert!(restored.restore_book(&snapshot).is_err());
+        assert!(restored.book_snapshot().orders.is_empty());
+        assert_eq!(balance_manager_rc.borrow().get(101, BalanceType::FREEZE, &usdt()), dec!(0));
+        assert!(restored
+            .restore_book(&BookSnapshot {
+                version: BOOK_SNAPSHOT_VERSION + 1,
+                ..snapshot
+            })
+            .is_err());
+    }
+
+    #[test]
+    fn test_order_fill_messages() {
+        let mut balance_manager = get_simple_balance_manager();
+        init_balance(&mut balance_manager);
+        let message_manager = Rc::new(RefCell::new(NullMessageManager::default()));
+        let mut market = Market::new(
+            &get_simple_market_config(),
+            Rc::new(RefCell::new(balance_manager)),
+            Rc::new(RefCell::new(Sequencer::default())),
+            get_fee_tier_manager(),
+            Rc::new(RefCell::new(DummyHistoryWriter)),
+            message_manager.clone(),
+            Metrics::default(),
+        )
+        .unwrap();
+        for price in &[dec!(1), dec!(1.1), dec!(1.2)] {
+            market
+                .put_order(true, limit_order_input(102, OrderSide::ASK, dec!(2), *price, TimeInForce::GTC))
+                .unwrap();
+        }
+        let taker = market
+            .put_order(true, limit_order_input(101, OrderSide::BID, dec!(7), dec!(1.2), TimeInForce::GTC))
+            .unwrap();
+        let fills: Vec<OrderFillMessage> = message_manager
+            .borrow()
+            .messages
+            .iter()
+            .filter(|(subject, _)| subject == "order_fills.ETH_USDT")
+            .map(|(_, payload)| message::decode_order_fill_message(payload).unwrap())
+            .collect();
+        assert_eq!(fills.len(), 6);
+        let taker_fills: Vec<(Decimal, Decimal, Decimal, OrderFillStatus)> = fills
+            .iter()
+            .filter(|fill| fill.order_id == taker.id)
+            .map(|fill| (fill.filled, fill.cumulative_filled, fill.remain, fill.status))
+            .collect();
+        assert_eq!(
+            taker_fills,
+            vec![
+                (dec!(2), dec!(2), dec!(5), OrderFillStatus::PartiallyFilled),
+                (dec!(2), dec!(4), dec!(3), OrderFillStatus::PartiallyFilled),
+                (dec!(2), dec!(6), dec!(1), OrderFillStatus::PartiallyFilled),
+            ]
+        );
+        // every maker is filled by its only fill
+        for fill in fills.iter().filter(|fill| fill.order_id != taker.id) {
+            assert_eq!((fill.role, fill.status), (MarketRole::MAKER, OrderFillStatus::Filled));
+        }
+    }
+
+    // the orders, trades and fills of a command can be put back in the order they were sent
+    #[test]
+    fn test_message_sequence() {
+        let mut balance_manager = get_simple_balance_manager();
+        init_balance(&mut balance_manager);
+        let message_manager = Rc::new(RefCell::new(NullMessageManager::default()));
+        let mut market = Market::new(
+            &get_simple_market_config(),
+            Rc::new(RefCell::new(balance_manager)),
+            Rc::new(RefCell::new(Sequencer::default())),
+            get_fee_tier_manager(),
+            Rc::new(RefCell::new(DummyHistoryWriter)),
+            message_manager.clone(),
+            Metrics::default(),
+        )
+        .unwrap();
+        for (operation_log_id, price) in [dec!(1), dec!(1.1), dec!(1.2)].iter().enumerate() {
+            market
+                .put_order(true, limit_order_input(102, OrderSide::ASK, dec!(2), *price, TimeInForce::GTC))
+                .unwrap();
+            // as the controller does after each command
+            message_manager.borrow_mut().set_operation_log_id(operation_log_id as u64 + 1);
+        }
+        let sent = message_manager.borrow().messages.len();
+        market
+            .put_order(true, limit_order_input(101, OrderSide::BID, dec!(7), dec!(1.2), TimeInForce::GTC))
+            .unwrap();
+
+        let message_manager = message_manager.borrow();
+        let seqs: Vec<u64> = message_manager
+            .messages
+            .iter()
+            .map(|(_, payload)| message::decode_message_seq(payload).unwrap())
+            .collect();
+        assert!(seqs.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", seqs);
+        let topics: std::collections::HashSet<&str> = message_manager.messages[sent..]
+            .iter()
+            .map(|(subject, _)| subject.split('.').next().unwrap())
+            .collect();
+        assert_eq!(topics, ["orders", "trades", "order_fills"].iter().copied().collect());
+    }
+
+    #[test]
+    fn test_iceberg_order_refresh_loses_priority() {
+        let mut balance_manager = get_simple_balance_manager();
+        init_balance(&mut balance_manager);
+        balance_manager.add(103, BalanceType::AVAILABLE, &usdt(), &dec!(300)).unwrap();
+        let balance_manager_rc = Rc::new(RefCell::new(balance_manager));
+        let mut market = get_simple_market(balance_manager_rc);
+        let iceberg_order = market
+            .put_order(true, iceberg_order_input(102, OrderSide::ASK, dec!(10), dec!(2), dec!(1)))
+            .unwrap();
+        let normal_order = market
+            .put_order(true, limit_order_input(101, OrderSide::ASK, dec!(3), dec!(1), TimeInForce::GTC))
+            .unwrap();
+        market
+            .put_order(true, limit_order_input(103, OrderSide::BID, dec!(2), dec!(1), TimeInForce::GTC))
+            .unwrap();
+        // the displayed slice is taken and refreshed with a new book entry
+        let refreshed_order = market.get(iceberg_order.id).unwrap();
+        assert_eq!(refreshed_order.remain, dec!(8));
+        assert_eq!(refreshed_order.visible, dec!(2));
+        assert!(refreshed_order.priority > normal_order.priority);
+        assert!(refreshed_order.update_time >= iceberg_order.update_time);
+        // so the normal order at the same price goes first now
+        let order = market
+            .put_order(true, limit_order_input(103, OrderSide::BID, dec!(1), dec!(1), TimeInForce::GTC))
+            .unwrap();
+        // the refresh takes no order id
+        assert_eq!(order.id, normal_order.id + 2);
+        assert_eq!(market.get(normal_order.id).unwrap().remain, dec!(2));
+        assert_eq!(market.get(iceberg_order.id).unwrap().remain, dec!(8));
+    }
+
+    #[test]
+    fn test_order_expire_between_trades() {
+        let mut balance_manager = get_simple_balance_manager();
+        init_balance(&mut balance_manager);
+        let balance_manager_rc = Rc::new(RefCell::new(balance_manager));
+        let mut market = get_simple_market(balance_manager_rc.clone());
+        let clock = Rc::new(MockClock::new(1_600_000_000.0));
+        market.set_clock(clock.clone());
+        let expire_at = clock.now() + 100.0;
+        let ask_order_input = OrderInput {
+            expire_at: Some(expire_at),
+            ..limit_order_input(102, OrderSide::ASK, dec!(10), dec!(1), TimeInForce::GTT)
+        };
+        let ask_order = market.put_order(true, ask_order_input.clone()).unwrap();
+        market
+            .put_order(true, limit_order_input(101, OrderSide::BID, dec!(3), dec!(1), TimeInForce::GTC))
+            .unwrap();
+        assert_eq!(market.trade_count, 1);
+        clock.advance(50.0);
+        assert!(market.expire_orders(true, clock.now()).is_empty());
+
+        clock.advance(50.0);
+        // the order can't be placed any more once the clock reaches its expiry
+        assert_eq!(
+            market.put_order(true, ask_order_input).unwrap_err().to_string(),
+            "order already expired"
+        );
+        let expired = market.expire_orders(true, clock.now());
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].id, ask_order.id);
+        assert!(market.get(ask_order.id).is_none());
+        {
+            let balance_manager = balance_manager_rc.borrow();
+            assert_eq!(balance_manager.get(102, BalanceType::FREEZE, &eth()), dec!(0));
+            assert_eq!(balance_manager.get(102, BalanceType::AVAILABLE, &eth()), dec!(997));
+        }
+        // the expired order is not matched any more
+        let bid_order = market
+            .put_order(true, limit_order_input(101, OrderSide::BID, dec!(3), dec!(1), TimeInForce::GTC))
+            .unwrap();
+        assert_eq!(market.trade_count, 1);
+        assert_eq!(bid_order.finished_base, dec!(0));
+        assert_eq!(market.bids.len(), 1);
+    }
+
+    #[test]
+    fn test_time_in_force_flags() {
+        let now = utils::current_timestamp();
+        let limit = |time_in_force| limit_order_input(101, OrderSide::BID, dec!(1), dec!(1), time_in_force);
+        let market_bid = |time_in_force| OrderInput {
+            type_: OrderType::MARKET,
+            price: dec!(0),
+            ..limit(time_in_force)
+        };
+        let check = |order_input: OrderInput| order_input.check_flags().map_err(|e| e.to_string());
+
+        assert!(check(limit(TimeInForce::GTC)).is_ok());
+        assert!(check(OrderInput {
+            expire_at: Some(now + 100.0),
+            post_only: true,
+            ..limit(TimeInForce::GTT)
+        })
+        .is_ok());
+        assert!(check(market_bid(TimeInForce::IOC)).is_ok());
+
+        assert_eq!(check(limit(TimeInForce::GTT)).unwrap_err(), "GTT orders require expire_at");
+        for time_in_force in &[TimeInForce::GTC, TimeInForce::IOC, TimeInForce::FOK] {
+            assert_eq!(
+                check(OrderInput {
+                    expire_at: Some(now + 100.0),
+                    ..limit(*time_in_force)
+                })
+                .unwrap_err(),
+                format!("expire_at is only valid for GTT orders, not {:?}", time_in_force)
+            );
+        }
+        assert_eq!(
+            check(OrderInput {
+                expire_at: Some(now + 100.0),
+                ..market_bid(TimeInForce::GTT)
+            })
+            .unwrap_err(),
+            "GTT is only valid for limit orders"
+        );
+        assert_eq!(
+            check(OrderInput {
+                post_only: true,
+                ..limit(TimeInForce::IOC)
+            })
+            .unwrap_err(),
+            "post-only is only valid for GTC or GTT limit orders, not IOC LIMIT"
+        );
+        assert_eq!(
+            check(OrderInput {
+                post_only: true,
+                ..limit(TimeInForce::FOK)
+            })
+            .unwrap_err(),
+            "post-only is only valid for GTC or GTT limit orders, not FOK LIMIT"
+        );
+        assert_eq!(
+            check(OrderInput {
+                post_only: true,
+                ..market_bid(TimeInForce::GTC)
+            })
+            .unwrap_err(),
+            "post-only is only valid for GTC or GTT limit orders, not GTC MARKET"
+        );
+        assert_eq!(
+            check(OrderInput {
+                display_qty: dec!(0.5),
+                ..limit(TimeInForce::FOK)
+            })
+            .unwrap_err(),
+            "iceberg is only valid for GTC or GTT limit orders, not FOK LIMIT"
+        );
+        assert_eq!(
+            check(OrderInput {
+                amount: dec!(0),
+                quote_amount: dec!(10),
+                ..market_bid(TimeInForce::FOK)
+            })
+            .unwrap_err(),
+            "fill-or-kill is not supported for market orders with quote amount"
+        );
+
+        // rejected before anything is frozen
+        let mut balance_manager = get_simple_balance_manager();
+        init_balance(&mut balance_manager);
+        let balance_manager_rc = Rc::new(RefCell::new(balance_manager));
+        let mut market = get_simple_market(balance_manager_rc.clone());
+        let result = market.put_order(false, limit(TimeInForce::GTT));
+        assert_eq!(result.unwrap_err().to_string(), "GTT orders require expire_at");
+        assert_eq!(balance_manager_rc.borrow().get(101, BalanceType::FREEZE, &usdt()), dec!(0));
+        assert!(market.bids.is_empty());
+    }
+
+    struct BlockSelfMatch;
+    impl SurveillanceHook for BlockSelfMatch {
+        fn check_match(&mut self, taker: &Order, maker: &Order) -> SurveillanceVerdict {
+            if taker.user == maker.user {
+                SurveillanceVerdict::Block("same beneficial owner".to_string())
+            } else {
+                SurveillanceVerdict::Allow
+            }
+        }
+    }
+
+    #[test]
+    fn test_surveillance_block() {
+        let mut balance_manager = get_simple_balance_manager();
+        init_balance(&mut balance_manager);
+        let balance_manager_rc = Rc::new(RefCell::new(balance_manager));
+        let mut market = get_simple_market(balance_manager_rc.clone());
+        market.set_surveillance_hook(Box::new(BlockSelfMatch));
+
+        let ask_order = market
+            .put_order(true, limit_order_input(101, OrderSide::ASK, dec!(10), dec!(1), TimeInForce::GTC))
+            .unwrap();
+        // self trades are allowed by the order, but blocked by the hook
+        let bid_order = market
+            .put_order(true, limit_order_input(101, OrderSide::BID, dec!(10), dec!(1), TimeInForce::GTC))
+            .unwrap();
+        assert_eq!(market.trade_count, 0);
+        assert_eq!(bid_order.finished_base, dec!(0));
+        assert!(market.get(bid_order.id).is_none());
+        assert_eq!(market.get(ask_order.id).unwrap().remain, dec!(10));
+        assert_eq!(balance_manager_rc.borrow().get(101, BalanceType::FREEZE, &usdt()), dec!(0));
+
+        // other users still trade with the maker
+        market
+            .put_order(true, limit_order_input(102, OrderSide::BID, dec!(10), dec!(1), TimeInForce::GTC))
+            .unwrap();
+        assert_eq!(market.trade_count, 1);
+        assert!(market.get(ask_order.id).is_none());
+    }
+
+    #[test]
+    fn test_depth_aggregation() {
+        let mut balance_manager = get_simple_balance_manager();
+        init_balance(&mut balance_manager);
+        let balance_manager_rc = Rc::new(RefCell::new(balance_manager));
+        let mut market = get_simple_market(balance_manager_rc);
+        for (amount, price) in &[
+            (dec!(1), dec!(1.01)),
+            (dec!(2), dec!(1.04)),
+            (dec!(3), dec!(1.04)),
+            (dec!(4), dec!(1.12)),
+        ] {
+            market
+                .put_order(true, limit_order_input(102, OrderSide::ASK, *amount, *price, TimeInForce::GTC))
+                .unwrap();
+        }
+        for (amount, price) in &[(dec!(1), dec!(0.99)), (dec!(2), dec!(0.91)), (dec!(3), dec!(0.88))] {
+            market
+                .put_order(true, limit_order_input(101, OrderSide::BID, *amount, *price, TimeInForce::GTC))
+                .unwrap();
+        }
+        let raw = market.depth(10, &dec!(0));
+        let levels = |infos: &Vec<PriceInfo>| infos.iter().map(|info| (info.price, info.amount)).collect::<Vec<_>>();
+        assert_eq!(
+            levels(&raw.asks),
+            vec![(dec!(1.01), dec!(1)), (dec!(1.04), dec!(5)), (dec!(1.12), dec!(4))]
+        );
+        assert_eq!(
+            levels(&raw.bids),
+            vec![(dec!(0.99), dec!(1)), (dec!(0.91), dec!(2)), (dec!(0.88), dec!(3))]
+        );
+
+        // asks are grouped upwards and bids downwards, so the groups never cross
+        let grouped = market.depth(10, &dec!(0.1));
+        assert_eq!(levels(&grouped.asks), vec![(dec!(1.1), dec!(6)), (dec!(1.2), dec!(4))]);
+        assert_eq!(levels(&grouped.bids), vec![(dec!(0.9), dec!(3)), (dec!(0.8), dec!(3))]);
+        // the total amount is kept
+        let total = |infos: &Vec<PriceInfo>| infos.iter().map(|info| info.amount).sum::<Decimal>();
+        assert_eq!(total(&grouped.asks), total(&raw.asks));
+        assert_eq!(total(&grouped.bids), total(&raw.bids));
+
+        let limited = market.depth(1, &dec!(0.1));
+        assert_eq!(levels(&limited.asks), vec![(dec!(1.1), dec!(6))]);
+
+        assert!(market.check_depth_interval(&dec!(0.01)).is_ok());
+        assert!(market.check_depth_interval(&dec!(0.001)).is_err());
+        assert!(market.check_depth_interval(&dec!(-1)).is_err());
+    }
+
+    #[test]
+    fn test_book_update() {
+        let mut balance_manager = get_simple_balance_manager();
+        init_balance(&mut balance_manager);
+        let balance_manager_rc = Rc::new(RefCell::new(balance_manager));
+        let mut market = get_simple_market(balance_manager_rc);
+        market
+            .put_order(true, limit_order_input(102, OrderSide::ASK, dec!(1), dec!(1.1), TimeInForce::GTC))
+            .unwrap();
+
+        let mut updates = market.subscribe_book();
+        let snapshot = updates.try_next().unwrap().unwrap();
+        assert!(snapshot.snapshot);
+        assert_eq!(snapshot.asks.len(), 1);
+        assert_eq!((snapshot.asks[0].price, snapshot.asks[0].amount), (dec!(1.1), dec!(1)));
+        assert!(snapshot.bids.is_empty());
+
+        market
+            .put_order(true, limit_order_input(102, OrderSide::ASK, dec!(2), dec!(1.04), TimeInForce::GTC))
+            .unwrap();
+        let second = market
+            .put_order(true, limit_order_input(102, OrderSide::ASK, dec!(3), dec!(1.04), TimeInForce::GTC))
+            .unwrap();
+        // the taker never rests, only the maker level changes
+        market
+            .put_order(
+                true,
+                limit_order_input(101, OrderSide::BID, dec!(2.5), dec!(1.04), TimeInForce::GTC),
+            )
+            .unwrap();
+        market.cancel(true, second.id);
+
+        let mut last_seq = snapshot.seq;
+        let mut changes = Vec::new();
+        while let Ok(Some(update)) = updates.try_next() {
+            assert!(!update.snapshot);
+            assert_eq!(update.prev_seq, last_seq);
+            assert!(update.seq > last_seq);
+            assert!(update.bids.is_empty());
+            last_seq = update.seq;
+            changes.extend(update.asks.iter().map(|level| (level.update_type, level.price, level.amount)));
+        }
+        assert_eq!(
+            changes,
+            vec![
+                (BookUpdateType::ADD, dec!(1.04), dec!(2)),
+                (BookUpdateType::MODIFY, dec!(1.04), dec!(5)),
+                (BookUpdateType::MODIFY, dec!(1.04), dec!(2.5)),
+                (BookUpdateType::REMOVE, dec!(1.04), dec!(0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_trade_subscription() {
+        let mut balance_manager = get_simple_balance_manager();
+        init_balance(&mut balance_manager);
+        let balance_manager_rc = Rc::new(RefCell::new(balance_manager));
+        let mut market = get_simple_market(balance_manager_rc);
+        let mut trades = market.subscribe_trades();
+        let ask = market
+            .put_order(true, limit_order_input(102, OrderSide::ASK, dec!(2), dec!(1.04), TimeInForce::GTC))
+            .unwrap();
+        assert!(trades.try_next().is_err());
+        let bid = market
+            .put_order(true, limit_order_input(101, OrderSide::BID, dec!(1.5), dec!(1.1), TimeInForce::GTC))
+            .unwrap();
+        let trade = trades.try_next().unwrap().unwrap();
+        assert_eq!((trade.price, trade.amount), (dec!(1.04), dec!(1.5)));
+        assert_eq!((trade.ask_order_id, trade.ask_role), (ask.id, MarketRole::MAKER));
+        assert_eq!((trade.bid_order_id, trade.bid_role), (bid.id, MarketRole::TAKER));
+        assert!(trades.try_next().is_err());
+    }
+
+    #[test]
+    fn test_trade_timestamps_ordered() {
+        let mut balance_manager = get_simple_balance_manager();
+        init_balance(&mut balance_manager);
+        let balance_manager_rc = Rc::new(RefCell::new(balance_manager));
+        let mut market = get_simple_market(balance_manager_rc);
+        // the clock doesn't move, as if everything happened within the same millisecond
+        let clock = Rc::new(MockClock::new(1_615_379_696.123));
+        market.set_clock(clock.clone());
+        let mut trades = market.subscribe_trades();
+        let first_ask = market
+            .put_order(true, limit_order_input(102, OrderSide::ASK, dec!(1), dec!(1.04), TimeInForce::GTC))
+            .unwrap();
+        let second_ask = market
+            .put_order(true, limit_order_input(102, OrderSide::ASK, dec!(1), dec!(1.04), TimeInForce::GTC))
+            .unwrap();
+        assert!(first_ask.create_time < second_ask.create_time);
+        market
+            .put_order(true, limit_order_input(101, OrderSide::BID, dec!(2), dec!(1.04), TimeInForce::GTC))
+            .unwrap();
+        let first = trades.try_next().unwrap().unwrap();
+        let second = trades.try_next().unwrap().unwrap();
+        assert_eq!((first.ask_order_id, second.ask_order_id), (first_ask.id, second_ask.id));
+        assert!(first.timestamp < second.timestamp);
+        assert_eq!(
+            FTimestamp(second.timestamp).as_micros() - FTimestamp(first.timestamp).as_micros(),
+            1
+        );
+        assert_eq!(FTimestamp(first.timestamp).as_micros() / 1000, 1_615_379_696_123);
+    }
+
+    #[test]
+    fn test_cancel_all_for_user() {
+        let mut balance_manager = get_simple_balance_manager();
+        init_balance(&mut balance_manager);
+        let balance_manager_rc = Rc::new(RefCell::new(balance_manager));
+        let mut market = get_simple_market(balance_manager_rc.clone());
+        assert!(market.cancel_all_for_user(true, 102).is_empty());
+        for price in &[dec!(1.1), dec!(1.2)] {
+            market
+                .put_order(true, limit_order_input(102, OrderSide::ASK, dec!(2), *price, TimeInForce::GTC))
+                .unwrap();
+        }
+        market
+            .put_order(true, limit_order_input(101, OrderSide::ASK, dec!(1), dec!(1.3), TimeInForce::GTC))
+            .unwrap();
+        assert_eq!(balance_manager_rc.borrow_mut().get(102, BalanceType::FREEZE, &eth()), dec!(4));
+
+        let canceled = market.cancel_all_for_user(true, 102);
+        assert_eq!(canceled.len(), 2);
+        assert!(canceled.iter().all(|order| order.user == 102));
+        assert_eq!(balance_manager_rc.borrow_mut().get(102, BalanceType::FREEZE, &eth()), dec!(0));
+        assert_eq!(market.asks.len(), 1);
+        assert!(market.cancel_all_for_user(true, 102).is_empty());
+    }
+
+    // return the ids of the asks in matching order
+    fn ask_queue(market: &Market) -> Vec<u64> {
+        market.asks.values().map(|order_rc| order_rc.borrow().id).collect()
+    }
+
+    #[test]
+    fn test_amend_order_decrease_keeps_priority() {
+        let mut balance_manager = get_simple_balance_manager();
+        init_balance(&mut balance_manager);
+        let balance_manager_rc = Rc::new(RefCell::new(balance_manager));
+        let mut market = get_simple_market(balance_manager_rc.clone());
+        let first = market
+            .put_order(true, limit_order_input(102, OrderSide::ASK, dec!(5), dec!(1.1), TimeInForce::GTC))
+            .unwrap();
+        let second = market
+            .put_order(true, limit_order_input(101, OrderSide::ASK, dec!(5), dec!(1.1), TimeInForce::GTC))
+            .unwrap();
+
+        let amended = market.amend_order(true, first.id, None, Some(dec!(3))).unwrap();
+        assert_eq!((amended.id, amended.amount, amended.remain), (first.id, dec!(3), dec!(3)));
+        assert_eq!(ask_queue(&market), vec![first.id, second.id]);
+        assert_eq!(balance_manager_rc.borrow_mut().get(102, BalanceType::FREEZE, &eth()), dec!(3));
+
+        // the first order is still matched first
+        market
+            .put_order(true, limit_order_input(101, OrderSide::BID, dec!(1), dec!(1.1), TimeInForce::GTC))
+            .unwrap();
+        assert_eq!(market.get(first.id).unwrap().remain, dec!(2));
+        assert_eq!(market.get(second.id).unwrap().remain, dec!(5));
+    }
+
+    #[test]
+    fn test_amend_order_requeue() {
+        let mut balance_manager = get_simple_balance_manager();
+        init_balance(&mut balance_manager);
+        let balance_manager_rc = Rc::new(RefCell::new(balance_manager));
+        let mut market = get_simple_market(balance_manager_rc.clone());
+        let first = market
+            .put_order(true, limit_order_input(102, OrderSide::ASK, dec!(5), dec!(1.1), TimeInForce::GTC))
+            .unwrap();
+        let second = market
+            .put_order(true, limit_order_input(101, OrderSide::ASK, dec!(5), dec!(1.1), TimeInForce::GTC))
+            .unwrap();
+
+        // increasing the amount queues the order again
+        market.amend_order(true, first.id, None, Some(dec!(6))).unwrap();
+        assert_eq!(ask_queue(&market), vec![second.id, first.id]);
+        assert_eq!(balance_manager_rc.borrow_mut().get(102, BalanceType::FREEZE, &eth()), dec!(6));
+
+        // so does changing the price, even back to a better one
+        market.amend_order(true, second.id, Some(dec!(1.2)), None).unwrap();
+        market.amend_order(true, second.id, Some(dec!(1.1)), None).unwrap();
+        assert_eq!(ask_queue(&market), vec![first.id, second.id]);
+
+        // a bid freezes the quote delta
+        let bid = market
+            .put_order(true, limit_order_input(101, OrderSide::BID, dec!(10), dec!(1), TimeInForce::GTC))
+            .unwrap();
+        market.amend_order(true, bid.id, Some(dec!(1.05)), Some(dec!(20))).unwrap();
+        assert_eq!(balance_manager_rc.borrow_mut().get(101, BalanceType::FREEZE, &usdt()), dec!(21));
+        assert!(market.amend_order(true, bid.id, Some(dec!(1.1)), None).is_err());
+        assert!(market.amend_order(true, bid.id, None, Some(dec!(1000))).is_err());
+    }
+
+    #[test]
+    fn test_replay_order_ids() {
+        // replay the same operations on a fresh market, as the operation log is replayed
+        let replay = || {
+            let mut balance_manager = get_simple_balance_manager();
+            init_balance(&mut balance_manager);
+            let mut market = get_simple_market(Rc::new(RefCell::new(balance_manager)));
+            let mut ids = Vec::new();
+            let iceberg = market
+                .put_order(false, iceberg_order_input(102, OrderSide::ASK, dec!(3), dec!(1), dec!(1.1)))
+                .unwrap();
+            ids.push(iceberg.id);
+            for amount in &[dec!(1), dec!(1.5)] {
+                let order = market
+                    .put_order(false, limit_order_input(101, OrderSide::BID, *amount, dec!(1.1), TimeInForce::GTC))
+                    .unwrap();
+                ids.push(order.id);
+            }
+            let ask = market
+                .put_order(false, limit_order_input(101, OrderSide::ASK, dec!(2), dec!(1.2), TimeInForce::GTC))
+                .unwrap();
+            ids.push(ask.id);
+            ids.push(market.amend_order(false, ask.id, Some(dec!(1.3)), None).unwrap().priority);
+            ids.push(market.get(iceberg.id).unwrap().priority);
+            ids
+        };
+        let ids = replay();
+        assert_eq!(ids, replay());
+        // the iceberg slices and the amended order queue again without taking order ids
+        assert_eq!(ids, vec![1, 2, 3, 4, 7, 5]);
+    }
+
+    #[test]
+    fn test_pro_rata_allocation() {
+        assert_eq!(
+            pro_rata_allocation(dec!(12), &[dec!(10), dec!(20), dec!(30)], 4),
+            vec![dec!(2), dec!(4), dec!(6)]
+        );
+        // the rounded off lot goes to the earliest maker
+        assert_eq!(
+            pro_rata_allocation(dec!(1), &[dec!(1), dec!(1), dec!(1)], 4),
+            vec![dec!(0.3334), dec!(0.3333), dec!(0.3333)]
+        );
+        // and to the least filled one before
+        assert_eq!(
+            pro_rata_allocation(dec!(0.0003), &[dec!(5), dec!(0.0001), dec!(5)], 4),
+            vec![dec!(0.0001), dec!(0.0001), dec!(0.0001)]
+        );
+        // the whole level is taken
+        assert_eq!(pro_rata_allocation(dec!(7), &[dec!(2), dec!(3)], 4), vec![dec!(2), dec!(3)]);
+
+        let sizes = [dec!(3.7), dec!(0.0009), dec!(12.3456), dec!(1)];
+        for total in vec![dec!(0.0001), dec!(0.0133), dec!(5.5555), dec!(17)] {
+            let allocation = pro_rata_allocation(total, &sizes, 4);
+            assert_eq!(allocation.iter().copied().sum::<Decimal>(), total);
+            for (amount, size) in allocation.iter().zip(sizes.iter()) {
+                assert_eq!(amount.round_dp(4), *amount);
+                assert!(amount <= size);
+            }
+        }
+    }
+
+    #[test]
+    fn test_pro_rata_matching() {
+        let mut balance_manager = get_simple_balance_manager();
+        init_balance(&mut balance_manager);
+        balance_manager.add(103, BalanceType::AVAILABLE, &eth(), &dec!(1000)).unwrap();
+        let balance_manager_rc = Rc::new(RefCell::new(balance_manager));
+        let mut market = get_simple_market(balance_manager_rc.clone());
+        market.matching_mode = config::MatchingMode::ProRata;
+        let ask1 = market
+            .put_order(false, limit_order_input(101, OrderSide::ASK, dec!(10), dec!(1), TimeInForce::GTC))
+            .unwrap();
+        let ask2 = market
+            .put_order(false, limit_order_input(103, OrderSide::ASK, dec!(20), dec!(1), TimeInForce::GTC))
+            .unwrap();
+        let ask3 = market
+            .put_order(false, limit_order_input(101, OrderSide::ASK, dec!(10), dec!(2), TimeInForce::GTC))
+            .unwrap();
+
+        let bid = market
+            .put_order(false, limit_order_input(102, OrderSide::BID, dec!(3), dec!(2), TimeInForce::GTC))
+            .unwrap();
+        assert_eq!(bid.finished_base, dec!(3));
+        assert_eq!(market.get(ask1.id).unwrap().remain, dec!(9));
+        assert_eq!(market.get(ask2.id).unwrap().remain, dec!(18));
+        // the next level is not reached
+        assert_eq!(market.get(ask3.id).unwrap().remain, dec!(10));
+
+        // 0.0033 and 0.0066, the rounded off lot goes to the first maker
+        let bid = market
+            .put_order(false, limit_order_input(102, OrderSide::BID, dec!(0.01), dec!(1), TimeInForce::GTC))
+            .unwrap();
+        assert_eq!(bid.finished_base, dec!(0.01));
+        assert_eq!(market.get(ask1.id).unwrap().remain, dec!(8.9966));
+        assert_eq!(market.get(ask2.id).unwrap().remain, dec!(17.9934));
+
+        let balance_manager = balance_manager_rc.borrow();
+        assert_eq!(balance_manager.get(102, BalanceType::AVAILABLE, &eth()), dec!(1003.01));
+        assert_eq!(balance_manager.get(103, BalanceType::AVAILABLE, &usdt()), dec!(2.0066));
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let mut balance_manager = get_simple_balance_manager();
+        init_balance(&mut balance_manager);
+        let balance_manager_rc = Rc::new(RefCell::new(balance_manager));
+        let mut market = get_simple_market(balance_manager_rc);
+        market.circuit_breaker.config = config::CircuitBreakerConfig {
+            max_move: dec!(0.1),
+            ..Default::default()
+        };
+        let trade_at = |market: &mut Market, price: Decimal| {
+            market
+                .put_order(true, limit_order_input(101, OrderSide::ASK, dec!(1), price, TimeInForce::GTC))
+                .unwrap();
+            market.put_order(true, limit_order_input(102, OrderSide::BID, dec!(1), price, TimeInForce::GTC))
+        };
+        let now = utils::current_timestamp();
+        trade_at(&mut market, dec!(1)).unwrap();
+        trade_at(&mut market, dec!(1.1)).unwrap();
+        assert_eq!(market.check_circuit_breaker(now), None);
+
+        // 20% from the oldest price in the window
+        trade_at(&mut market, dec!(1.2)).unwrap();
+        let until = market.check_circuit_breaker(now).unwrap();
+        assert_eq!(until, now + 300.0);
+        assert!(market.is_halted() && market.status().halted);
+        let err = market
+            .put_order(true, limit_order_input(101, OrderSide::ASK, dec!(1), dec!(1.2), TimeInForce::GTC))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "market halted");
+        // the orders in the operation log were accepted before the halt
+        assert!(market
+            .put_order(false, limit_order_input(101, OrderSide::ASK, dec!(1), dec!(1.2), TimeInForce::GTC))
+            .is_ok());
+
+        // the price move is measured from the trades after resuming
+        market.resume();
+        trade_at(&mut market, dec!(1.2)).unwrap();
+        assert_eq!(market.check_circuit_breaker(now), None);
+    }
+
+    #[test]
+    fn test_market_state() {
+        let mut balance_manager = get_simple_balance_manager();
+        init_balance(&mut balance_manager);
+        let balance_manager_rc = Rc::new(RefCell::new(balance_manager));
+        let mut market = get_simple_market(balance_manager_rc.clone());
+        let ask = |price: Decimal| limit_order_input(102, OrderSide::ASK, dec!(1), price, TimeInForce::GTC);
+        let bid = |price: Decimal| limit_order_input(101, OrderSide::BID, dec!(1), price, TimeInForce::GTC);
+        let resting_ask = market.put_order(true, ask(dec!(1.1))).unwrap();
+
+        // only the orders which don't match
+        market.set_state(MarketState::PostOnly);
+        assert_eq!(market.status().state, MarketState::PostOnly);
+        let err = market.put_order(true, bid(dec!(1.1))).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "market is post-only: only the orders resting on the book are accepted"
+        );
+        assert!(market
+            .put_order(true, limit_order_input(101, OrderSide::BID, dec!(1), dec!(1), TimeInForce::IOC))
+            .is_err());
+        let resting_bid = market.put_order(true, bid(dec!(1))).unwrap();
+        assert_eq!(market.trade_count, 0);
+
+        // the open orders can be reduced and canceled, nothing new is placed
+        market.set_state(MarketState::CancelOnly);
+        let err = market.put_order(true, bid(dec!(0.9))).unwrap_err();
+        assert_eq!(err.to_string(), "market is cancel_only: new orders are rejected");
+        assert!(market.check_order(&bid(dec!(0.9))).is_err());
+        assert!(market
+            .put_trigger_order(dec!(1.2), TriggerDirection::ABOVE, ask(dec!(1.2)))
+            .is_err());
+        assert!(market.amend_order(true, resting_ask.id, Some(dec!(1.2)), None).is_err());
+        assert!(market.amend_order(true, resting_ask.id, None, Some(dec!(2))).is_err());
+        market.amend_order(true, resting_ask.id, None, Some(dec!(0.5))).unwrap();
+        assert_eq!(balance_manager_rc.borrow().get(102, BalanceType::FREEZE, &eth()), dec!(0.5));
+        market.cancel(true, resting_bid.id);
+        assert_eq!(balance_manager_rc.borrow().get(101, BalanceType::FREEZE, &usdt()), dec!(0));
+        // the orders in the operation log were accepted before the change
+        assert!(market.put_order(false, bid(dec!(0.9))).is_ok());
+
+        // the remaining orders are all canceled by the caller
+        market.set_state(MarketState::Delisted);
+        assert_eq!(market.cancel_all(true).len(), 2);
+        assert!(market.orders.is_empty());
+        assert_eq!(balance_manager_rc.borrow().get(102, BalanceType::FREEZE, &eth()), dec!(0));
+        assert!(market.put_order(true, ask(dec!(1.1))).is_err());
+
+        market.set_state(MarketState::Active);
+        market.put_order(true, ask(dec!(1.1))).unwrap();
+        market.put_order(true, bid(dec!(1.1))).unwrap();
+        assert_eq!(market.trade_count, 1);
+        assert_eq!("cancel_only".parse::<MarketState>().unwrap(), MarketState::CancelOnly);
+    }
+
+    #[test]
+    fn test_verify_integrity() {
+        let mut balance_manager = get_simple_balance_manager();
+        init_balance(&mut balance_manager);
+        let balance_manager_rc = Rc::new(RefCell::new(balance_manager));
+        let mut market = get_simple_market(balance_manager_rc.clone());
+        market
+            .put_order(true, limit_order_input(102, OrderSide::ASK, dec!(1), dec!(1.1), TimeInForce::GTC))
+            .unwrap();
+        let bid = market
+            .put_order(true, limit_order_input(101, OrderSide::BID, dec!(1), dec!(1), TimeInForce::GTC))
+            .unwrap();
+        let mut markets = HashMap::new();
+        markets.insert(market.name.to_string(), market);
+        assert!(verify_integrity(&markets, &balance_manager_rc.borrow()).is_empty());
+
+        // a crossing bid put on the book without matching or freezing, and a balance frozen without any order
+        let id = bid.id + 10;
+        markets.get_mut("ETH_USDT").unwrap().insert_order(Rc::new(RefCell::new(Order {
+            id,
+            priority: id,
+            price: dec!(1.2),
+            ..bid
+        })));
+        balance_manager_rc.borrow_mut().frozen(102, &usdt(), &dec!(5)).unwrap();
+        let violations = verify_integrity(&markets, &balance_manager_rc.borrow());
+        assert_eq!(
+            violations,
+            vec![
+                IntegrityViolation::CrossedBook {
+                    market: "ETH_USDT".to_string(),
+                    best_bid: dec!(1.2),
+                    best_ask: dec!(1.1),
+                },
+                IntegrityViolation::FrozenMismatch {
+                    user_id: 101,
+                    asset: usdt(),
+                    frozen: dec!(1),
+                    reserved: dec!(2.2),
+                },
+                IntegrityViolation::FrozenMismatch {
+                    user_id: 102,
+                    asset: usdt(),
+                    frozen: dec!(5),
+                    reserved: dec!(0),
+                },
+            ]
+        );
+        assert_eq!(
+            violations[0].to_string(),
+            "book of ETH_USDT is crossed: best bid 1.2 >= best ask 1.1"
+        );
+    }
+
+    #[test]
+    fn test_ticker_window() {
+        let mut ticker = Ticker::default();
+        let now = 1_600_000_000.0;
+        ticker.record(now, dec!(10), dec!(1), dec!(10));
+        ticker.record(now + 100.0, dec!(12), dec!(2), dec!(24));
+        ticker.record(now + 200.0, dec!(9), dec!(1), dec!(9));
+        ticker.record(now + 300.0, dec!(11), dec!(1), dec!(11));
+
+        let summary = ticker.summary(now + 300.0, dec!(11));
+        assert_eq!((summary.open, summary.high, summary.low), (dec!(10), dec!(12), dec!(9)));
+        assert_eq!((summary.base_volume, summary.quote_volume), (dec!(5), dec!(54)));
+        assert_eq!(summary.price_change_percent, dec!(10));
+
+        // the first two trades are older than 24 hours
+        let summary = ticker.summary(now + TICKER_WINDOW + 150.0, dec!(11));
+        assert_eq!((summary.open, summary.high, summary.low), (dec!(9), dec!(11), dec!(9)));
+        assert_eq!((summary.base_volume, summary.quote_volume), (dec!(2), dec!(20)));
+        assert_eq!(summary.price_change_percent, dec!(22.22));
+
+        let summary = ticker.summary(now + TICKER_WINDOW + 400.0, dec!(11));
+        assert_eq!((summary.open, summary.high, summary.low), (dec!(0), dec!(0), dec!(0)));
+        assert_eq!((summary.base_volume, summary.quote_volume), (dec!(0), dec!(0)));
+        assert_eq!(summary.last_price, dec!(11));
+    }
+
+    #[test]
+    fn test_order_batch() {
+        let mut balance_manager = get_simple_balance_manager();
+        init_balance(&mut balance_manager);
+        let balance_manager_rc = Rc::new(RefCell::new(balance_manager));
+        let mut markets = HashMap::new();
+        markets.insert(String::from("ETH_USDT"), get_simple_market(balance_manager_rc.clone()));
+        // 300 USDT covers each bid alone, but not both
+        let orders = vec![
+            limit_order_input(101, OrderSide::BID, dec!(1), dec!(200), TimeInForce::GTC),
+            limit_order_input(101, OrderSide::BID, dec!(1), dec!(150), TimeInForce::GTC),
+        ];
+
+        // all or nothing: the second order overspends, so none is placed
+        let (idx, _) = check_order_batch(&markets, &orders).unwrap_err();
+        assert_eq!(idx, 1);
+        let market = markets.get_mut("ETH_USDT").unwrap();
+        assert!(market.bids.is_empty());
+        assert_eq!(balance_manager_rc.borrow_mut().get(101, BalanceType::FREEZE, &usdt()), dec!(0));
+        assert!(check_order_batch(&markets, &orders[..1]).is_ok());
+
+        // best effort: each order is placed or rejected on its own
+        let market = markets.get_mut("ETH_USDT").unwrap();
+        let results: Vec<Result<Order>> = orders.into_iter().map(|order| market.put_order(false, order)).collect();
+        assert_eq!(results[0].as_ref().unwrap().price, dec!(200));
+        assert!(results[1].is_err());
+        assert_eq!(balance_manager_rc.borrow_mut().get(101, BalanceType::FREEZE, &usdt()), dec!(200));
+
+        // the balances are summed by user
+        let orders = vec![
+            limit_order_input(102, OrderSide::BID, dec!(1), dec!(200), TimeInForce::GTC),
+            limit_order_input(101, OrderSide::ASK, dec!(1), dec!(300), TimeInForce::GTC),
+        ];
+        assert!(check_order_batch(&markets, &orders).is_ok());
+    }
+
+    #[test]
+    fn test_cancel_orders() {
+        let mut balance_manager = get_simple_balance_manager();
+        init_balance(&mut balance_manager);
+        let balance_manager_rc = Rc::new(RefCell::new(balance_manager));
+        let mut market = get_simple_market(balance_manager_rc.clone());
+        let own = market
+            .put_order(false, limit_order_input(101, OrderSide::BID, dec!(1), dec!(100), TimeInForce::GTC))
+            .unwrap();
+        let cancelled = market
+            .put_order(false, limit_order_input(101, OrderSide::BID, dec!(1), dec!(90), TimeInForce::GTC))
+            .unwrap();
+        let foreign = market
+            .put_order(false, limit_order_input(102, OrderSide::BID, dec!(1), dec!(80), TimeInForce::GTC))
+            .unwrap();
+        market.cancel(false, cancelled.id);
+        assert_eq!(balance_manager_rc.borrow().get(101, BalanceType::FREEZE, &usdt()), dec!(100));
+
+        let results = market.cancel_orders(false, 101, &[own.id, foreign.id, cancelled.id, 999]);
+        assert!(matches!(results[0], CancelResult::Cancelled(order) if order.id == own.id));
+        assert!(matches!(results[1], CancelResult::NotOwner));
+        assert!(matches!(results[2], CancelResult::NotFound));
+        assert!(matches!(results[3], CancelResult::NotFound));
+        // the cancelled order is unfrozen, the foreign one is still on the book
+        assert_eq!(balance_manager_rc.borrow().get(101, BalanceType::FREEZE, &usdt()), dec!(0));
+        assert_eq!(balance_manager_rc.borrow().get(101, BalanceType::AVAILABLE, &usdt()), dec!(300));
+        assert!(market.get(foreign.id).is_some());
+        assert_eq!(balance_manager_rc.borrow().get(102, BalanceType::FREEZE, &usdt()), dec!(80));
+    }
+
+    #[test]
+    fn test_subscribe_orders() {
+        let mut balance_manager = get_simple_balance_manager();
+        init_balance(&mut balance_manager);
+        let mut market = get_simple_market(Rc::new(RefCell::new(balance_manager)));
+        let (sender, mut receiver) = futures_channel::mpsc::channel(16);
+        market.subscribe_orders(101, sender);
+
+        let ask = market
+            .put_order(true, limit_order_input(101, OrderSide::ASK, dec!(2), dec!(10), TimeInForce::GTC))
+            .unwrap();
+        market
+            .put_order(true, limit_order_input(102, OrderSide::BID, dec!(1), dec!(10), TimeInForce::GTC))
+            .unwrap();
+        market
+            .put_order(true, limit_order_input(102, OrderSide::BID, dec!(1), dec!(10), TimeInForce::GTC))
+            .unwrap();
+
+        // placed, partially filled, then filled; the orders of the other user are not sent
+        let events: Vec<OrderMessage> = std::iter::from_fn(|| receiver.try_next().ok().flatten()).collect();
+        assert_eq!(events.len(), 3);
+        assert!(events.iter().all(|event| event.order.id == ask.id));
+        assert_eq!(events[0].event, OrderEventType::PUT);
+        assert_eq!(events[1].event, OrderEventType::UPDATE);
+        assert_eq!(events[1].order.remain, dec!(1));
+        assert_eq!(events[2].event, OrderEventType::FINISH);
+        assert!(events[2].order.remain.is_zero());
+    }
+
+    #[test]
+    fn test_open_order_limit() {
+        let mut balance_manager = get_simple_balance_manager();
+        init_balance(&mut balance_manager);
+        let mut market = get_simple_market(Rc::new(RefCell::new(balance_manager)));
+        let limits = OpenOrderLimits::new(&config::OpenOrderLimitConfig {
+            max_open_orders_per_market: 2,
+            user_overrides: vec![config::OpenOrderLimit {
+                user_id: 102,
+                max_open_orders_per_market: 0,
+            }],
+        });
+        // as `Controller::order_put` does
+        let put = |market: &mut Market, user_id: u32, price: Decimal| -> Result<Order> {
+            limits.check(market, user_id, 0)?;
+            market.put_order(true, limit_order_input(user_id, OrderSide::ASK, dec!(1), price, TimeInForce::GTC))
+        };
+
+        let first = put(&mut market, 101, dec!(10)).unwrap();
+        put(&mut market, 101, dec!(11)).unwrap();
+        let err = put(&mut market, 101, dec!(12)).unwrap_err();
+        assert!(err.to_string().starts_with("too many open orders"));
+        // the other user is not limited
+        for i in 0..3 {
+            put(&mut market, 102, dec!(20) + Decimal::from(i)).unwrap();
+        }
+
+        // a cancel frees a slot
+        market.cancel(true, first.id);
+        put(&mut market, 101, dec!(12)).unwrap();
+        assert!(put(&mut market, 101, dec!(13)).is_err());
+        assert!(limits.check(&market, 101, 0).is_err());
+        assert_eq!(limits.limit(101), 2);
+    }
 }
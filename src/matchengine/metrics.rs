@@ -0,0 +1,146 @@
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Response, Server};
+use prometheus::{Encoder, Gauge, Histogram, HistogramOpts, IntCounter, IntGauge, Registry, TextEncoder};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+
+// All the metrics are atomics shared with the http server, updating them takes no lock.
+// Only operations from requests are counted, replayed ones are not.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    pub orders_placed: IntCounter,
+    pub orders_cancelled: IntCounter,
+    pub trades_executed: IntCounter,
+    // seconds spent in matching a new order
+    pub matching_latency: Histogram,
+    // entries in the dedup cache of balance updates
+    pub balance_cache_size: IntGauge,
+    pub balance_cache_hits: IntCounter,
+    pub balance_cache_misses: IntCounter,
+    pub balance_cache_inserts: IntCounter,
+    // inserts into a full cache, which push the least recently used entry out
+    pub balance_cache_evictions: IntCounter,
+    // operation logs waiting to be written to the db
+    pub operation_log_lag: IntGauge,
+    // seconds of the last slice made by the engine itself, forked slices are not seen here
+    pub snapshot_duration: Gauge,
+    // history rows waiting to be written to the db, of all the tables
+    pub history_queue_depth: IntGauge,
+    // history rows appended to the spill file as the queue was full
+    pub history_rows_spilled: IntCounter,
+    // commands waiting to be executed by the engine
+    pub command_queue_depth: IntGauge,
+    // commands rejected as the queue was too long
+    pub commands_shed: IntCounter,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        let registry = Registry::new_custom(Some("dingir".to_string()), None).unwrap();
+        let metrics = Metrics {
+            orders_placed: IntCounter::new("orders_placed_total", "Orders placed").unwrap(),
+            orders_cancelled: IntCounter::new("orders_cancelled_total", "Orders cancelled").unwrap(),
+            trades_executed: IntCounter::new("trades_executed_total", "Trades executed").unwrap(),
+            matching_latency: Histogram::with_opts(
+                HistogramOpts::new("matching_latency_seconds", "Time to match a new order")
+                    .buckets(prometheus::exponential_buckets(0.000_005, 2.0, 16).unwrap()),
+            )
+            .unwrap(),
+            balance_cache_size: IntGauge::new("balance_cache_size", "Entries in the balance update cache").unwrap(),
+            balance_cache_hits: IntCounter::new("balance_cache_hits_total", "Duplicate balance updates found in the cache").unwrap(),
+            balance_cache_misses: IntCounter::new("balance_cache_misses_total", "Balance updates not found in the cache").unwrap(),
+            balance_cache_inserts: IntCounter::new("balance_cache_inserts_total", "Entries inserted into the balance update cache")
+                .unwrap(),
+            balance_cache_evictions: IntCounter::new(
+                "balance_cache_evictions_total",
+                "Entries evicted from the full balance update cache",
+            )
+            .unwrap(),
+            operation_log_lag: IntGauge::new("operation_log_lag", "Operation logs not written to the db yet").unwrap(),
+            snapshot_duration: Gauge::new("snapshot_duration_seconds", "Duration of the last slice").unwrap(),
+            history_queue_depth: IntGauge::new("history_queue_depth", "History rows not written to the db yet").unwrap(),
+            history_rows_spilled: IntCounter::new("history_rows_spilled_total", "History rows spilled to the local file").unwrap(),
+            command_queue_depth: IntGauge::new("command_queue_depth", "Commands waiting to be executed by the engine").unwrap(),
+            commands_shed: IntCounter::new("commands_shed_total", "Commands rejected as the engine was busy").unwrap(),
+            registry,
+        };
+        metrics.registry.register(Box::new(metrics.orders_placed.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.orders_cancelled.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.trades_executed.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.matching_latency.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.balance_cache_size.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.balance_cache_hits.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.balance_cache_misses.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.balance_cache_inserts.clone())).unwrap();
+        metrics
+            .registry
+            .register(Box::new(metrics.balance_cache_evictions.clone()))
+            .unwrap();
+        metrics.registry.register(Box::new(metrics.operation_log_lag.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.snapshot_duration.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.history_queue_depth.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.history_rows_spilled.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.command_queue_depth.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.commands_shed.clone())).unwrap();
+        metrics
+    }
+    // the prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
+
+// every path returns the metrics
+pub async fn serve(addr: SocketAddr, metrics: Metrics) -> Result<(), hyper::Error> {
+    let make_service = make_service_fn(move |_| {
+        let metrics = metrics.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |_request| {
+                let response = Response::builder()
+                    .header(CONTENT_TYPE, TextEncoder::new().format_type())
+                    .body(Body::from(metrics.render()))
+                    .unwrap();
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    });
+    tracing::info!("serving metrics on {}", addr);
+    Server::bind(&addr).serve(make_service).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.orders_placed.inc();
+        metrics.orders_placed.inc();
+        metrics.matching_latency.observe(0.00001);
+        metrics.operation_log_lag.set(3);
+
+        let text = metrics.render();
+        assert!(text.contains("# TYPE dingir_orders_placed_total counter\ndingir_orders_placed_total 2\n"));
+        assert!(text.contains("# TYPE dingir_operation_log_lag gauge\ndingir_operation_log_lag 3\n"));
+        assert!(text.contains("dingir_matching_latency_seconds_count 1\n"));
+        assert!(text.contains("dingir_matching_latency_seconds_bucket{le=\"+Inf\"} 1\n"));
+        // every sample line is a metric name, optional labels and a number
+        for line in text.lines().filter(|line| !line.starts_with('#')) {
+            let mut parts = line.rsplitn(2, ' ');
+            let value = parts.next().unwrap();
+            assert!(value.parse::<f64>().is_ok(), "{}", line);
+            assert!(parts.next().unwrap().starts_with("dingir_"), "{}", line);
+        }
+    }
+}
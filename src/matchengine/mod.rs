@@ -1,8 +1,19 @@
 pub mod asset;
+pub mod clickhouse;
+pub mod client;
+pub mod command_queue;
 pub mod controller;
 pub mod dto;
+pub mod fee;
 pub mod history;
+pub mod idempotency;
+pub mod kline;
 pub mod market;
+pub mod metrics;
 pub mod persist;
+pub mod reserves;
 pub mod sequencer;
 pub mod server;
+pub mod subscription;
+pub mod surveillance;
+pub mod websocket;
@@ -0,0 +1,236 @@
+use crate::asset::BalanceManager;
+use rust_decimal::prelude::Zero;
+use rust_decimal::Decimal;
+use sha2::{Digest, Sha256};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+// A Merkle tree over the balances the exchange owes, so users can check theirs are counted in the
+// published root. A leaf is sha256(0x00 || user_id as u32 big endian || asset || 0x00 || balance),
+// with the balance as a decimal string without trailing zeros. A node is
+// sha256(0x01 || left || right), and the last node of an odd level is carried up as is.
+// The leaves are ordered by user and asset, so the same balances always give the same root.
+
+pub type Hash = [u8; 32];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LiabilityLeaf {
+    pub user_id: u32,
+    pub asset: String,
+    // AVAILABLE + FREEZE
+    pub balance: Decimal,
+}
+
+impl LiabilityLeaf {
+    pub fn hash(&self) -> Hash {
+        let mut hasher = Sha256::new();
+        hasher.update([0u8]);
+        hasher.update(self.user_id.to_be_bytes());
+        hasher.update(self.asset.as_bytes());
+        hasher.update([0u8]);
+        hasher.update(canonical_decimal(&self.balance).as_bytes());
+        hasher.finalize().into()
+    }
+}
+
+// without trailing zeros, so the scale of a balance doesn't matter
+fn canonical_decimal(value: &Decimal) -> String {
+    let s = value.to_string();
+    if s.contains('.') {
+        s.trim_end_matches('0').trim_end_matches('.').to_string()
+    } else {
+        s
+    }
+}
+
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([1u8]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProofStep {
+    pub sibling: Hash,
+    // the sibling is the left child
+    pub is_left: bool,
+}
+
+// the non-zero balances of all the users but the fee account
+pub fn liability_leaves(balance_manager: &BalanceManager) -> Vec<LiabilityLeaf> {
+    let mut balances: BTreeMap<(u32, &str), Decimal> = BTreeMap::new();
+    for (k, amount) in balance_manager.balances.iter() {
+        if balance_manager.is_fee_account(k.user_id) {
+            continue;
+        }
+        *balances.entry((k.user_id, &k.asset)).or_insert_with(Decimal::zero) += amount;
+    }
+    balances
+        .into_iter()
+        .filter(|(_, balance)| !balance.is_zero())
+        .map(|((user_id, asset), balance)| LiabilityLeaf {
+            user_id,
+            asset: asset.to_string(),
+            balance,
+        })
+        .collect()
+}
+
+pub struct LiabilityTree {
+    leaves: Vec<LiabilityLeaf>,
+    // from the leaf hashes up to the root
+    levels: Vec<Vec<Hash>>,
+}
+
+impl LiabilityTree {
+    pub fn new(mut leaves: Vec<LiabilityLeaf>) -> LiabilityTree {
+        leaves.sort_by(|a, b| (a.user_id, &a.asset).cmp(&(b.user_id, &b.asset)));
+        let mut levels = vec![leaves.iter().map(LiabilityLeaf::hash).collect::<Vec<Hash>>()];
+        while levels.last().unwrap().len() > 1 {
+            let next = levels
+                .last()
+                .unwrap()
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => node_hash(left, right),
+                    [single] => *single,
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(next);
+        }
+        LiabilityTree { leaves, levels }
+    }
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+    // all zeros for an empty tree
+    pub fn root(&self) -> Hash {
+        self.levels.last().unwrap().first().copied().unwrap_or_default()
+    }
+    // the leaves of a user, with their positions and paths to the root
+    pub fn inclusion_proof(&self, user_id: u32) -> Vec<(usize, &LiabilityLeaf, Vec<ProofStep>)> {
+        let start = self
+            .leaves
+            .binary_search_by(|leaf| {
+                if leaf.user_id < user_id {
+                    Ordering::Less
+                } else {
+                    Ordering::Greater
+                }
+            })
+            .unwrap_err();
+        self.leaves[start..]
+            .iter()
+            .take_while(|leaf| leaf.user_id == user_id)
+            .enumerate()
+            .map(|(offset, leaf)| (start + offset, leaf, self.path(start + offset)))
+            .collect()
+    }
+    fn path(&self, mut index: usize) -> Vec<ProofStep> {
+        let mut path = Vec::new();
+        for level in &self.levels[..self.levels.len() - 1] {
+            let sibling = index ^ 1;
+            // a carried up node has no sibling
+            if sibling < level.len() {
+                path.push(ProofStep {
+                    sibling: level[sibling],
+                    is_left: sibling < index,
+                });
+            }
+            index /= 2;
+        }
+        path
+    }
+}
+
+pub fn verify_inclusion(leaf: &LiabilityLeaf, path: &[ProofStep], root: &Hash) -> bool {
+    let hash = path.iter().fold(leaf.hash(), |hash, step| {
+        if step.is_left {
+            node_hash(&step.sibling, &hash)
+        } else {
+            node_hash(&hash, &step.sibling)
+        }
+    });
+    hash == *root
+}
+
+pub fn to_hex(hash: &Hash) -> String {
+    hex::encode(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::*;
+
+    fn leaf(user_id: u32, asset: &str, balance: Decimal) -> LiabilityLeaf {
+        LiabilityLeaf {
+            user_id,
+            asset: asset.to_string(),
+            balance,
+        }
+    }
+
+    fn get_leaves() -> Vec<LiabilityLeaf> {
+        vec![
+            leaf(101, "USDT", dec!(100)),
+            leaf(101, "ETH", dec!(1.5)),
+            leaf(102, "USDT", dec!(20.25)),
+            leaf(103, "ETH", dec!(0.001)),
+            leaf(104, "USDT", dec!(7)),
+        ]
+    }
+
+    #[test]
+    fn test_valid_proof() {
+        let tree = LiabilityTree::new(get_leaves());
+        let root = tree.root();
+        for user_id in 101..=104 {
+            for (_, leaf, path) in tree.inclusion_proof(user_id) {
+                assert!(verify_inclusion(leaf, &path, &root), "{:?}", leaf);
+            }
+        }
+        let proofs = tree.inclusion_proof(101);
+        assert_eq!(proofs.len(), 2);
+        assert_eq!((proofs[0].0, proofs[0].1.asset.as_str()), (0, "ETH"));
+        assert!(tree.inclusion_proof(105).is_empty());
+
+        // the order of the input and the scale of the balances don't change the root
+        let mut leaves = get_leaves();
+        leaves.reverse();
+        leaves[0].balance = dec!(7.00);
+        assert_eq!(LiabilityTree::new(leaves).root(), root);
+    }
+
+    #[test]
+    fn test_tampered_leaf() {
+        let tree = LiabilityTree::new(get_leaves());
+        let root = tree.root();
+        let (_, leaf, path) = tree.inclusion_proof(102).pop().unwrap();
+        let mut tampered = leaf.clone();
+        tampered.balance = dec!(2025);
+        assert!(!verify_inclusion(&tampered, &path, &root));
+        let mut tampered = leaf.clone();
+        tampered.user_id = 101;
+        assert!(!verify_inclusion(&tampered, &path, &root));
+        // nor does a changed balance keep the root
+        let mut leaves = get_leaves();
+        leaves[2].balance = dec!(20.24);
+        assert_ne!(LiabilityTree::new(leaves).root(), root);
+    }
+
+    #[test]
+    fn test_single_and_empty_tree() {
+        let tree = LiabilityTree::new(vec![leaf(101, "USDT", dec!(1))]);
+        let (_, leaf, path) = tree.inclusion_proof(101).pop().unwrap();
+        assert!(path.is_empty());
+        assert_eq!(tree.root(), leaf.hash());
+        assert_eq!(LiabilityTree::new(Vec::new()).root(), [0u8; 32]);
+    }
+}
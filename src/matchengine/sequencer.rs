@@ -3,17 +3,18 @@ pub struct Sequencer {
     order_id: u64,
     trade_id: u64,
     operation_log_id: u64,
+    // sequence of the order book updates, it is not persisted since subscribers start from a snapshot
+    book_update_id: u64,
 }
 
 impl Sequencer {
     pub fn reset(&mut self) {
-        self.set_operation_log_id(0);
-        self.set_order_id(0);
-        self.set_trade_id(0);
+        tracing::debug!("reset sequencer");
+        *self = Sequencer::default();
     }
     pub fn next_order_id(&mut self) -> u64 {
         self.order_id += 1;
-        //log::debug!("next_order_id {}", self.order_id);
+        //tracing::debug!("next_order_id {}", self.order_id);
         self.order_id
     }
     pub fn next_trade_id(&mut self) -> u64 {
@@ -24,6 +25,10 @@ impl Sequencer {
         self.operation_log_id += 1;
         self.operation_log_id
     }
+    pub fn next_book_update_id(&mut self) -> u64 {
+        self.book_update_id += 1;
+        self.book_update_id
+    }
     pub fn get_operation_log_id(&self) -> u64 {
         self.operation_log_id
     }
@@ -33,16 +38,46 @@ impl Sequencer {
     pub fn get_order_id(&self) -> u64 {
         self.order_id
     }
+    pub fn get_book_update_id(&self) -> u64 {
+        self.book_update_id
+    }
     pub fn set_operation_log_id(&mut self, id: u64) {
-        log::debug!("set operation_log id {}", id);
+        tracing::debug!("set operation_log id {}", id);
         self.operation_log_id = id;
     }
     pub fn set_trade_id(&mut self, id: u64) {
-        log::debug!("set trade id {}", id);
+        tracing::debug!("set trade id {}", id);
         self.trade_id = id;
     }
+    // ids can only move forward, otherwise they may be assigned twice
     pub fn set_order_id(&mut self, id: u64) {
-        log::debug!("set order id {}", id);
+        tracing::debug!("set order id {}", id);
+        if id < self.order_id {
+            panic!("order id can't go back from {} to {}", self.order_id, id);
+        }
         self.order_id = id;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_order_id() {
+        let mut sequencer = Sequencer::default();
+        assert_eq!(sequencer.next_order_id(), 1);
+        sequencer.set_order_id(10);
+        assert_eq!(sequencer.next_order_id(), 11);
+        sequencer.reset();
+        assert_eq!(sequencer.next_order_id(), 1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_order_id_go_back() {
+        let mut sequencer = Sequencer::default();
+        sequencer.set_order_id(10);
+        sequencer.set_order_id(9);
+    }
+}
@@ -1,13 +1,76 @@
+use futures::{Stream, StreamExt};
+use std::pin::Pin;
+use std::sync::Arc;
 use tonic::{self, Request, Response, Status};
+use tonic_reflection::server::ServerReflectionServer;
 
 //use rust_decimal::Decimal;
 pub use crate::dto::*;
 
+use crate::auth::{self, ApiKeyStore, AuthError, UserScope};
+use crate::command_queue::{CommandKind, CommandQueue, CommandReceiver};
+use crate::config::{self, Permission};
+use anyhow::Context;
+use tonic::transport::{Certificate, Identity, ServerTlsConfig};
+
 //use crate::me_history::HistoryWriter;
-use crate::controller::G_RT;
 use crate::controller::G_STUB;
+use crate::controller::{self, G_RT};
+
+pub struct GrpcHandler {
+    pub auth: Arc<ApiKeyStore>,
+    // the writing calls go through it, the queries read the state at once
+    pub commands: Arc<CommandQueue>,
+    // held while a slice is written, one at a time
+    pub snapshot_lock: Arc<tokio::sync::Mutex<()>>,
+}
+
+fn api_key<'a, T>(auth: &ApiKeyStore, request: &'a Request<T>) -> Option<&'a str> {
+    request.metadata().get(auth.header()).and_then(|key| key.to_str().ok())
+}
+
+// the common name of the client certificate, under mutual tls
+fn peer_subject<T>(request: &Request<T>) -> Option<String> {
+    let certs = request.peer_certs()?;
+    certs.first().and_then(|cert| auth::subject_common_name(cert.get_ref()))
+}
+
+fn authenticate<T>(auth: &ApiKeyStore, request: &Request<T>) -> Result<UserScope, AuthError> {
+    auth.authenticate_peer(peer_subject(request).as_deref(), api_key(auth, request))
+}
+
+// None to serve plaintext
+pub fn tls_config(config: &config::GrpcTlsConfig) -> anyhow::Result<Option<ServerTlsConfig>> {
+    if config.plaintext {
+        return Ok(None);
+    }
+    if config.cert_path.is_empty() || config.key_path.is_empty() {
+        anyhow::bail!("grpc_tls.cert_path and grpc_tls.key_path are required, or set grpc_tls.plaintext for local development");
+    }
+    let read = |path: &str| std::fs::read(path).with_context(|| format!("read {}", path));
+    let mut tls = ServerTlsConfig::new().identity(Identity::from_pem(read(&config.cert_path)?, read(&config.key_path)?));
+    if !config.client_ca_path.is_empty() {
+        // the clients without a certificate signed by the ca fail the handshake
+        tls = tls.client_ca_root(Certificate::from_pem(read(&config.client_ca_path)?));
+    }
+    Ok(Some(tls))
+}
+
+// Reject the calls without a valid key early. The permission of each call is checked by its handler,
+// since the interceptor doesn't know which method is called.
+pub fn auth_interceptor(auth: Arc<ApiKeyStore>) -> impl Fn(Request<()>) -> Result<Request<()>, Status> + Send + Sync + 'static {
+    move |request: Request<()>| {
+        authenticate(&auth, &request)?;
+        Ok(request)
+    }
+}
 
-pub struct GrpcHandler {}
+impl GrpcHandler {
+    fn authorize<T>(&self, request: &Request<T>, permission: Permission, user_id: Option<u32>) -> Result<(), Status> {
+        authenticate(&self.auth, request)?.authorize(permission, user_id)?;
+        Ok(())
+    }
+}
 
 macro_rules! get_stub {
     () => {
@@ -15,15 +78,16 @@ macro_rules! get_stub {
     };
 }
 
-fn run_blocking_the_world_task<F, G>(f: G) -> Result<(), Status>
+fn run_blocking_the_world_task<T, F, G>(f: G) -> Result<T, Status>
 where
     G: FnOnce() -> F + Send + 'static, //We need additional wrapping to send the using of controller into another thread
-    F: std::future::Future<Output = Result<(), Status>> + 'static,
+    F: std::future::Future<Output = Result<T, Status>> + 'static,
+    T: Send + 'static,
 {
-    println!("We start a handling with block-the-world (grpc) mode");
+    tracing::info!("start a block-the-world task");
     //let handle = get_stub!().rt.clone();
 
-    let thr_handle = std::thread::spawn(move || -> Result<(), Status> {
+    let thr_handle = std::thread::spawn(move || -> Result<T, Status> {
         unsafe {
             (*G_RT).block_on(f())
             //            just for verification
@@ -33,23 +97,97 @@ where
 
     //simply block the thread in a crude way ...
     let ret = thr_handle.join();
-    println!("Block-the-world task done, continue running");
+    tracing::info!("block-the-world task done");
     ret.unwrap()
 }
 
+// Lists the services and their calls for tools like grpcurl, none when disabled in the config.
+// It is served without the api keys, it tells nothing but the protos.
+pub fn reflection_service(
+    enabled: bool,
+) -> anyhow::Result<Option<ServerReflectionServer<impl tonic_reflection::server::ServerReflection>>> {
+    if !enabled {
+        return Ok(None);
+    }
+    let service = tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(FILE_DESCRIPTOR_SET)
+        .build()?;
+    Ok(Some(service))
+}
+
+// Execute the queued commands one at a time, until the grpc handler is dropped
+pub async fn run_commands(mut receiver: CommandReceiver) {
+    while let Some(command) = receiver.next().await {
+        command(get_stub!());
+    }
+}
+
+// Resolves on SIGTERM or ctrl-c. The writing calls are rejected from then on,
+// and the process is killed if it is still running after `shutdown_timeout`.
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("listen to SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.ok();
+
+    let stub = get_stub!();
+    stub.engine_status.start_shutdown();
+    let timeout = stub.settings.shutdown_timeout;
+    tracing::info!("shutting down, exit in {:?} at most", timeout);
+    std::thread::spawn(move || {
+        std::thread::sleep(timeout);
+        tracing::error!("shutdown takes longer than {:?}, exit now", timeout);
+        std::process::exit(1);
+    });
+}
+
+// Run after the server has stopped, so no call is running
+pub fn graceful_shutdown() -> Result<(), Status> {
+    run_blocking_the_world_task(|| async {
+        let stub = get_stub!();
+        stub.shutdown().await.map_err(|err| Status::unknown(format!("{}", err)))
+    })
+}
+
 #[tonic::async_trait]
 impl Matchengine for GrpcHandler {
     async fn asset_list(&self, request: Request<AssetListRequest>) -> Result<Response<AssetListResponse>, Status> {
+        self.authorize(&request, Permission::ReadOnly, None)?;
         let stub = get_stub!();
         Ok(Response::new(stub.asset_list(request.into_inner())?))
     }
 
+    async fn asset_register(&self, request: Request<AssetRegisterRequest>) -> Result<Response<AssetRegisterResponse>, Status> {
+        self.authorize(&request, Permission::Admin, None)?;
+        let req = request.into_inner();
+        let result = self
+            .commands
+            .execute(CommandKind::Admin, move |stub| stub.asset_register(true, req))
+            .await?;
+        Ok(Response::new(result))
+    }
+
     async fn balance_query(&self, request: Request<BalanceQueryRequest>) -> Result<Response<BalanceQueryResponse>, Status> {
+        self.authorize(&request, Permission::ReadOnly, Some(request.get_ref().user_id))?;
         let stub = get_stub!();
         Ok(Response::new(stub.balance_query(request.into_inner())?))
     }
 
+    async fn fee_tier_query(&self, request: Request<FeeTierQueryRequest>) -> Result<Response<FeeTierQueryResponse>, Status> {
+        self.authorize(&request, Permission::ReadOnly, Some(request.get_ref().user_id))?;
+        let stub = get_stub!();
+        Ok(Response::new(stub.fee_tier_query(request.into_inner())?))
+    }
+
     async fn order_query(&self, request: tonic::Request<OrderQueryRequest>) -> Result<tonic::Response<OrderQueryResponse>, tonic::Status> {
+        self.authorize(&request, Permission::ReadOnly, Some(request.get_ref().user_id))?;
         let stub = get_stub!();
         Ok(Response::new(stub.order_query(request.into_inner())?))
     }
@@ -60,50 +198,300 @@ impl Matchengine for GrpcHandler {
         &self,
         request: tonic::Request<OrderBookDepthRequest>,
     ) -> Result<tonic::Response<OrderBookDepthResponse>, tonic::Status> {
+        self.authorize(&request, Permission::ReadOnly, None)?;
         let stub = get_stub!();
         Ok(Response::new(stub.order_book_depth(request.into_inner())?))
     }
+    // the orders of all the users are in it
+    async fn order_book_snapshot(
+        &self,
+        request: tonic::Request<OrderBookSnapshotRequest>,
+    ) -> Result<tonic::Response<OrderBookSnapshotResponse>, tonic::Status> {
+        self.authorize(&request, Permission::Admin, None)?;
+        let stub = get_stub!();
+        Ok(Response::new(stub.order_book_snapshot(request.into_inner())?))
+    }
+    type OrderBookSubscribeStream = Pin<Box<dyn Stream<Item = Result<OrderBookUpdate, Status>> + Send + Sync + 'static>>;
+    async fn order_book_subscribe(
+        &self,
+        request: tonic::Request<OrderBookSubscribeRequest>,
+    ) -> Result<tonic::Response<Self::OrderBookSubscribeStream>, tonic::Status> {
+        self.authorize(&request, Permission::ReadOnly, None)?;
+        let stub = get_stub!();
+        let updates = stub.order_book_subscribe(request.into_inner())?;
+        Ok(Response::new(Box::pin(updates.map(|update| Ok(book_update_to_proto(&update))))))
+    }
     async fn order_detail(&self, request: tonic::Request<OrderDetailRequest>) -> Result<tonic::Response<OrderInfo>, tonic::Status> {
+        self.authorize(&request, Permission::ReadOnly, None)?;
+        let req = request.into_inner();
+        let stub = get_stub!();
+        // the book first, then the history
+        match stub.order_detail(req.clone()) {
+            Err(status) if status.code() == tonic::Code::NotFound => {}
+            result => return Ok(Response::new(result?)),
+        }
+        let pool = stub.history_pools.read().clone();
+        Ok(Response::new(controller::order_history_detail(&pool, req).await?))
+    }
+    type SubscribeTradesStream = Pin<Box<dyn Stream<Item = Result<TradeInfo, Status>> + Send + Sync + 'static>>;
+    async fn my_trades(&self, request: Request<MyTradesRequest>) -> Result<Response<MyTradesResponse>, Status> {
+        self.authorize(&request, Permission::ReadOnly, Some(request.get_ref().user_id))?;
+        let pool = get_stub!().history_pools.read().clone();
+        Ok(Response::new(controller::my_trades(&pool, request.into_inner()).await?))
+    }
+
+    async fn subscribe_trades(
+        &self,
+        request: tonic::Request<SubscribeTradesRequest>,
+    ) -> Result<tonic::Response<Self::SubscribeTradesStream>, tonic::Status> {
+        self.authorize(&request, Permission::ReadOnly, None)?;
+        let stub = get_stub!();
+        let trades = stub.subscribe_trades(request.into_inner())?;
+        Ok(Response::new(Box::pin(trades.map(|trade| Ok(trade_to_proto(&trade))))))
+    }
+    type SubscribeBalancesStream = Pin<Box<dyn Stream<Item = Result<BalanceUpdate, Status>> + Send + Sync + 'static>>;
+    async fn subscribe_balances(
+        &self,
+        request: tonic::Request<SubscribeBalancesRequest>,
+    ) -> Result<tonic::Response<Self::SubscribeBalancesStream>, tonic::Status> {
+        self.authorize(&request, Permission::ReadOnly, Some(request.get_ref().user_id))?;
+        let stub = get_stub!();
+        let changes = stub.subscribe_balances(request.into_inner())?;
+        Ok(Response::new(Box::pin(changes.map(|change| Ok(balance_change_to_proto(&change))))))
+    }
+
+    type SubscribeOrdersStream = Pin<Box<dyn Stream<Item = Result<OrderUpdate, Status>> + Send + Sync + 'static>>;
+    async fn subscribe_orders(
+        &self,
+        request: tonic::Request<SubscribeOrdersRequest>,
+    ) -> Result<tonic::Response<Self::SubscribeOrdersStream>, tonic::Status> {
+        self.authorize(&request, Permission::ReadOnly, Some(request.get_ref().user_id))?;
         let stub = get_stub!();
-        Ok(Response::new(stub.order_detail(request.into_inner())?))
+        let updates = stub.subscribe_orders(request.into_inner())?;
+        Ok(Response::new(Box::pin(updates.map(|update| Ok(order_message_to_proto(&update))))))
     }
+
     async fn market_list(&self, request: tonic::Request<MarketListRequest>) -> Result<tonic::Response<MarketListResponse>, tonic::Status> {
+        self.authorize(&request, Permission::ReadOnly, None)?;
         let stub = get_stub!();
         Ok(Response::new(stub.market_list(request.into_inner())?))
     }
+    // the operator is the name of the api key, the one in the request is trusted only without authentication
+    async fn adjust_balance(&self, request: Request<AdjustBalanceRequest>) -> Result<Response<AdjustBalanceResponse>, Status> {
+        self.authorize(&request, Permission::Admin, None)?;
+        let operator = self.auth.key_name(api_key(&self.auth, &request)).map(String::from);
+        let mut req = request.into_inner();
+        if self.auth.is_enabled() {
+            req.operator = operator.unwrap_or_default();
+        }
+        let result = self
+            .commands
+            .execute(CommandKind::Admin, move |stub| stub.adjust_balance(true, req))
+            .await?;
+        Ok(Response::new(result))
+    }
+    async fn total_liabilities(&self, request: Request<TotalLiabilitiesRequest>) -> Result<Response<TotalLiabilitiesResponse>, Status> {
+        self.authorize(&request, Permission::Admin, None)?;
+        let stub = get_stub!();
+        Ok(Response::new(stub.total_liabilities(request.into_inner())?))
+    }
+    async fn commit_liabilities(&self, request: Request<CommitLiabilitiesRequest>) -> Result<Response<CommitLiabilitiesResponse>, Status> {
+        self.authorize(&request, Permission::Admin, None)?;
+        let req = request.into_inner();
+        let result = self
+            .commands
+            .execute(CommandKind::Admin, move |stub| stub.commit_liabilities(req))
+            .await?;
+        Ok(Response::new(result))
+    }
+    async fn get_inclusion_proof(&self, request: Request<GetInclusionProofRequest>) -> Result<Response<GetInclusionProofResponse>, Status> {
+        self.authorize(&request, Permission::ReadOnly, Some(request.get_ref().user_id))?;
+        let stub = get_stub!();
+        Ok(Response::new(stub.get_inclusion_proof(request.into_inner())?))
+    }
+    async fn market_resume(&self, request: Request<MarketResumeRequest>) -> Result<Response<MarketResumeResponse>, Status> {
+        self.authorize(&request, Permission::Admin, None)?;
+        let req = request.into_inner();
+        let result = self
+            .commands
+            .execute(CommandKind::Admin, move |stub| stub.market_resume(true, req))
+            .await?;
+        Ok(Response::new(result))
+    }
+    async fn market_set_state(&self, request: Request<MarketSetStateRequest>) -> Result<Response<MarketSetStateResponse>, Status> {
+        self.authorize(&request, Permission::Admin, None)?;
+        let req = request.into_inner();
+        let result = self
+            .commands
+            .execute(CommandKind::Admin, move |stub| stub.market_set_state(true, req))
+            .await?;
+        Ok(Response::new(result))
+    }
     async fn market_summary(
         &self,
         request: tonic::Request<MarketSummaryRequest>,
     ) -> Result<tonic::Response<MarketSummaryResponse>, tonic::Status> {
+        self.authorize(&request, Permission::ReadOnly, None)?;
         let stub = get_stub!();
         Ok(Response::new(stub.market_summary(request.into_inner())?))
     }
 
-    async fn balance_update(&self, request: Request<BalanceUpdateRequest>) -> Result<Response<BalanceUpdateResponse>, Status> {
+    async fn get_klines(&self, request: Request<GetKlinesRequest>) -> Result<Response<GetKlinesResponse>, Status> {
+        self.authorize(&request, Permission::ReadOnly, None)?;
         let stub = get_stub!();
-        Ok(Response::new(stub.update_balance(true, request.into_inner())?))
+        Ok(Response::new(stub.get_klines(request.into_inner())?))
     }
 
-    async fn order_put(&self, request: Request<OrderPutRequest>) -> Result<Response<OrderInfo>, Status> {
+    async fn health(&self, request: tonic::Request<HealthRequest>) -> Result<tonic::Response<HealthResponse>, tonic::Status> {
+        self.authorize(&request, Permission::ReadOnly, None)?;
         let stub = get_stub!();
-        Ok(Response::new(stub.order_put(true, request.into_inner())?))
+        Ok(Response::new(stub.health(request.into_inner())?))
     }
 
-    async fn order_cancel(&self, request: tonic::Request<OrderCancelRequest>) -> Result<tonic::Response<OrderInfo>, tonic::Status> {
+    async fn health_check(
+        &self,
+        request: tonic::Request<HealthCheckRequest>,
+    ) -> Result<tonic::Response<HealthCheckResponse>, tonic::Status> {
+        self.authorize(&request, Permission::ReadOnly, None)?;
         let stub = get_stub!();
-        Ok(Response::new(stub.order_cancel(true, request.into_inner())?))
+        Ok(Response::new(stub.health_check(request.into_inner())?))
+    }
+
+    async fn balance_update(&self, request: Request<BalanceUpdateRequest>) -> Result<Response<BalanceUpdateResponse>, Status> {
+        self.authorize(&request, Permission::Admin, None)?;
+        let req = request.into_inner();
+        let result = self
+            .commands
+            .execute(CommandKind::Admin, move |stub| stub.update_balance(true, req))
+            .await?;
+        Ok(Response::new(result))
+    }
+
+    async fn order_put(&self, request: Request<OrderPutRequest>) -> Result<Response<OrderInfo>, Status> {
+        self.authorize(&request, Permission::Trade, Some(request.get_ref().user_id))?;
+        let req = request.into_inner();
+        let result = self
+            .commands
+            .execute(CommandKind::Place, move |stub| stub.order_put(true, req))
+            .await?;
+        Ok(Response::new(result))
+    }
+
+    async fn order_batch_put(&self, request: Request<OrderBatchPutRequest>) -> Result<Response<OrderBatchPutResponse>, Status> {
+        self.authorize(&request, Permission::Trade, None)?;
+        for order in &request.get_ref().orders {
+            self.authorize(&request, Permission::Trade, Some(order.user_id))?;
+        }
+        let req = request.into_inner();
+        let results = self
+            .commands
+            .execute(CommandKind::Place, move |stub| {
+                stub.place_orders(true, batch_mode_from_proto(req.mode), req.orders)
+            })
+            .await?;
+        Ok(Response::new(OrderBatchPutResponse {
+            results: results.into_iter().map(order_batch_put_result).collect(),
+        }))
+    }
+
+    async fn trigger_order_put(&self, request: Request<TriggerOrderPutRequest>) -> Result<Response<TriggerOrderInfo>, Status> {
+        self.authorize(
+            &request,
+            Permission::Trade,
+            request.get_ref().order.as_ref().map(|order| order.user_id),
+        )?;
+        let req = request.into_inner();
+        let result = self
+            .commands
+            .execute(CommandKind::Place, move |stub| stub.trigger_order_put(true, req))
+            .await?;
+        Ok(Response::new(result))
+    }
+
+    async fn trigger_order_cancel(&self, request: Request<OrderCancelRequest>) -> Result<Response<TriggerOrderInfo>, Status> {
+        self.authorize(&request, Permission::Trade, Some(request.get_ref().user_id))?;
+        let req = request.into_inner();
+        let result = self
+            .commands
+            .execute(CommandKind::Cancel, move |stub| stub.trigger_order_cancel(true, req))
+            .await?;
+        Ok(Response::new(result))
+    }
+
+    async fn order_batch_cancel(&self, request: Request<OrderBatchCancelRequest>) -> Result<Response<OrderBatchCancelResponse>, Status> {
+        self.authorize(&request, Permission::Trade, Some(request.get_ref().user_id))?;
+        let req = request.into_inner();
+        let result = self
+            .commands
+            .execute(CommandKind::Cancel, move |stub| stub.order_batch_cancel(true, req))
+            .await?;
+        Ok(Response::new(result))
+    }
+
+    async fn order_cancel(&self, request: tonic::Request<OrderCancelRequest>) -> Result<tonic::Response<OrderInfo>, tonic::Status> {
+        self.authorize(&request, Permission::Trade, Some(request.get_ref().user_id))?;
+        let req = request.into_inner();
+        let result = self
+            .commands
+            .execute(CommandKind::Cancel, move |stub| stub.order_cancel(true, req))
+            .await?;
+        Ok(Response::new(result))
+    }
+
+    async fn order_amend(&self, request: tonic::Request<OrderAmendRequest>) -> Result<tonic::Response<OrderInfo>, tonic::Status> {
+        self.authorize(&request, Permission::Trade, Some(request.get_ref().user_id))?;
+        let req = request.into_inner();
+        let result = self
+            .commands
+            .execute(CommandKind::Place, move |stub| stub.order_amend(true, req))
+            .await?;
+        Ok(Response::new(result))
     }
+
     async fn order_cancel_all(
         &self,
         request: tonic::Request<OrderCancelAllRequest>,
     ) -> Result<tonic::Response<OrderCancelAllResponse>, tonic::Status> {
+        self.authorize(&request, Permission::Trade, Some(request.get_ref().user_id))?;
+        let req = request.into_inner();
+        let result = self
+            .commands
+            .execute(CommandKind::Cancel, move |stub| stub.order_cancel_all(true, req))
+            .await?;
+        Ok(Response::new(result))
+    }
+
+    // The state is copied by a command, so the slice matches the operation log exactly.
+    // The slice is written after it, trading goes on meanwhile.
+    async fn make_snapshot(&self, request: Request<MakeSnapshotRequest>) -> Result<Response<MakeSnapshotResponse>, Status> {
+        self.authorize(&request, Permission::Admin, None)?;
+        let _running = self
+            .snapshot_lock
+            .try_lock()
+            .map_err(|_| Status::failed_precondition("a snapshot is being made"))?;
+        let req = request.into_inner();
+        let job = self
+            .commands
+            .execute(CommandKind::Admin, move |stub| stub.begin_snapshot(req))
+            .await?;
+        job.run().await.map_err(|err| Status::unknown(format!("{}", err)))?;
+        let result = self
+            .commands
+            .execute(CommandKind::Admin, move |stub| Ok(stub.finish_snapshot(&job)))
+            .await?;
+        Ok(Response::new(result))
+    }
+
+    async fn verify_integrity(&self, request: Request<VerifyIntegrityRequest>) -> Result<Response<VerifyIntegrityResponse>, Status> {
+        self.authorize(&request, Permission::Admin, None)?;
         let stub = get_stub!();
-        Ok(Response::new(stub.order_cancel_all(true, request.into_inner())?))
+        Ok(Response::new(stub.verify_integrity(request.into_inner())?))
     }
 
-    // This is the only blocking call of the server
+    // The debug calls are blocking as well
     #[cfg(debug_assertions)]
     async fn debug_dump(&self, request: Request<DebugDumpRequest>) -> Result<Response<DebugDumpResponse>, Status> {
+        self.authorize(&request, Permission::Admin, None)?;
         run_blocking_the_world_task(|| async {
             let stub = get_stub!();
             stub.debug_dump(request.into_inner()).await.map(|_| ())
@@ -124,6 +512,7 @@ impl Matchengine for GrpcHandler {
 
     #[cfg(debug_assertions)]
     async fn debug_reset(&self, request: Request<DebugResetRequest>) -> Result<Response<DebugResetResponse>, Status> {
+        self.authorize(&request, Permission::Admin, None)?;
         run_blocking_the_world_task(|| async {
             let stub = get_stub!();
             stub.debug_reset(request.into_inner()).await.map(|_| ())
@@ -144,6 +533,7 @@ impl Matchengine for GrpcHandler {
 
     #[cfg(debug_assertions)]
     async fn debug_reload(&self, request: Request<DebugReloadRequest>) -> Result<Response<DebugReloadResponse>, Status> {
+        self.authorize(&request, Permission::Admin, None)?;
         run_blocking_the_world_task(|| async {
             let stub = get_stub!();
             stub.debug_reload(request.into_inner()).await.map(|_| ())
@@ -163,19 +553,217 @@ impl Matchengine for GrpcHandler {
 
     #[cfg(not(debug_assertions))]
     async fn debug_dump(&self, request: Request<DebugDumpRequest>) -> Result<Response<DebugDumpResponse>, Status> {
-        println!("Warning: Not avaliable in release build");
+        self.authorize(&request, Permission::Admin, None)?;
+        tracing::warn!("not available in release build");
         Ok(Response::new(DebugDumpResponse {}))
     }
 
     #[cfg(not(debug_assertions))]
     async fn debug_reset(&self, request: Request<DebugResetRequest>) -> Result<Response<DebugResetResponse>, Status> {
-        println!("Warning: Not avaliable in release build");
+        self.authorize(&request, Permission::Admin, None)?;
+        tracing::warn!("not available in release build");
         Ok(Response::new(DebugResetResponse {}))
     }
 
     #[cfg(not(debug_assertions))]
     async fn debug_reload(&self, request: Request<DebugReloadRequest>) -> Result<Response<DebugReloadResponse>, Status> {
-        println!("Warning: Not avaliable in release build");
+        self.authorize(&request, Permission::Admin, None)?;
+        tracing::warn!("not available in release build");
         Ok(Response::new(DebugReloadResponse {}))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::Metrics;
+
+    fn get_handler() -> GrpcHandler {
+        let key = |key: &str, permission| config::ApiKey {
+            key: key.to_string(),
+            permission,
+            user_id: None,
+            name: String::new(),
+        };
+        GrpcHandler {
+            auth: Arc::new(ApiKeyStore::new(&config::AuthConfig {
+                keys: vec![key("reader", Permission::ReadOnly), key("trader", Permission::Trade)],
+                ..Default::default()
+            })),
+            commands: Arc::new(CommandQueue::new(&Default::default(), Metrics::default()).0),
+            snapshot_lock: Default::default(),
+        }
+    }
+
+    fn with_key<T>(message: T, key: &str) -> Request<T> {
+        let mut request = Request::new(message);
+        request.metadata_mut().insert("x-api-key", key.parse().unwrap());
+        request
+    }
+
+    // the calls are rejected before reaching the controller
+    #[tokio::test]
+    async fn test_permission_denied() {
+        let handler = get_handler();
+        let order = OrderPutRequest {
+            user_id: 101,
+            ..Default::default()
+        };
+        let status = handler.order_put(with_key(order, "reader")).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+
+        let status = handler
+            .make_snapshot(with_key(MakeSnapshotRequest::default(), "trader"))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+
+        let status = handler
+            .adjust_balance(with_key(AdjustBalanceRequest::default(), "trader"))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+
+        let status = handler.order_query(Request::new(OrderQueryRequest::default())).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+    }
+
+    // as grpcurl lists the calls
+    #[tokio::test]
+    async fn test_reflection_lists_calls() {
+        use prost::Message;
+        use tonic_reflection::proto::server_reflection_client::ServerReflectionClient;
+        use tonic_reflection::proto::server_reflection_request::MessageRequest;
+        use tonic_reflection::proto::server_reflection_response::MessageResponse;
+        use tonic_reflection::proto::ServerReflectionRequest;
+
+        assert!(reflection_service(false).unwrap().is_none());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let incoming = futures::stream::unfold(listener, |listener| async move {
+            let stream = listener.accept().await.map(|(stream, _)| stream);
+            Some((stream, listener))
+        });
+        let server = tonic::transport::Server::builder()
+            .add_optional_service(reflection_service(true).unwrap())
+            .serve_with_incoming(incoming);
+        tokio::spawn(server);
+
+        let mut client = ServerReflectionClient::connect(format!("http://{}", addr)).await.unwrap();
+        let request = |message_request| ServerReflectionRequest {
+            host: String::new(),
+            message_request: Some(message_request),
+        };
+        let requests = futures::stream::iter(vec![
+            request(MessageRequest::ListServices(String::new())),
+            request(MessageRequest::FileContainingSymbol("matchengine.Matchengine".to_string())),
+        ]);
+        let mut responses = client.server_reflection_info(requests).await.unwrap().into_inner();
+
+        let services = match responses.next().await.unwrap().unwrap().message_response {
+            Some(MessageResponse::ListServicesResponse(list)) => list.service.into_iter().map(|service| service.name).collect::<Vec<_>>(),
+            response => panic!("unexpected response {:?}", response),
+        };
+        assert!(services.contains(&"matchengine.Matchengine".to_string()));
+        let files = match responses.next().await.unwrap().unwrap().message_response {
+            Some(MessageResponse::FileDescriptorResponse(response)) => response.file_descriptor_proto,
+            response => panic!("unexpected response {:?}", response),
+        };
+        let methods: Vec<String> = files
+            .iter()
+            .map(|file| prost_types::FileDescriptorProto::decode(&file[..]).unwrap())
+            .flat_map(|file| file.service)
+            .filter(|service| service.name() == "Matchengine")
+            .flat_map(|service| service.method)
+            .map(|method| method.name().to_string())
+            .collect();
+        for method in &["OrderPut", "OrderCancel", "BalanceQuery", "MakeSnapshot", "VerifyIntegrity"] {
+            assert!(methods.contains(&method.to_string()), "{} is not listed", method);
+        }
+    }
+
+    fn test_cert(name: &str) -> Vec<u8> {
+        std::fs::read(format!("{}/tests/certs/{}", env!("CARGO_MANIFEST_DIR"), name)).unwrap()
+    }
+
+    async fn register_asset_over_tls(addr: std::net::SocketAddr, client: Option<&str>) -> Result<(), Status> {
+        use tonic::transport::{ClientTlsConfig, Endpoint};
+        let mut tls = ClientTlsConfig::new()
+            .domain_name("localhost")
+            .ca_certificate(Certificate::from_pem(test_cert("ca.pem")));
+        if let Some(client) = client {
+            let identity = Identity::from_pem(test_cert(&format!("{}.pem", client)), test_cert(&format!("{}.key", client)));
+            tls = tls.identity(identity);
+        }
+        let channel = Endpoint::from_shared(format!("https://{}", addr))
+            .unwrap()
+            .tls_config(tls)
+            .unwrap()
+            .connect()
+            .await
+            .map_err(|err| Status::unavailable(err.to_string()))?;
+        matchengine_client::MatchengineClient::new(channel)
+            .asset_register(AssetRegisterRequest::default())
+            .await
+            .map(|_| ())
+    }
+
+    // the certificates of tests/certs are signed by ca.pem, but rogue.pem by another ca
+    #[tokio::test]
+    async fn test_mutual_tls() {
+        assert!(tls_config(&Default::default()).is_err());
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/certs");
+        let tls = tls_config(&config::GrpcTlsConfig {
+            plaintext: false,
+            cert_path: format!("{}/server.pem", dir),
+            key_path: format!("{}/server.key", dir),
+            client_ca_path: format!("{}/ca.pem", dir),
+        })
+        .unwrap()
+        .unwrap();
+
+        let mut handler = get_handler();
+        handler.auth = Arc::new(ApiKeyStore::new(&config::AuthConfig {
+            client_certs: vec![config::ClientCert {
+                subject: "reader-bot".to_string(),
+                permission: Permission::ReadOnly,
+                user_id: None,
+            }],
+            ..Default::default()
+        }));
+        let interceptor = auth_interceptor(handler.auth.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let incoming = futures::stream::unfold(listener, |listener| async move {
+            let stream = listener.accept().await.map(|(stream, _)| stream);
+            Some((stream, listener))
+        });
+        let server = tonic::transport::Server::builder()
+            .tls_config(tls)
+            .unwrap()
+            .add_service(MatchengineServer::with_interceptor(handler, interceptor))
+            .serve_with_incoming(incoming);
+        tokio::spawn(server);
+
+        // rejected in the handshake, before any authentication of the call
+        for client in &[None, Some("rogue")] {
+            let status = register_asset_over_tls(addr, *client).await.unwrap_err();
+            assert_ne!(status.code(), tonic::Code::Unauthenticated, "{:?} got through", client);
+            assert_ne!(status.code(), tonic::Code::PermissionDenied, "{:?} got through", client);
+        }
+        // a valid certificate of no configured subject
+        let status = register_asset_over_tls(addr, Some("stranger")).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        // the subject is read only, the call is checked by the handler before reaching the controller
+        let status = register_asset_over_tls(addr, Some("reader")).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+    }
+
+    #[test]
+    fn test_auth_interceptor() {
+        let interceptor = auth_interceptor(get_handler().auth);
+        assert!(interceptor(with_key((), "reader")).is_ok());
+        assert_eq!(interceptor(with_key((), "guess")).unwrap_err().code(), tonic::Code::Unauthenticated);
+        assert_eq!(interceptor(Request::new(())).unwrap_err().code(), tonic::Code::Unauthenticated);
+    }
+}
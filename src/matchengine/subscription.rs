@@ -0,0 +1,107 @@
+use futures_channel::mpsc::{channel, Receiver, Sender};
+
+// max pending messages of a subscriber before it is dropped
+pub const SUBSCRIPTION_BUFFER_SIZE: usize = 1024;
+
+// Fan out messages of the matching engine to the streaming subscribers.
+// The engine never waits for a subscriber: a slow one whose buffer is full is dropped,
+// and it should subscribe again.
+pub struct SubscriptionHub<T: Clone> {
+    buffer_size: usize,
+    senders: Vec<Sender<T>>,
+}
+
+impl<T: Clone> Default for SubscriptionHub<T> {
+    fn default() -> Self {
+        SubscriptionHub::new(SUBSCRIPTION_BUFFER_SIZE)
+    }
+}
+
+impl<T: Clone> SubscriptionHub<T> {
+    pub fn new(buffer_size: usize) -> SubscriptionHub<T> {
+        SubscriptionHub {
+            buffer_size,
+            senders: Vec::new(),
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.senders.is_empty()
+    }
+    pub fn len(&self) -> usize {
+        self.senders.len()
+    }
+    pub fn clear(&mut self) {
+        self.senders.clear();
+    }
+    // `initial` is delivered before any published message
+    pub fn subscribe(&mut self, initial: Option<T>) -> Receiver<T> {
+        // the channel can hold one more message for each sender, so leave room for it
+        let (mut sender, receiver) = channel(self.buffer_size.saturating_sub(1));
+        if let Some(message) = initial {
+            sender.try_send(message).ok();
+        }
+        self.senders.push(sender);
+        receiver
+    }
+    // share a channel with the other hubs, so one receiver gets the messages of all of them
+    pub fn attach(&mut self, sender: Sender<T>) {
+        self.senders.push(sender);
+    }
+    pub fn publish(&mut self, message: &T) {
+        // send through the stored sender: a fresh clone has its own slot in the channel,
+        // so it would never see the buffer as full
+        let senders = std::mem::take(&mut self.senders);
+        self.senders = senders
+            .into_iter()
+            .filter_map(|mut sender| match sender.try_send(message.clone()) {
+                Ok(()) => Some(sender),
+                Err(_) => {
+                    tracing::warn!("drop a slow or closed subscriber");
+                    None
+                }
+            })
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_publish() {
+        let mut hub = SubscriptionHub::new(4);
+        let mut receiver = hub.subscribe(Some(0));
+        hub.publish(&1);
+        hub.publish(&2);
+        assert_eq!(receiver.try_next().unwrap(), Some(0));
+        assert_eq!(receiver.try_next().unwrap(), Some(1));
+        assert_eq!(receiver.try_next().unwrap(), Some(2));
+        assert!(receiver.try_next().is_err());
+    }
+
+    #[test]
+    fn test_drop_slow_subscriber() {
+        let mut hub = SubscriptionHub::new(2);
+        let mut slow = hub.subscribe(None);
+        let mut fast = hub.subscribe(None);
+        for i in 0..4 {
+            hub.publish(&i);
+            assert_eq!(fast.try_next().unwrap(), Some(i));
+        }
+        assert_eq!(hub.len(), 1);
+        // the buffered messages are still delivered, then the stream ends
+        assert_eq!(slow.try_next().unwrap(), Some(0));
+        assert_eq!(slow.try_next().unwrap(), Some(1));
+        assert_eq!(slow.try_next().unwrap(), None);
+    }
+
+    #[test]
+    fn test_drop_closed_subscriber() {
+        let mut hub = SubscriptionHub::new(2);
+        let receiver = hub.subscribe(None);
+        drop(receiver);
+        hub.publish(&0);
+        assert!(hub.is_empty());
+    }
+}
@@ -0,0 +1,24 @@
+use crate::market::Order;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum SurveillanceVerdict {
+    Allow,
+    // the match is executed, the reason is logged for review
+    Flag(String),
+    // the match is not executed and the remainder of the taker is canceled
+    Block(String),
+}
+
+// Pre-trade surveillance, asked before each match of a taker with a maker is executed.
+// The hook is asked again when the operation log is replayed, so it must decide the same for the
+// same sequence of orders, without depending on the wall clock or any external state.
+pub trait SurveillanceHook {
+    fn check_match(&mut self, taker: &Order, maker: &Order) -> SurveillanceVerdict;
+}
+
+pub struct NoopSurveillance;
+impl SurveillanceHook for NoopSurveillance {
+    fn check_match(&mut self, _taker: &Order, _maker: &Order) -> SurveillanceVerdict {
+        SurveillanceVerdict::Allow
+    }
+}
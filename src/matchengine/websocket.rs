@@ -0,0 +1,402 @@
+use crate::asset::BalanceChange;
+use crate::auth::{ApiKeyStore, UserScope};
+use crate::config::{self, Permission};
+use crate::controller::G_STUB;
+use crate::dto::*;
+use crate::market::BookUpdate;
+use crate::message::OrderMessage;
+use crate::types::Trade;
+use futures::channel::mpsc::Receiver;
+use futures::stream::{self, AbortHandle, BoxStream, SelectAll};
+use futures::{SinkExt, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::tungstenite::Message;
+use tonic::Status;
+
+// The streaming subscriptions of the grpc service, for the clients speaking websocket.
+// Every frame is a json text. A client sends
+//   {"op": "auth", "key": "..."}
+//   {"op": "subscribe", "channel": "depth", "market": "ETH_USDT"}
+//   {"op": "subscribe", "channel": "trades", "market": "ETH_USDT"}
+//   {"op": "subscribe", "channel": "balances", "user_id": 101}
+//   {"op": "subscribe", "channel": "orders", "user_id": 101}
+//   {"op": "unsubscribe", "channel": ..., ...}
+//   {"op": "ping"}
+// and each of them is replied by an event, "authenticated", "subscribed", "unsubscribed", "pong"
+// or "error". The updates come as {"event": "update", "channel": ..., ..., "data": ...}, with the
+// data in the json form of the grpc messages. A client too slow for its updates is dropped like a
+// grpc subscriber, it should connect again.
+
+macro_rules! get_stub {
+    () => {
+        unsafe { G_STUB.as_mut().unwrap() }
+    };
+}
+
+// where the streams come from
+pub trait FeedSource: Send + Sync {
+    fn subscribe_book(&self, market: &str) -> Result<Receiver<BookUpdate>, Status>;
+    fn subscribe_trades(&self, market: &str) -> Result<Receiver<Trade>, Status>;
+    fn subscribe_balances(&self, user_id: u32) -> Result<Receiver<BalanceChange>, Status>;
+    fn subscribe_orders(&self, user_id: u32) -> Result<Receiver<OrderMessage>, Status>;
+}
+
+// The fan-out of the grpc streams. The controller is not shared between threads, so this
+// must be used on the thread of the grpc service.
+pub struct EngineFeeds;
+
+impl FeedSource for EngineFeeds {
+    fn subscribe_book(&self, market: &str) -> Result<Receiver<BookUpdate>, Status> {
+        get_stub!().order_book_subscribe(OrderBookSubscribeRequest {
+            market: market.to_string(),
+        })
+    }
+    fn subscribe_trades(&self, market: &str) -> Result<Receiver<Trade>, Status> {
+        get_stub!().subscribe_trades(SubscribeTradesRequest {
+            market: market.to_string(),
+        })
+    }
+    fn subscribe_balances(&self, user_id: u32) -> Result<Receiver<BalanceChange>, Status> {
+        get_stub!().subscribe_balances(SubscribeBalancesRequest { user_id })
+    }
+    fn subscribe_orders(&self, user_id: u32) -> Result<Receiver<OrderMessage>, Status> {
+        get_stub!().subscribe_orders(SubscribeOrdersRequest { user_id })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(tag = "channel", rename_all = "snake_case")]
+pub enum Channel {
+    Depth { market: String },
+    Trades { market: String },
+    Balances { user_id: u32 },
+    Orders { user_id: u32 },
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum ClientMessage {
+    Auth { key: String },
+    Subscribe(Channel),
+    Unsubscribe(Channel),
+    Ping,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ServerMessage {
+    Authenticated,
+    Subscribed(Channel),
+    Unsubscribed(Channel),
+    Pong,
+    Error {
+        message: String,
+    },
+    Update {
+        #[serde(flatten)]
+        channel: Channel,
+        data: serde_json::Value,
+    },
+}
+
+impl ServerMessage {
+    fn error(message: impl ToString) -> ServerMessage {
+        ServerMessage::Error {
+            message: message.to_string(),
+        }
+    }
+    fn to_frame(&self) -> Message {
+        Message::Text(serde_json::to_string(self).unwrap())
+    }
+}
+
+// an update frame of a subscription, or None once its stream ends
+type SubscriptionItem = (u64, Channel, Option<Message>);
+
+fn update_frames<S, T, F>(id: u64, channel: Channel, updates: S, to_data: F) -> BoxStream<'static, SubscriptionItem>
+where
+    S: Stream<Item = T> + Send + 'static,
+    F: Fn(&T) -> serde_json::Value + Send + 'static,
+{
+    let end_channel = channel.clone();
+    updates
+        .map(move |update| {
+            let message = ServerMessage::Update {
+                channel: channel.clone(),
+                data: to_data(&update),
+            };
+            (id, channel.clone(), Some(message.to_frame()))
+        })
+        .chain(stream::once(async move { (id, end_channel, None) }))
+        .boxed()
+}
+
+struct Session {
+    auth: Arc<ApiKeyStore>,
+    feeds: Arc<dyn FeedSource>,
+    // None before a valid key is given, with authentication enabled
+    scope: Option<UserScope>,
+    next_id: u64,
+    subscriptions: HashMap<Channel, (u64, AbortHandle)>,
+}
+
+impl Session {
+    fn new(auth: Arc<ApiKeyStore>, feeds: Arc<dyn FeedSource>) -> Session {
+        let scope = auth.authenticate(None).ok();
+        Session {
+            auth,
+            feeds,
+            scope,
+            next_id: 0,
+            subscriptions: HashMap::new(),
+        }
+    }
+
+    fn handle(&mut self, text: &str, updates: &mut SelectAll<BoxStream<'static, SubscriptionItem>>) -> ServerMessage {
+        let message: ClientMessage = match serde_json::from_str(text) {
+            Ok(message) => message,
+            Err(e) => return ServerMessage::error(format!("invalid message: {}", e)),
+        };
+        match message {
+            ClientMessage::Auth { key } => match self.auth.authenticate(Some(&key)) {
+                Ok(scope) => {
+                    self.scope = Some(scope);
+                    ServerMessage::Authenticated
+                }
+                Err(e) => ServerMessage::error(e),
+            },
+            ClientMessage::Subscribe(channel) => {
+                if self.subscriptions.contains_key(&channel) {
+                    return ServerMessage::Subscribed(channel);
+                }
+                match self.subscribe(&channel) {
+                    Ok(stream) => {
+                        let (stream, handle) = stream::abortable(stream);
+                        self.subscriptions.insert(channel.clone(), (self.next_id, handle));
+                        self.next_id += 1;
+                        updates.push(stream.boxed());
+                        ServerMessage::Subscribed(channel)
+                    }
+                    Err(message) => ServerMessage::error(message),
+                }
+            }
+            ClientMessage::Unsubscribe(channel) => match self.subscriptions.remove(&channel) {
+                Some((_, handle)) => {
+                    handle.abort();
+                    ServerMessage::Unsubscribed(channel)
+                }
+                None => ServerMessage::error("not subscribed"),
+            },
+            ClientMessage::Ping => ServerMessage::Pong,
+        }
+    }
+
+    fn subscribe(&self, channel: &Channel) -> Result<BoxStream<'static, SubscriptionItem>, String> {
+        let user_id = match channel {
+            Channel::Balances { user_id } | Channel::Orders { user_id } => Some(*user_id),
+            _ => None,
+        };
+        let scope = self.scope.ok_or_else(|| "missing api key".to_string())?;
+        scope.authorize(Permission::ReadOnly, user_id).map_err(|e| e.to_string())?;
+        let id = self.next_id;
+        let stream = match channel {
+            Channel::Depth { market } => {
+                let updates = self.feeds.subscribe_book(market).map_err(|e| e.message().to_string())?;
+                update_frames(id, channel.clone(), updates, |update| json_data(&book_update_to_proto(update)))
+            }
+            Channel::Trades { market } => {
+                let trades = self.feeds.subscribe_trades(market).map_err(|e| e.message().to_string())?;
+                update_frames(id, channel.clone(), trades, |trade| json_data(&trade_to_proto(trade)))
+            }
+            Channel::Balances { user_id } => {
+                let changes = self.feeds.subscribe_balances(*user_id).map_err(|e| e.message().to_string())?;
+                update_frames(id, channel.clone(), changes, |change| json_data(&balance_change_to_proto(change)))
+            }
+            Channel::Orders { user_id } => {
+                let updates = self.feeds.subscribe_orders(*user_id).map_err(|e| e.message().to_string())?;
+                update_frames(id, channel.clone(), updates, |update| json_data(&order_message_to_proto(update)))
+            }
+        };
+        Ok(stream)
+    }
+
+    // whether the end of a stream is not by an unsubscribe, but by the client falling behind
+    fn is_dropped(&self, id: u64, channel: &Channel) -> bool {
+        matches!(self.subscriptions.get(channel), Some((current, _)) if *current == id)
+    }
+}
+
+fn json_data<T: Serialize>(data: &T) -> serde_json::Value {
+    serde_json::to_value(data).unwrap()
+}
+
+pub async fn serve(listener: TcpListener, config: config::WebsocketConfig, auth: Arc<ApiKeyStore>, feeds: Arc<dyn FeedSource>) {
+    tracing::info!("serving websocket on {:?}", listener.local_addr());
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::error!("websocket accept fail: {}", e);
+                continue;
+            }
+        };
+        let (config, auth, feeds) = (config.clone(), auth.clone(), feeds.clone());
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, config, auth, feeds).await {
+                tracing::warn!("websocket client {} exit: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    config: config::WebsocketConfig,
+    auth: Arc<ApiKeyStore>,
+    feeds: Arc<dyn FeedSource>,
+) -> anyhow::Result<()> {
+    let socket = tokio_tungstenite::accept_async(stream).await?;
+    let (mut sink, mut source) = socket.split();
+    let mut session = Session::new(auth, feeds);
+    let mut updates = SelectAll::new();
+    let mut ping = tokio::time::interval(config.ping_interval);
+    let mut last_seen = Instant::now();
+    loop {
+        tokio::select! {
+            message = source.next() => {
+                let message = match message {
+                    Some(message) => message?,
+                    None => return Ok(()),
+                };
+                last_seen = Instant::now();
+                match message {
+                    Message::Text(text) => sink.send(session.handle(&text, &mut updates).to_frame()).await?,
+                    Message::Close(_) => return Ok(()),
+                    // the pings are answered by tungstenite, and a pong only keeps the client alive
+                    _ => {}
+                }
+            }
+            Some((id, channel, frame)) = updates.next(), if !updates.is_empty() => match frame {
+                Some(frame) => sink.send(frame).await?,
+                None if session.is_dropped(id, &channel) => {
+                    sink.send(Message::Close(None)).await.ok();
+                    return Err(anyhow::anyhow!("dropped as too slow for {:?}", channel));
+                }
+                None => {}
+            },
+            _ = ping.tick() => {
+                if last_seen.elapsed() > config.idle_timeout {
+                    return Err(anyhow::anyhow!("idle for {:?}", last_seen.elapsed()));
+                }
+                sink.send(Message::Ping(Vec::new())).await?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::subscription::SubscriptionHub;
+    use rust_decimal_macros::*;
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct TestFeeds {
+        balances: Mutex<SubscriptionHub<BalanceChange>>,
+    }
+
+    impl FeedSource for TestFeeds {
+        fn subscribe_book(&self, _market: &str) -> Result<Receiver<BookUpdate>, Status> {
+            Err(Status::invalid_argument("invalid market"))
+        }
+        fn subscribe_trades(&self, _market: &str) -> Result<Receiver<Trade>, Status> {
+            Err(Status::invalid_argument("invalid market"))
+        }
+        fn subscribe_balances(&self, _user_id: u32) -> Result<Receiver<BalanceChange>, Status> {
+            Ok(self.balances.lock().unwrap().subscribe(None))
+        }
+        fn subscribe_orders(&self, _user_id: u32) -> Result<Receiver<OrderMessage>, Status> {
+            Err(Status::unimplemented(""))
+        }
+    }
+
+    async fn receive<S>(client: &mut S) -> serde_json::Value
+    where
+        S: Stream<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin,
+    {
+        match client.next().await.unwrap().unwrap() {
+            Message::Text(text) => serde_json::from_str(&text).unwrap(),
+            other => panic!("unexpected frame {:?}", other),
+        }
+    }
+
+    async fn request<S>(client: &mut S, text: &str) -> serde_json::Value
+    where
+        S: Stream<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + futures::Sink<Message> + Unpin,
+        <S as futures::Sink<Message>>::Error: std::fmt::Debug,
+    {
+        client.send(Message::Text(text.to_string())).await.unwrap();
+        receive(client).await
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_handshake() {
+        let auth = Arc::new(ApiKeyStore::new(&config::AuthConfig {
+            header: "x-api-key".to_string(),
+            keys: vec![config::ApiKey {
+                key: "trader_101".to_string(),
+                permission: Permission::Trade,
+                user_id: Some(101),
+                name: String::new(),
+            }],
+            ..Default::default()
+        }));
+        let feeds = Arc::new(TestFeeds::default());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, Default::default(), auth, feeds.clone()));
+
+        let (mut client, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr)).await.unwrap();
+        let client = &mut client;
+
+        let subscribe = r#"{"op": "subscribe", "channel": "balances", "user_id": 101}"#;
+        assert_eq!(request(client, subscribe).await["event"], "error");
+        assert_eq!(
+            request(client, r#"{"op": "auth", "key": "trader_101"}"#).await["event"],
+            "authenticated"
+        );
+        let reply = request(client, r#"{"op": "subscribe", "channel": "balances", "user_id": 102}"#).await;
+        assert_eq!(reply["message"], "permission denied");
+        let reply = request(client, subscribe).await;
+        assert_eq!(
+            reply,
+            serde_json::json!({"event": "subscribed", "channel": "balances", "user_id": 101})
+        );
+        let reply = request(client, r#"{"op": "subscribe", "channel": "depth", "market": "ETH_BTC"}"#).await;
+        assert_eq!(reply["message"], "invalid market");
+        assert_eq!(request(client, r#"{"op": "ping"}"#).await["event"], "pong");
+
+        feeds.balances.lock().unwrap().publish(&BalanceChange {
+            user_id: 101,
+            asset: "USDT".to_string(),
+            available: dec!(1.5),
+            frozen: dec!(0),
+        });
+        let update = receive(client).await;
+        assert_eq!(update["event"], "update");
+        assert_eq!(update["channel"], "balances");
+        assert_eq!(update["data"]["available"], "1.5");
+
+        let reply = request(client, r#"{"op": "unsubscribe", "channel": "balances", "user_id": 101}"#).await;
+        assert_eq!(reply["event"], "unsubscribed");
+        assert!(request(client, "subscribe").await["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("invalid message"));
+    }
+}
@@ -74,7 +74,7 @@ impl<'c, C: RdConsumerExt> SimpleConsumer<'c, C> {
         if let Err(e) = self.consumer.subscribe(topic_list.as_slice()) {
             return e;
         }
-        log::info!("start consuming topic {:?}", topic_list);
+        tracing::info!("start consuming topic {:?}", topic_list);
         let mut stream = f(self.consumer);
 
         loop {
@@ -139,16 +139,16 @@ where
                 .and_then(|json_str| serde_json::from_str::<U::DataType>(&json_str).map_err(|e| format_err!("Decode json fail: {}", e)))
             {
                 Ok(t) => {
-                    log::debug!("{:?}", t);
+                    tracing::debug!("{:?}", t);
                     <Self as TypedMessageHandlerAsync<'c, C>>::on_message(&self, t, cr)
                 }
                 Err(e) => {
-                    log::error!("{}", e);
+                    tracing::error!("{}", e);
                     Box::pin(async {})
                 }
             }
         } else {
-            log::error!("Receive empty message");
+            tracing::error!("Receive empty message");
             Box::pin(async {})
         }
     }
@@ -0,0 +1,207 @@
+use crate::config;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+static DEAD_LETTER_COUNT: AtomicU64 = AtomicU64::new(0);
+
+// messages dead-lettered since the process started
+pub fn dead_letter_count() -> u64 {
+    DEAD_LETTER_COUNT.load(Ordering::Relaxed)
+}
+
+// one json line of the dead-letter file, the message can be pushed to `topic` again as it is
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub topic: String,
+    pub key: Option<String>,
+    pub message: String,
+    pub attempts: u32,
+}
+
+pub struct DeadLetterSink {
+    path: PathBuf,
+    // opened on the first dead letter
+    file: Option<File>,
+}
+
+impl DeadLetterSink {
+    pub fn new(path: &str) -> DeadLetterSink {
+        DeadLetterSink {
+            path: PathBuf::from(path),
+            file: None,
+        }
+    }
+    pub fn append(&mut self, topic: &str, key: Option<&str>, message: &str, attempts: u32) {
+        DEAD_LETTER_COUNT.fetch_add(1, Ordering::Relaxed);
+        if let Err(e) = self.write_line(topic, key, message, attempts) {
+            // the log is the last place the message can be found
+            tracing::error!("fail to dead-letter message {} to {}: {}", message, topic, e);
+        }
+    }
+    fn write_line(&mut self, topic: &str, key: Option<&str>, message: &str, attempts: u32) -> Result<()> {
+        if self.file.is_none() {
+            self.file = Some(OpenOptions::new().create(true).append(true).open(&self.path)?);
+        }
+        let mut line = serde_json::to_string(&DeadLetter {
+            topic: topic.to_string(),
+            key: key.map(String::from),
+            message: message.to_string(),
+            attempts,
+        })?;
+        line.push('\n');
+        self.file.as_mut().unwrap().write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+struct RetryEntry {
+    topic: &'static str,
+    key: Option<String>,
+    message: String,
+    // failed sends so far
+    attempts: u32,
+    retry_at: Instant,
+}
+
+// Failed messages wait here with exponential backoff. It is bounded, once full or after
+// `max_retries` failures the message goes to the dead-letter sink, so the sender never blocks on it.
+// Retried messages may be delivered after newer messages of the same partition.
+pub struct RetryBuffer {
+    entries: VecDeque<RetryEntry>,
+    capacity: usize,
+    max_retries: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    sink: Arc<Mutex<DeadLetterSink>>,
+}
+
+impl RetryBuffer {
+    pub fn new(config: &config::DeadLetterConfig, sink: Arc<Mutex<DeadLetterSink>>) -> RetryBuffer {
+        RetryBuffer {
+            entries: VecDeque::new(),
+            capacity: config.retry_capacity,
+            max_retries: config.max_retries,
+            initial_backoff: config.initial_backoff,
+            max_backoff: config.max_backoff,
+            sink,
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    fn backoff(&self, attempts: u32) -> Duration {
+        let factor = 1u32.checked_shl(attempts.saturating_sub(1)).unwrap_or(u32::MAX);
+        self.initial_backoff
+            .checked_mul(factor)
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff)
+    }
+    // the message has failed `attempts` times
+    pub fn push(&mut self, topic: &'static str, key: Option<String>, message: String, attempts: u32, now: Instant) {
+        if attempts >= self.max_retries || self.entries.len() >= self.capacity {
+            self.sink.lock().unwrap().append(topic, key.as_deref(), &message, attempts);
+            return;
+        }
+        let retry_at = now + self.backoff(attempts);
+        self.entries.push_back(RetryEntry {
+            topic,
+            key,
+            message,
+            attempts,
+            retry_at,
+        });
+    }
+    // resend the due messages, `send` returns false if it fails again
+    pub fn retry<F>(&mut self, now: Instant, mut send: F)
+    where
+        F: FnMut(&'static str, Option<&str>, &str) -> bool,
+    {
+        let (due, waiting): (VecDeque<RetryEntry>, VecDeque<RetryEntry>) = self.entries.drain(..).partition(|entry| entry.retry_at <= now);
+        self.entries = waiting;
+        for entry in due {
+            if !send(entry.topic, entry.key.as_deref(), &entry.message) {
+                self.push(entry.topic, entry.key, entry.message, entry.attempts + 1, now);
+            }
+        }
+    }
+    // on shutdown nothing is left behind
+    pub fn dead_letter_all(&mut self) {
+        let mut sink = self.sink.lock().unwrap();
+        for entry in self.entries.drain(..) {
+            sink.append(entry.topic, entry.key.as_deref(), &entry.message, entry.attempts);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+
+    #[test]
+    fn test_retry_then_dead_letter() {
+        let path = std::env::temp_dir().join(format!("dead_letter_test_{}.log", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let config = config::DeadLetterConfig {
+            retry_capacity: 1,
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(150),
+            path: path.to_str().unwrap().to_string(),
+        };
+        let sink = Arc::new(Mutex::new(DeadLetterSink::new(&config.path)));
+        let mut buffer = RetryBuffer::new(&config, sink);
+        assert_eq!(buffer.backoff(1), Duration::from_millis(100));
+        assert_eq!(buffer.backoff(2), Duration::from_millis(150));
+        assert_eq!(buffer.backoff(40), Duration::from_millis(150));
+
+        let count = dead_letter_count();
+        let now = Instant::now();
+        buffer.push("balances", Some("101".to_string()), "a".to_string(), 1, now);
+        // the buffer is full
+        buffer.push("balances", None, "b".to_string(), 1, now);
+        assert_eq!(buffer.len(), 1);
+
+        let mut sent = Vec::new();
+        // not due yet
+        buffer.retry(now, |_, _, message| {
+            sent.push(message.to_string());
+            false
+        });
+        assert!(sent.is_empty());
+        let mut now = now;
+        for _ in 0..2 {
+            now += Duration::from_secs(1);
+            buffer.retry(now, |_, _, message| {
+                sent.push(message.to_string());
+                false
+            });
+        }
+        assert_eq!(sent, vec!["a", "a"]);
+        assert!(buffer.is_empty());
+        assert!(dead_letter_count() >= count + 2);
+
+        let letters: Vec<DeadLetter> = BufReader::new(File::open(&path).unwrap())
+            .lines()
+            .map(|line| serde_json::from_str(&line.unwrap()).unwrap())
+            .collect();
+        assert_eq!(letters.len(), 2);
+        assert_eq!((letters[0].message.as_str(), letters[0].attempts), ("b", 1));
+        assert_eq!(
+            (letters[1].message.as_str(), letters[1].key.as_deref(), letters[1].attempts),
+            ("a", Some("101"), 3)
+        );
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -1,5 +1,6 @@
+use crate::config;
 use crate::market::Order;
-use crate::types::{OrderEventType, SimpleResult, Trade};
+use crate::types::{MarketRole, OrderEventType, OrderFillStatus, OrderSide, SimpleResult, Trade};
 use core::cell::RefCell;
 
 use anyhow::{anyhow, Result};
@@ -7,28 +8,63 @@ use crossbeam_channel::RecvTimeoutError;
 use rdkafka::client::ClientContext;
 use rdkafka::config::ClientConfig;
 use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+use rdkafka::message::Message;
 use rdkafka::producer::{BaseProducer, BaseRecord, DeliveryResult, Producer, ProducerContext};
 
+use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
 
 use std::collections::LinkedList;
-use std::sync::Arc;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
 
 pub mod consumer;
+pub mod deadletter;
 pub mod persist;
 
-pub struct SimpleProducerContext;
-impl ClientContext for SimpleProducerContext {}
+pub use deadletter::dead_letter_count;
+use deadletter::{DeadLetterSink, RetryBuffer};
+
+// without a key, the partitioner spreads the messages over the partitions
+fn new_record<'a>(topic_name: &'a str, key: Option<&'a str>, message: &'a str) -> BaseRecord<'a, str, str> {
+    let record = BaseRecord::to(topic_name).payload(message);
+    match key {
+        Some(key) => record.key(key),
+        None => record,
+    }
+}
+
+pub struct SimpleProducerContext {
+    dead_letters: Arc<Mutex<DeadLetterSink>>,
+    // false once librdkafka reports all the brokers down, true again on the next delivery
+    connected: Arc<AtomicBool>,
+}
+impl ClientContext for SimpleProducerContext {
+    fn error(&self, error: KafkaError, reason: &str) {
+        tracing::error!("kafka client err: {}: {}", error, reason);
+        if let KafkaError::Global(RDKafkaErrorCode::AllBrokersDown) = error {
+            self.connected.store(false, Ordering::Relaxed);
+        }
+    }
+}
 impl ProducerContext for SimpleProducerContext {
     type DeliveryOpaque = ();
     fn delivery(&self, result: &DeliveryResult, _: Self::DeliveryOpaque) {
         match result {
-            // TODO: how to handle this err
-            Err(e) => println!("kafka send err: {:?}", e),
+            // librdkafka has retried until `message.timeout.ms`, so give up the message
+            Err((e, message)) => {
+                tracing::error!("kafka send err: {:?}", e);
+                let key = message.key_view::<str>().and_then(|key| key.ok());
+                let payload = message.payload_view::<str>().and_then(|payload| payload.ok()).unwrap_or_default();
+                self.dead_letters.lock().unwrap().append(message.topic(), key, payload, 1);
+            }
             Ok(_r) => {
                 //println!("kafka send done: {:?}", r)
+                self.connected.store(true, Ordering::Relaxed);
             }
         }
     }
@@ -36,6 +72,7 @@ impl ProducerContext for SimpleProducerContext {
 pub const ORDERS_TOPIC: &str = "orders";
 pub const TRADES_TOPIC: &str = "trades";
 pub const BALANCES_TOPIC: &str = "balances";
+pub const ORDER_FILLS_TOPIC: &str = "order_fills";
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct BalanceMessage {
@@ -43,10 +80,186 @@ pub struct BalanceMessage {
     pub user_id: u32,
     pub asset: String,
     pub business: String,
+    /// Deprecated: kept for one release, use `change_mantissa` and `change_scale` instead.
     pub change: String,
+    pub change_mantissa: i128,
+    pub change_scale: u32,
+}
+
+impl BalanceMessage {
+    pub fn new(timestamp: f64, user_id: u32, asset: String, business: String, change: Decimal) -> BalanceMessage {
+        BalanceMessage {
+            timestamp,
+            user_id,
+            asset,
+            business,
+            change: change.to_string(),
+            change_mantissa: change.mantissa(),
+            change_scale: change.scale(),
+        }
+    }
+    pub fn change_decimal(&self) -> Decimal {
+        Decimal::from_i128_with_scale(self.change_mantissa, self.change_scale)
+    }
+}
+
+// Every message carries `schema_version`, consumers should decode with the `decode_*` functions.
+// Bump the version whenever the fields of a message change.
+// balance v2: `change_mantissa` and `change_scale` are added
+// balance v3, the others v2: `seq` is added
+pub const BALANCE_MESSAGE_VERSION: u32 = 3;
+pub const ORDER_MESSAGE_VERSION: u32 = 2;
+pub const TRADE_MESSAGE_VERSION: u32 = 2;
+pub const ORDER_FILL_MESSAGE_VERSION: u32 = 2;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Versioned<T> {
+    pub schema_version: u32,
+    // see `MessageSequence`
+    pub seq: u64,
+    #[serde(flatten)]
+    pub message: T,
+}
+
+const MESSAGE_COUNT_BITS: u32 = 24;
+
+// The `seq` of the messages, increasing across all the topics, so the consumers can order and dedup
+// them. It is the id of the last operation log in the high bits and the count of the messages sent
+// since in the low 24 bits. The operation logs are replayed on restart, so the numbers go on
+// increasing after it, unless the commands of the last messages were lost before being logged.
+#[derive(Debug, Default, Clone)]
+pub struct MessageSequence {
+    operation_log_id: u64,
+    count: u64,
+}
+
+impl MessageSequence {
+    pub fn set_operation_log_id(&mut self, id: u64) {
+        if id != self.operation_log_id {
+            self.operation_log_id = id;
+            self.count = 0;
+        }
+    }
+    pub fn next(&mut self) -> u64 {
+        self.count += 1;
+        debug_assert!(self.count < 1 << MESSAGE_COUNT_BITS, "too many messages for one operation");
+        (self.operation_log_id << MESSAGE_COUNT_BITS) | self.count
+    }
+}
+
+fn default_schema_version() -> u32 {
+    1
+}
+
+// messages before versioning have no `schema_version`, they are v1
+#[derive(Deserialize)]
+struct SchemaVersion {
+    #[serde(default = "default_schema_version")]
+    schema_version: u32,
+}
+
+#[derive(Deserialize)]
+struct BalanceMessageV1 {
+    timestamp: f64,
+    user_id: u32,
+    asset: String,
+    business: String,
+    change: String,
+}
+
+pub fn encode_balance_message(balance: &BalanceMessage, seq: u64) -> String {
+    serde_json::to_string(&Versioned {
+        schema_version: BALANCE_MESSAGE_VERSION,
+        seq,
+        message: balance,
+    })
+    .unwrap()
+}
+
+pub fn encode_order_message(order: &OrderMessage, seq: u64) -> String {
+    serde_json::to_string(&Versioned {
+        schema_version: ORDER_MESSAGE_VERSION,
+        seq,
+        message: order,
+    })
+    .unwrap()
+}
+
+pub fn encode_trade_message(trade: &Trade, seq: u64) -> String {
+    serde_json::to_string(&Versioned {
+        schema_version: TRADE_MESSAGE_VERSION,
+        seq,
+        message: trade,
+    })
+    .unwrap()
+}
+
+pub fn encode_order_fill_message(fill: &OrderFillMessage, seq: u64) -> String {
+    serde_json::to_string(&Versioned {
+        schema_version: ORDER_FILL_MESSAGE_VERSION,
+        seq,
+        message: fill,
+    })
+    .unwrap()
+}
+
+pub fn decode_balance_message(payload: &str) -> Result<BalanceMessage> {
+    match serde_json::from_str::<SchemaVersion>(payload)?.schema_version {
+        1 => {
+            let v1: BalanceMessageV1 = serde_json::from_str(payload)?;
+            let change = Decimal::from_str(&v1.change)?;
+            Ok(BalanceMessage::new(v1.timestamp, v1.user_id, v1.asset, v1.business, change))
+        }
+        2 | 3 => Ok(serde_json::from_str(payload)?),
+        version => Err(anyhow!("unsupported balance message version {}", version)),
+    }
+}
+
+pub fn decode_trade_message(payload: &str) -> Result<Trade> {
+    match serde_json::from_str::<SchemaVersion>(payload)?.schema_version {
+        1 | 2 => Ok(serde_json::from_str(payload)?),
+        version => Err(anyhow!("unsupported trade message version {}", version)),
+    }
 }
 
-#[derive(Debug, Serialize)] //, Deserialize)]
+pub fn decode_order_fill_message(payload: &str) -> Result<OrderFillMessage> {
+    match serde_json::from_str::<SchemaVersion>(payload)?.schema_version {
+        1 | 2 => Ok(serde_json::from_str(payload)?),
+        version => Err(anyhow!("unsupported order fill message version {}", version)),
+    }
+}
+
+#[derive(Deserialize)]
+struct MessageSeq {
+    #[serde(default)]
+    seq: u64,
+}
+
+// the `seq` of a message of any topic, 0 for the messages before it was added
+pub fn decode_message_seq(payload: &str) -> Result<u64> {
+    Ok(serde_json::from_str::<MessageSeq>(payload)?.seq)
+}
+
+// One side of a trade, seen from the order. Both the maker and the taker get one per trade,
+// after the trade message.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct OrderFillMessage {
+    pub timestamp: f64,
+    pub market: String,
+    pub order_id: u64,
+    pub user_id: u32,
+    pub trade_id: u64,
+    pub side: OrderSide,
+    pub role: MarketRole,
+    pub price: Decimal,
+    // base amount of this fill
+    pub filled: Decimal,
+    pub cumulative_filled: Decimal,
+    pub remain: Decimal,
+    pub status: OrderFillStatus,
+}
+
+#[derive(Debug, Clone, Serialize)] //, Deserialize)]
 pub struct OrderMessage {
     pub event: OrderEventType,
     pub order: Order,
@@ -58,91 +271,122 @@ pub struct OrderMessage {
 pub struct MessageSenderStatus {
     trades_len: usize,
     orders_len: usize,
+    fills_len: usize,
     balances_len: usize,
+    retry_len: usize,
 }
 
+// topic, partition key and payload
+pub type KafkaMessage = (&'static str, Option<String>, String);
+
 pub struct KafkaMessageSender {
     producer: Arc<BaseProducer<SimpleProducerContext>>,
-    orders_list: RefCell<LinkedList<String>>,
-    trades_list: RefCell<LinkedList<String>>,
-    balances_list: RefCell<LinkedList<String>>,
-    receiver: crossbeam_channel::Receiver<(&'static str, String)>,
+    orders_list: RefCell<LinkedList<(Option<String>, String)>>,
+    trades_list: RefCell<LinkedList<(Option<String>, String)>>,
+    fills_list: RefCell<LinkedList<(Option<String>, String)>>,
+    balances_list: RefCell<LinkedList<(Option<String>, String)>>,
+    // messages kafka refused, other than a full queue
+    retry_buffer: RefCell<RetryBuffer>,
+    receiver: crossbeam_channel::Receiver<KafkaMessage>,
 }
 
 impl KafkaMessageSender {
-    pub fn new(brokers: &str, receiver: crossbeam_channel::Receiver<(&'static str, String)>) -> Result<KafkaMessageSender> {
+    pub fn new(
+        brokers: &str,
+        dead_letter: &config::DeadLetterConfig,
+        connected: Arc<AtomicBool>,
+        receiver: crossbeam_channel::Receiver<KafkaMessage>,
+    ) -> Result<KafkaMessageSender> {
+        let dead_letters = Arc::new(Mutex::new(DeadLetterSink::new(&dead_letter.path)));
         let producer = ClientConfig::new()
             .set("bootstrap.servers", brokers)
             .set("queue.buffering.max.ms", "1")
-            .create_with_context(SimpleProducerContext)?;
+            .create_with_context(SimpleProducerContext {
+                dead_letters: dead_letters.clone(),
+                connected,
+            })?;
         let arc = Arc::new(producer);
 
         Ok(KafkaMessageSender {
             producer: arc,
             trades_list: RefCell::new(LinkedList::new()),
             orders_list: RefCell::new(LinkedList::new()),
+            fills_list: RefCell::new(LinkedList::new()),
             balances_list: RefCell::new(LinkedList::new()),
+            retry_buffer: RefCell::new(RetryBuffer::new(dead_letter, dead_letters)),
             receiver,
         })
     }
-    pub fn on_message(&self, topic_name: &str, message: &str) -> SimpleResult {
-        log::debug!("KAFKA: push {} message: {}", topic_name, message);
+    pub fn on_message(&self, topic_name: &'static str, key: Option<&str>, message: &str) -> SimpleResult {
+        tracing::debug!("KAFKA: push {} message: {}", topic_name, message);
         let mut list = match topic_name {
             BALANCES_TOPIC => self.balances_list.borrow_mut(),
             TRADES_TOPIC => self.trades_list.borrow_mut(),
             ORDERS_TOPIC => self.orders_list.borrow_mut(),
+            ORDER_FILLS_TOPIC => self.fills_list.borrow_mut(),
             _ => unreachable!(),
         };
 
         // busy, so not push message now
         if !list.is_empty() {
-            list.push_back(message.to_string());
+            list.push_back((key.map(String::from), message.to_string()));
             return Ok(());
         }
-        let record = BaseRecord::to(topic_name).key("").payload(message);
-        let result = self.producer.send(record);
+        let result = self.producer.send(new_record(topic_name, key, message));
         if result.is_err() {
-            log::error!("fail to push message {} to {}", message, topic_name);
+            tracing::error!("fail to push message {} to {}", message, topic_name);
             if let Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), _)) = result {
-                list.push_back(message.to_string());
+                list.push_back((key.map(String::from), message.to_string()));
                 return Ok(());
             }
-            return Err(anyhow!("kafka push err"));
+            self.retry_buffer
+                .borrow_mut()
+                .push(topic_name, key.map(String::from), message.to_string(), 1, Instant::now());
         }
         Ok(())
     }
     pub fn finish(self) -> SimpleResult {
         self.flush();
+        self.retry_buffer.borrow_mut().dead_letter_all();
         self.producer.flush(std::time::Duration::from_millis(1000));
         drop(self);
         Ok(())
     }
 
     // if kafka is full, queue messages in list, so here flush them.
-    fn flush_list(&self, topic_name: &str) {
+    fn flush_list(&self, topic_name: &'static str) {
         let mut list = match topic_name {
             BALANCES_TOPIC => self.balances_list.borrow_mut(),
             TRADES_TOPIC => self.trades_list.borrow_mut(),
             ORDERS_TOPIC => self.orders_list.borrow_mut(),
+            ORDER_FILLS_TOPIC => self.fills_list.borrow_mut(),
             _ => unreachable!(),
         };
-        for message in list.iter() {
-            let result = self.producer.send(BaseRecord::to(topic_name).key("").payload(message.as_str()));
-
-            if result.is_err() {
-                //println!("fail to push message {} to {}", message_str, topic_name);
-                if let Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), _)) = result {
-                    break;
+        // keep the messages not sent yet in the list
+        while let Some((key, message)) = list.front() {
+            match self.producer.send(new_record(topic_name, key.as_deref(), message)) {
+                Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), _)) => break,
+                Err(_) => {
+                    //println!("fail to push message {} to {}", message_str, topic_name);
+                    self.retry_buffer
+                        .borrow_mut()
+                        .push(topic_name, key.clone(), message.clone(), 1, Instant::now());
                 }
+                Ok(_) => {}
             }
+            list.pop_front();
         }
-        list.clear();
     }
 
     fn flush(&self) {
         self.flush_list(BALANCES_TOPIC);
         self.flush_list(ORDERS_TOPIC);
         self.flush_list(TRADES_TOPIC);
+        self.flush_list(ORDER_FILLS_TOPIC);
+        let producer = &self.producer;
+        self.retry_buffer.borrow_mut().retry(Instant::now(), |topic_name, key, message| {
+            producer.send(new_record(topic_name, key, message)).is_ok()
+        });
         self.producer.poll(Duration::from_millis(0));
     }
 
@@ -152,18 +396,18 @@ impl KafkaMessageSender {
         let timeout_interval = std::time::Duration::from_millis(100);
         loop {
             if self.is_block() {
-                log::warn!("kafka sender buffer is full");
+                tracing::warn!("kafka sender buffer is full");
                 // skip receiving from channel, so main server can know something goes wrong
                 // sleep to avoid cpu 100% usage
                 thread::sleep(flush_interval);
             } else {
                 match self.receiver.recv_timeout(timeout_interval) {
-                    Ok((topic, message)) => {
-                        self.on_message(topic, &message).ok();
+                    Ok((topic, key, message)) => {
+                        self.on_message(topic, key.as_deref(), &message).ok();
                     }
                     Err(RecvTimeoutError::Timeout) => {}
                     Err(RecvTimeoutError::Disconnected) => {
-                        log::info!("kafka producer disconnected");
+                        tracing::info!("kafka producer disconnected");
                         break;
                     }
                 }
@@ -175,12 +419,13 @@ impl KafkaMessageSender {
             }
         }
         self.finish().ok();
-        log::info!("kafka sender exit");
+        tracing::info!("kafka sender exit");
     }
 
     pub fn is_block(&self) -> bool {
         self.trades_list.borrow_mut().len() >= 100
             || self.orders_list.borrow_mut().len() >= 100
+            || self.fills_list.borrow_mut().len() >= 100
             || self.balances_list.borrow_mut().len() >= 100
     }
 
@@ -188,7 +433,9 @@ impl KafkaMessageSender {
         MessageSenderStatus {
             trades_len: self.trades_list.borrow_mut().len(),
             orders_len: self.orders_list.borrow_mut().len(),
+            fills_len: self.fills_list.borrow_mut().len(),
             balances_len: self.balances_list.borrow_mut().len(),
+            retry_len: self.retry_buffer.borrow().len(),
         }
     }
 }
@@ -196,17 +443,45 @@ impl KafkaMessageSender {
 pub trait MessageManager {
     fn push_order_message(&mut self, order: &OrderMessage);
     fn push_trade_message(&mut self, trade: &Trade);
+    fn push_order_fill_message(&mut self, fill: &OrderFillMessage);
     fn push_balance_message(&mut self, balance: &BalanceMessage);
+    // true if the backend can't keep up, then the server stops accepting requests
+    fn is_block(&self) -> bool {
+        false
+    }
+    // false if the message broker is known to be unreachable
+    fn is_connected(&self) -> bool {
+        true
+    }
+    // on shutdown, wait for the pushed messages to be sent or dead-lettered
+    fn finish(&mut self) {}
+    // called with the id of each operation log, the `seq` of the messages restart from it
+    fn set_operation_log_id(&mut self, _id: u64) {}
+}
+
+// Dropping the sender stops the thread once it has flushed its buffers.
+// The new sender is never used, it only keeps the fields valid.
+fn finish_sender_thread<T>(sender: &mut crossbeam_channel::Sender<T>, handle: &mut Option<thread::JoinHandle<()>>) {
+    *sender = crossbeam_channel::bounded(1).0;
+    if let Some(handle) = handle.take() {
+        if handle.join().is_err() {
+            tracing::error!("message sender thread panicked");
+        }
+    }
 }
 
 pub struct ChannelMessageManager {
-    pub sender: crossbeam_channel::Sender<(&'static str, String)>,
+    pub sender: crossbeam_channel::Sender<KafkaMessage>,
+    pub partition_strategy: config::PartitionStrategy,
+    pub sequence: MessageSequence,
+    pub connected: Arc<AtomicBool>,
+    pub handle: Option<thread::JoinHandle<()>>,
 }
 
 impl ChannelMessageManager {
-    fn push_message(&self, message: String, topic_name: &'static str) {
-        //log::debug!("KAFKA: push {} message: {}", topic_name, message);
-        self.sender.try_send((topic_name, message)).unwrap();
+    fn push_message(&self, message: String, topic_name: &'static str, key: Option<String>) {
+        //tracing::debug!("KAFKA: push {} message: {}", topic_name, message);
+        self.sender.try_send((topic_name, key, message)).unwrap();
     }
     pub fn is_block(&self) -> bool {
         self.sender.len() >= (self.sender.capacity().unwrap() as f64 * 0.9) as usize
@@ -215,16 +490,32 @@ impl ChannelMessageManager {
 
 impl MessageManager for ChannelMessageManager {
     fn push_order_message(&mut self, order: &OrderMessage) {
-        let message = serde_json::to_string(&order).unwrap();
-        self.push_message(message, ORDERS_TOPIC)
+        let message = encode_order_message(order, self.sequence.next());
+        self.push_message(message, ORDERS_TOPIC, order_partition_key(self.partition_strategy, order))
     }
     fn push_trade_message(&mut self, trade: &Trade) {
-        let message = serde_json::to_string(&trade).unwrap();
-        self.push_message(message, TRADES_TOPIC)
+        let message = encode_trade_message(trade, self.sequence.next());
+        self.push_message(message, TRADES_TOPIC, trade_partition_key(self.partition_strategy, trade))
+    }
+    fn push_order_fill_message(&mut self, fill: &OrderFillMessage) {
+        let message = encode_order_fill_message(fill, self.sequence.next());
+        self.push_message(message, ORDER_FILLS_TOPIC, order_fill_partition_key(self.partition_strategy, fill))
     }
     fn push_balance_message(&mut self, balance: &BalanceMessage) {
-        let message = serde_json::to_string(&balance).unwrap();
-        self.push_message(message, BALANCES_TOPIC)
+        let message = encode_balance_message(balance, self.sequence.next());
+        self.push_message(message, BALANCES_TOPIC, balance_partition_key(self.partition_strategy, balance))
+    }
+    fn is_block(&self) -> bool {
+        ChannelMessageManager::is_block(self)
+    }
+    fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+    fn finish(&mut self) {
+        finish_sender_thread(&mut self.sender, &mut self.handle);
+    }
+    fn set_operation_log_id(&mut self, id: u64) {
+        self.sequence.set_operation_log_id(id);
     }
 }
 
@@ -232,13 +523,290 @@ pub struct DummyMessageManager;
 impl MessageManager for DummyMessageManager {
     fn push_order_message(&mut self, _order: &OrderMessage) {}
     fn push_trade_message(&mut self, _trade: &Trade) {}
+    fn push_order_fill_message(&mut self, _fill: &OrderFillMessage) {}
     fn push_balance_message(&mut self, _balance: &BalanceMessage) {}
 }
 
-pub fn new_message_manager_with_kafka_backend(brokers: &str) -> Result<ChannelMessageManager> {
+// Kafka keeps the order only inside a partition, and the messages of the same key
+// always go to the same partition. Keys are user ids or market names.
+pub fn order_partition_key(strategy: config::PartitionStrategy, order: &OrderMessage) -> Option<String> {
+    match strategy {
+        config::PartitionStrategy::ByUser => Some(order.order.user.to_string()),
+        config::PartitionStrategy::ByMarket => Some(order.order.market.to_string()),
+        config::PartitionStrategy::RoundRobin => None,
+    }
+}
+pub fn trade_partition_key(strategy: config::PartitionStrategy, trade: &Trade) -> Option<String> {
+    match strategy {
+        config::PartitionStrategy::ByUser | config::PartitionStrategy::ByMarket => Some(trade.market.clone()),
+        config::PartitionStrategy::RoundRobin => None,
+    }
+}
+pub fn order_fill_partition_key(strategy: config::PartitionStrategy, fill: &OrderFillMessage) -> Option<String> {
+    match strategy {
+        config::PartitionStrategy::ByUser => Some(fill.user_id.to_string()),
+        config::PartitionStrategy::ByMarket => Some(fill.market.clone()),
+        config::PartitionStrategy::RoundRobin => None,
+    }
+}
+pub fn balance_partition_key(strategy: config::PartitionStrategy, balance: &BalanceMessage) -> Option<String> {
+    match strategy {
+        config::PartitionStrategy::ByUser | config::PartitionStrategy::ByMarket => Some(balance.user_id.to_string()),
+        config::PartitionStrategy::RoundRobin => None,
+    }
+}
+
+// keep the pushed messages as (subject, json) for tests
+#[derive(Default)]
+pub struct NullMessageManager {
+    pub messages: Vec<(String, String)>,
+    pub sequence: MessageSequence,
+}
+impl MessageManager for NullMessageManager {
+    fn push_order_message(&mut self, order: &OrderMessage) {
+        let message = encode_order_message(order, self.sequence.next());
+        self.messages.push((order_subject(order), message));
+    }
+    fn push_trade_message(&mut self, trade: &Trade) {
+        let message = encode_trade_message(trade, self.sequence.next());
+        self.messages.push((trade_subject(trade), message));
+    }
+    fn push_order_fill_message(&mut self, fill: &OrderFillMessage) {
+        let message = encode_order_fill_message(fill, self.sequence.next());
+        self.messages.push((order_fill_subject(fill), message));
+    }
+    fn push_balance_message(&mut self, balance: &BalanceMessage) {
+        let message = encode_balance_message(balance, self.sequence.next());
+        self.messages.push((balance_subject(balance), message));
+    }
+    fn set_operation_log_id(&mut self, id: u64) {
+        self.sequence.set_operation_log_id(id);
+    }
+}
+
+// NATS subjects, so subscribers can pick markets or assets with wildcards like `trades.*`
+pub fn order_subject(order: &OrderMessage) -> String {
+    format!("{}.{}", ORDERS_TOPIC, order.order.market)
+}
+pub fn trade_subject(trade: &Trade) -> String {
+    format!("{}.{}", TRADES_TOPIC, trade.market)
+}
+pub fn order_fill_subject(fill: &OrderFillMessage) -> String {
+    format!("{}.{}", ORDER_FILLS_TOPIC, fill.market)
+}
+pub fn balance_subject(balance: &BalanceMessage) -> String {
+    format!("{}.{}", BALANCES_TOPIC, balance.asset)
+}
+
+pub struct NatsMessageSender {
+    connection: nats::Connection,
+    receiver: crossbeam_channel::Receiver<(String, String)>,
+}
+
+impl NatsMessageSender {
+    pub fn new(url: &str, receiver: crossbeam_channel::Receiver<(String, String)>) -> Result<NatsMessageSender> {
+        let connection = nats::connect(url)?;
+        Ok(NatsMessageSender { connection, receiver })
+    }
+    // the client buffers and reconnects by itself, so messages are published one by one
+    pub fn start(self) {
+        for (subject, message) in self.receiver.iter() {
+            tracing::debug!("NATS: push {} message: {}", subject, message);
+            if let Err(e) = self.connection.publish(&subject, &message) {
+                tracing::error!("fail to push message {} to {}: {}", message, subject, e);
+            }
+        }
+        tracing::info!("nats producer disconnected");
+        self.connection.flush().ok();
+        tracing::info!("nats sender exit");
+    }
+}
+
+pub struct NatsMessageManager {
+    pub sender: crossbeam_channel::Sender<(String, String)>,
+    pub handle: Option<thread::JoinHandle<()>>,
+    pub sequence: MessageSequence,
+}
+
+impl NatsMessageManager {
+    fn push_message(&self, message: String, subject: String) {
+        self.sender.try_send((subject, message)).unwrap();
+    }
+}
+
+impl MessageManager for NatsMessageManager {
+    fn push_order_message(&mut self, order: &OrderMessage) {
+        let message = encode_order_message(order, self.sequence.next());
+        self.push_message(message, order_subject(order))
+    }
+    fn push_trade_message(&mut self, trade: &Trade) {
+        let message = encode_trade_message(trade, self.sequence.next());
+        self.push_message(message, trade_subject(trade))
+    }
+    fn push_order_fill_message(&mut self, fill: &OrderFillMessage) {
+        let message = encode_order_fill_message(fill, self.sequence.next());
+        self.push_message(message, order_fill_subject(fill))
+    }
+    fn push_balance_message(&mut self, balance: &BalanceMessage) {
+        let message = encode_balance_message(balance, self.sequence.next());
+        self.push_message(message, balance_subject(balance))
+    }
+    fn is_block(&self) -> bool {
+        self.sender.len() >= (self.sender.capacity().unwrap() as f64 * 0.9) as usize
+    }
+    fn finish(&mut self) {
+        finish_sender_thread(&mut self.sender, &mut self.handle);
+    }
+    fn set_operation_log_id(&mut self, id: u64) {
+        self.sequence.set_operation_log_id(id);
+    }
+}
+
+pub fn new_message_manager_with_nats_backend(url: &str) -> Result<NatsMessageManager> {
     let (sender, receiver) = crossbeam_channel::bounded(100);
-    let kafka_sender = KafkaMessageSender::new(brokers, receiver)?;
-    // TODO: join handle?
-    std::thread::spawn(move || kafka_sender.start());
-    Ok(ChannelMessageManager { sender })
+    let nats_sender = NatsMessageSender::new(url, receiver)?;
+    let handle = std::thread::spawn(move || nats_sender.start());
+    Ok(NatsMessageManager {
+        sender,
+        handle: Some(handle),
+        sequence: MessageSequence::default(),
+    })
+}
+
+pub fn new_message_manager(settings: &config::Settings) -> Result<Rc<RefCell<dyn MessageManager>>> {
+    Ok(match settings.message_backend {
+        config::MessageBackend::Kafka => Rc::new(RefCell::new(new_message_manager_with_kafka_backend(
+            &settings.brokers,
+            settings.partition_strategy,
+            &settings.dead_letter,
+        )?)),
+        config::MessageBackend::Nats => Rc::new(RefCell::new(new_message_manager_with_nats_backend(&settings.nats_url)?)),
+    })
+}
+
+pub fn new_message_manager_with_kafka_backend(
+    brokers: &str,
+    partition_strategy: config::PartitionStrategy,
+    dead_letter: &config::DeadLetterConfig,
+) -> Result<ChannelMessageManager> {
+    let (sender, receiver) = crossbeam_channel::bounded(100);
+    let connected = Arc::new(AtomicBool::new(true));
+    let kafka_sender = KafkaMessageSender::new(brokers, dead_letter, connected.clone(), receiver)?;
+    let handle = std::thread::spawn(move || kafka_sender.start());
+    Ok(ChannelMessageManager {
+        sender,
+        partition_strategy,
+        sequence: MessageSequence::default(),
+        connected,
+        handle: Some(handle),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::*;
+
+    #[test]
+    fn test_balance_message_change_round_trip() {
+        for change in vec![dec!(1.2300), dec!(-0.00000001), dec!(0), dec!(123456789.987654321)] {
+            let message = BalanceMessage::new(0.0, 101, String::from("USDT"), String::from("deposit"), change);
+            let json = serde_json::to_string(&message).unwrap();
+            let decoded: BalanceMessage = serde_json::from_str(&json).unwrap();
+            let decoded_change = decoded.change_decimal();
+            assert_eq!(decoded_change, change);
+            assert_eq!(decoded_change.scale(), change.scale());
+            assert_eq!(decoded.change, change.to_string());
+        }
+    }
+
+    fn get_trade() -> Trade {
+        Trade {
+            id: 1,
+            timestamp: 0.0,
+            market: String::from("ETH_USDT"),
+            base: String::from("ETH"),
+            quote: String::from("USDT"),
+            price: dec!(1.1),
+            amount: dec!(2),
+            quote_amount: dec!(2.2),
+            ask_user_id: 101,
+            ask_order_id: 1,
+            ask_role: MarketRole::MAKER,
+            ask_fee: dec!(0),
+            ask_fee_asset: String::from("USDT"),
+            bid_user_id: 102,
+            bid_order_id: 2,
+            bid_role: MarketRole::TAKER,
+            bid_fee: dec!(0),
+            bid_fee_asset: String::from("ETH"),
+        }
+    }
+
+    #[test]
+    fn test_message_subjects() {
+        let mut message_manager = NullMessageManager::default();
+        let balance = BalanceMessage::new(0.0, 101, String::from("USDT"), String::from("deposit"), dec!(1));
+        message_manager.push_balance_message(&balance);
+        let trade = get_trade();
+        message_manager.push_trade_message(&trade);
+        let subjects: Vec<&str> = message_manager.messages.iter().map(|(subject, _)| subject.as_str()).collect();
+        assert_eq!(subjects, vec!["balances.USDT", "trades.ETH_USDT"]);
+        let decoded = decode_trade_message(&message_manager.messages[1].1).unwrap();
+        assert_eq!((decoded.id, decoded.price), (trade.id, trade.price));
+    }
+
+    #[test]
+    fn test_partition_key() {
+        let trade = get_trade();
+        let balance = BalanceMessage::new(0.0, 101, String::from("USDT"), String::from("deposit"), dec!(1));
+        for strategy in &[config::PartitionStrategy::ByUser, config::PartitionStrategy::ByMarket] {
+            // all the trades of a market and all the balance changes of a user keep their order
+            assert_eq!(trade_partition_key(*strategy, &trade), Some(String::from("ETH_USDT")));
+            assert_eq!(balance_partition_key(*strategy, &balance), Some(String::from("101")));
+        }
+        assert_eq!(trade_partition_key(config::PartitionStrategy::RoundRobin, &trade), None);
+        assert_eq!(balance_partition_key(config::PartitionStrategy::RoundRobin, &balance), None);
+    }
+
+    #[test]
+    fn test_decode_balance_message_versions() {
+        // a v1 payload has only the string change
+        let v1 = r#"{"timestamp":1.5,"user_id":101,"asset":"USDT","business":"deposit","change":"1.2300"}"#;
+        let decoded = decode_balance_message(v1).unwrap();
+        assert_eq!(decoded.change_decimal(), dec!(1.2300));
+        assert_eq!(decoded.change_scale, 4);
+
+        let message = BalanceMessage::new(1.5, 101, String::from("USDT"), String::from("deposit"), dec!(-0.5));
+        let v3 = encode_balance_message(&message, 7);
+        assert!(v3.contains(r#""schema_version":3"#));
+        let decoded = decode_balance_message(&v3).unwrap();
+        assert_eq!((decoded.change_mantissa, decoded.change_scale), (-5, 1));
+        assert_eq!(decoded.change, "-0.5");
+        assert_eq!(decode_message_seq(&v3).unwrap(), 7);
+        assert_eq!(decode_message_seq(v1).unwrap(), 0);
+
+        let future = v3.replace(r#""schema_version":3"#, r#""schema_version":4"#);
+        assert!(decode_balance_message(&future).is_err());
+    }
+
+    #[test]
+    fn test_message_sequence() {
+        let mut message_manager = NullMessageManager::default();
+        let balance = BalanceMessage::new(0.0, 101, String::from("USDT"), String::from("deposit"), dec!(1));
+        message_manager.set_operation_log_id(41);
+        message_manager.push_balance_message(&balance);
+        message_manager.push_trade_message(&get_trade());
+        // the messages of the next command
+        message_manager.set_operation_log_id(42);
+        message_manager.push_trade_message(&get_trade());
+        message_manager.set_operation_log_id(42);
+        message_manager.push_balance_message(&balance);
+        let seqs: Vec<u64> = message_manager
+            .messages
+            .iter()
+            .map(|(_, payload)| decode_message_seq(payload).unwrap())
+            .collect();
+        assert_eq!(seqs, vec![(41 << 24) + 1, (41 << 24) + 2, (42 << 24) + 1, (42 << 24) + 2]);
+    }
 }
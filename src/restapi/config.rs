@@ -1,5 +1,7 @@
 use serde::{Deserialize, Serialize};
 
+use crate::config::AuthConfig;
+
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 #[serde(default)]
 pub struct Trading {
@@ -18,11 +20,102 @@ impl Default for Trading {
     }
 }
 
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct RateLimit {
+    // max burst of requests
+    pub capacity: u32,
+    pub refill_per_second: f64,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct RateLimitGroup {
+    // a request belongs to the group with the longest matching prefix
+    pub path_prefix: String,
+    #[serde(flatten)]
+    pub limit: RateLimit,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    // no groups means no limit
+    pub groups: Vec<RateLimitGroup>,
+    // clients sending this header are limited by its value rather than their ip
+    pub api_key_header: Option<String>,
+    // behind a proxy, take the client ip from a header like `X-Real-IP` set by the proxy
+    pub real_ip_header: Option<String>,
+    #[serde(with = "humantime_serde")]
+    pub sweep_interval: std::time::Duration,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            groups: Vec::new(),
+            api_key_header: None,
+            real_ip_header: None,
+            sweep_interval: std::time::Duration::from_secs(60),
+        }
+    }
+}
+
+// The origins allowed to call the apis from browsers. The default allows none, and "*" allows any.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct CorsConfig {
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    // the request headers a page may send besides the simple ones
+    pub allowed_headers: Vec<String>,
+    // whether the cookies and the auth headers are exposed, the origins are then echoed rather than "*"
+    pub allow_credentials: bool,
+    // how long a browser may cache a preflight result
+    #[serde(with = "humantime_serde")]
+    pub max_age: std::time::Duration,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            allowed_origins: Vec::new(),
+            allowed_methods: vec!["GET".to_string(), "POST".to_string()],
+            allowed_headers: vec!["content-type".to_string(), "x-api-key".to_string()],
+            allow_credentials: false,
+            max_age: std::time::Duration::from_secs(3600),
+        }
+    }
+}
+
+// the grpc service the json-rpc calls are forwarded to
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct MatchengineConfig {
+    pub url: String,
+    // sent with every call if not empty, in the header of the matchengine auth config
+    pub api_key: String,
+    pub api_key_header: String,
+}
+
+impl Default for MatchengineConfig {
+    fn default() -> Self {
+        MatchengineConfig {
+            url: "http://127.0.0.1:50051".to_string(),
+            api_key: String::new(),
+            api_key_header: "x-api-key".to_string(),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 #[serde(default)]
 pub struct Settings {
     pub workers: Option<usize>,
     pub trading: Trading,
+    pub rate_limit: RateLimitConfig,
+    // all the rest apis are read-only, any valid key is accepted
+    pub auth: AuthConfig,
+    pub matchengine: MatchengineConfig,
+    pub cors: CorsConfig,
 }
 
 impl Default for Settings {
@@ -30,6 +123,10 @@ impl Default for Settings {
         Settings {
             workers: None,
             trading: Default::default(),
+            rate_limit: Default::default(),
+            auth: Default::default(),
+            matchengine: Default::default(),
+            cors: Default::default(),
         }
     }
 }
@@ -0,0 +1,166 @@
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use std::convert::TryFrom;
+
+use super::config;
+
+// Decide the CORS headers of a request by the `Origin` of the browser. The requests of other
+// origins are served without CORS headers, so the browser keeps their responses from the page.
+pub struct Cors {
+    config: config::CorsConfig,
+}
+
+pub type CorsHeaders = Vec<(HeaderName, HeaderValue)>;
+
+impl Cors {
+    pub fn new(config: &config::CorsConfig) -> Cors {
+        Cors {
+            config: config::CorsConfig {
+                allowed_methods: config.allowed_methods.iter().map(|method| method.to_uppercase()).collect(),
+                allowed_headers: config.allowed_headers.iter().map(|name| name.to_lowercase()).collect(),
+                ..config.clone()
+            },
+        }
+    }
+    // without any origin allowed, no CORS headers are sent as before
+    pub fn is_enabled(&self) -> bool {
+        !self.config.allowed_origins.is_empty()
+    }
+    fn is_allowed_origin(&self, origin: &str) -> bool {
+        self.config
+            .allowed_origins
+            .iter()
+            .any(|allowed| allowed == "*" || allowed == origin)
+    }
+    fn origin_headers(&self, origin: &str) -> CorsHeaders {
+        let wildcard = !self.config.allow_credentials && self.config.allowed_origins.iter().any(|allowed| allowed == "*");
+        let mut headers = Vec::new();
+        if wildcard {
+            headers.push((header::ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*")));
+        } else if let Ok(origin) = HeaderValue::from_str(origin) {
+            headers.push((header::ACCESS_CONTROL_ALLOW_ORIGIN, origin));
+            // the response depends on the origin, so caches must keep them apart
+            headers.push((header::VARY, HeaderValue::from_static("Origin")));
+        }
+        if self.config.allow_credentials {
+            headers.push((header::ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true")));
+        }
+        headers
+    }
+    // the headers added to the response of an actual request
+    pub fn response_headers(&self, origin: Option<&str>) -> CorsHeaders {
+        match origin {
+            Some(origin) if self.is_allowed_origin(origin) => self.origin_headers(origin),
+            _ => Vec::new(),
+        }
+    }
+    pub fn is_preflight(method: &str, origin: Option<&str>, request_method: Option<&str>) -> bool {
+        method == "OPTIONS" && origin.is_some() && request_method.is_some()
+    }
+    // None rejects the preflight, so the browser doesn't send the actual request
+    pub fn preflight(&self, origin: Option<&str>, request_method: Option<&str>, request_headers: Option<&str>) -> Option<CorsHeaders> {
+        let origin = origin.filter(|origin| self.is_allowed_origin(origin))?;
+        let request_method = request_method?.trim().to_uppercase();
+        if !self.config.allowed_methods.contains(&request_method) {
+            return None;
+        }
+        let request_headers: Vec<String> = request_headers
+            .unwrap_or_default()
+            .split(',')
+            .map(|name| name.trim().to_lowercase())
+            .filter(|name| !name.is_empty())
+            .collect();
+        if !request_headers.iter().all(|name| self.config.allowed_headers.contains(name)) {
+            return None;
+        }
+        let mut headers = self.origin_headers(origin);
+        headers.push((
+            header::ACCESS_CONTROL_ALLOW_METHODS,
+            HeaderValue::try_from(self.config.allowed_methods.join(", ")).ok()?,
+        ));
+        if !request_headers.is_empty() {
+            headers.push((
+                header::ACCESS_CONTROL_ALLOW_HEADERS,
+                HeaderValue::try_from(request_headers.join(", ")).ok()?,
+            ));
+        }
+        headers.push((header::ACCESS_CONTROL_MAX_AGE, HeaderValue::from(self.config.max_age.as_secs())));
+        Some(headers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_cors(allow_credentials: bool) -> Cors {
+        Cors::new(&config::CorsConfig {
+            allowed_origins: vec!["https://app.example.com".to_string()],
+            allow_credentials,
+            ..Default::default()
+        })
+    }
+
+    fn header_value<'a>(headers: &'a CorsHeaders, name: &HeaderName) -> Option<&'a str> {
+        headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.to_str().unwrap())
+    }
+
+    #[test]
+    fn test_allowed_origin() {
+        let cors = get_cors(true);
+        let headers = cors.response_headers(Some("https://app.example.com"));
+        assert_eq!(
+            header_value(&headers, &header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            Some("https://app.example.com")
+        );
+        assert_eq!(header_value(&headers, &header::ACCESS_CONTROL_ALLOW_CREDENTIALS), Some("true"));
+        assert_eq!(header_value(&headers, &header::VARY), Some("Origin"));
+
+        // a wildcard is only sent without credentials
+        let cors = Cors::new(&config::CorsConfig {
+            allowed_origins: vec!["*".to_string()],
+            ..Default::default()
+        });
+        let headers = cors.response_headers(Some("https://any.example.com"));
+        assert_eq!(header_value(&headers, &header::ACCESS_CONTROL_ALLOW_ORIGIN), Some("*"));
+    }
+
+    #[test]
+    fn test_disallowed_origin() {
+        let cors = get_cors(true);
+        assert!(cors.response_headers(Some("https://evil.example.com")).is_empty());
+        assert!(cors.response_headers(None).is_empty());
+        assert!(cors.preflight(Some("https://evil.example.com"), Some("GET"), None).is_none());
+        // nothing is allowed by default
+        assert!(!Cors::new(&Default::default()).is_enabled());
+    }
+
+    #[test]
+    fn test_preflight() {
+        let cors = get_cors(false);
+        assert!(Cors::is_preflight("OPTIONS", Some("https://app.example.com"), Some("POST")));
+        assert!(!Cors::is_preflight("OPTIONS", Some("https://app.example.com"), None));
+
+        let headers = cors
+            .preflight(Some("https://app.example.com"), Some("post"), Some("Content-Type, X-API-KEY"))
+            .unwrap();
+        assert_eq!(
+            header_value(&headers, &header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            Some("https://app.example.com")
+        );
+        assert_eq!(header_value(&headers, &header::ACCESS_CONTROL_ALLOW_METHODS), Some("GET, POST"));
+        assert_eq!(
+            header_value(&headers, &header::ACCESS_CONTROL_ALLOW_HEADERS),
+            Some("content-type, x-api-key")
+        );
+        assert_eq!(header_value(&headers, &header::ACCESS_CONTROL_MAX_AGE), Some("3600"));
+        assert_eq!(header_value(&headers, &header::ACCESS_CONTROL_ALLOW_CREDENTIALS), None);
+
+        assert!(cors.preflight(Some("https://app.example.com"), Some("DELETE"), None).is_none());
+        assert!(cors
+            .preflight(Some("https://app.example.com"), Some("GET"), Some("x-other"))
+            .is_none());
+    }
+}
@@ -5,6 +5,8 @@ use thiserror::Error;
 use actix_web::error::{QueryPayloadError, ResponseError};
 use actix_web::{http::StatusCode, HttpResponse};
 
+use crate::auth::AuthError;
+
 // It is better to use strong typed error for APIs.
 // Use thiserror rather than anyhow if you are a library or service that wants to design your own dedicated error type(s)
 // so that on failures the caller gets exactly the information that you choose.
@@ -17,6 +19,8 @@ pub enum ErrorType {
     Forbidden,
     #[error("Invalid request")]
     BadRequest,
+    #[error("Too many requests")]
+    TooManyRequests,
     #[error("Unknown Internal Error")]
     Unknown,
 }
@@ -61,6 +65,26 @@ impl From<QueryPayloadError> for RpcError {
     }
 }
 
+impl From<AuthError> for RpcError {
+    fn from(original: AuthError) -> RpcError {
+        RpcError::new(ErrorType::Forbidden, original.to_string())
+    }
+}
+
+// rejections of the auth middleware, unlike the api errors they use the http status
+impl ResponseError for AuthError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AuthError::MissingKey | AuthError::InvalidKey => StatusCode::UNAUTHORIZED,
+            AuthError::PermissionDenied => StatusCode::FORBIDDEN,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(RpcError::from(self.clone()))
+    }
+}
+
 impl From<sqlx::Error> for RpcError {
     fn from(original: sqlx::Error) -> RpcError {
         RpcError::unknown(&original.to_string())
@@ -0,0 +1,80 @@
+use actix_web::http::header;
+use actix_web::{HttpRequest, HttpResponse};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+// Conditional GET for the polled read-only apis. The ETag is a hash of the json body, so a client
+// sending it back in `If-None-Match` gets an empty 304 while the resource is unchanged.
+
+pub fn body_etag(body: &[u8]) -> String {
+    format!("\"{}\"", hex::encode(&Sha256::digest(body)[..16]))
+}
+
+// `If-None-Match` is a list of tags or "*", and is compared weakly
+pub fn is_not_modified(if_none_match: Option<&str>, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    if_none_match.map_or(false, |tags| {
+        tags.split(',')
+            .map(|tag| tag.trim())
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+    })
+}
+
+pub fn json_with_etag<T: Serialize>(req: &HttpRequest, value: &T) -> HttpResponse {
+    let body = match serde_json::to_vec(value) {
+        Ok(body) => body,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    let etag = body_etag(&body);
+    let if_none_match = req.headers().get(header::IF_NONE_MATCH).and_then(|value| value.to_str().ok());
+    if is_not_modified(if_none_match, &etag) {
+        return HttpResponse::NotModified().header(header::ETAG, etag).finish();
+    }
+    HttpResponse::Ok()
+        .header(header::ETAG, etag)
+        .content_type("application/json")
+        .body(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+    use serde_json::json;
+
+    fn response_etag(response: &HttpResponse) -> String {
+        response.headers().get(header::ETAG).unwrap().to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_conditional_get() {
+        let ticker = json!({"market": "ETH_USDT", "last": 1800.5});
+        let response = json_with_etag(&TestRequest::default().to_http_request(), &ticker);
+        assert_eq!(response.status(), StatusCode::OK);
+        let etag = response_etag(&response);
+
+        // unchanged
+        let req = TestRequest::default()
+            .header(header::IF_NONE_MATCH, etag.as_str())
+            .to_http_request();
+        let response = json_with_etag(&req, &ticker);
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response_etag(&response), etag);
+
+        // changed
+        let ticker = json!({"market": "ETH_USDT", "last": 1801});
+        let response = json_with_etag(&req, &ticker);
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_ne!(response_etag(&response), etag);
+    }
+
+    #[test]
+    fn test_if_none_match() {
+        let etag = body_etag(b"[]");
+        assert!(is_not_modified(Some(&format!("\"other\", W/{}", etag)), &etag));
+        assert!(is_not_modified(Some("*"), &etag));
+        assert!(!is_not_modified(Some("\"other\""), &etag));
+        assert!(!is_not_modified(None, &etag));
+    }
+}
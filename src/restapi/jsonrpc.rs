@@ -0,0 +1,310 @@
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use futures::future::{join_all, LocalBoxFuture};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tonic::metadata::{Ascii, MetadataKey, MetadataValue};
+use tonic::transport::Channel;
+
+use super::config::MatchengineConfig;
+use crate::auth::UserScope;
+use crate::config::Permission;
+use crate::dto::matchengine_client::MatchengineClient;
+use crate::dto::*;
+
+// JSON-RPC 2.0 over POST, for batching the read-only queries of the matchengine in one round trip.
+// The params of a call are the fields of its grpc request, and the result is the grpc response.
+// Each call of a batch succeeds or fails on its own, and a call without an id is a notification
+// which gets no response entry.
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+// the matchengine failed the call, `data` has the grpc code
+pub const SERVER_ERROR: i64 = -32000;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct JsonRpcError {
+    pub code: i64,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+impl JsonRpcError {
+    pub fn new(code: i64, message: impl ToString) -> JsonRpcError {
+        JsonRpcError {
+            code,
+            message: message.to_string(),
+            data: None,
+        }
+    }
+}
+
+impl From<tonic::Status> for JsonRpcError {
+    fn from(status: tonic::Status) -> JsonRpcError {
+        let code = match status.code() {
+            tonic::Code::InvalidArgument => INVALID_PARAMS,
+            _ => SERVER_ERROR,
+        };
+        JsonRpcError {
+            code,
+            message: status.message().to_string(),
+            data: Some(Value::from(format!("{:?}", status.code()))),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct JsonRpcRequest {
+    jsonrpc: String,
+    method: String,
+    #[serde(default)]
+    params: Value,
+    // absent or null for a notification
+    #[serde(default)]
+    id: Option<Value>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct JsonRpcResponse {
+    jsonrpc: &'static str,
+    id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<JsonRpcError>,
+}
+
+impl JsonRpcResponse {
+    fn new(id: Value, result: Result<Value, JsonRpcError>) -> JsonRpcResponse {
+        let (result, error) = match result {
+            Ok(result) => (Some(result), None),
+            Err(error) => (None, Some(error)),
+        };
+        JsonRpcResponse {
+            jsonrpc: "2.0",
+            id,
+            result,
+            error,
+        }
+    }
+}
+
+pub trait RpcBackend {
+    fn call<'a>(&'a self, method: &'a str, params: Value) -> LocalBoxFuture<'a, Result<Value, JsonRpcError>>;
+}
+
+// the params are laid over the default request, so the fields can be left out as in protobuf
+fn parse_params<T: Default + Serialize + DeserializeOwned>(params: Value) -> Result<T, JsonRpcError> {
+    let mut request = serde_json::to_value(T::default()).unwrap();
+    match params {
+        Value::Object(fields) => request.as_object_mut().unwrap().extend(fields),
+        Value::Null => {}
+        _ => return Err(JsonRpcError::new(INVALID_PARAMS, "params must be an object")),
+    }
+    serde_json::from_value(request).map_err(|e| JsonRpcError::new(INVALID_PARAMS, e))
+}
+
+#[derive(Clone)]
+pub struct MatchengineBackend {
+    client: MatchengineClient<Channel>,
+    api_key: Option<(MetadataKey<Ascii>, MetadataValue<Ascii>)>,
+}
+
+impl MatchengineBackend {
+    // connected on the first call
+    pub fn new(config: &MatchengineConfig) -> anyhow::Result<MatchengineBackend> {
+        let channel = tonic::transport::Endpoint::from_shared(config.url.clone())?.connect_lazy()?;
+        let api_key = if config.api_key.is_empty() {
+            None
+        } else {
+            Some((
+                MetadataKey::from_bytes(config.api_key_header.to_lowercase().as_bytes())?,
+                MetadataValue::from_str(&config.api_key)?,
+            ))
+        };
+        Ok(MatchengineBackend {
+            client: MatchengineClient::new(channel),
+            api_key,
+        })
+    }
+
+    fn request<T>(&self, message: T) -> tonic::Request<T> {
+        let mut request = tonic::Request::new(message);
+        if let Some((key, value)) = &self.api_key {
+            request.metadata_mut().insert(key.clone(), value.clone());
+        }
+        request
+    }
+}
+
+macro_rules! dispatch {
+    ($backend:expr, $method:expr, $params:expr, $($name:literal => $call:ident($request:ty),)*) => {
+        match $method {
+            $($name => {
+                let request = $backend.request(parse_params::<$request>($params)?);
+                let response = $backend.client.clone().$call(request).await?;
+                Ok(serde_json::to_value(response.into_inner()).unwrap())
+            })*
+            _ => Err(JsonRpcError::new(METHOD_NOT_FOUND, format!("method not found: {}", $method))),
+        }
+    };
+}
+
+impl RpcBackend for MatchengineBackend {
+    fn call<'a>(&'a self, method: &'a str, params: Value) -> LocalBoxFuture<'a, Result<Value, JsonRpcError>> {
+        Box::pin(async move {
+            dispatch!(self, method, params,
+                "asset_list" => asset_list(AssetListRequest),
+                "balance_query" => balance_query(BalanceQueryRequest),
+                "fee_tier_query" => fee_tier_query(FeeTierQueryRequest),
+                "get_klines" => get_klines(GetKlinesRequest),
+                "market_list" => market_list(MarketListRequest),
+                "market_summary" => market_summary(MarketSummaryRequest),
+                "order_book_depth" => order_book_depth(OrderBookDepthRequest),
+                "order_detail" => order_detail(OrderDetailRequest),
+                "order_query" => order_query(OrderQueryRequest),
+            )
+        })
+    }
+}
+
+// a key bound to a user only sees the calls and the results of that user
+fn check_user(scope: Option<UserScope>, value: &Value) -> Result<(), JsonRpcError> {
+    match (scope, value.get("user_id").and_then(Value::as_u64)) {
+        (Some(scope), Some(user_id)) => scope
+            .authorize(Permission::ReadOnly, Some(user_id as u32))
+            .map_err(|e| JsonRpcError::new(SERVER_ERROR, e)),
+        _ => Ok(()),
+    }
+}
+
+async fn call_one(backend: &dyn RpcBackend, scope: Option<UserScope>, call: Value) -> Option<JsonRpcResponse> {
+    let request: JsonRpcRequest = match serde_json::from_value(call) {
+        Ok(request) => request,
+        Err(e) => return Some(JsonRpcResponse::new(Value::Null, Err(JsonRpcError::new(INVALID_REQUEST, e)))),
+    };
+    let result = if request.jsonrpc != "2.0" {
+        Err(JsonRpcError::new(INVALID_REQUEST, "jsonrpc must be \"2.0\""))
+    } else {
+        match check_user(scope, &request.params) {
+            Ok(()) => match backend.call(&request.method, request.params).await {
+                Ok(result) => check_user(scope, &result).map(|_| result),
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        }
+    };
+    request.id.map(|id| JsonRpcResponse::new(id, result))
+}
+
+// None if there is nothing to respond, for notifications only
+pub async fn handle_body(backend: &dyn RpcBackend, scope: Option<UserScope>, body: &[u8]) -> Option<Value> {
+    let body: Value = match serde_json::from_slice(body) {
+        Ok(body) => body,
+        Err(e) => {
+            return Some(json_value(JsonRpcResponse::new(
+                Value::Null,
+                Err(JsonRpcError::new(PARSE_ERROR, e)),
+            )))
+        }
+    };
+    match body {
+        Value::Array(calls) if calls.is_empty() => Some(json_value(JsonRpcResponse::new(
+            Value::Null,
+            Err(JsonRpcError::new(INVALID_REQUEST, "empty batch")),
+        ))),
+        Value::Array(calls) => {
+            let responses: Vec<JsonRpcResponse> = join_all(calls.into_iter().map(|call| call_one(backend, scope, call)))
+                .await
+                .into_iter()
+                .flatten()
+                .collect();
+            if responses.is_empty() {
+                None
+            } else {
+                Some(json_value(responses))
+            }
+        }
+        call => call_one(backend, scope, call).await.map(json_value),
+    }
+}
+
+fn json_value<T: Serialize>(value: T) -> Value {
+    serde_json::to_value(value).unwrap()
+}
+
+pub async fn jsonrpc(req: HttpRequest, body: web::Bytes, backend: web::Data<MatchengineBackend>) -> HttpResponse {
+    let scope = HttpMessage::extensions(&req).get::<UserScope>().copied();
+    match handle_body(backend.get_ref(), scope, &body).await {
+        Some(response) => HttpResponse::Ok().json(response),
+        None => HttpResponse::NoContent().finish(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    struct TestBackend;
+
+    impl RpcBackend for TestBackend {
+        fn call<'a>(&'a self, method: &'a str, params: Value) -> LocalBoxFuture<'a, Result<Value, JsonRpcError>> {
+            Box::pin(async move {
+                match method {
+                    "balance_query" => {
+                        let request: BalanceQueryRequest = parse_params(params)?;
+                        Ok(json!({"user_id": request.user_id, "balances": []}))
+                    }
+                    "order_detail" => Err(tonic::Status::not_found("order not found").into()),
+                    "market_list" => Ok(json!({"markets": []})),
+                    _ => Err(JsonRpcError::new(METHOD_NOT_FOUND, method)),
+                }
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mixed_batch() {
+        let body = json!([
+            {"jsonrpc": "2.0", "method": "balance_query", "params": {"user_id": 101}, "id": 1},
+            {"jsonrpc": "2.0", "method": "order_detail", "params": {"market": "ETH_USDT", "order_id": 7}, "id": "b"},
+            {"jsonrpc": "2.0", "method": "market_list"},
+            {"jsonrpc": "2.0", "method": "balance_query", "params": {"user_id": "x"}, "id": 3},
+            {"jsonrpc": "2.0", "method": "market_list", "id": 4},
+        ]);
+        let response = handle_body(&TestBackend, None, body.to_string().as_bytes()).await.unwrap();
+        // the notification has no entry, the others keep their order and ids
+        assert_eq!(
+            response,
+            json!([
+                {"jsonrpc": "2.0", "id": 1, "result": {"user_id": 101, "balances": []}},
+                {"jsonrpc": "2.0", "id": "b", "error": {"code": SERVER_ERROR, "message": "order not found", "data": "NotFound"}},
+                {"jsonrpc": "2.0", "id": 3, "error": response[2]["error"].clone()},
+                {"jsonrpc": "2.0", "id": 4, "result": {"markets": []}},
+            ])
+        );
+        assert_eq!(response[2]["error"]["code"], INVALID_PARAMS);
+
+        let notifications = json!([{"jsonrpc": "2.0", "method": "market_list"}]);
+        assert!(handle_body(&TestBackend, None, notifications.to_string().as_bytes())
+            .await
+            .is_none());
+        let response = handle_body(&TestBackend, None, b"[{").await.unwrap();
+        assert_eq!(response["error"]["code"], PARSE_ERROR);
+    }
+
+    #[tokio::test]
+    async fn test_user_bound_scope() {
+        let scope = Some(UserScope {
+            permission: Permission::ReadOnly,
+            user_id: Some(102),
+        });
+        let body = json!({"jsonrpc": "2.0", "method": "balance_query", "params": {"user_id": 101}, "id": 1});
+        let response = handle_body(&TestBackend, scope, body.to_string().as_bytes()).await.unwrap();
+        assert_eq!(response["error"]["message"], "permission denied");
+    }
+}
@@ -1,8 +1,13 @@
 pub mod config;
+pub mod cors;
 pub mod errors;
+pub mod etag;
+pub mod jsonrpc;
 pub mod mock;
 pub mod personal_history;
+pub mod precision;
 pub mod public_history;
+pub mod ratelimit;
 pub mod state;
 pub mod tradingview;
 pub mod types;
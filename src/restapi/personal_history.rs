@@ -1,13 +1,32 @@
-use actix_web::{web, HttpRequest};
+use actix_web::{web, HttpMessage, HttpRequest};
 
 use actix_web::web::Json;
+use chrono::NaiveDateTime;
 use core::cmp::min;
 use serde::Serialize;
+use std::fmt;
+use std::str::FromStr;
 
-use crate::models::{tablenames::ORDERHISTORY, OrderHistory};
+use crate::auth::UserScope;
+use crate::config::Permission;
+use crate::models::{
+    tablenames::{BALANCEHISTORY, ORDERHISTORY},
+    DecimalDbType, OrderHistory, TimestampDbType,
+};
+use crate::types::BusinessKind;
+use rust_decimal::prelude::Zero;
 
+use super::precision::round_all;
 use super::{errors::RpcError, state::AppState};
 
+// a key bound to a user can only read the history of that user
+fn check_user_scope(req: &HttpRequest, user_id: i32) -> Result<(), RpcError> {
+    match HttpMessage::extensions(req).get::<UserScope>() {
+        Some(scope) => Ok(scope.authorize(Permission::ReadOnly, Some(user_id as u32))?),
+        None => Ok(()),
+    }
+}
+
 #[derive(Serialize)]
 pub struct OrderResponse {
     total: i64,
@@ -23,6 +42,7 @@ pub async fn my_orders(req: HttpRequest, data: web::Data<AppState>) -> Result<Js
         }
         _ => user_id.unwrap(),
     };
+    check_user_scope(&req, user_id)?;
     let qstring = qstring::QString::from(req.query_string());
     let limit = min(100, qstring.get("limit").unwrap_or_default().parse::<usize>().unwrap_or(20));
     let offset = qstring.get("offset").unwrap_or_default().parse::<usize>().unwrap_or(0);
@@ -33,7 +53,8 @@ pub async fn my_orders(req: HttpRequest, data: web::Data<AppState>) -> Result<Js
         "select * from {} where {} order by id desc limit {} offset {}",
         table, condition, limit, offset
     );
-    let orders: Vec<OrderHistory> = sqlx::query_as(&order_query).bind(market).bind(user_id).fetch_all(&data.db).await?;
+    let mut orders: Vec<OrderHistory> = sqlx::query_as(&order_query).bind(market).bind(user_id).fetch_all(&data.db).await?;
+    round_all(&req, &data.precision, &mut orders);
     let count_query = format!("select count(*) from {} where {}", table, condition);
     let total: i64 = sqlx::query_scalar(&count_query)
         .bind(market)
@@ -42,3 +63,480 @@ pub async fn my_orders(req: HttpRequest, data: web::Data<AppState>) -> Result<Js
         .await?;
     Ok(Json(OrderResponse { total, orders }))
 }
+
+#[derive(sqlx::FromRow, Debug, Clone, Serialize)]
+pub struct BalanceHistoryItem {
+    pub id: i32,
+    pub time: TimestampDbType,
+    pub asset: String,
+    pub business: BusinessKind,
+    pub change: DecimalDbType,
+    pub balance: DecimalDbType,
+    pub detail: String,
+}
+
+#[derive(Serialize)]
+pub struct BalanceHistoryResponse {
+    records: Vec<BalanceHistoryItem>,
+    // pass it as `cursor` to get the next page, none on the last page
+    next_cursor: Option<String>,
+}
+
+// A history is listed by (time, id) descending, and a page starts right after the cursor.
+// Rows inserted later are newer than the cursor, so they don't shift the following pages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct HistoryCursor {
+    // unix timestamp in seconds, the precision of the time columns
+    pub time: i64,
+    pub id: i64,
+}
+
+impl HistoryCursor {
+    pub fn new(time: &TimestampDbType, id: i64) -> HistoryCursor {
+        HistoryCursor {
+            time: time.timestamp(),
+            id,
+        }
+    }
+    fn time(&self) -> TimestampDbType {
+        NaiveDateTime::from_timestamp(self.time, 0)
+    }
+}
+
+// `rows` are queried with one more row than `limit`, which tells there is a next page
+fn next_page_cursor<T>(rows: &mut Vec<T>, limit: usize, cursor_of: impl Fn(&T) -> HistoryCursor) -> Option<String> {
+    if rows.len() > limit {
+        rows.truncate(limit);
+        rows.last().map(|row| cursor_of(row).to_string())
+    } else {
+        None
+    }
+}
+
+impl fmt::Display for HistoryCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}_{}", self.time, self.id)
+    }
+}
+
+impl FromStr for HistoryCursor {
+    type Err = RpcError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || RpcError::bad_request("invalid cursor");
+        let mut parts = s.splitn(2, '_');
+        let time = parts.next().and_then(|time| time.parse().ok()).ok_or_else(invalid)?;
+        let id = parts.next().and_then(|id| id.parse().ok()).ok_or_else(invalid)?;
+        Ok(HistoryCursor { time, id })
+    }
+}
+
+#[cfg(sqlxverf)]
+fn sqlverf_balance_history() {
+    let time = NaiveDateTime::from_timestamp(0, 0);
+    sqlx::query_as!(
+        BalanceHistoryItem,
+        "select id, time, asset, business, change, balance, detail from balance_history
+        where user_id = $1 and asset = $2 and business = $3 and (time, id) < ($4, $5)
+        order by time desc, id desc limit 21",
+        1,
+        "ETH",
+        "trade",
+        time,
+        10000,
+    );
+}
+
+// the filters are bound in the order of user_id, asset, business and cursor
+fn balance_history_query(asset: bool, business: bool, cursor: bool, limit: usize) -> String {
+    let mut conditions = vec!["user_id = $1".to_string()];
+    if asset {
+        conditions.push(format!("asset = ${}", conditions.len() + 1));
+    }
+    if business {
+        conditions.push(format!("business = ${}", conditions.len() + 1));
+    }
+    if cursor {
+        let n = conditions.len();
+        conditions.push(format!("(time, id) < (${}, ${})", n + 1, n + 2));
+    }
+    format!(
+        "select id, time, asset, business, change, balance, detail from {} where {} order by time desc, id desc limit {}",
+        BALANCEHISTORY,
+        conditions.join(" and "),
+        limit
+    )
+}
+
+pub async fn balance_history(req: HttpRequest, data: web::Data<AppState>) -> Result<Json<BalanceHistoryResponse>, RpcError> {
+    let user_id = req.match_info().get("user_id").unwrap_or_default().parse::<i32>();
+    let user_id = match user_id {
+        Err(_) => {
+            return Err(RpcError::bad_request("invalid user_id"));
+        }
+        _ => user_id.unwrap(),
+    };
+    check_user_scope(&req, user_id)?;
+    let qstring = qstring::QString::from(req.query_string());
+    let limit = min(100, qstring.get("limit").unwrap_or_default().parse::<usize>().unwrap_or(20)).max(1);
+    let asset = qstring.get("asset").filter(|asset| !asset.is_empty());
+    let business = qstring.get("business").filter(|business| !business.is_empty());
+    let cursor = match qstring.get("cursor").filter(|cursor| !cursor.is_empty()) {
+        Some(cursor) => Some(cursor.parse::<HistoryCursor>()?),
+        None => None,
+    };
+
+    // one more row to know whether there is a next page
+    let history_query = balance_history_query(asset.is_some(), business.is_some(), cursor.is_some(), limit + 1);
+    let mut query = sqlx::query_as::<_, BalanceHistoryItem>(&history_query).bind(user_id);
+    if let Some(asset) = asset {
+        query = query.bind(asset);
+    }
+    if let Some(business) = business {
+        query = query.bind(business);
+    }
+    if let Some(cursor) = cursor {
+        query = query.bind(cursor.time()).bind(cursor.id);
+    }
+    let mut records = query.fetch_all(&data.db).await?;
+    let next_cursor = next_page_cursor(&mut records, limit, |item| HistoryCursor::new(&item.time, item.id as i64));
+    round_all(&req, &data.precision, &mut records);
+    Ok(Json(BalanceHistoryResponse { records, next_cursor }))
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OrderStatus {
+    Open,
+    Filled,
+    Cancelled,
+}
+
+impl FromStr for OrderStatus {
+    type Err = RpcError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "open" => Ok(OrderStatus::Open),
+            "filled" => Ok(OrderStatus::Filled),
+            "cancelled" => Ok(OrderStatus::Cancelled),
+            _ => Err(RpcError::bad_request("invalid status")),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct OrderHistoryItem {
+    #[serde(flatten)]
+    pub order: OrderHistory,
+    pub status: OrderStatus,
+    pub executed_amount: DecimalDbType,
+    // none if nothing is executed
+    pub average_price: Option<DecimalDbType>,
+}
+
+// only finished orders are written to the history, a finished order not fully executed is cancelled
+impl From<OrderHistory> for OrderHistoryItem {
+    fn from(order: OrderHistory) -> OrderHistoryItem {
+        let status = if order.finished_base >= order.amount {
+            OrderStatus::Filled
+        } else {
+            OrderStatus::Cancelled
+        };
+        let average_price = if order.finished_base.is_zero() {
+            None
+        } else {
+            Some(order.finished_quote / order.finished_base)
+        };
+        OrderHistoryItem {
+            status,
+            executed_amount: order.finished_base,
+            average_price,
+            order,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct OrderHistoryResponse {
+    orders: Vec<OrderHistoryItem>,
+    // pass it as `cursor` to get the next page, none on the last page
+    next_cursor: Option<String>,
+}
+
+// all the fields are optional, and they are bound after user_id in this order
+#[derive(Debug, Default)]
+struct OrderHistoryFilter<'a> {
+    market: Option<&'a str>,
+    status: Option<OrderStatus>,
+    // finish time range in unix seconds, [start_time, end_time)
+    start_time: Option<i64>,
+    end_time: Option<i64>,
+    cursor: Option<HistoryCursor>,
+}
+
+impl<'a> OrderHistoryFilter<'a> {
+    fn from_query(qstring: &'a qstring::QString) -> Result<OrderHistoryFilter<'a>, RpcError> {
+        let param = |name: &str| qstring.get(name).filter(|value: &&str| !value.is_empty());
+        let parse_time = |name| match param(name) {
+            Some(time) => time
+                .parse::<i64>()
+                .map(Some)
+                .map_err(|_| RpcError::bad_request("invalid time range")),
+            None => Ok(None),
+        };
+        let status = match param("status") {
+            Some(status) => Some(status.parse::<OrderStatus>()?),
+            None => None,
+        };
+        if status == Some(OrderStatus::Open) {
+            return Err(RpcError::bad_request(
+                "the order history has finished orders only, query open orders from the matchengine",
+            ));
+        }
+        let cursor = match param("cursor") {
+            Some(cursor) => Some(cursor.parse::<HistoryCursor>()?),
+            None => None,
+        };
+        Ok(OrderHistoryFilter {
+            market: param("market"),
+            status,
+            start_time: parse_time("start_time")?,
+            end_time: parse_time("end_time")?,
+            cursor,
+        })
+    }
+}
+
+#[cfg(sqlxverf)]
+fn sqlverf_order_history() {
+    let time = NaiveDateTime::from_timestamp(0, 0);
+    sqlx::query_as!(
+        OrderHistory,
+        "select * from order_history
+        where user_id = $1 and market = $2 and finished_base < amount and finish_time >= $3 and finish_time < $4
+        and (finish_time, id) < ($5, $6)
+        order by finish_time desc, id desc limit 21",
+        1,
+        "ETH_USDT",
+        time,
+        time,
+        time,
+        10000,
+    );
+}
+
+fn order_history_query(filter: &OrderHistoryFilter, limit: usize) -> String {
+    let mut conditions = vec!["user_id = $1".to_string()];
+    if filter.market.is_some() {
+        conditions.push(format!("market = ${}", conditions.len() + 1));
+    }
+    // the status takes no parameter, so it is pushed after the numbered conditions
+    let mut n = conditions.len();
+    if filter.start_time.is_some() {
+        n += 1;
+        conditions.push(format!("finish_time >= ${}", n));
+    }
+    if filter.end_time.is_some() {
+        n += 1;
+        conditions.push(format!("finish_time < ${}", n));
+    }
+    if filter.cursor.is_some() {
+        conditions.push(format!("(finish_time, id) < (${}, ${})", n + 1, n + 2));
+    }
+    match filter.status {
+        Some(OrderStatus::Filled) => conditions.push("finished_base >= amount".to_string()),
+        Some(OrderStatus::Cancelled) => conditions.push("finished_base < amount".to_string()),
+        Some(OrderStatus::Open) | None => {}
+    }
+    format!(
+        "select * from {} where {} order by finish_time desc, id desc limit {}",
+        ORDERHISTORY,
+        conditions.join(" and "),
+        limit
+    )
+}
+
+pub async fn orders(req: HttpRequest, data: web::Data<AppState>) -> Result<Json<OrderHistoryResponse>, RpcError> {
+    let user_id = req.match_info().get("user_id").unwrap_or_default().parse::<i32>();
+    let user_id = match user_id {
+        Err(_) => {
+            return Err(RpcError::bad_request("invalid user_id"));
+        }
+        _ => user_id.unwrap(),
+    };
+    check_user_scope(&req, user_id)?;
+    let qstring = qstring::QString::from(req.query_string());
+    let limit = min(100, qstring.get("limit").unwrap_or_default().parse::<usize>().unwrap_or(20)).max(1);
+    let filter = OrderHistoryFilter::from_query(&qstring)?;
+
+    // one more row to know whether there is a next page
+    let order_query = order_history_query(&filter, limit + 1);
+    let mut query = sqlx::query_as::<_, OrderHistory>(&order_query).bind(user_id);
+    if let Some(market) = filter.market {
+        query = query.bind(market);
+    }
+    if let Some(start_time) = filter.start_time {
+        query = query.bind(NaiveDateTime::from_timestamp(start_time, 0));
+    }
+    if let Some(end_time) = filter.end_time {
+        query = query.bind(NaiveDateTime::from_timestamp(end_time, 0));
+    }
+    if let Some(cursor) = filter.cursor {
+        query = query.bind(cursor.time()).bind(cursor.id);
+    }
+    let mut rows = query.fetch_all(&data.db).await?;
+    let next_cursor = next_page_cursor(&mut rows, limit, |order| HistoryCursor::new(&order.finish_time, order.id));
+    let mut orders: Vec<OrderHistoryItem> = rows.into_iter().map(OrderHistoryItem::from).collect();
+    round_all(&req, &data.precision, &mut orders);
+    Ok(Json(OrderHistoryResponse { orders, next_cursor }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{OrderSide, OrderType};
+    use rust_decimal_macros::*;
+
+    #[test]
+    fn utest_balance_history_query() {
+        assert_eq!(
+            balance_history_query(false, false, false, 21),
+            "select id, time, asset, business, change, balance, detail from balance_history where user_id = $1 order by time desc, id desc limit 21"
+        );
+        assert_eq!(
+            balance_history_query(false, true, true, 21),
+            "select id, time, asset, business, change, balance, detail from balance_history \
+            where user_id = $1 and business = $2 and (time, id) < ($3, $4) order by time desc, id desc limit 21"
+        );
+    }
+
+    #[test]
+    fn test_balance_history_cursor() {
+        let cursor = HistoryCursor { time: 1614556800, id: 42 };
+        assert_eq!(cursor.to_string(), "1614556800_42");
+        assert_eq!("1614556800_42".parse::<HistoryCursor>().unwrap(), cursor);
+        assert!("1614556800".parse::<HistoryCursor>().is_err());
+        assert!("abc_42".parse::<HistoryCursor>().is_err());
+    }
+
+    fn new_item(id: i32, time: i64) -> BalanceHistoryItem {
+        BalanceHistoryItem {
+            id,
+            time: NaiveDateTime::from_timestamp(time, 0),
+            asset: "ETH".to_string(),
+            business: BusinessKind::Trade,
+            change: dec!(1),
+            balance: dec!(1),
+            detail: String::new(),
+        }
+    }
+
+    // what the query does: rows before the cursor, newest first
+    fn page(table: &[BalanceHistoryItem], cursor: Option<HistoryCursor>, limit: usize) -> Vec<BalanceHistoryItem> {
+        let mut rows: Vec<BalanceHistoryItem> = table
+            .iter()
+            .filter(|item| cursor.map_or(true, |cursor| HistoryCursor::new(&item.time, item.id as i64) < cursor))
+            .cloned()
+            .collect();
+        rows.sort_by_key(|item| std::cmp::Reverse(HistoryCursor::new(&item.time, item.id as i64)));
+        rows.truncate(limit);
+        rows
+    }
+
+    #[test]
+    fn test_pagination_with_concurrent_inserts() {
+        // several rows share a second, the id breaks the tie
+        let mut table: Vec<BalanceHistoryItem> = (1..=10).map(|id| new_item(id, 1000 + (id as i64) / 3)).collect();
+        let mut seen = Vec::new();
+        let mut cursor = None;
+        let mut next_id = 11;
+        loop {
+            let rows = page(&table, cursor, 3);
+            if rows.is_empty() {
+                break;
+            }
+            seen.extend(rows.iter().map(|item| item.id));
+            cursor = rows.last().map(|item| HistoryCursor::new(&item.time, item.id as i64));
+            // new rows arrive between the requests, some in the same second as the first page
+            table.push(new_item(next_id, 1003));
+            next_id += 1;
+        }
+        assert_eq!(seen, vec![10, 9, 8, 7, 6, 5, 4, 3, 2, 1]);
+    }
+
+    fn new_order(amount: DecimalDbType, finished_base: DecimalDbType, finished_quote: DecimalDbType) -> OrderHistory {
+        OrderHistory {
+            id: 1,
+            create_time: NaiveDateTime::from_timestamp(1000, 0),
+            finish_time: NaiveDateTime::from_timestamp(1010, 0),
+            user_id: 101,
+            market: "ETH_USDT".to_string(),
+            order_type: OrderType::LIMIT,
+            order_side: OrderSide::BID,
+            price: dec!(100),
+            amount,
+            taker_fee: dec!(0.001),
+            maker_fee: dec!(0.001),
+            finished_base,
+            finished_quote,
+            finished_fee: dec!(0),
+            finished_paid_fee: dec!(0),
+        }
+    }
+
+    #[test]
+    fn test_order_history_item() {
+        let filled = OrderHistoryItem::from(new_order(dec!(2), dec!(2), dec!(190)));
+        assert_eq!(filled.status, OrderStatus::Filled);
+        assert_eq!((filled.executed_amount, filled.average_price), (dec!(2), Some(dec!(95))));
+
+        let partially_cancelled = OrderHistoryItem::from(new_order(dec!(2), dec!(1), dec!(99)));
+        assert_eq!(partially_cancelled.status, OrderStatus::Cancelled);
+        assert_eq!(partially_cancelled.average_price, Some(dec!(99)));
+
+        let cancelled = OrderHistoryItem::from(new_order(dec!(2), dec!(0), dec!(0)));
+        assert_eq!(cancelled.status, OrderStatus::Cancelled);
+        assert_eq!((cancelled.executed_amount, cancelled.average_price), (dec!(0), None));
+    }
+
+    #[test]
+    fn utest_order_history_query() {
+        let qstring = qstring::QString::from("status=filled");
+        let filter = OrderHistoryFilter::from_query(&qstring).unwrap();
+        assert_eq!(
+            order_history_query(&filter, 21),
+            "select * from order_history where user_id = $1 and finished_base >= amount order by finish_time desc, id desc limit 21"
+        );
+
+        let qstring = qstring::QString::from("status=cancelled&market=ETH_USDT&start_time=1000&end_time=2000&cursor=1500_7");
+        let filter = OrderHistoryFilter::from_query(&qstring).unwrap();
+        assert_eq!(filter.cursor, Some(HistoryCursor { time: 1500, id: 7 }));
+        assert_eq!(
+            order_history_query(&filter, 21),
+            "select * from order_history where user_id = $1 and market = $2 and finish_time >= $3 and finish_time < $4 \
+            and (finish_time, id) < ($5, $6) and finished_base < amount order by finish_time desc, id desc limit 21"
+        );
+
+        // no status means both
+        let qstring = qstring::QString::from("status=&market=ETH_USDT");
+        let filter = OrderHistoryFilter::from_query(&qstring).unwrap();
+        assert_eq!(
+            order_history_query(&filter, 21),
+            "select * from order_history where user_id = $1 and market = $2 order by finish_time desc, id desc limit 21"
+        );
+
+        for query in &["status=open", "status=closed", "start_time=abc"] {
+            assert!(OrderHistoryFilter::from_query(&qstring::QString::from(*query)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_empty_order_history() {
+        let mut rows: Vec<OrderHistory> = Vec::new();
+        let next_cursor = next_page_cursor(&mut rows, 20, |order| HistoryCursor::new(&order.finish_time, order.id));
+        let response = OrderHistoryResponse {
+            orders: rows.into_iter().map(OrderHistoryItem::from).collect(),
+            next_cursor,
+        };
+        assert_eq!(serde_json::to_string(&response).unwrap(), r#"{"orders":[],"next_cursor":null}"#);
+    }
+}
@@ -0,0 +1,171 @@
+use actix_web::HttpRequest;
+use anyhow::Result;
+use rust_decimal::Decimal;
+use std::collections::HashMap;
+
+use super::personal_history::{BalanceHistoryItem, OrderHistoryItem};
+use crate::asset::AssetManager;
+use crate::config;
+use crate::models::{OrderHistory, TradeRecord};
+use crate::types::OrderSide;
+
+// The amounts in the responses are rounded to the prec_show of their assets, as the balances
+// queried from the matchengine. `precision=raw` in the query gets them as they are saved.
+pub struct DisplayPrecision {
+    assets: AssetManager,
+    // market name -> (base, quote)
+    markets: HashMap<String, (String, String)>,
+}
+
+impl DisplayPrecision {
+    pub fn new(assets: &[config::Asset], markets: &[config::Market]) -> Result<DisplayPrecision> {
+        Ok(DisplayPrecision {
+            assets: AssetManager::new(assets)?,
+            markets: markets
+                .iter()
+                .map(|market| (market.name.clone(), (market.base.name.clone(), market.quote.name.clone())))
+                .collect(),
+        })
+    }
+    pub fn is_raw(req: &HttpRequest) -> bool {
+        qstring::QString::from(req.query_string()).get("precision") == Some("raw")
+    }
+    pub fn round(&self, asset: &str, value: Decimal) -> Decimal {
+        self.assets.round_show(asset, value)
+    }
+    // the amounts of an unknown market are left as they are
+    fn market_assets(&self, market: &str) -> (&str, &str) {
+        match self.markets.get(market) {
+            Some((base, quote)) => (base, quote),
+            None => ("", ""),
+        }
+    }
+}
+
+pub trait RoundShow {
+    fn round_show(&mut self, precision: &DisplayPrecision);
+}
+
+impl RoundShow for BalanceHistoryItem {
+    fn round_show(&mut self, precision: &DisplayPrecision) {
+        self.change = precision.round(&self.asset, self.change);
+        self.balance = precision.round(&self.asset, self.balance);
+    }
+}
+// the fee rates are not amounts, the fee is paid in the asset received unless converted to the paid one
+// the fee rates are not amounts, and the fee is paid in the asset received
+impl RoundShow for OrderHistory {
+    fn round_show(&mut self, precision: &DisplayPrecision) {
+        let (base, quote) = precision.market_assets(&self.market);
+        let (fee_asset, paid_asset) = match self.order_side {
+            OrderSide::ASK => (quote, base),
+            OrderSide::BID => (base, quote),
+        };
+        self.price = precision.round(quote, self.price);
+        self.amount = precision.round(base, self.amount);
+        self.finished_base = precision.round(base, self.finished_base);
+        self.finished_quote = precision.round(quote, self.finished_quote);
+        self.finished_fee = precision.round(fee_asset, self.finished_fee);
+        self.finished_paid_fee = precision.round(paid_asset, self.finished_paid_fee);
+    }
+}
+
+impl RoundShow for OrderHistoryItem {
+    fn round_show(&mut self, precision: &DisplayPrecision) {
+        let (base, quote) = precision.market_assets(&self.order.market);
+        self.executed_amount = precision.round(base, self.executed_amount);
+        self.average_price = self.average_price.map(|price| precision.round(quote, price));
+        self.order.round_show(precision);
+    }
+}
+
+impl RoundShow for TradeRecord {
+    fn round_show(&mut self, precision: &DisplayPrecision) {
+        let (base, quote) = precision.market_assets(&self.market);
+        self.price = precision.round(quote, self.price);
+        self.amount = precision.round(base, self.amount);
+        self.quote_amount = precision.round(quote, self.quote_amount);
+    }
+}
+
+pub fn round_all<T: RoundShow>(req: &HttpRequest, precision: &DisplayPrecision, items: &mut [T]) {
+    if !DisplayPrecision::is_raw(req) {
+        for item in items.iter_mut() {
+            item.round_show(precision);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use chrono::NaiveDateTime;
+    use rust_decimal_macros::*;
+
+    fn get_precision() -> DisplayPrecision {
+        let assets = vec![
+            config::Asset {
+                name: "ETH".to_string(),
+                prec_save: 8,
+                prec_show: 4,
+                ..Default::default()
+            },
+            config::Asset {
+                name: "USDT".to_string(),
+                prec_save: 6,
+                prec_show: 6,
+                ..Default::default()
+            },
+        ];
+        let markets = vec![config::Market {
+            name: "ETH_USDT".to_string(),
+            base: config::MarketUnit {
+                name: "ETH".to_string(),
+                prec: 4,
+            },
+            quote: config::MarketUnit {
+                name: "USDT".to_string(),
+                prec: 2,
+            },
+            ..Default::default()
+        }];
+        DisplayPrecision::new(&assets, &markets).unwrap()
+    }
+
+    fn get_trades() -> Vec<TradeRecord> {
+        vec![TradeRecord {
+            time: NaiveDateTime::from_timestamp(0, 0),
+            market: "ETH_USDT".to_string(),
+            trade_id: 1,
+            price: dec!(1520.123456),
+            amount: dec!(0.12345678),
+            quote_amount: dec!(187.669253),
+            taker_side: OrderSide::BID,
+        }]
+    }
+
+    #[test]
+    fn test_raw_and_rounded() {
+        let precision = get_precision();
+
+        let req = TestRequest::default()
+            .uri("/restapi/recenttrades/ETH_USDT?precision=raw")
+            .to_http_request();
+        let mut raw = get_trades();
+        round_all(&req, &precision, &mut raw);
+        let req = TestRequest::default().uri("/restapi/recenttrades/ETH_USDT").to_http_request();
+        let mut rounded = get_trades();
+        round_all(&req, &precision, &mut rounded);
+
+        // only ETH shows fewer digits than it saves
+        assert_eq!(raw[0].amount, dec!(0.12345678));
+        assert_eq!(rounded[0].amount, dec!(0.1235));
+        assert_eq!(raw[0].price, rounded[0].price);
+        assert_eq!(raw[0].quote_amount, rounded[0].quote_amount);
+
+        // the same as the balances from the matchengine
+        assert_eq!(precision.round("ETH", dec!(1.00005)), dec!(1.0000));
+        assert_eq!(precision.round("BTC", dec!(1.00005)), dec!(1.00005));
+    }
+}
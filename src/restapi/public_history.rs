@@ -1,4 +1,4 @@
-use actix_web::{web, HttpRequest, Responder};
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
 
 use actix_web::web::Json;
 
@@ -8,7 +8,7 @@ use crate::models::{
 };
 use core::cmp::min;
 
-use super::{errors::RpcError, state::AppState, types};
+use super::{errors::RpcError, etag::json_with_etag, precision::round_all, state::AppState, types};
 use models::{DecimalDbType, TimestampDbType};
 use rust_decimal::prelude::*;
 
@@ -16,11 +16,11 @@ fn check_market_exists(_market: &str) -> bool {
     // TODO
     true
 }
-pub async fn recent_trades(req: HttpRequest, data: web::Data<AppState>) -> impl Responder {
+pub async fn recent_trades(req: HttpRequest, data: web::Data<AppState>) -> Result<HttpResponse, RpcError> {
     let market = req.match_info().get("market").unwrap();
     let qstring = qstring::QString::from(req.query_string());
     let limit = min(100, qstring.get("limit").unwrap_or_default().parse::<usize>().unwrap_or(20));
-    log::debug!("recent_trades market {} limit {}", market, limit);
+    tracing::debug!("recent_trades market {} limit {}", market, limit);
     if !check_market_exists(market) {
         return Err(RpcError::bad_request("invalid market"));
     }
@@ -33,9 +33,10 @@ pub async fn recent_trades(req: HttpRequest, data: web::Data<AppState>) -> impl
 
     let sql_query = format!("select * from {} where market = $1 order by time desc limit {}", TRADERECORD, limit);
 
-    let trades: Vec<models::TradeRecord> = sqlx::query_as(&sql_query).bind(market).fetch_all(&data.db).await?;
+    let mut trades: Vec<models::TradeRecord> = sqlx::query_as(&sql_query).bind(market).fetch_all(&data.db).await?;
+    round_all(&req, &data.precision, &mut trades);
 
-    Ok(Json(trades))
+    Ok(json_with_etag(&req, &trades))
 }
 
 #[derive(sqlx::FromRow, Debug, Clone)]
@@ -67,7 +68,7 @@ pub async fn order_trades(
     app_state: web::Data<AppState>,
     web::Path((market_name, order_id)): web::Path<(String, i64)>,
 ) -> Result<Json<types::OrderTradeResult>, RpcError> {
-    log::debug!("order_trades market {} order_id {}", market_name, order_id);
+    tracing::debug!("order_trades market {} order_id {}", market_name, order_id);
 
     let sql_query = format!(
         "
@@ -0,0 +1,210 @@
+use actix_web::dev::ServiceRequest;
+use actix_web::error::ResponseError;
+use actix_web::http::{header, StatusCode};
+use actix_web::HttpResponse;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use super::config;
+use super::errors::{ErrorType, RpcError};
+
+struct TokenBucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, limit: &config::RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.refill_per_second).min(limit.capacity as f64);
+        self.updated_at = now;
+    }
+    // an idle bucket is full again, dropping it loses nothing
+    fn is_full(&self, limit: &config::RateLimit, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+        self.tokens + elapsed * limit.refill_per_second >= limit.capacity as f64
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimited {
+    // seconds until a token is available
+    pub retry_after: u64,
+}
+
+impl fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "too many requests, retry after {} seconds", self.retry_after)
+    }
+}
+
+impl ResponseError for RateLimited {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::TOO_MANY_REQUESTS
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(StatusCode::TOO_MANY_REQUESTS)
+            .header(header::RETRY_AFTER, self.retry_after.to_string())
+            .json(RpcError::new(ErrorType::TooManyRequests, self.to_string()))
+    }
+}
+
+// Token buckets per route group and client, shared by all the workers.
+// Buckets are created full on the first request, and the full ones are swept periodically.
+pub struct RateLimiter {
+    config: config::RateLimitConfig,
+    buckets: Mutex<HashMap<(usize, String), TokenBucket>>,
+    last_sweep: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &config::RateLimitConfig) -> RateLimiter {
+        RateLimiter {
+            config: config.clone(),
+            buckets: Mutex::new(HashMap::new()),
+            last_sweep: Mutex::new(Instant::now()),
+        }
+    }
+    pub fn is_enabled(&self) -> bool {
+        !self.config.groups.is_empty()
+    }
+    // the group with the longest matching prefix
+    fn group_of(&self, path: &str) -> Option<usize> {
+        self.config
+            .groups
+            .iter()
+            .enumerate()
+            .filter(|(_, group)| path.starts_with(&group.path_prefix))
+            .max_by_key(|(_, group)| group.path_prefix.len())
+            .map(|(idx, _)| idx)
+    }
+    // the api key if the client sends one, otherwise its ip
+    pub fn client_key(&self, req: &ServiceRequest) -> String {
+        let header_value = |name: &Option<String>| {
+            name.as_ref()
+                .and_then(|name| req.headers().get(name.as_str()))
+                .and_then(|value| value.to_str().ok())
+                .map(String::from)
+        };
+        if let Some(api_key) = header_value(&self.config.api_key_header) {
+            return format!("key:{}", api_key);
+        }
+        let ip = header_value(&self.config.real_ip_header).or_else(|| req.peer_addr().map(|addr| addr.ip().to_string()));
+        format!("ip:{}", ip.unwrap_or_default())
+    }
+    pub fn check(&self, client: &str, path: &str) -> Result<(), RateLimited> {
+        self.check_at(client, path, Instant::now())
+    }
+    pub fn check_at(&self, client: &str, path: &str, now: Instant) -> Result<(), RateLimited> {
+        let group = match self.group_of(path) {
+            Some(group) => group,
+            None => return Ok(()),
+        };
+        self.sweep(now);
+        let limit = &self.config.groups[group].limit;
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry((group, client.to_string())).or_insert_with(|| TokenBucket {
+            tokens: limit.capacity as f64,
+            updated_at: now,
+        });
+        bucket.refill(limit, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let retry_after = ((1.0 - bucket.tokens) / limit.refill_per_second).ceil().max(1.0);
+            Err(RateLimited {
+                retry_after: retry_after as u64,
+            })
+        }
+    }
+    fn sweep(&self, now: Instant) {
+        {
+            let mut last_sweep = self.last_sweep.lock().unwrap();
+            if now.saturating_duration_since(*last_sweep) < self.config.sweep_interval {
+                return;
+            }
+            *last_sweep = now;
+        }
+        let groups = &self.config.groups;
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets.retain(|(group, _), bucket| !bucket.is_full(&groups[*group].limit, now));
+        tracing::debug!("rate limiter sweep done, {} buckets left", buckets.len());
+    }
+    fn len(&self) -> usize {
+        self.buckets.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn get_rate_limiter() -> RateLimiter {
+        RateLimiter::new(&config::RateLimitConfig {
+            groups: vec![
+                config::RateLimitGroup {
+                    path_prefix: "/restapi".to_string(),
+                    limit: config::RateLimit {
+                        capacity: 3,
+                        refill_per_second: 0.5,
+                    },
+                },
+                config::RateLimitGroup {
+                    path_prefix: "/restapi/tradingview".to_string(),
+                    limit: config::RateLimit {
+                        capacity: 1,
+                        refill_per_second: 1.0,
+                    },
+                },
+            ],
+            sweep_interval: Duration::from_secs(60),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_burst_then_reject() {
+        let limiter = get_rate_limiter();
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.check_at("ip:1.1.1.1", "/restapi/ping", now).is_ok());
+        }
+        assert_eq!(
+            limiter.check_at("ip:1.1.1.1", "/restapi/recenttrades/ETH_USDT", now),
+            Err(RateLimited { retry_after: 2 })
+        );
+        // other clients and groups have their own buckets
+        assert!(limiter.check_at("ip:2.2.2.2", "/restapi/ping", now).is_ok());
+        assert!(limiter.check_at("ip:1.1.1.1", "/restapi/tradingview/time", now).is_ok());
+        assert!(limiter.check_at("ip:1.1.1.1", "/restapi/tradingview/time", now).is_err());
+        // paths outside of any group are not limited
+        assert!(limiter.check_at("ip:1.1.1.1", "/metrics", now).is_ok());
+
+        // one token is refilled after 2 seconds
+        let later = now + Duration::from_secs(2);
+        assert!(limiter.check_at("ip:1.1.1.1", "/restapi/ping", later).is_ok());
+        assert!(limiter.check_at("ip:1.1.1.1", "/restapi/ping", later).is_err());
+    }
+
+    #[test]
+    fn test_sweep_idle_buckets() {
+        let limiter = get_rate_limiter();
+        let now = Instant::now();
+        limiter.check_at("ip:1.1.1.1", "/restapi/ping", now).unwrap();
+        limiter.check_at("ip:2.2.2.2", "/restapi/ping", now).unwrap();
+        assert_eq!(limiter.len(), 2);
+
+        // 1.1.1.1 keeps busy, 2.2.2.2 is idle and full again when the sweep runs
+        let later = now + Duration::from_secs(61);
+        for _ in 0..3 {
+            limiter.check_at("ip:1.1.1.1", "/restapi/ping", now + Duration::from_secs(59)).ok();
+        }
+        limiter.check_at("ip:1.1.1.1", "/restapi/ping", later).ok();
+        assert_eq!(limiter.len(), 1);
+    }
+}
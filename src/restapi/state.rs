@@ -1,4 +1,5 @@
 use super::config::Settings;
+use super::precision::DisplayPrecision;
 use super::types::{TickerResult, UserInfo};
 
 use sqlx::postgres::Postgres;
@@ -9,6 +10,7 @@ pub struct AppState {
     pub user_addr_map: Mutex<HashMap<String, UserInfo>>,
     pub db: sqlx::pool::Pool<Postgres>,
     pub config: Settings,
+    pub precision: DisplayPrecision,
 }
 
 #[derive(Debug)]
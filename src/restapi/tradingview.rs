@@ -1,5 +1,5 @@
 use actix_web::web::{self, Data, Json};
-use actix_web::{HttpRequest, Responder};
+use actix_web::{HttpRequest, HttpResponse, Responder};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::{
@@ -8,6 +8,7 @@ use std::{
 };
 
 use super::errors::RpcError;
+use super::etag::json_with_etag;
 use super::types::{KlineReq, KlineResult, TickerResult};
 use crate::restapi::state;
 
@@ -20,7 +21,7 @@ use crate::models::tablenames::TRADERECORD;
 pub async fn unix_timestamp(_req: HttpRequest) -> impl Responder {
     format!("{}", SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs())
 }
-pub async fn chart_config(_req: HttpRequest) -> impl Responder {
+pub async fn chart_config(req: HttpRequest) -> impl Responder {
     let value = json!({
         "supports_search": true,
         "supports_group_request": false,
@@ -38,19 +39,18 @@ pub async fn chart_config(_req: HttpRequest) -> impl Responder {
         "symbols_types": [{"name": "ETH_USDT", "value": "ETH_USDT"}],
         "supported_resolutions": [1, 5, 15, 30, 60, 120, 240, 360, 720, 1440, 4320, 10080] // minutes
     });
-    value.to_string()
+    json_with_etag(&req, &value)
 }
 
-// TODO: Result<web::Json<T>, RpcError>
-pub async fn symbols(req: HttpRequest) -> Result<String, RpcError> {
+pub async fn symbols(req: HttpRequest) -> Result<HttpResponse, RpcError> {
     let qstring = qstring::QString::from(req.query_string());
     let symbol = qstring.get("symbol");
     if symbol.is_none() {
         return Err(RpcError::bad_request("no `symbol` param"));
     };
     let _market = symbol.unwrap().split(':').last().unwrap();
-    log::debug!("kline get symbol {:?}", symbol);
-    Ok(json!(
+    tracing::debug!("kline get symbol {:?}", symbol);
+    let value = json!(
         {
             "name": "ETH_USDT",
             "ticker": "ETH_USDT",
@@ -69,8 +69,8 @@ pub async fn symbols(req: HttpRequest) -> Result<String, RpcError> {
             "minmovement2": 0,
             "minmov2": 0
         }
-    )
-    .to_string())
+    );
+    Ok(json_with_etag(&req, &value))
 }
 
 use chrono::{self, DurationRound};
@@ -105,7 +105,7 @@ pub async fn ticker(
     req: HttpRequest,
     web::Path((TickerInv(ticker_inv), market_name)): web::Path<(TickerInv, String)>,
     app_state: Data<state::AppState>,
-) -> Result<Json<TickerResult>, RpcError> {
+) -> Result<HttpResponse, RpcError> {
     let cache = req.app_data::<state::AppCache>().expect("App cache not found");
     let now_ts: DateTime<Utc> = SystemTime::now().into();
     let update_inv = app_state.config.trading.ticker_update_interval;
@@ -116,15 +116,15 @@ pub async fn ticker(
         //range of cache is [-inv, +inv] on now
         let now_ts_dur = Duration::from_secs(now_ts.timestamp() as u64);
         let cached_now = Duration::from_secs(cached_resp.to);
-        log::debug!(
+        tracing::debug!(
             "cache judge {}, {}, {}",
             cached_now.as_secs(),
             update_inv.as_secs(),
             now_ts_dur.as_secs()
         );
         if cached_now + update_inv > now_ts_dur && now_ts_dur > cached_now - update_inv {
-            log::debug!("use cached response");
-            return Ok(Json(cached_resp.clone()));
+            tracing::debug!("use cached response");
+            return Ok(json_with_etag(&req, cached_resp));
         }
     }
 
@@ -148,7 +148,7 @@ pub async fn ticker(
         .clone()
         .checked_sub_signed(ticker_inv)
         .ok_or_else(|| RpcError::unknown("Internal clock error"))?;
-    log::debug!("query ticker from {} to {}", from_ts, now_ts);
+    tracing::debug!("query ticker from {} to {}", from_ts, now_ts);
 
     let ticker_ret: TickerItem = sqlx::query_as(&core_query)
         .bind(&market_name)
@@ -171,9 +171,10 @@ pub async fn ticker(
         to: now_ts.timestamp() as u64,
     };
 
+    let response = json_with_etag(&req, &ret);
     //update cache
-    ticker_ret_cache.insert(market_name, ret.clone());
-    Ok(Json(ret))
+    ticker_ret_cache.insert(market_name, ret);
+    Ok(response)
 }
 
 #[derive(sqlx::FromRow, Debug, Clone)]
@@ -224,10 +225,10 @@ impl actix_web::error::ResponseError for TradeViewError {
 pub async fn history(req_origin: HttpRequest, app_state: Data<state::AppState>) -> Result<Json<KlineResult>, TradeViewError> {
     let req: web::Query<KlineReq> = web::Query::from_query(req_origin.query_string())?;
     let req = req.into_inner();
-    log::debug!("kline req {:?}", req);
+    tracing::debug!("kline req {:?}", req);
 
     if req.usemock.is_some() {
-        log::debug!("Use mock mode");
+        tracing::debug!("Use mock mode");
         return Ok(Json(mock::fake_kline_result(&req)));
     }
 
@@ -262,7 +263,7 @@ pub async fn history(req_origin: HttpRequest, app_state: Data<state::AppState>)
         out_v.push(item.sum.to_f32().unwrap_or(0.0));
     }
 
-    log::debug!("Query {} results", out_t.len());
+    tracing::debug!("Query {} results", out_t.len());
 
     if out_t.is_empty() {
         let next_query = format!("select time from {} where time < $1 order by time desc limit 1", TRADERECORD);
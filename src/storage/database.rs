@@ -1,11 +1,15 @@
 use std::collections::{hash_map, HashMap, VecDeque};
 use std::marker::PhantomData;
-use std::time::Instant;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc::error::TrySendError;
 use tokio::{sync, task};
 
 use anyhow::{anyhow, Result};
+use thiserror::Error;
 
+use crate::config;
 use crate::models;
 use crate::types;
 
@@ -17,6 +21,105 @@ use types::DbType;
 pub const QUERY_LIMIT: i64 = 1000;
 pub const INSERT_LIMIT: i64 = 5000;
 
+pub fn pool_options(config: &config::DbPoolConfig) -> sqlx::pool::PoolOptions<DbType> {
+    sqlx::pool::PoolOptions::new()
+        .max_connections(config.max_connections)
+        .min_connections(config.min_connections)
+        // the time `acquire` waits for a connection, it fails with `PoolTimedOut` afterwards
+        .connect_timeout(config.acquire_timeout)
+        .idle_timeout(config.idle_timeout)
+}
+
+// connections are opened on the first queries
+pub fn connect_pool_lazy(config: &config::DbPoolConfig, url: &str) -> Result<sqlx::Pool<DbType>> {
+    Ok(pool_options(config).connect_lazy(url)?)
+}
+
+// The writes go to the primary, the read-only queries to the replica if there is one. A replica
+// may lag behind, so what was just written may not be read back from it at once.
+#[derive(Clone)]
+pub struct DbPools {
+    primary: sqlx::Pool<DbType>,
+    replica: Option<sqlx::Pool<DbType>>,
+}
+
+impl DbPools {
+    // an empty `replica_url` means no replica
+    pub fn connect_lazy(config: &config::DbPoolConfig, primary_url: &str, replica_url: &str) -> Result<DbPools> {
+        Ok(DbPools {
+            primary: connect_pool_lazy(config, primary_url)?,
+            replica: if replica_url.is_empty() {
+                None
+            } else {
+                Some(connect_pool_lazy(config, replica_url)?)
+            },
+        })
+    }
+    pub fn primary(&self) -> &sqlx::Pool<DbType> {
+        &self.primary
+    }
+    pub fn read(&self) -> &sqlx::Pool<DbType> {
+        self.replica.as_ref().unwrap_or(&self.primary)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum MigrationError {
+    // an applied migration was edited afterwards, the schema may not be what the code expects
+    #[error("applied migration {0} has been modified, refuse to start")]
+    ChecksumMismatch(i64),
+    #[error("migrate fail: {0}")]
+    Migrate(#[from] sqlx::migrate::MigrateError),
+    #[error("db error: {0}")]
+    Sqlx(#[from] sqlx::Error),
+}
+
+// the migrations not applied yet, checking the applied ones are unchanged
+pub fn pending_migrations<'a>(
+    migrator: &'a sqlx::migrate::Migrator,
+    applied: &[(i64, Vec<u8>)],
+) -> std::result::Result<Vec<&'a sqlx::migrate::Migration>, MigrationError> {
+    let mut pending = Vec::new();
+    for migration in migrator.iter() {
+        match applied.iter().find(|(version, _)| *version == migration.version) {
+            Some((_, checksum)) if checksum.as_slice() != &*migration.checksum => {
+                return Err(MigrationError::ChecksumMismatch(migration.version))
+            }
+            Some(_) => {}
+            None => pending.push(migration),
+        }
+    }
+    Ok(pending)
+}
+
+// Applies the pending migrations embedded in the binary, sqlx records them in `_sqlx_migrations`.
+// Running it again applies nothing.
+pub async fn run_migrations(
+    conn: &mut types::ConnectionType,
+    migrator: &sqlx::migrate::Migrator,
+) -> std::result::Result<(), MigrationError> {
+    let table: (Option<String>,) = sqlx::query_as("select to_regclass('_sqlx_migrations')::text")
+        .fetch_one(&mut *conn)
+        .await?;
+    let applied: Vec<(i64, Vec<u8>)> = if table.0.is_some() {
+        sqlx::query_as("select version, checksum from _sqlx_migrations where success order by version")
+            .fetch_all(&mut *conn)
+            .await?
+    } else {
+        Vec::new()
+    };
+    let pending = pending_migrations(migrator, &applied)?;
+    if pending.is_empty() {
+        tracing::info!("db schema is up to date");
+        return Ok(());
+    }
+    for migration in &pending {
+        tracing::info!("apply migration {} {}", migration.version, migration.description);
+    }
+    migrator.run(conn).await?;
+    Ok(())
+}
+
 //https://play.rust-lang.org/?version=stable&mode=debug&edition=2018&gist=66bb75f8bb7b55d6bc8bfdb9d97ceb79
 
 //tracing the progress in a single tag
@@ -126,6 +229,10 @@ struct DatabaseWriterTask<T> {
     data: Vec<T>,
     notify_flag: Option<TaskNotifyFlag>,
     benchmark: Option<(Instant, u32)>,
+    // when the first entry of the batch was appended
+    created: Instant,
+    // entries written before a failure, they are not retried
+    written: usize,
 }
 
 impl<T> DatabaseWriterTask<T> {
@@ -134,11 +241,22 @@ impl<T> DatabaseWriterTask<T> {
             data: Vec::new(),
             notify_flag: None,
             benchmark: None,
+            created: Instant::now(),
+            written: 0,
         }
     }
 
-    fn is_limited(&self) -> bool {
-        self.data.len() >= INSERT_LIMIT as usize
+    fn is_limited(&self, config: &DatabaseWriterConfig) -> bool {
+        self.data.len() >= config.batch_size.min(INSERT_LIMIT as usize)
+    }
+
+    fn flush_deadline(&self, config: &DatabaseWriterConfig) -> Instant {
+        self.created + config.flush_interval
+    }
+
+    // a batch is written once it is full or it has waited for the flush interval
+    fn is_ready(&self, config: &DatabaseWriterConfig, now: Instant) -> bool {
+        self.is_limited(config) || now >= self.flush_deadline(config)
     }
 
     fn is_empty(&self) -> bool {
@@ -161,10 +279,19 @@ impl<T> DatabaseWriterTask<T> {
     }
 }
 
+// The oldest batch is spawned first, it is the only one which may be partial. On shutdown the
+// partial batches are written without waiting.
+fn is_flush_ready<T>(tasks: &VecDeque<DatabaseWriterTask<T>>, config: &DatabaseWriterConfig, now: Instant, grace_down: bool) -> bool {
+    match tasks.back() {
+        Some(task) => grace_down || task.is_ready(config, now),
+        None => false,
+    }
+}
+
 enum WriterMsg<T> {
     Data(T, Option<TaskNotification>),
     Done(DatabaseWriterTask<T>),
-    Fail(sqlx::Error, DatabaseWriterTask<T>),
+    Fail(StorageError, DatabaseWriterTask<T>),
     Exit(bool),
 }
 
@@ -177,7 +304,7 @@ where
     async fn execute(mut self, mut conn: sqlx::pool::PoolConnection<DbType>, ret: sync::mpsc::Sender<WriterMsg<U>>) {
         let entries = &self.data;
 
-        log::debug!(
+        tracing::debug!(
             "{} (by batch for {} entries)",
             <InsertTable as CommonSQLQuery<U, sqlx::Postgres>>::sql_statement(),
             entries.len()
@@ -185,7 +312,7 @@ where
         let ret = match InsertTableBatch::sql_query_fine(entries.as_slice(), &mut conn).await {
             Ok(_) => {
                 if let Some((now, len)) = self.benchmark {
-                    log::debug!(
+                    tracing::debug!(
                         "insert {} items into {} takes {}",
                         len,
                         U::table_name(),
@@ -195,15 +322,16 @@ where
                 ret.send(WriterMsg::Done(self)).await
             }
             Err((resident, e)) => {
+                self.written += self.data.len() - resident.len();
                 self.data = resident;
                 ret.send(WriterMsg::Fail(e, self)).await
             }
         };
 
         if ret.is_err() {
-            log::error!("channel has closed, data lost");
+            tracing::error!("channel has closed, data lost");
         } else {
-            log::debug!("minitask for table {} has normally exit", U::table_name());
+            tracing::debug!("minitask for table {} has normally exit", U::table_name());
         }
     }
 }
@@ -223,7 +351,39 @@ impl DatabaseWriterStatus {
     }
 }
 
-pub struct DatabaseWriterEntryImpl<'a, U: std::clone::Clone + Send>(&'a mut sync::mpsc::Sender<WriterMsg<U>>);
+// The entries appended and not written yet, shared by the writer, its entries and the scheduler.
+// Appending fails once `limit` entries are queued, so the memory held by a stalled writer is bounded.
+#[derive(Clone, Debug)]
+struct QueueBound {
+    queued: Arc<AtomicUsize>,
+    limit: usize,
+}
+
+impl QueueBound {
+    fn new(limit: usize) -> Self {
+        QueueBound {
+            queued: Arc::new(AtomicUsize::new(0)),
+            limit,
+        }
+    }
+
+    fn depth(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    fn try_acquire(&self) -> bool {
+        let limit = self.limit;
+        self.queued
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| if n < limit { Some(n + 1) } else { None })
+            .is_ok()
+    }
+
+    fn release(&self, n: usize) {
+        self.queued.fetch_sub(n, Ordering::Relaxed);
+    }
+}
+
+pub struct DatabaseWriterEntryImpl<'a, U: std::clone::Clone + Send>(&'a mut sync::mpsc::Sender<WriterMsg<U>>, &'a QueueBound);
 
 impl<U> DatabaseWriterEntryImpl<'_, U>
 where
@@ -235,8 +395,13 @@ where
 
     pub fn append_with_notify(self, item: U, notify: Option<TaskNotification>) -> Result<(), U> {
         // must not block
-        //log::debug!("append item done {:?}", item);
+        //tracing::debug!("append item done {:?}", item);
+        if !self.1.try_acquire() {
+            return Err(item);
+        }
+        let queue = self.1;
         self.0.try_send(WriterMsg::Data(item, notify)).map_err(|e| {
+            queue.release(1);
             if let WriterMsg::Data(u, _) = match e {
                 TrySendError::Full(m) => m,
                 TrySendError::Closed(m) => m,
@@ -248,14 +413,14 @@ where
     }
 }
 
-pub struct DatabaseWriterEntry<U: std::clone::Clone + Send>(sync::mpsc::Sender<WriterMsg<U>>);
+pub struct DatabaseWriterEntry<U: std::clone::Clone + Send>(sync::mpsc::Sender<WriterMsg<U>>, QueueBound);
 
 impl<U> DatabaseWriterEntry<U>
 where
     U: std::clone::Clone + Send,
 {
     pub fn gen(&mut self) -> DatabaseWriterEntryImpl<'_, U> {
-        DatabaseWriterEntryImpl(&mut self.0)
+        DatabaseWriterEntryImpl(&mut self.0, &self.1)
     }
 }
 
@@ -271,6 +436,7 @@ where
     complete_notify: sync::watch::Receiver<TaskNotifyFlag>,
 
     config: DatabaseWriterConfig,
+    queue: QueueBound,
     status_send: Option<sync::watch::Sender<DatabaseWriterStatus>>,
     complete_send: Option<sync::watch::Sender<TaskNotifyFlag>>,
 
@@ -281,7 +447,12 @@ where
 pub struct DatabaseWriterConfig {
     pub apply_benchmark: bool,
     pub spawn_limit: i32,
+    // at most this many entries are queued, appending fails beyond it
     pub capability_limit: usize,
+    // entries of a batch, at most INSERT_LIMIT
+    pub batch_size: usize,
+    // a partial batch is written after waiting this long, zero writes it as soon as possible
+    pub flush_interval: Duration,
 }
 
 impl<U> DatabaseWriter<U>
@@ -296,6 +467,7 @@ where
             scheduler: None,
             sender: None,
             config: config.clone(),
+            queue: QueueBound::new(config.capability_limit),
             status: s_rx,
             complete_notify: cp_rx,
             status_send: Some(s_tx),
@@ -305,7 +477,7 @@ where
     }
 
     pub fn get_entry(&self) -> Option<DatabaseWriterEntry<U>> {
-        self.sender.as_ref().map(|sd| DatabaseWriterEntry(sd.clone()))
+        self.sender.as_ref().map(|sd| DatabaseWriterEntry(sd.clone(), self.queue.clone()))
     }
 
     pub fn append(&mut self, item: U) -> Result<(), U> {
@@ -314,29 +486,40 @@ where
 
     pub fn append_with_notify(&mut self, item: U, notify: Option<TaskNotification>) -> Result<(), U> {
         // must not block
-        //log::debug!("append item done {:?}", item);
+        //tracing::debug!("append item done {:?}", item);
         match &mut self.sender {
-            Some(sd) => DatabaseWriterEntryImpl(sd).append_with_notify(item, notify),
+            Some(sd) => DatabaseWriterEntryImpl(sd, &self.queue).append_with_notify(item, notify),
             None => Err(item),
         }
     }
 
     //we consider no block for writer anymore
     pub fn is_block(&self) -> bool {
-        self.sender.is_none() || ((self.config.capability_limit as f64 * 0.9) as usize) < self.status().pending_count
+        self.sender.is_none() || ((self.config.capability_limit as f64 * 0.9) as usize) < self.queue_depth()
+    }
+
+    // entries appended and not written yet, including the batches being written
+    pub fn queue_depth(&self) -> usize {
+        self.queue.depth()
     }
 
     pub fn status(&self) -> DatabaseWriterStatus {
         self.status.borrow().clone()
     }
 
-    pub async fn finish(self) -> types::SimpleResult {
-        match self.sender {
+    pub async fn finish(mut self) -> types::SimpleResult {
+        self.close().await
+    }
+
+    // wait for the pending data to be written, appending fails afterwards
+    pub async fn close(&mut self) -> types::SimpleResult {
+        match self.sender.take() {
             Some(sd) => {
                 sd.send(WriterMsg::Exit(true))
                     .await
                     .map_err(|e| anyhow!("Send exit notify fail: {}", e))?;
                 self.scheduler
+                    .take()
                     .unwrap()
                     .await
                     .map_err(|e| anyhow!("Wait scheuler exit fail: {}", e))?;
@@ -356,6 +539,7 @@ struct DatabaseWriterScheduleCtx<T> {
     pool: sqlx::Pool<DbType>,
     complete_notify: sync::watch::Sender<TaskNotifyFlag>,
     status_notify: sync::watch::Sender<DatabaseWriterStatus>,
+    queue: QueueBound,
 
     config: DatabaseWriterConfig,
 }
@@ -376,11 +560,18 @@ where
         loop {
             self.status_notify.send(status_tracing.clone()).ok();
 
+            let flush_ready = is_flush_ready(&next_task_stack, &self.config, Instant::now(), grace_down);
+            let flush_at = next_task_stack
+                .back()
+                .filter(|_| !flush_ready)
+                .map(|task| task.flush_deadline(&self.config));
+
             tokio::select! {
                 Ok(conn) = self.pool.acquire(), if !error_task_stack.is_empty() => {
                     tokio::spawn(error_task_stack.pop_back().unwrap().execute(conn, self.ctrl_notify.clone()));
                 }
                 Ok(conn) = self.pool.acquire(), if (
+                        flush_ready &&
                         !next_task_stack.is_empty() &&
                         status_tracing.spawning_tasks < self.config.spawn_limit
                 )   => {
@@ -395,10 +586,11 @@ where
                     }
                     tokio::spawn(task.execute(conn, self.ctrl_notify.clone()));
                 }
+                _ = tokio::time::sleep_until(tokio::time::Instant::from_std(flush_at.unwrap_or_else(Instant::now))), if flush_at.is_some() => {}
                 Some(msg) = self.ctrl_chn.recv() => {
                     match msg {
                         WriterMsg::Data(data, notify) => {
-                            if next_task_stack.is_empty() || next_task_stack.front().unwrap().is_limited(){
+                            if next_task_stack.is_empty() || next_task_stack.front().unwrap().is_limited(&self.config){
                                 next_task_stack.push_front(DatabaseWriterTask::new());
                             }
                             next_task_stack.front_mut().unwrap().add_data(data, notify);
@@ -406,18 +598,23 @@ where
                         },
                         WriterMsg::Done(mut ctx) => {
                             status_tracing.spawning_tasks -= 1;
+                            self.queue.release(ctx.written + ctx.data.len());
                             if let Some(notifies) = ctx.notify_flag.take() {
                                 self.complete_notify.send(notify_tracing.finish_from(notifies)).ok();
                             }
-                            if grace_down && status_tracing.spawning_tasks == 0 {break;}
+                            if grace_down && status_tracing.spawning_tasks == 0
+                                && next_task_stack.is_empty() && error_task_stack.is_empty() {break;}
                         },
-                        WriterMsg::Fail(err, ctx) => {
-                            log::error!("exec sql:  fail: {}. retry", err);
+                        WriterMsg::Fail(err, mut ctx) => {
+                            tracing::error!("exec sql:  fail: {}. retry", err);
+                            self.queue.release(std::mem::take(&mut ctx.written));
                             error_task_stack.push_front(ctx);
                         },
                         WriterMsg::Exit(grace) => {
                             grace_down = true;
-                            if !grace || status_tracing.spawning_tasks == 0 {
+                            // a grace exit waits for the data not spawned yet as well
+                            if !grace || (status_tracing.spawning_tasks == 0
+                                && next_task_stack.is_empty() && error_task_stack.is_empty()) {
                                 break;
                             }
                         },
@@ -427,10 +624,10 @@ where
         }
 
         if !next_task_stack.is_empty() || !error_task_stack.is_empty() {
-            log::error!("Data for {} has lost because of non-grace exit", U::table_name());
+            tracing::error!("Data for {} has lost because of non-grace exit", U::table_name());
         }
 
-        log::info!("db scheduler thread for {}  \texit", U::table_name());
+        tracing::info!("db scheduler thread for {}  \texit", U::table_name());
     }
 }
 
@@ -457,11 +654,12 @@ where
             status_notify: self.status_send.take().unwrap(),
             complete_notify: self.complete_send.take().unwrap(),
             pool: pool.clone(),
+            queue: self.queue.clone(),
             config: self.config.clone(),
         };
 
         //no url output (we do not need)
-        log::info!("db writer for {} config: {:?}", U::table_name(), ctx.config);
+        tracing::info!("db writer for {} config: {:?}", U::table_name(), ctx.config);
         self.scheduler = Some(tokio::spawn(ctx.schedule()));
 
         Ok(self)
@@ -469,3 +667,154 @@ where
 }
 
 pub type OperationLogSender = DatabaseWriter<models::OperationLog>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_config() -> DatabaseWriterConfig {
+        DatabaseWriterConfig {
+            apply_benchmark: false,
+            spawn_limit: 4,
+            capability_limit: 8192,
+            batch_size: 10,
+            flush_interval: Duration::from_millis(100),
+        }
+    }
+
+    #[test]
+    fn test_batch_flush_thresholds() {
+        let config = get_config();
+        let mut task = DatabaseWriterTask::<u32>::new();
+        for i in 0..3 {
+            task.add_data(i, None);
+        }
+        // a partial batch waits for the interval
+        assert!(!task.is_ready(&config, task.created));
+        assert!(!task.is_ready(&config, task.created + Duration::from_millis(99)));
+        assert!(task.is_ready(&config, task.created + Duration::from_millis(100)));
+        // a full one is written at once
+        for i in 3..10 {
+            task.add_data(i, None);
+        }
+        assert!(task.is_ready(&config, task.created));
+
+        // on shutdown a partial batch is drained at once
+        let mut tasks = VecDeque::new();
+        assert!(!is_flush_ready(&tasks, &config, Instant::now(), true));
+        let mut task = DatabaseWriterTask::<u32>::new();
+        task.add_data(0, None);
+        let created = task.created;
+        tasks.push_front(task);
+        assert!(!is_flush_ready(&tasks, &config, created, false));
+        assert!(is_flush_ready(&tasks, &config, created, true));
+
+        // the batch size is capped by the insert limit
+        let config = DatabaseWriterConfig {
+            batch_size: usize::MAX,
+            flush_interval: Duration::from_secs(3600),
+            ..get_config()
+        };
+        let mut task = DatabaseWriterTask::<i64>::new();
+        for i in 0..INSERT_LIMIT {
+            task.add_data(i, None);
+        }
+        assert!(task.is_ready(&config, task.created));
+        // without an interval, every batch is written as soon as possible
+        let config = DatabaseWriterConfig {
+            flush_interval: Duration::default(),
+            ..get_config()
+        };
+        let mut task = DatabaseWriterTask::<u32>::new();
+        task.add_data(0, None);
+        assert!(task.is_ready(&config, task.created));
+    }
+
+    #[test]
+    fn test_stalled_writer_bound() {
+        let config = DatabaseWriterConfig {
+            capability_limit: 100,
+            ..get_config()
+        };
+        let mut writer = DatabaseWriter::<u32>::new(&config);
+        // a scheduler which never writes anything
+        let (tx, _rx) = sync::mpsc::channel(CHANNEL_LIMIT);
+        writer.sender = Some(tx);
+        for i in 0..150 {
+            assert_eq!(writer.append(i).is_ok(), i < 100, "{}", i);
+        }
+        assert_eq!(writer.queue_depth(), 100);
+        assert!(writer.is_block());
+        // the entries share the bound
+        let mut entry = writer.get_entry().unwrap();
+        assert_eq!(entry.gen().append(150), Err(150));
+
+        // written entries make room again
+        writer.queue.release(10);
+        assert!(entry.gen().append(150).is_ok());
+        assert_eq!(writer.queue_depth(), 91);
+    }
+
+    #[test]
+    fn test_db_pool_config() {
+        let mut conf = config_rs::Config::new();
+        conf.merge(config_rs::File::from_str(
+            "db_pool:\n  max_connections: 32\n  min_connections: 4\n  acquire_timeout: 5s\n",
+            config_rs::FileFormat::Yaml,
+        ))
+        .unwrap();
+        let settings: config::Settings = conf.try_into().unwrap();
+        assert_eq!(
+            settings.db_pool,
+            config::DbPoolConfig {
+                max_connections: 32,
+                min_connections: 4,
+                acquire_timeout: Duration::from_secs(5),
+                // not set
+                idle_timeout: Some(Duration::from_secs(600)),
+            }
+        );
+
+        let status = tonic::Status::from(StorageError::from(sqlx::Error::PoolTimedOut));
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+    }
+
+    #[tokio::test]
+    async fn test_read_pool_routing() {
+        let config = config::DbPoolConfig::default();
+        let primary = "postgres://exchange@127.0.0.1/exchange";
+        let pools = DbPools::connect_lazy(&config, primary, "").unwrap();
+        assert!(std::ptr::eq(pools.read(), pools.primary()));
+
+        let pools = DbPools::connect_lazy(&config, primary, "postgres://exchange@127.0.0.2/exchange").unwrap();
+        assert!(std::ptr::eq(pools.read(), pools.replica.as_ref().unwrap()));
+        assert!(!std::ptr::eq(pools.read(), pools.primary()));
+    }
+
+    #[test]
+    fn test_pending_migrations() {
+        let migrator = &crate::persist::MIGRATOR;
+        let all: Vec<(i64, Vec<u8>)> = migrator.iter().map(|m| (m.version, m.checksum.to_vec())).collect();
+        let versions = |pending: Vec<&sqlx::migrate::Migration>| pending.iter().map(|m| m.version).collect::<Vec<i64>>();
+
+        let pending = pending_migrations(migrator, &[]).unwrap();
+        assert_eq!(versions(pending), all.iter().map(|(version, _)| *version).collect::<Vec<i64>>());
+        // once all are applied, running again is a no-op
+        assert!(pending_migrations(migrator, &all).unwrap().is_empty());
+        // versions of other migrators in the same table are ignored
+        let mut applied = all.clone();
+        applied.push((1, vec![0u8; 48]));
+        assert!(pending_migrations(migrator, &applied).unwrap().is_empty());
+
+        let (last_version, _) = all.last().cloned().unwrap();
+        let pending = pending_migrations(migrator, &all[..all.len() - 1]).unwrap();
+        assert_eq!(versions(pending), vec![last_version]);
+
+        let mut modified = all.clone();
+        modified[0].1[0] ^= 0xff;
+        match pending_migrations(migrator, &modified) {
+            Err(MigrationError::ChecksumMismatch(version)) => assert_eq!(version, all[0].0),
+            other => panic!("unexpected {:?}", other.map(versions)),
+        }
+    }
+}
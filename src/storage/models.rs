@@ -1,6 +1,6 @@
-use crate::types::OrderSide;
+use crate::types::{BusinessKind, OrderSide};
 use chrono::NaiveDateTime;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 pub type DecimalDbType = rust_decimal::Decimal;
 // https://github.com/launchbadge/sqlx/blob/master/sqlx-core/src/postgres/types/mod.rs
@@ -15,13 +15,18 @@ pub mod tablenames {
     pub const ORDERSLICE: &str = "order_slice";
     pub const BALANCESLICE: &str = "balance_slice";
     pub const SLICEHISTORY: &str = "slice_history";
+    pub const TRIGGERORDERSLICE: &str = "trigger_order_slice";
+    pub const MARKETSTATESLICE: &str = "market_state_slice";
+    pub const ASSETSLICE: &str = "asset_slice";
+    pub const SLICEPAYLOAD: &str = "slice_payload";
     //TODO: should rename to another one which is better distinguished with trade_history?
     pub const TRADERECORD: &str = "trade_record";
+    pub const KLINE: &str = "kline";
 }
 
 use tablenames::*;
 
-#[derive(sqlx::FromRow, Debug, Clone)]
+#[derive(sqlx::FromRow, Debug, Clone, Serialize)]
 pub struct BalanceHistory {
     //for renaming, add #[sqlx(type_name = "<row name>")] in corresponding
     //field (not like diesel imply within the derive macro)
@@ -29,7 +34,7 @@ pub struct BalanceHistory {
     pub time: TimestampDbType,
     pub user_id: i32,
     pub asset: String,
-    pub business: String,
+    pub business: BusinessKind,
     pub change: DecimalDbType,
     pub balance: DecimalDbType,
     // TODO: change it to jsonb
@@ -51,10 +56,12 @@ pub struct OrderHistory {
     pub maker_fee: DecimalDbType,
     pub finished_base: DecimalDbType,
     pub finished_quote: DecimalDbType,
+    // in the received asset, and converted to the paid asset
     pub finished_fee: DecimalDbType,
+    pub finished_paid_fee: DecimalDbType,
 }
 
-#[derive(sqlx::FromRow, Debug, Clone)]
+#[derive(sqlx::FromRow, Debug, Clone, Serialize)]
 pub struct TradeHistory {
     pub time: TimestampDbType,
     pub user_id: i32,
@@ -90,9 +97,11 @@ pub struct BalanceSlice {
     pub asset: String,
     pub t: i16, // Enum: AVAILABLE or FREEZE
     pub balance: DecimalDbType,
+    // the freeze purpose of a FREEZE balance held for other than orders, empty otherwise
+    pub purpose: String,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BalanceSliceInsert {
     //pub id: i32,
     pub slice_id: i64, // Unix timestamp
@@ -100,9 +109,10 @@ pub struct BalanceSliceInsert {
     pub asset: String,
     pub t: i16, // Enum: AVAILABLE or FREEZE
     pub balance: DecimalDbType,
+    pub purpose: String,
 }
 
-#[derive(sqlx::FromRow, Debug, Clone)]
+#[derive(sqlx::FromRow, Debug, Clone, Serialize, Deserialize)]
 pub struct OrderSlice {
     pub id: i64,
     pub slice_id: i64,
@@ -123,6 +133,45 @@ pub struct OrderSlice {
     pub finished_base: DecimalDbType,
     pub finished_quote: DecimalDbType,
     pub finished_fee: DecimalDbType,
+    pub finished_paid_fee: DecimalDbType,
+    pub display_qty: DecimalDbType,
+    pub visible: DecimalDbType,
+    pub priority: i64,
+    pub expire_at: Option<TimestampDbType>,
+}
+
+#[derive(sqlx::FromRow, Debug, Clone, Serialize, Deserialize)]
+pub struct TriggerOrderSlice {
+    pub id: i64,
+    pub slice_id: i64,
+    pub market: String,
+    // json of market::TriggerOrder
+    pub params: String,
+}
+
+// only the markets which are not active
+#[derive(sqlx::FromRow, Debug, Clone, Serialize, Deserialize)]
+pub struct MarketStateSlice {
+    pub slice_id: i64,
+    pub market: String,
+    pub state: String,
+}
+
+// only the assets registered at runtime, not in the config
+#[derive(sqlx::FromRow, Debug, Clone, Serialize, Deserialize)]
+pub struct AssetSlice {
+    pub slice_id: i64,
+    pub name: String,
+    // json of config::Asset
+    pub params: String,
+}
+
+// a compressed slice in one row, in place of the rows of the other slice tables
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct SlicePayload {
+    pub slice_id: i64,
+    // a codec header, then the compressed rows
+    pub data: Vec<u8>,
 }
 
 // xx_id here means the last persisted entry id
@@ -134,6 +183,14 @@ pub struct SliceHistory {
     pub end_trade_id: i64,
 }
 
+// traded quote amount of a user in one day, aggregated from trade_history
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct UserDailyVolume {
+    pub user_id: i32,
+    pub day: i64,
+    pub volume: DecimalDbType,
+}
+
 #[derive(sqlx::FromRow, Debug, Clone, Serialize)]
 pub struct TradeRecord {
     pub time: TimestampDbType,
@@ -145,6 +202,20 @@ pub struct TradeRecord {
     pub taker_side: OrderSide,
 }
 
+// a completed candle, `interval` in seconds
+#[derive(sqlx::FromRow, Debug, Clone, Serialize)]
+pub struct Kline {
+    pub time: TimestampDbType,
+    pub market: String,
+    pub interval: i64,
+    pub open: DecimalDbType,
+    pub high: DecimalDbType,
+    pub low: DecimalDbType,
+    pub close: DecimalDbType,
+    pub volume: DecimalDbType,
+    pub quote_volume: DecimalDbType,
+}
+
 /*
     Not like diesel, we still need more code for insert action here
     May be we could use macro to save these works
@@ -214,7 +285,7 @@ impl sqlxextend::TableSchemas for OrderHistory {
     fn table_name() -> &'static str {
         ORDERHISTORY
     }
-    const ARGN: i32 = 14;
+    const ARGN: i32 = 15;
     //fn default_argsn() -> Vec<i32>{ vec![1] }
 }
 
@@ -234,6 +305,7 @@ impl sqlxextend::BindQueryArg<'_, DbType> for OrderHistory {
         arg.add(&self.finished_base);
         arg.add(&self.finished_quote);
         arg.add(&self.finished_fee);
+        arg.add(&self.finished_paid_fee);
     }
 }
 
@@ -264,7 +336,7 @@ impl sqlxextend::TableSchemas for OrderSlice {
     fn table_name() -> &'static str {
         ORDERSLICE
     }
-    const ARGN: i32 = 17;
+    const ARGN: i32 = 22;
     //fn default_argsn() -> Vec<i32>{ vec![1] }
 }
 
@@ -287,18 +359,82 @@ impl sqlxextend::BindQueryArg<'_, DbType> for OrderSlice {
         arg.add(&self.finished_base);
         arg.add(&self.finished_quote);
         arg.add(&self.finished_fee);
+        arg.add(&self.finished_paid_fee);
+        arg.add(&self.display_qty);
+        arg.add(&self.visible);
+        arg.add(self.priority);
+        arg.add(self.expire_at);
     }
 }
 
 impl sqlxextend::SqlxAction<'_, sqlxextend::InsertTable, DbType> for OrderSlice {}
 
+/* --------------------- models::TriggerOrderSlice -----------------------------*/
+
+impl sqlxextend::TableSchemas for TriggerOrderSlice {
+    fn table_name() -> &'static str {
+        TRIGGERORDERSLICE
+    }
+    const ARGN: i32 = 4;
+    //fn default_argsn() -> Vec<i32>{ vec![1] }
+}
+
+impl sqlxextend::BindQueryArg<'_, DbType> for TriggerOrderSlice {
+    fn bind_args<'g, 'q: 'g>(&'q self, arg: &mut impl sqlx::Arguments<'g, Database = DbType>) {
+        arg.add(self.id);
+        arg.add(self.slice_id);
+        arg.add(&self.market);
+        arg.add(&self.params);
+    }
+}
+
+impl sqlxextend::SqlxAction<'_, sqlxextend::InsertTable, DbType> for TriggerOrderSlice {}
+
+/* --------------------- models::MarketStateSlice -----------------------------*/
+
+impl sqlxextend::TableSchemas for MarketStateSlice {
+    fn table_name() -> &'static str {
+        MARKETSTATESLICE
+    }
+    const ARGN: i32 = 3;
+}
+
+impl sqlxextend::BindQueryArg<'_, DbType> for MarketStateSlice {
+    fn bind_args<'g, 'q: 'g>(&'q self, arg: &mut impl sqlx::Arguments<'g, Database = DbType>) {
+        arg.add(self.slice_id);
+        arg.add(&self.market);
+        arg.add(&self.state);
+    }
+}
+
+impl sqlxextend::SqlxAction<'_, sqlxextend::InsertTable, DbType> for MarketStateSlice {}
+
+/* --------------------- models::AssetSlice -----------------------------*/
+
+impl sqlxextend::TableSchemas for AssetSlice {
+    fn table_name() -> &'static str {
+        ASSETSLICE
+    }
+    const ARGN: i32 = 3;
+}
+
+impl sqlxextend::BindQueryArg<'_, DbType> for AssetSlice {
+    fn bind_args<'g, 'q: 'g>(&'q self, arg: &mut impl sqlx::Arguments<'g, Database = DbType>) {
+        arg.add(self.slice_id);
+        arg.add(&self.name);
+        arg.add(&self.params);
+    }
+}
+
+impl sqlxextend::SqlxAction<'_, sqlxextend::InsertTable, DbType> for AssetSlice {}
+
 /* --------------------- models::BalanceSliceInsert -----------------------------*/
 
 impl sqlxextend::TableSchemas for BalanceSliceInsert {
     fn table_name() -> &'static str {
         BALANCESLICE
     }
-    const ARGN: i32 = 5;
+    const ARGN: i32 = 6;
     fn default_argsn() -> Vec<i32> {
         vec![1]
     }
@@ -311,11 +447,42 @@ impl sqlxextend::BindQueryArg<'_, DbType> for BalanceSliceInsert {
         arg.add(&self.asset);
         arg.add(self.t);
         arg.add(&self.balance);
+        arg.add(&self.purpose);
     }
 }
 
 impl sqlxextend::SqlxAction<'_, sqlxextend::InsertTable, DbType> for BalanceSliceInsert {}
 
+// a balance is written once per slice, so dumping a slice again is idempotent
+impl sqlxextend::UpsertSchemas for BalanceSliceInsert {
+    fn conflict_columns() -> &'static [&'static str] {
+        &["slice_id", "user_id", "asset", "t", "purpose"]
+    }
+    fn update_columns() -> &'static [&'static str] {
+        &["balance"]
+    }
+}
+
+impl sqlxextend::SqlxAction<'_, sqlxextend::UpsertTable, DbType> for BalanceSliceInsert {}
+
+/* --------------------- models::SlicePayload -----------------------------*/
+
+impl sqlxextend::TableSchemas for SlicePayload {
+    fn table_name() -> &'static str {
+        SLICEPAYLOAD
+    }
+    const ARGN: i32 = 2;
+}
+
+impl sqlxextend::BindQueryArg<'_, DbType> for SlicePayload {
+    fn bind_args<'g, 'q: 'g>(&'q self, arg: &mut impl sqlx::Arguments<'g, Database = DbType>) {
+        arg.add(self.slice_id);
+        arg.add(&self.data);
+    }
+}
+
+impl sqlxextend::SqlxAction<'_, sqlxextend::InsertTable, DbType> for SlicePayload {}
+
 /* --------------------- models::SliceHistory -----------------------------*/
 
 impl sqlxextend::TableSchemas for SliceHistory {
@@ -360,3 +527,27 @@ impl sqlxextend::BindQueryArg<'_, DbType> for TradeRecord {
 }
 
 impl sqlxextend::SqlxAction<'_, sqlxextend::InsertTable, DbType> for TradeRecord {}
+
+/* --------------------- models::Kline -----------------------------*/
+impl sqlxextend::TableSchemas for Kline {
+    fn table_name() -> &'static str {
+        KLINE
+    }
+    const ARGN: i32 = 9;
+}
+
+impl sqlxextend::BindQueryArg<'_, DbType> for Kline {
+    fn bind_args<'g, 'q: 'g>(&'q self, arg: &mut impl sqlx::Arguments<'g, Database = DbType>) {
+        arg.add(self.time);
+        arg.add(&self.market);
+        arg.add(self.interval);
+        arg.add(self.open);
+        arg.add(self.high);
+        arg.add(self.low);
+        arg.add(self.close);
+        arg.add(self.volume);
+        arg.add(self.quote_volume);
+    }
+}
+
+impl sqlxextend::SqlxAction<'_, sqlxextend::InsertTable, DbType> for Kline {}
@@ -1,5 +1,76 @@
 use std::future::Future;
 use std::pin::Pin;
+use std::time::Duration;
+use thiserror::Error;
+
+// postgres sqlstate codes
+const UNIQUE_VIOLATION: &str = "23505";
+const SERIALIZATION_FAILURE: &str = "40001";
+const DEADLOCK_DETECTED: &str = "40P01";
+// the class of connection exceptions
+const CONNECTION_EXCEPTION_CLASS: &str = "08";
+
+// serialization failures of a batch insert are retried this many times
+const WRITE_RETRIES: u32 = 3;
+const WRITE_RETRY_BACKOFF: Duration = Duration::from_millis(10);
+
+// The sqlx errors by what the caller can do about them.
+#[derive(Error, Debug)]
+pub enum StorageError {
+    #[error("unique violation: {0}")]
+    UniqueViolation(String),
+    // the connection is lost or can not be made
+    #[error("connection failure: {0}")]
+    Connection(sqlx::Error),
+    // no connection of the pool is free before the acquire timeout
+    #[error("no db connection available before the acquire timeout")]
+    PoolTimedOut,
+    // a conflict with a concurrent transaction, running it again may succeed
+    #[error("serialization failure: {0}")]
+    SerializationFailure(String),
+    #[error("row not found")]
+    NotFound,
+    #[error("db error: {0}")]
+    Other(sqlx::Error),
+}
+
+impl StorageError {
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, StorageError::SerializationFailure(_))
+    }
+}
+
+impl From<sqlx::Error> for StorageError {
+    fn from(e: sqlx::Error) -> StorageError {
+        match e {
+            sqlx::Error::RowNotFound => StorageError::NotFound,
+            sqlx::Error::PoolTimedOut => StorageError::PoolTimedOut,
+            sqlx::Error::Io(_) | sqlx::Error::Tls(_) | sqlx::Error::PoolClosed => StorageError::Connection(e),
+            sqlx::Error::Database(dberr) => {
+                let code = dberr.code().map(|code| code.into_owned()).unwrap_or_default();
+                match code.as_str() {
+                    UNIQUE_VIOLATION => StorageError::UniqueViolation(dberr.message().to_string()),
+                    SERIALIZATION_FAILURE | DEADLOCK_DETECTED => StorageError::SerializationFailure(dberr.message().to_string()),
+                    code if code.starts_with(CONNECTION_EXCEPTION_CLASS) => StorageError::Connection(sqlx::Error::Database(dberr)),
+                    _ => StorageError::Other(sqlx::Error::Database(dberr)),
+                }
+            }
+            e => StorageError::Other(e),
+        }
+    }
+}
+
+impl From<StorageError> for tonic::Status {
+    fn from(e: StorageError) -> tonic::Status {
+        match e {
+            StorageError::UniqueViolation(_) => tonic::Status::already_exists(e.to_string()),
+            StorageError::PoolTimedOut => tonic::Status::resource_exhausted(e.to_string()),
+            StorageError::SerializationFailure(_) => tonic::Status::aborted(e.to_string()),
+            StorageError::NotFound => tonic::Status::not_found(e.to_string()),
+            StorageError::Connection(_) | StorageError::Other(_) => tonic::Status::unavailable(e.to_string()),
+        }
+    }
+}
 
 pub enum SqlResultExt {
     QueryResult,
@@ -110,7 +181,7 @@ impl FinalQuery<sqlx::Postgres> for InsertTable {
         match res {
             Err(sqlx::Error::Database(dberr)) => {
                 if let Some(code) = dberr.code() {
-                    if code == "23505" {
+                    if code == UNIQUE_VIOLATION {
                         return Ok(SqlResultExt::Issue((0, "Insert no line")));
                     }
                 }
@@ -194,6 +265,42 @@ impl<T: TableSchemas> CommonSQLQuery<T, sqlx::Postgres> for InsertTable {
     }
 }
 
+/* -------- Upsert: insert, or update the columns of the row with the same key ----------- */
+
+pub trait UpsertSchemas: TableSchemas {
+    // the columns of the unique key
+    fn conflict_columns() -> &'static [&'static str];
+    // the columns updated with the values of the conflicting insert
+    fn update_columns() -> &'static [&'static str];
+}
+
+pub struct UpsertTable {}
+
+impl FinalQuery<sqlx::Postgres> for UpsertTable {
+    fn query_final(res: Result<<sqlx::Postgres as sqlx::Database>::QueryResult, sqlx::Error>) -> Result<SqlResultExt, sqlx::Error> {
+        if res?.rows_affected() != 1 {
+            Ok(SqlResultExt::Issue((0, "Upsert no line")))
+        } else {
+            Ok(SqlResultExt::QueryResult)
+        }
+    }
+}
+
+impl<T: UpsertSchemas> CommonSQLQuery<T, sqlx::Postgres> for UpsertTable {
+    fn sql_statement() -> String {
+        format!(
+            "{} ON CONFLICT ({}) DO UPDATE SET {}",
+            <InsertTable as CommonSQLQuery<T, sqlx::Postgres>>::sql_statement(),
+            T::conflict_columns().join(","),
+            T::update_columns()
+                .iter()
+                .map(|column| format!("{} = EXCLUDED.{}", column, column))
+                .collect::<Vec<String>>()
+                .join(",")
+        )
+    }
+}
+
 pub struct InsertTableBatch {}
 
 impl<'a, T, DB> BindQueryArg<'a, DB> for [T]
@@ -255,8 +362,19 @@ where
 {
 }
 
+// We split the whole array into a group of arrays with length = 2^n (or less than 8)
+// to reduce the number of cached prepared statements (which is default)
+fn batch_chunk_len(len: usize) -> usize {
+    if len < 8 {
+        return len;
+    }
+    (3..11).rev().map(|n| 1 << n).find(|size| len >= *size).unwrap()
+}
+
 impl InsertTableBatch {
-    pub async fn sql_query_fine<'c, 'a, Q, C, DB>(qr_v: &'a [Q], conn: &'c mut C) -> Result<SqlResultExt, (Vec<Q>, sqlx::Error)>
+    // On failure, the entries not inserted are returned with the error. Serialization failures
+    // are retried with backoff first.
+    pub async fn sql_query_fine<'c, 'a, Q, C, DB>(qr_v: &'a [Q], conn: &'c mut C) -> Result<SqlResultExt, (Vec<Q>, StorageError)>
     where
         DB: CommonSQLQueryWithBind,
         for<'r> &'r mut C: sqlx::Executor<'r, Database = DB>,
@@ -271,28 +389,20 @@ impl InsertTableBatch {
         //recursive in async is more difficult so put it in the loop
 
         let mut qr_vm = qr_v;
-
-        //we split the whole array into a group arrys with lengh = 2^n (or less 8)
-        //to reduce the number of cached prepare statement (which is default)
-        while qr_vm.len() >= 8 {
-            for n in (3..11).rev() {
-                if qr_vm.len() >= (1 << n) {
-                    let qr_used = &qr_vm[..(1 << n)];
-                    //log::debug!("batch {} queries", qr_used.len());
-                    if let Err(e) = Self::sql_query(qr_used, &mut *conn).await {
-                        return Err((qr_vm.to_vec(), e));
-                    }
-                    qr_vm = &qr_vm[(1 << n)..];
-                    break;
+        while !qr_vm.is_empty() {
+            let qr_used = &qr_vm[..batch_chunk_len(qr_vm.len())];
+            let mut attempt = 0;
+            //tracing::debug!("batch {} queries", qr_used.len());
+            while let Err(e) = Self::sql_query(qr_used, &mut *conn).await {
+                let e = StorageError::from(e);
+                if !e.is_retryable() || attempt >= WRITE_RETRIES {
+                    return Err((qr_vm.to_vec(), e));
                 }
+                tracing::warn!("batch insert fail: {}. retry", e);
+                tokio::time::sleep(WRITE_RETRY_BACKOFF * 2u32.pow(attempt)).await;
+                attempt += 1;
             }
-        }
-
-        if !qr_vm.is_empty() {
-            //log::debug!("batch {} queries", qr_vm.len());
-            if let Err(e) = Self::sql_query(qr_vm, &mut *conn).await {
-                return Err((qr_vm.to_vec(), e));
-            }
+            qr_vm = &qr_vm[qr_used.len()..];
         }
 
         Ok(SqlResultExt::QueryResult)
@@ -358,4 +468,86 @@ mod tests {
             "INSERT INTO just_test VALUES ($1,$2,$3) ON CONFLICT DO NOTHING"
         );
     }
+
+    // a postgres error carrying only a sqlstate
+    #[derive(Debug)]
+    struct TestDbError(&'static str);
+
+    impl std::fmt::Display for TestDbError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "sqlstate {}", self.0)
+        }
+    }
+
+    impl std::error::Error for TestDbError {}
+
+    impl sqlx::error::DatabaseError for TestDbError {
+        fn message(&self) -> &str {
+            self.0
+        }
+        fn code(&self) -> Option<std::borrow::Cow<'_, str>> {
+            Some(self.0.into())
+        }
+        fn as_error(&self) -> &(dyn std::error::Error + Send + Sync + 'static) {
+            self
+        }
+        fn as_error_mut(&mut self) -> &mut (dyn std::error::Error + Send + Sync + 'static) {
+            self
+        }
+        fn into_error(self: Box<Self>) -> Box<dyn std::error::Error + Send + Sync + 'static> {
+            self
+        }
+    }
+
+    fn db_error(code: &'static str) -> StorageError {
+        StorageError::from(sqlx::Error::Database(Box::new(TestDbError(code))))
+    }
+
+    impl UpsertSchemas for TestSchema {
+        fn conflict_columns() -> &'static [&'static str] {
+            &["id", "name"]
+        }
+        fn update_columns() -> &'static [&'static str] {
+            &["value", "time"]
+        }
+    }
+
+    #[test]
+    fn upsert_statement() {
+        assert_eq!(
+            <UpsertTable as CommonSQLQuery<TestSchema, sqlx::Postgres>>::sql_statement(),
+            "INSERT INTO just_test VALUES ($1,$2,$3) ON CONFLICT (id,name) DO UPDATE SET value = EXCLUDED.value,time = EXCLUDED.time"
+        );
+    }
+
+    #[test]
+    fn storage_error_kinds() {
+        assert!(matches!(db_error("23505"), StorageError::UniqueViolation(_)));
+        assert!(matches!(db_error("40001"), StorageError::SerializationFailure(_)));
+        assert!(matches!(db_error("40P01"), StorageError::SerializationFailure(_)));
+        assert!(matches!(db_error("08006"), StorageError::Connection(_)));
+        assert!(matches!(db_error("42P01"), StorageError::Other(_)));
+        assert!(matches!(StorageError::from(sqlx::Error::RowNotFound), StorageError::NotFound));
+        assert!(matches!(StorageError::from(sqlx::Error::PoolTimedOut), StorageError::PoolTimedOut));
+        assert!(matches!(StorageError::from(sqlx::Error::PoolClosed), StorageError::Connection(_)));
+        let io = std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset");
+        assert!(matches!(StorageError::from(sqlx::Error::Io(io)), StorageError::Connection(_)));
+
+        // only the conflicts are retried
+        assert!(db_error("40001").is_retryable());
+        assert!(!db_error("23505").is_retryable());
+        assert!(!StorageError::from(sqlx::Error::PoolClosed).is_retryable());
+
+        assert_eq!(tonic::Status::from(db_error("23505")).code(), tonic::Code::AlreadyExists);
+        assert_eq!(tonic::Status::from(StorageError::NotFound).code(), tonic::Code::NotFound);
+        assert_eq!(tonic::Status::from(db_error("40001")).code(), tonic::Code::Aborted);
+    }
+
+    #[test]
+    fn batch_chunks() {
+        assert_eq!(batch_chunk_len(5), 5);
+        assert_eq!(batch_chunk_len(8), 8);
+        assert_eq!(batch_chunk_len(100), 64);
+        assert_eq!(batch_chunk_len(5000), 1024);
+    }
 }
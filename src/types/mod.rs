@@ -32,7 +32,153 @@ pub enum OrderType {
     MARKET,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub enum TimeInForce {
+    // rest on the book until filled or canceled
+    GTC,
+    // match what you can, cancel the remainder
+    IOC,
+    // match fully or cancel entirely
+    FOK,
+    // rest on the book until filled, canceled or `expire_at`
+    GTT,
+}
+
+impl Default for TimeInForce {
+    fn default() -> Self {
+        TimeInForce::GTC
+    }
+}
+
+impl TimeInForce {
+    // whether the remainder of a limit order stays on the book
+    pub fn is_resting(self) -> bool {
+        matches!(self, TimeInForce::GTC | TimeInForce::GTT)
+    }
+}
+
+// what to do when the taker would match a resting order of the same user
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub enum SelfTradePrevention {
+    // execute the self trade as usual
+    Allow,
+    // cancel the remainder of the taker
+    CancelNewest,
+    // cancel the resting maker and continue matching
+    CancelOldest,
+    // cancel both the maker and the remainder of the taker
+    CancelBoth,
+}
+
+impl Default for SelfTradePrevention {
+    fn default() -> Self {
+        SelfTradePrevention::Allow
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub enum TriggerDirection {
+    // trigger when the last price rises to or above the trigger price
+    ABOVE,
+    // trigger when the last price falls to or below the trigger price
+    BELOW,
+}
+
+// how a price level changed in an order book update
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub enum BookUpdateType {
+    ADD,
+    MODIFY,
+    REMOVE,
+}
+
+// the category of a balance change, unknown names are kept as `Custom`
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Hash, Clone)]
+#[serde(from = "String", into = "String")]
+pub enum BusinessKind {
+    Deposit,
+    Withdraw,
+    Trade,
+    Fee,
+    // a negative maker fee credited to the maker
+    Rebate,
+    Transfer,
+    Adjustment,
+    Custom(String),
+}
+
+impl BusinessKind {
+    pub fn as_str(&self) -> &str {
+        match self {
+            BusinessKind::Deposit => "deposit",
+            BusinessKind::Withdraw => "withdraw",
+            BusinessKind::Trade => "trade",
+            BusinessKind::Fee => "fee",
+            BusinessKind::Rebate => "rebate",
+            BusinessKind::Transfer => "transfer",
+            BusinessKind::Adjustment => "adjustment",
+            BusinessKind::Custom(name) => name,
+        }
+    }
+}
+
+impl From<&str> for BusinessKind {
+    fn from(name: &str) -> BusinessKind {
+        match name {
+            "deposit" => BusinessKind::Deposit,
+            "withdraw" => BusinessKind::Withdraw,
+            "trade" => BusinessKind::Trade,
+            "fee" => BusinessKind::Fee,
+            "rebate" => BusinessKind::Rebate,
+            "transfer" => BusinessKind::Transfer,
+            "adjustment" => BusinessKind::Adjustment,
+            _ => BusinessKind::Custom(name.to_string()),
+        }
+    }
+}
+
+impl From<String> for BusinessKind {
+    fn from(name: String) -> BusinessKind {
+        BusinessKind::from(name.as_str())
+    }
+}
+
+impl From<BusinessKind> for String {
+    fn from(kind: BusinessKind) -> String {
+        kind.to_string()
+    }
+}
+
+impl std::fmt::Display for BusinessKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// stored as varchar in db
+impl sqlx::Type<DbType> for BusinessKind {
+    fn type_info() -> <DbType as sqlx::Database>::TypeInfo {
+        <String as sqlx::Type<DbType>>::type_info()
+    }
+    fn compatible(ty: &<DbType as sqlx::Database>::TypeInfo) -> bool {
+        <String as sqlx::Type<DbType>>::compatible(ty)
+    }
+}
+
+impl<'q> sqlx::Encode<'q, DbType> for BusinessKind {
+    fn encode_by_ref(&self, buf: &mut <DbType as sqlx::database::HasArguments<'q>>::ArgumentBuffer) -> sqlx::encode::IsNull {
+        <&str as sqlx::Encode<DbType>>::encode(self.as_str(), buf)
+    }
+}
+
+impl<'r> sqlx::Decode<'r, DbType> for BusinessKind {
+    fn decode(value: <DbType as sqlx::database::HasValueRef<'r>>::ValueRef) -> Result<Self, sqlx::error::BoxDynError> {
+        let name = <&str as sqlx::Decode<DbType>>::decode(value)?;
+        Ok(BusinessKind::from(name))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Trade {
     pub id: u64,
     pub timestamp: f64, // unix epoch timestamp,
@@ -47,11 +193,16 @@ pub struct Trade {
     pub ask_order_id: u64,
     pub ask_role: MarketRole, // take/make
     pub ask_fee: rust_decimal::Decimal,
+    // the asset the fee is charged in
+    #[serde(default)]
+    pub ask_fee_asset: String,
 
     pub bid_user_id: u32,
     pub bid_order_id: u64,
     pub bid_role: MarketRole,
     pub bid_fee: rust_decimal::Decimal,
+    #[serde(default)]
+    pub bid_fee_asset: String,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
@@ -61,6 +212,13 @@ pub enum OrderEventType {
     FINISH = 3,
 }
 
+// the state of an order after one of its fills
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub enum OrderFillStatus {
+    PartiallyFilled,
+    Filled,
+}
+
 //pub type DbType = diesel::mysql::Mysql;
 //pub type ConnectionType = diesel::mysql::MysqlConnection;
 pub type DbType = sqlx::Postgres;
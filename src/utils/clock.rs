@@ -0,0 +1,106 @@
+use super::{current_timestamp, FTimestamp};
+use std::cell::Cell;
+use std::rc::Rc;
+
+// The wall-clock of the engine, so the time-dependent behaviors can be tested with a clock that
+// only moves when told to.
+pub trait Clock {
+    // seconds since the unix epoch
+    fn now(&self) -> f64;
+}
+
+pub struct SystemClock;
+impl Clock for SystemClock {
+    fn now(&self) -> f64 {
+        current_timestamp()
+    }
+}
+
+pub struct MockClock {
+    now: Cell<f64>,
+}
+
+impl MockClock {
+    pub fn new(now: f64) -> MockClock {
+        MockClock { now: Cell::new(now) }
+    }
+    pub fn advance(&self, secs: f64) {
+        self.now.set(self.now.get() + secs);
+    }
+    pub fn set(&self, now: f64) {
+        self.now.set(now);
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> f64 {
+        self.now.get()
+    }
+}
+
+// Clamps the backward jumps of a clock, e.g. an NTP correction of the system clock, so the time
+// never goes back. It stands still instead until the clock catches up.
+pub struct MonotonicClock {
+    clock: Rc<dyn Clock>,
+    last: Cell<f64>,
+}
+
+impl MonotonicClock {
+    pub fn new(clock: Rc<dyn Clock>) -> MonotonicClock {
+        MonotonicClock {
+            clock,
+            last: Cell::new(f64::MIN),
+        }
+    }
+}
+
+impl Clock for MonotonicClock {
+    fn now(&self) -> f64 {
+        let now = self.clock.now().max(self.last.get());
+        self.last.set(now);
+        now
+    }
+}
+
+// Timestamps which strictly increase at microsecond resolution, so the events stamped within the
+// same microsecond, or while the clock steps back, still get distinct times in their order.
+#[derive(Default)]
+pub struct MonotonicTimestamps {
+    last_micros: i64,
+}
+
+impl MonotonicTimestamps {
+    pub fn next(&mut self, now: f64) -> f64 {
+        let micros = FTimestamp(now).as_micros().max(self.last_micros + 1);
+        self.last_micros = micros;
+        FTimestamp::from_micros(micros).0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backward_clock_jump() {
+        let clock = Rc::new(MockClock::new(1_615_379_696.0));
+        let monotonic = MonotonicClock::new(clock.clone());
+        let mut timestamps = MonotonicTimestamps::default();
+        let mut emitted = vec![monotonic.now()];
+        clock.advance(1.0);
+        emitted.push(monotonic.now());
+        // stepped back by NTP
+        clock.advance(-5.0);
+        emitted.push(monotonic.now());
+        emitted.push(monotonic.now());
+        clock.advance(10.0);
+        emitted.push(monotonic.now());
+        assert_eq!(
+            emitted,
+            vec![1_615_379_696.0, 1_615_379_697.0, 1_615_379_697.0, 1_615_379_697.0, 1_615_379_702.0]
+        );
+
+        let strict: Vec<f64> = emitted.iter().map(|now| timestamps.next(*now)).collect();
+        assert!(strict.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+}
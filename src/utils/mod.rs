@@ -1,2 +1,4 @@
+pub mod clock;
 pub mod timeutil;
+pub use clock::*;
 pub use timeutil::*;
@@ -22,8 +22,22 @@ pub fn current_naive_time() -> NaiveDateTime {
     chrono::Local::now().naive_local()
 }
 
+// Fractional seconds since the unix epoch, as the timestamps are serialized. A f64 of the seconds
+// keeps microseconds exactly, finer digits are noise, so the conversions round to microseconds.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
 pub struct FTimestamp(pub f64);
 
+pub const MICROS_PER_SEC: i64 = 1_000_000;
+
+impl FTimestamp {
+    pub fn from_micros(micros: i64) -> FTimestamp {
+        FTimestamp(micros as f64 / MICROS_PER_SEC as f64)
+    }
+    pub fn as_micros(&self) -> i64 {
+        (self.0 * MICROS_PER_SEC as f64).round() as i64
+    }
+}
+
 impl From<FTimestamp> for f64 {
     fn from(f: FTimestamp) -> f64 {
         f.0
@@ -38,19 +52,20 @@ impl From<&f64> for FTimestamp {
 
 impl From<FTimestamp> for NaiveDateTime {
     fn from(f: FTimestamp) -> NaiveDateTime {
-        NaiveDateTime::from_timestamp(f.0 as i64, ((f.0 - f.0 as i64 as f64) * 1e9) as u32)
+        let micros = f.as_micros();
+        NaiveDateTime::from_timestamp(micros.div_euclid(MICROS_PER_SEC), (micros.rem_euclid(MICROS_PER_SEC) * 1000) as u32)
     }
 }
 
 impl From<&NaiveDateTime> for FTimestamp {
     fn from(f: &NaiveDateTime) -> FTimestamp {
-        FTimestamp(f.timestamp_nanos() as f64 / 1e9)
+        FTimestamp::from_micros(f.timestamp() * MICROS_PER_SEC + f.timestamp_subsec_micros() as i64)
     }
 }
 
 impl From<&DateTime<Utc>> for FTimestamp {
     fn from(f: &DateTime<Utc>) -> FTimestamp {
-        FTimestamp(f.timestamp() as f64)
+        FTimestamp::from(&f.naive_utc())
     }
 }
 
@@ -59,3 +74,21 @@ impl From<FTimestamp> for DateTime<Utc> {
         DateTime::<Utc>::from_utc(f.into(), Utc)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timestamp_micros() {
+        let t = FTimestamp(1_615_379_696.123_456);
+        assert_eq!(t.as_micros(), 1_615_379_696_123_456);
+        let time: NaiveDateTime = t.into();
+        assert_eq!(time.timestamp_subsec_nanos(), 123_456_000);
+        assert_eq!(FTimestamp::from(&time), t);
+        assert_eq!(FTimestamp::from_micros(1_615_379_696_123_457).0, 1_615_379_696.123_457);
+        // before the epoch the fraction is still positive in the NaiveDateTime
+        let time: NaiveDateTime = FTimestamp(-0.5).into();
+        assert_eq!((time.timestamp(), time.timestamp_subsec_micros()), (-1, 500_000));
+    }
+}